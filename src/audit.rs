@@ -0,0 +1,95 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// A mutating action and how it ended
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// e.g. create_volume or expand_filesystem
+    pub action: String,
+    pub params: serde_json::Value,
+    pub outcome: AuditOutcome,
+    /// What the action returned, such as the new volume id, on success
+    pub result: serde_json::Value,
+    pub error: Option<String>,
+}
+
+/// Append-only JSONL trail of every mutating action
+///
+/// Each entry is synced to disk before the action it records returns. Unlike the
+/// utilization log it is never rotated, so it can be kept as an audit record.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> AuditLog {
+        AuditLog { path: PathBuf::from(path) }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), io::Error> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+
+    /// Records the outcome of action, warning rather than failing when the log cannot
+    /// be written, as the action already happened
+    pub fn record<T: Serialize, E: fmt::Display>(
+        &self,
+        action: &str,
+        params: serde_json::Value,
+        result: &Result<T, E>,
+    ) {
+        let (outcome, result, error) = match result {
+            Ok(value) => (
+                AuditOutcome::Success,
+                serde_json::to_value(value).unwrap_or_default(),
+                None,
+            ),
+            Err(e) => (AuditOutcome::Failure, serde_json::Value::Null, Some(e.to_string())),
+        };
+        let entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default(),
+            action: action.to_string(),
+            params,
+            outcome,
+            result,
+            error,
+        };
+        if let Err(e) = self.append(&entry) {
+            warn!("Could not write {} to the audit log {} - {}", action, self.path.display(), e);
+        }
+    }
+
+    /// Entries written so far, oldest first
+    pub fn read(&self) -> Result<Vec<AuditEntry>, io::Error> {
+        fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(io::Error::from))
+            .collect()
+    }
+}
@@ -14,107 +14,1839 @@
  * limitations under the License.
  */
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use aws_config::{BehaviorVersion, SdkConfig};
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::imds;
+use aws_sdk_ec2::config::{Region, SharedCredentialsProvider};
+use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::operation::RequestId;
+use aws_sdk_ec2::operation::create_volume::CreateVolumeOutput;
+use aws_sdk_ec2::operation::create_volume::builders::CreateVolumeFluentBuilder;
+use aws_sdk_ec2::types::{
+    EbsInstanceBlockDeviceSpecification, Filter, InstanceBlockDeviceMappingSpecification,
+    ResourceType, Tag, TagSpecification, Volume, VolumeAttachmentState, VolumeModificationState,
+    VolumeState, VolumeType,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::runtime;
 
-#[derive(Debug, Clone)]
-pub struct GenericAWSError;
+use crate::clock::Clock;
+use crate::config;
+use crate::size::SizeGib;
+
+const ASSUME_ROLE_SESSION_NAME: &str = "ebs-autoscale-rust";
+/// How often, and how many times, a detaching volume is checked before giving up
+const DETACH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const DETACH_POLL_ATTEMPTS: u32 = 60;
+/// How often, and how many times, an attaching volume is checked before giving up
+const ATTACH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const ATTACH_POLL_ATTEMPTS: u32 = 60;
+
+/// Tag marking the volumes created by this tool
+pub const MANAGED_BY_TAG: &str = "managed-by";
+pub const MANAGED_BY_TAG_VALUE: &str = "ebs-autoscale-rust";
+/// Tag marking the volumes to delete when the instance terminates
+pub const DELETE_ON_TERM_TAG: &str = "delete-on-termination";
+pub const DELETE_ON_TERM_TAG_VALUE: &str = "true";
+/// Tag naming the instance a volume was created for, which stays set while the volume
+/// is detached
+pub const OWNER_TAG: &str = "owner-instance-id";
+/// Tag shared by every resource a scale event creates
+pub const SCALE_EVENT_ID_TAG: &str = "scale-event-id";
+
+#[derive(Debug, Clone, Default)]
+pub struct GenericAWSError {
+    /// API call that failed, e.g. DescribeVolumes
+    pub operation: Option<String>,
+    /// Request id AWS returned for the failed call, which AWS support asks for
+    pub request_id: Option<String>,
+}
 
 impl Error for GenericAWSError {}
 
 impl fmt::Display for GenericAWSError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error calling AWS API")
+        write!(f, "Error calling AWS API")?;
+        if let Some(operation) = &self.operation {
+            write!(f, " {}", operation)?;
+        }
+        if let Some(request_id) = &self.request_id {
+            write!(f, " (request id {})", request_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs a failed call and turns it into a GenericAWSError keeping its request id, which
+/// is also logged when log_request_id is set
+pub fn call_failed<E: RequestId + fmt::Display>(
+    operation: &str,
+    e: &E,
+    log_request_id: bool,
+) -> Box<GenericAWSError> {
+    let request_id = e.request_id().map(str::to_string);
+    match (&request_id, log_request_id) {
+        (Some(request_id), true) => {
+            warn!("{} failed (request id {}) - {}", operation, request_id, e)
+        }
+        _ => warn!("{} failed - {}", operation, e),
+    }
+    Box::new(GenericAWSError { operation: Some(operation.to_string()), request_id })
+}
+
+/// An EBS volume created by this tool, as reported by DescribeVolumes
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ManagedVolume {
+    pub volume_id: String,
+    pub size_gib: SizeGib,
+    pub vol_type: String,
+    pub state: String,
+    pub iops: Option<u64>,
+    /// Throughput in MiB/s
+    pub throughput: Option<u64>,
+    /// Device name of the attachment, if attached
+    pub device: Option<String>,
+    /// Instance the volume is attached to, if attached
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    pub multi_attach_enabled: bool,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl ManagedVolume {
+    pub fn has_tag(&self, key: &str, value: &str) -> bool {
+        self.tags.get(key).is_some_and(|tag_value| tag_value == value)
+    }
+
+    /// Whether the volume carries the managed-by tag, i.e. was created by this tool
+    pub fn is_managed(&self) -> bool {
+        self.has_tag(MANAGED_BY_TAG, MANAGED_BY_TAG_VALUE)
+    }
+
+    pub fn is_tagged_delete_on_term(&self) -> bool {
+        self.has_tag(DELETE_ON_TERM_TAG, DELETE_ON_TERM_TAG_VALUE)
+    }
+
+    /// Whether the volume is attached to the given instance
+    pub fn is_attached_to(&self, instance_id: &str) -> bool {
+        self.instance_id.as_deref() == Some(instance_id)
+    }
+
+    /// Whether the volume belongs to the given instance: attached to it, or detached
+    /// and created for it
+    pub fn belongs_to(&self, instance_id: &str) -> bool {
+        match &self.instance_id {
+            Some(attached_to) => attached_to == instance_id,
+            None => self.has_tag(OWNER_TAG, instance_id),
+        }
     }
 }
 
-pub trait AWS {
+impl From<&Volume> for ManagedVolume {
+    fn from(volume: &Volume) -> ManagedVolume {
+        ManagedVolume {
+            volume_id: volume.volume_id().unwrap_or_default().to_string(),
+            size_gib: SizeGib(volume.size().unwrap_or_default().max(0) as u64),
+            vol_type: volume.volume_type()
+                .map(|vol_type| vol_type.as_str().to_string())
+                .unwrap_or_default(),
+            state: volume.state()
+                .map(|state| state.as_str().to_string())
+                .unwrap_or_default(),
+            iops: volume.iops().map(|iops| iops.max(0) as u64),
+            throughput: volume.throughput().map(|throughput| throughput.max(0) as u64),
+            device: volume.attachments()
+                .first()
+                .and_then(|attachment| attachment.device())
+                .map(|device| device.to_string()),
+            instance_id: volume.attachments()
+                .first()
+                .and_then(|attachment| attachment.instance_id())
+                .map(str::to_string),
+            multi_attach_enabled: volume.multi_attach_enabled().unwrap_or(false),
+            tags: volume.tags()
+                .iter()
+                .filter_map(|tag| Some((tag.key()?.to_string(), tag.value()?.to_string())))
+                .collect(),
+        }
+    }
+}
+
+/// Whether an EC2 error code means the request was throttled
+pub fn is_throttling_code(code: Option<&str>) -> bool {
+    matches!(code, Some("RequestLimitExceeded") | Some("Throttling") | Some("ThrottlingException"))
+}
+
+/// A volume to create, as passed to request_ebs_volume
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeRequest {
+    pub size: SizeGib,
+    pub vol_type: String,
+    pub encrypted: bool,
+    pub iops: Option<u64>,
+    pub throughput: Option<u64>,
+    pub name: Option<String>,
+}
+
+/// Identity of the instance the autoscaler runs on, from IMDS
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceIdentity {
+    pub instance_id: String,
+    pub region: String,
+    pub availability_zone: String,
+}
+
+/// Progress of the latest ModifyVolume on a volume
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModificationState {
+    Modifying,
+    /// The new size is usable, but performance may be degraded until completed
+    Optimizing,
+    Completed,
+    Failed,
+}
+
+impl ModificationState {
+    /// Whether another ModifyVolume would be refused
+    pub fn in_progress(&self) -> bool {
+        matches!(self, ModificationState::Modifying | ModificationState::Optimizing)
+    }
+}
+
+impl From<&VolumeModificationState> for ModificationState {
+    fn from(state: &VolumeModificationState) -> ModificationState {
+        match state {
+            VolumeModificationState::Modifying => ModificationState::Modifying,
+            VolumeModificationState::Optimizing => ModificationState::Optimizing,
+            VolumeModificationState::Completed => ModificationState::Completed,
+            _ => ModificationState::Failed,
+        }
+    }
+}
+
+/// EC2 actions the autoscaler needs, checked on startup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ec2Action {
+    DescribeVolumes,
+    CreateVolume,
+    AttachVolume,
+}
+
+impl Ec2Action {
+    /// Name of the action in IAM policies
+    pub fn iam_name(&self) -> &'static str {
+        match self {
+            Ec2Action::DescribeVolumes => "ec2:DescribeVolumes",
+            Ec2Action::CreateVolume => "ec2:CreateVolume",
+            Ec2Action::AttachVolume => "ec2:AttachVolume",
+        }
+    }
+}
+
+/// Result of checking whether the credentials allow an action
+#[derive(Debug, Clone, PartialEq)]
+pub enum PermissionProbe {
+    Allowed,
+    Denied,
+    /// The check itself failed, so the permission is unknown
+    Inconclusive(String),
+}
+
+impl PermissionProbe {
+    /// Maps the error code of a (dry-run) call to a probe result
+    ///
+    /// Dry runs never succeed: DryRunOperation means the call would have been allowed.
+    pub fn from_error_code(code: Option<&str>, message: String) -> PermissionProbe {
+        match code {
+            Some("DryRunOperation") => PermissionProbe::Allowed,
+            Some("UnauthorizedOperation") | Some("AccessDenied") => PermissionProbe::Denied,
+            _ => PermissionProbe::Inconclusive(message),
+        }
+    }
+}
+
+/// Where the EC2 client gets its credentials from
+#[derive(Debug, Clone, PartialEq)]
+pub enum CredentialSource {
+    DefaultChain,
+    Profile(String),
+    /// Assume a role, using the profile (or the default chain) as source credentials
+    AssumeRole {
+        role_arn: String,
+        profile: Option<String>,
+    },
+}
+
+impl CredentialSource {
+    pub fn from_config(conf: &config::Config) -> CredentialSource {
+        match (&conf.assume_role_arn, &conf.aws_profile) {
+            (Some(role_arn), profile) => CredentialSource::AssumeRole {
+                role_arn: role_arn.clone(),
+                profile: profile.clone(),
+            },
+            (None, Some(profile)) => CredentialSource::Profile(profile.clone()),
+            (None, None) => CredentialSource::DefaultChain,
+        }
+    }
+
+    /// Profile used to load the base SDK config, if any
+    pub fn profile(&self) -> Option<&str> {
+        match self {
+            CredentialSource::DefaultChain => None,
+            CredentialSource::Profile(profile) => Some(profile),
+            CredentialSource::AssumeRole { profile, .. } => profile.as_deref(),
+        }
+    }
+}
+
+/// Builds the SDK config shared by the EC2 and SNS clients for a credential source
+///
+/// The region override, when set, takes precedence over the default region chain.
+pub async fn load_sdk_config(
+    source: &CredentialSource,
+    region: Option<String>,
+) -> SdkConfig {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(profile) = source.profile() {
+        loader = loader.profile_name(profile);
+    }
+    if let Some(region) = region {
+        loader = loader.region(Region::new(region));
+    }
+    let sdk_config = loader.load().await;
+    match source {
+        CredentialSource::AssumeRole { role_arn, .. } => {
+            let provider = AssumeRoleProvider::builder(role_arn.clone())
+                .session_name(ASSUME_ROLE_SESSION_NAME)
+                .configure(&sdk_config)
+                .build()
+                .await;
+            sdk_config.into_builder()
+                .credentials_provider(SharedCredentialsProvider::new(provider))
+                .build()
+        }
+        _ => sdk_config,
+    }
+}
+
+/// SDK config of the EC2 client: the shared config with endpoint_url as its endpoint
+/// when set, e.g. for GovCloud or China, still using the configured region
+///
+/// SNS keeps the regional endpoint of the shared config.
+pub fn ec2_sdk_config(sdk_config: &SdkConfig, endpoint_url: Option<&str>) -> SdkConfig {
+    match endpoint_url {
+        Some(endpoint_url) => sdk_config.clone().into_builder().endpoint_url(endpoint_url).build(),
+        None => sdk_config.clone(),
+    }
+}
+
+/// Tags of this instance, read through IMDS
+///
+/// Needs instance metadata tags enabled on the instance; without them IMDS has no tags.
+pub fn instance_tags() -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let imds_client = imds::Client::builder().build();
+    let keys = runtime.block_on(imds_client.get("/latest/meta-data/tags/instance"))?;
+    let mut tags = BTreeMap::new();
+    for key in keys.as_ref().lines().filter(|key| !key.is_empty()) {
+        let path = format!("/latest/meta-data/tags/instance/{}", key);
+        let value = runtime.block_on(imds_client.get(path))?;
+        tags.insert(key.to_string(), value.as_ref().to_string());
+    }
+    Ok(tags)
+}
+
+pub trait AWS: Send {
+    /// Creates a volume, tagged with name as its Name tag if set
     fn request_ebs_volume(
-        &mut self, size: u64, vol_type: String,
-        encrypted: bool, throughput: u64,
+        &mut self, size: SizeGib, vol_type: String,
+        encrypted: bool, iops: Option<u64>, throughput: Option<u64>, name: Option<String>,
+    ) -> Result<String, Box<GenericAWSError>>;
+    /// Creates several volumes, returning the result of each in the order requested
+    ///
+    /// Clients that can have the requests in flight at the same time do so; the others
+    /// create the volumes one after another.
+    fn request_ebs_volumes(
+        &mut self, requests: Vec<VolumeRequest>,
+    ) -> Vec<Result<String, Box<GenericAWSError>>> {
+        requests.into_iter()
+            .map(|request| self.request_ebs_volume(
+                request.size,
+                request.vol_type,
+                request.encrypted,
+                request.iops,
+                request.throughput,
+                request.name,
+            ))
+            .collect()
+    }
+    /// State of a volume, e.g. creating or available
+    fn volume_state(&mut self, volume_id: String) -> Result<String, Box<GenericAWSError>>;
+    /// Attaches a volume as device, returning the device
+    fn attach_ebs_volume(
+        &mut self, volume_id: String, device: String,
     ) -> Result<String, Box<GenericAWSError>>;
-    fn attach_ebs_volume(&mut self, device: String) -> Result<String, Box<GenericAWSError>>;
-    fn get_managed_ebs_volumes(&mut self);
-    fn count_mounted_ebs_volumes(&mut self) -> u32;
-    fn delete_ebs_volume(&mut self);
-    fn tag_as_delete_on_term(&mut self, device: String) -> Result<String, Box<GenericAWSError>>;
+    /// Volumes carrying the managed-by tag
+    fn get_managed_ebs_volumes(&mut self) -> Result<Vec<ManagedVolume>, Box<GenericAWSError>>;
+    /// Managed volumes attached to this instance
+    fn count_mounted_ebs_volumes(&mut self) -> Result<u32, Box<GenericAWSError>>;
+    /// Size of all the account's volumes of a type in the region, managed or not
+    fn account_storage_gib(&mut self, vol_type: String) -> Result<SizeGib, Box<GenericAWSError>>;
+    /// Whether the account encrypts every new volume in the region, whatever CreateVolume
+    /// asks for
+    fn ebs_encryption_by_default(&mut self) -> Result<bool, Box<GenericAWSError>>;
+    fn delete_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>>;
+    /// Detaches a volume and waits until it is available, so it can be deleted
+    fn detach_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>>;
+    /// Grows a volume in place to size GiB with ModifyVolume
+    fn modify_volume_size(
+        &mut self, volume_id: String, size: SizeGib,
+    ) -> Result<(), Box<GenericAWSError>>;
+    /// Changes the type of a volume in place with ModifyVolume, provisioning iops
+    fn modify_volume_type(
+        &mut self, volume_id: String, vol_type: String, iops: u64,
+    ) -> Result<(), Box<GenericAWSError>>;
+    /// State of the latest modification of a volume, None if it was never modified
+    fn volume_modification_state(
+        &mut self, volume_id: String,
+    ) -> Result<Option<ModificationState>, Box<GenericAWSError>>;
+    /// Sets DeleteOnTermination on the block device mapping of an attached device
+    fn set_delete_on_termination(
+        &mut self, device: String, flag: bool,
+    ) -> Result<(), Box<GenericAWSError>>;
+    /// Instance id, region and availability zone from IMDS
+    fn instance_identity(&mut self) -> Result<InstanceIdentity, Box<GenericAWSError>>;
+    /// Why the instance is being terminated, from the IMDS spot interruption or Auto
+    /// Scaling lifecycle notice, None without a notice
+    fn termination_notice(&mut self) -> Option<String>;
+    /// Publishes a message to an SNS topic
+    fn publish_event(
+        &mut self, topic_arn: String, message: String,
+    ) -> Result<(), Box<GenericAWSError>>;
+    /// Sets a tag on an instance, replacing its previous value
+    fn tag_instance(
+        &mut self, instance_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>>;
+    /// Sets a tag on a volume, replacing its previous value
+    fn tag_volume(
+        &mut self, volume_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>>;
+    /// Checks the credentials allow an action, using dry runs for mutating actions
+    fn probe_permission(
+        &mut self, action: Ec2Action, identity: Option<&InstanceIdentity>,
+    ) -> PermissionProbe;
+    /// Throttled calls in a row, reset by the next call that is not throttled
+    fn consecutive_throttles(&self) -> u32;
 }
 
-pub struct ConcreteAWS {}
+/// Tags a volume is created with: the managed-by and owner tags, its Name if set and
+/// the cost-allocation tags
+pub fn volume_tags(
+    instance_id: &str,
+    name: Option<&str>,
+    cost_allocation_tags: &BTreeMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut tags = vec![
+        (MANAGED_BY_TAG.to_string(), MANAGED_BY_TAG_VALUE.to_string()),
+        (OWNER_TAG.to_string(), instance_id.to_string()),
+    ];
+    if let Some(name) = name {
+        tags.push(("Name".to_string(), name.to_string()));
+    }
+    tags.extend(cost_allocation_tags.iter().map(|(key, value)| (key.clone(), value.clone())));
+    tags
+}
+
+/// volume_tags as the tag specification of a CreateVolume request
+pub fn volume_tag_specification(
+    instance_id: &str,
+    name: Option<&str>,
+    cost_allocation_tags: &BTreeMap<String, String>,
+) -> TagSpecification {
+    let tags = volume_tags(instance_id, name, cost_allocation_tags)
+        .into_iter()
+        .map(|(key, value)| Tag::builder().key(key).value(value).build())
+        .collect();
+    TagSpecification::builder()
+        .resource_type(ResourceType::Volume)
+        .set_tags(Some(tags))
+        .build()
+}
+
+pub struct ConcreteAWS {
+    runtime: runtime::Runtime,
+    client: aws_sdk_ec2::Client,
+    #[cfg(feature = "sns")]
+    sns_client: aws_sdk_sns::Client,
+    consecutive_throttles: u32,
+    log_request_ids: bool,
+    cost_allocation_tags: BTreeMap<String, String>,
+}
+
+impl ConcreteAWS {
+    pub fn new(conf: &config::Config) -> Result<ConcreteAWS, Box<dyn Error>> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let source = CredentialSource::from_config(conf);
+        let sdk_config = runtime.block_on(load_sdk_config(&source, conf.region.clone()));
+        Ok(ConcreteAWS {
+            runtime,
+            client: aws_sdk_ec2::Client::new(
+                &ec2_sdk_config(&sdk_config, conf.ec2_endpoint_url.as_deref())
+            ),
+            #[cfg(feature = "sns")]
+            sns_client: aws_sdk_sns::Client::new(&sdk_config),
+            consecutive_throttles: 0,
+            log_request_ids: conf.log_aws_request_ids,
+            cost_allocation_tags: conf.cost_allocation_tags.clone(),
+        })
+    }
+
+    fn track_throttling<T, E: ProvideErrorMetadata>(&mut self, result: &Result<T, E>) {
+        match result {
+            Err(e) if is_throttling_code(e.code()) => self.consecutive_throttles += 1,
+            _ => self.consecutive_throttles = 0,
+        }
+    }
+
+    /// CreateVolume call for a volume in the availability zone of the instance
+    fn create_volume_call(
+        &self, identity: &InstanceIdentity, request: VolumeRequest,
+    ) -> Result<CreateVolumeFluentBuilder, Box<GenericAWSError>> {
+        let size: i32 = request.size.as_u64().try_into().map_err(|_| {
+            warn!("{} is too large for CreateVolume", request.size);
+            Box::new(GenericAWSError {
+                operation: Some("CreateVolume".to_string()),
+                request_id: None,
+            })
+        })?;
+        Ok(self.client.create_volume()
+            .availability_zone(&identity.availability_zone)
+            .volume_type(VolumeType::from(request.vol_type.as_str()))
+            .size(size)
+            .encrypted(request.encrypted)
+            .set_iops(request.iops.and_then(|iops| iops.try_into().ok()))
+            .set_throughput(request.throughput.and_then(|throughput| throughput.try_into().ok()))
+            .tag_specifications(volume_tag_specification(
+                &identity.instance_id, request.name.as_deref(), &self.cost_allocation_tags,
+            )))
+    }
+
+    /// Volume id of a sent CreateVolume call
+    fn created_volume_id<E: ProvideErrorMetadata + RequestId + fmt::Display>(
+        &mut self, result: Result<CreateVolumeOutput, E>,
+    ) -> Result<String, Box<GenericAWSError>> {
+        self.track_throttling(&result);
+        let output = result.map_err(|e| call_failed("CreateVolume", &e, self.log_request_ids))?;
+        output.volume_id().map(str::to_string).ok_or_else(Box::<GenericAWSError>::default)
+    }
+}
 
 impl AWS for ConcreteAWS {
     fn request_ebs_volume(
-        &mut self, size: u64, vol_type: String,
-        encrypted: bool, throughput: u64
+        &mut self, size: SizeGib, vol_type: String,
+        encrypted: bool, iops: Option<u64>, throughput: Option<u64>, name: Option<String>,
     ) -> Result<String, Box<GenericAWSError>> {
-        Ok("/dev/test".to_string())
+        let identity = self.instance_identity()?;
+        let request = VolumeRequest { size, vol_type, encrypted, iops, throughput, name };
+        let call = self.create_volume_call(&identity, request)?;
+        let result = self.runtime.block_on(call.send());
+        self.created_volume_id(result)
     }
-    fn attach_ebs_volume(&mut self, device: String) -> Result<String, Box<GenericAWSError>> {
-        Ok("/dev/test".to_string())
+
+    fn request_ebs_volumes(
+        &mut self, requests: Vec<VolumeRequest>,
+    ) -> Vec<Result<String, Box<GenericAWSError>>> {
+        let identity = match self.instance_identity() {
+            Ok(identity) => identity,
+            Err(e) => return requests.iter().map(|_| Err(e.clone())).collect(),
+        };
+        let calls: Vec<_> = requests.into_iter()
+            .map(|request| self.create_volume_call(&identity, request))
+            .collect();
+        // Spawned before any is awaited, so the requests are in flight together
+        let sent = self.runtime.block_on(async {
+            let tasks: Vec<_> = calls.into_iter()
+                .map(|call| call.map(|call| tokio::spawn(call.send())))
+                .collect();
+            let mut sent = Vec::new();
+            for task in tasks {
+                sent.push(match task {
+                    Ok(task) => Ok(task.await),
+                    Err(e) => Err(e),
+                });
+            }
+            sent
+        });
+        sent.into_iter()
+            .map(|sent| {
+                let result = sent?.map_err(|e| {
+                    warn!("CreateVolume task failed - {}", e);
+                    Box::new(GenericAWSError {
+                        operation: Some("CreateVolume".to_string()),
+                        request_id: None,
+                    })
+                })?;
+                self.created_volume_id(result)
+            })
+            .collect()
+    }
+    fn volume_state(&mut self, volume_id: String) -> Result<String, Box<GenericAWSError>> {
+        let result = self.runtime.block_on(
+            self.client.describe_volumes()
+                .volume_ids(volume_id)
+                .send()
+        );
+        self.track_throttling(&result);
+        let output = result.map_err(|e| call_failed("DescribeVolumes", &e, self.log_request_ids))?;
+        output.volumes()
+            .first()
+            .and_then(|volume| volume.state())
+            .map(|state| state.as_str().to_string())
+            .ok_or_else(Box::<GenericAWSError>::default)
+    }
+    fn attach_ebs_volume(
+        &mut self, volume_id: String, device: String,
+    ) -> Result<String, Box<GenericAWSError>> {
+        let instance_id = self.instance_identity()?.instance_id;
+        let result = self.runtime.block_on(
+            self.client.attach_volume()
+                .volume_id(volume_id.clone())
+                .instance_id(instance_id)
+                .device(device.clone())
+                .send()
+        );
+        self.track_throttling(&result);
+        let output = result.map_err(|e| call_failed("AttachVolume", &e, self.log_request_ids))?;
+        let device = output.device().map(str::to_string).unwrap_or(device);
+        for _ in 0..ATTACH_POLL_ATTEMPTS {
+            let result = self.runtime.block_on(
+                self.client.describe_volumes()
+                    .volume_ids(volume_id.clone())
+                    .send()
+            );
+            self.track_throttling(&result);
+            let output = result
+                .map_err(|e| call_failed("DescribeVolumes", &e, self.log_request_ids))?;
+            let attached = output.volumes()
+                .first()
+                .and_then(|volume| volume.attachments().first())
+                .and_then(|attachment| attachment.state())
+                .is_some_and(|state| *state == VolumeAttachmentState::Attached);
+            if attached {
+                return Ok(device);
+            }
+            thread::sleep(ATTACH_POLL_INTERVAL);
+        }
+        warn!("{} was still attaching after {} checks", volume_id, ATTACH_POLL_ATTEMPTS);
+        Err(Box::<GenericAWSError>::default())
+    }
+    fn get_managed_ebs_volumes(&mut self) -> Result<Vec<ManagedVolume>, Box<GenericAWSError>> {
+        let filter = Filter::builder()
+            .name(format!("tag:{}", MANAGED_BY_TAG))
+            .values(MANAGED_BY_TAG_VALUE)
+            .build();
+        let result = self.runtime.block_on(
+            self.client.describe_volumes()
+                .filters(filter)
+                .send()
+        );
+        self.track_throttling(&result);
+        let output = result.map_err(|e| call_failed("DescribeVolumes", &e, self.log_request_ids))?;
+        Ok(output.volumes().iter().map(ManagedVolume::from).collect())
+    }
+    fn delete_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        let result = self.runtime.block_on(
+            self.client.delete_volume()
+                .volume_id(volume_id)
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("DeleteVolume", &e, self.log_request_ids))?;
+        Ok(())
+    }
+
+    fn detach_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        let result = self.runtime.block_on(
+            self.client.detach_volume()
+                .volume_id(volume_id.clone())
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("DetachVolume", &e, self.log_request_ids))?;
+        for _ in 0..DETACH_POLL_ATTEMPTS {
+            let result = self.runtime.block_on(
+                self.client.describe_volumes()
+                    .volume_ids(volume_id.clone())
+                    .send()
+            );
+            self.track_throttling(&result);
+            let output = result
+                .map_err(|e| call_failed("DescribeVolumes", &e, self.log_request_ids))?;
+            let available = output.volumes()
+                .first()
+                .and_then(|volume| volume.state())
+                .is_some_and(|state| *state == VolumeState::Available);
+            if available {
+                return Ok(());
+            }
+            thread::sleep(DETACH_POLL_INTERVAL);
+        }
+        warn!("{} was still detaching after {} checks", volume_id, DETACH_POLL_ATTEMPTS);
+        Err(Box::<GenericAWSError>::default())
+    }
+
+    fn count_mounted_ebs_volumes(&mut self) -> Result<u32, Box<GenericAWSError>> {
+        let instance_id = self.instance_identity()?.instance_id;
+        let mut count: usize = 0;
+        let mut next_token = None;
+        loop {
+            let managed = Filter::builder()
+                .name(format!("tag:{}", MANAGED_BY_TAG))
+                .values(MANAGED_BY_TAG_VALUE)
+                .build();
+            let attached = Filter::builder()
+                .name("attachment.instance-id")
+                .values(instance_id.clone())
+                .build();
+            let result = self.runtime.block_on(
+                self.client.describe_volumes()
+                    .filters(managed)
+                    .filters(attached)
+                    .set_next_token(next_token)
+                    .send()
+            );
+            self.track_throttling(&result);
+            let output = result
+                .map_err(|e| call_failed("DescribeVolumes", &e, self.log_request_ids))?;
+            count += output.volumes().len();
+            next_token = output.next_token().map(str::to_string);
+            if next_token.is_none() {
+                return Ok(count.try_into().unwrap_or(u32::MAX));
+            }
+        }
+    }
+    fn account_storage_gib(&mut self, vol_type: String) -> Result<SizeGib, Box<GenericAWSError>> {
+        let mut total = 0;
+        let mut next_token = None;
+        loop {
+            let filter = Filter::builder()
+                .name("volume-type")
+                .values(vol_type.clone())
+                .build();
+            let result = self.runtime.block_on(
+                self.client.describe_volumes()
+                    .filters(filter)
+                    .set_next_token(next_token)
+                    .send()
+            );
+            self.track_throttling(&result);
+            let output = result
+                .map_err(|e| call_failed("DescribeVolumes", &e, self.log_request_ids))?;
+            total += output.volumes()
+                .iter()
+                .map(|volume| volume.size().unwrap_or_default().max(0) as u64)
+                .sum::<u64>();
+            next_token = output.next_token().map(str::to_string);
+            if next_token.is_none() {
+                return Ok(SizeGib(total));
+            }
+        }
+    }
+    fn ebs_encryption_by_default(&mut self) -> Result<bool, Box<GenericAWSError>> {
+        let result = self.runtime.block_on(self.client.get_ebs_encryption_by_default().send());
+        self.track_throttling(&result);
+        let output = result
+            .map_err(|e| call_failed("GetEbsEncryptionByDefault", &e, self.log_request_ids))?;
+        Ok(output.ebs_encryption_by_default().unwrap_or(false))
+    }
+    fn set_delete_on_termination(
+        &mut self, device: String, flag: bool,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let instance_id = self.instance_identity()?.instance_id;
+        let mapping = InstanceBlockDeviceMappingSpecification::builder()
+            .device_name(device)
+            .ebs(EbsInstanceBlockDeviceSpecification::builder()
+                .delete_on_termination(flag)
+                .build())
+            .build();
+        let result = self.runtime.block_on(
+            self.client.modify_instance_attribute()
+                .instance_id(instance_id)
+                .block_device_mappings(mapping)
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("ModifyInstanceAttribute", &e, self.log_request_ids))?;
+        Ok(())
+    }
+
+    fn instance_identity(&mut self) -> Result<InstanceIdentity, Box<GenericAWSError>> {
+        let imds_client = imds::Client::builder().build();
+        let get = |path: &'static str| {
+            self.runtime.block_on(imds_client.get(path))
+                .map(|value| value.as_ref().to_string())
+                .map_err(|e| {
+                    warn!("Could not read {} from IMDS - {}", path, e);
+                    Box::<GenericAWSError>::default()
+                })
+        };
+        Ok(InstanceIdentity {
+            instance_id: get("/latest/meta-data/instance-id")?,
+            region: get("/latest/meta-data/placement/region")?,
+            availability_zone: get("/latest/meta-data/placement/availability-zone")?,
+        })
+    }
+
+    fn termination_notice(&mut self) -> Option<String> {
+        let imds_client = imds::Client::builder().build();
+        // Both paths are missing (404) until a termination is scheduled
+        let get = |path: &'static str| {
+            self.runtime.block_on(imds_client.get(path))
+                .ok()
+                .map(|value| value.as_ref().to_string())
+        };
+        if let Some(action) = get("/latest/meta-data/spot/instance-action") {
+            let action: serde_json::Value = serde_json::from_str(&action).unwrap_or_default();
+            if action["action"] == "terminate" {
+                return Some("spot interruption".to_string());
+            }
+        }
+        match get("/latest/meta-data/autoscaling/target-lifecycle-state") {
+            Some(state) if state == "Terminated" => Some("Auto Scaling termination".to_string()),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "sns")]
+    fn publish_event(
+        &mut self, topic_arn: String, message: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.runtime.block_on(
+            self.sns_client.publish()
+                .topic_arn(topic_arn)
+                .message(message)
+                .send()
+        ).map_err(|e| call_failed("SNS Publish", &e, self.log_request_ids))?;
+        Ok(())
+    }
+
+    /// Config::validate refuses sns_topic_arn without the sns feature, so this is not reached
+    #[cfg(not(feature = "sns"))]
+    fn publish_event(
+        &mut self, _topic_arn: String, _message: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        warn!("Built without the sns feature - not publishing");
+        Err(Box::new(GenericAWSError {
+            operation: Some("SNS Publish".to_string()),
+            request_id: None,
+        }))
+    }
+
+    fn modify_volume_size(
+        &mut self, volume_id: String, size: SizeGib,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let size = i32::try_from(size.as_u64()).map_err(|_| Box::<GenericAWSError>::default())?;
+        let result = self.runtime.block_on(
+            self.client.modify_volume()
+                .volume_id(volume_id)
+                .size(size)
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("ModifyVolume", &e, self.log_request_ids))?;
+        Ok(())
+    }
+
+    fn modify_volume_type(
+        &mut self, volume_id: String, vol_type: String, iops: u64,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let iops = i32::try_from(iops).map_err(|_| Box::<GenericAWSError>::default())?;
+        let result = self.runtime.block_on(
+            self.client.modify_volume()
+                .volume_id(volume_id)
+                .volume_type(VolumeType::from(vol_type.as_str()))
+                .iops(iops)
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("ModifyVolume", &e, self.log_request_ids))?;
+        Ok(())
+    }
+
+    fn volume_modification_state(
+        &mut self, volume_id: String,
+    ) -> Result<Option<ModificationState>, Box<GenericAWSError>> {
+        let result = self.runtime.block_on(
+            self.client.describe_volumes_modifications()
+                .volume_ids(volume_id)
+                .send()
+        );
+        self.track_throttling(&result);
+        let output = match result {
+            Ok(output) => output,
+            Err(e) if e.code() == Some("InvalidVolumeModification.NotFound") => return Ok(None),
+            Err(e) => {
+                let operation = "DescribeVolumesModifications";
+                return Err(call_failed(operation, &e, self.log_request_ids));
+            }
+        };
+        Ok(output.volumes_modifications()
+            .iter()
+            .max_by_key(|modification| modification.start_time().map(|time| time.secs()))
+            .and_then(|modification| modification.modification_state())
+            .map(ModificationState::from))
     }
-    fn get_managed_ebs_volumes(&mut self) {
 
+    fn tag_instance(
+        &mut self, instance_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let result = self.runtime.block_on(
+            self.client.create_tags()
+                .resources(instance_id)
+                .tags(Tag::builder().key(key).value(value).build())
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("CreateTags", &e, self.log_request_ids))?;
+        Ok(())
     }
-    fn delete_ebs_volume(&mut self) {
 
+    fn tag_volume(
+        &mut self, volume_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let result = self.runtime.block_on(
+            self.client.create_tags()
+                .resources(volume_id)
+                .tags(Tag::builder().key(key).value(value).build())
+                .send()
+        );
+        self.track_throttling(&result);
+        result.map_err(|e| call_failed("CreateTags", &e, self.log_request_ids))?;
+        Ok(())
     }
 
-    fn count_mounted_ebs_volumes(&mut self) -> u32 {
-        30
+    fn probe_permission(
+        &mut self, action: Ec2Action, identity: Option<&InstanceIdentity>,
+    ) -> PermissionProbe {
+        let result = match action {
+            Ec2Action::DescribeVolumes => self.runtime.block_on(
+                self.client.describe_volumes()
+                    .max_results(5)
+                    .send()
+            ).map(|_| ()).map_err(|e| (e.code().map(str::to_string), e.to_string())),
+            Ec2Action::CreateVolume => {
+                let Some(identity) = identity else {
+                    return PermissionProbe::Inconclusive("availability zone unknown".to_string());
+                };
+                self.runtime.block_on(
+                    self.client.create_volume()
+                        .dry_run(true)
+                        .availability_zone(&identity.availability_zone)
+                        .volume_type(VolumeType::Gp3)
+                        .size(1)
+                        .send()
+                ).map(|_| ()).map_err(|e| (e.code().map(str::to_string), e.to_string()))
+            }
+            Ec2Action::AttachVolume => {
+                let Some(identity) = identity else {
+                    return PermissionProbe::Inconclusive("instance id unknown".to_string());
+                };
+                // The volume does not exist, but permissions are checked before the volume id
+                self.runtime.block_on(
+                    self.client.attach_volume()
+                        .dry_run(true)
+                        .instance_id(&identity.instance_id)
+                        .volume_id("vol-00000000000000000")
+                        .device("/dev/xvdzz")
+                        .send()
+                ).map(|_| ()).map_err(|e| (e.code().map(str::to_string), e.to_string()))
+            }
+        };
+        match result {
+            Ok(()) => PermissionProbe::Allowed,
+            Err((code, message)) => PermissionProbe::from_error_code(code.as_deref(), message),
+        }
     }
-    fn tag_as_delete_on_term(&mut self, device: String) -> Result<String, Box<GenericAWSError>> {
-        Ok("/dev/test".to_string())
+
+    fn consecutive_throttles(&self) -> u32 {
+        self.consecutive_throttles
     }
 }
 
 pub struct MockAWS {
     pub simulate_aws_err: bool,
+    /// AttachVolume fails while set, with every other call succeeding
+    pub fail_attach: bool,
+    /// AttachVolume fails once this many more attaches have succeeded, if set
+    pub attaches_before_failure: Option<u32>,
+    /// Times volume_state reports creating before available, u32::MAX for never
+    pub creating_checks: u32,
+    /// Times volume_state fails before answering, u32::MAX for always
+    pub volume_state_failures: u32,
+    pub encryption_by_default: bool,
     pub mounted_devices_count: u32,
+    pub managed_volumes: Vec<ManagedVolume>,
+    pub imds_available: bool,
+    /// Probe results for actions that are not allowed
+    pub permission_probes: Vec<(Ec2Action, PermissionProbe)>,
+    /// How long each delete takes, to simulate a hanging DeleteVolume
+    pub delete_delay: Duration,
+    /// Termination notice IMDS reports
+    pub termination_notice: Option<String>,
+    /// DescribeVolumes and CreateVolume are throttled while set
+    pub throttling: Arc<AtomicBool>,
+    pub consecutive_throttles: u32,
+    /// Storage used by all the account's volumes
+    pub account_storage_gib: u64,
+    /// Modification states returned for a volume, one per call, None once exhausted
+    pub modification_states: BTreeMap<String, Vec<ModificationState>>,
+    /// Calls made so far, shared so tests can inspect them after boxing the mock
+    pub calls: Arc<Mutex<Vec<String>>>,
+    /// DescribeVolumes calls made to list the managed volumes
+    pub describes: Arc<AtomicUsize>,
+    /// Request id carried by the simulated errors
+    pub request_id: Option<String>,
+}
+
+impl MockAWS {
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    fn error(&self) -> Box<GenericAWSError> {
+        Box::new(GenericAWSError { operation: None, request_id: self.request_id.clone() })
+    }
+
+    fn check_throttling(&mut self) -> Result<(), Box<GenericAWSError>> {
+        if self.throttling.load(Ordering::Relaxed) {
+            self.consecutive_throttles += 1;
+            return Err(self.error());
+        }
+        self.consecutive_throttles = 0;
+        Ok(())
+    }
+
+    /// Number of recorded calls to a trait method
+    pub fn count_calls(calls: &Arc<Mutex<Vec<String>>>, method: &str) -> usize {
+        calls.lock().unwrap()
+            .iter()
+            .filter(|call| call.split(' ').next() == Some(method))
+            .count()
+    }
 }
 
 impl Default for MockAWS {
     fn default() -> Self {
         MockAWS {
             simulate_aws_err: false,
+            fail_attach: false,
+            attaches_before_failure: None,
+            creating_checks: 0,
+            volume_state_failures: 0,
+            encryption_by_default: false,
             mounted_devices_count: 1,
+            managed_volumes: Vec::new(),
+            imds_available: true,
+            permission_probes: Vec::new(),
+            delete_delay: Duration::ZERO,
+            termination_notice: None,
+            throttling: Arc::new(AtomicBool::new(false)),
+            consecutive_throttles: 0,
+            account_storage_gib: 0,
+            modification_states: BTreeMap::new(),
+            calls: Arc::new(Mutex::new(Vec::new())),
+            describes: Arc::new(AtomicUsize::new(0)),
+            request_id: None,
         }
     }
 }
 
 impl AWS for MockAWS {
     fn request_ebs_volume(
-        &mut self, _size: u64, _vol_type: String,
-        _encrypted: bool, _throughput: u64
+        &mut self, size: SizeGib, vol_type: String,
+        _encrypted: bool, _iops: Option<u64>, _throughput: Option<u64>, name: Option<String>,
     ) -> Result<String, Box<GenericAWSError>>{
+        self.check_throttling()?;
         if self.simulate_aws_err {
-            return Err(Box::new(GenericAWSError))
+            return Err(self.error())
         }
-        Ok("/dev/test".to_string())
+        match name {
+            Some(name) => self.record(
+                format!("request_ebs_volume {} {} {}", size.as_u64(), vol_type, name)
+            ),
+            None => self.record(format!("request_ebs_volume {} {}", size.as_u64(), vol_type)),
+        }
+        Ok("vol-test".to_string())
     }
 
-    fn attach_ebs_volume(&mut self, _device: String) -> Result<String, Box<GenericAWSError>>{
+    fn volume_state(&mut self, _volume_id: String) -> Result<String, Box<GenericAWSError>> {
         if self.simulate_aws_err {
-            return Err(Box::new(GenericAWSError))
+            return Err(self.error())
+        }
+        if self.volume_state_failures > 0 {
+            self.volume_state_failures -= 1;
+            return Err(self.error());
+        }
+        if self.creating_checks > 0 {
+            self.creating_checks -= 1;
+            return Ok("creating".to_string());
         }
-        Ok("/dev/test".to_string())
+        Ok("available".to_string())
     }
 
-    fn get_managed_ebs_volumes(&mut self) {
+    fn attach_ebs_volume(
+        &mut self, volume_id: String, device: String,
+    ) -> Result<String, Box<GenericAWSError>>{
+        if self.simulate_aws_err || self.fail_attach || self.attaches_before_failure == Some(0) {
+            return Err(self.error())
+        }
+        if let Some(left) = self.attaches_before_failure.as_mut() {
+            *left -= 1;
+        }
+        self.record(format!("attach_ebs_volume {} {}", volume_id, device));
+        Ok(device)
+    }
+
+    fn get_managed_ebs_volumes(&mut self) -> Result<Vec<ManagedVolume>, Box<GenericAWSError>> {
+        self.describes.fetch_add(1, Ordering::Relaxed);
+        self.check_throttling()?;
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        Ok(self.managed_volumes.clone())
+    }
+
+    fn delete_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        if !self.delete_delay.is_zero() {
+            thread::sleep(self.delete_delay);
+        }
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("delete_ebs_volume {}", volume_id));
+        Ok(())
+    }
+
+    fn detach_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("detach_ebs_volume {}", volume_id));
+        Ok(())
+    }
+
+    fn count_mounted_ebs_volumes(&mut self) -> Result<u32, Box<GenericAWSError>> {
+        Ok(self.mounted_devices_count)
+    }
 
+    fn account_storage_gib(&mut self, _vol_type: String) -> Result<SizeGib, Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        Ok(SizeGib(self.account_storage_gib))
     }
 
-    fn delete_ebs_volume(&mut self) {
+    fn ebs_encryption_by_default(&mut self) -> Result<bool, Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        Ok(self.encryption_by_default)
+    }
 
+    fn set_delete_on_termination(
+        &mut self, device: String, flag: bool,
+    ) -> Result<(), Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("set_delete_on_termination {} {}", device, flag));
+        Ok(())
+    }
+
+    fn instance_identity(&mut self) -> Result<InstanceIdentity, Box<GenericAWSError>> {
+        if !self.imds_available {
+            return Err(self.error())
+        }
+        Ok(InstanceIdentity {
+            instance_id: "i-0123456789abcdef0".to_string(),
+            region: "us-east-1".to_string(),
+            availability_zone: "us-east-1a".to_string(),
+        })
+    }
+
+    fn termination_notice(&mut self) -> Option<String> {
+        self.termination_notice.clone()
+    }
+
+    fn publish_event(
+        &mut self, topic_arn: String, message: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("publish_event {} {}", topic_arn, message));
+        Ok(())
+    }
+
+    fn modify_volume_size(
+        &mut self, volume_id: String, size: SizeGib,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.check_throttling()?;
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("modify_volume_size {} {}", volume_id, size.as_u64()));
+        Ok(())
+    }
+
+    fn modify_volume_type(
+        &mut self, volume_id: String, vol_type: String, iops: u64,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.check_throttling()?;
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("modify_volume_type {} {} {}", volume_id, vol_type, iops));
+        Ok(())
+    }
+
+    fn volume_modification_state(
+        &mut self, volume_id: String,
+    ) -> Result<Option<ModificationState>, Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        match self.modification_states.get_mut(&volume_id) {
+            Some(states) if !states.is_empty() => Ok(Some(states.remove(0))),
+            _ => Ok(None),
+        }
     }
 
-    fn count_mounted_ebs_volumes(&mut self) -> u32 {
-        self.mounted_devices_count
+    fn tag_instance(
+        &mut self, instance_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        if self.simulate_aws_err {
+            return Err(self.error())
+        }
+        self.record(format!("tag_instance {} {} {}", instance_id, key, value));
+        Ok(())
     }
 
-    fn tag_as_delete_on_term(&mut self, device: String) -> Result<String, Box<GenericAWSError>> {
+    fn tag_volume(
+        &mut self, volume_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
         if self.simulate_aws_err {
-            return Err(Box::new(GenericAWSError))
+            return Err(self.error())
+        }
+        self.record(format!("tag_volume {} {} {}", volume_id, key, value));
+        Ok(())
+    }
+
+    fn probe_permission(
+        &mut self, action: Ec2Action, _identity: Option<&InstanceIdentity>,
+    ) -> PermissionProbe {
+        self.permission_probes.iter()
+            .find(|(probed, _)| *probed == action)
+            .map(|(_, probe)| probe.clone())
+            .unwrap_or(PermissionProbe::Allowed)
+    }
+
+    fn consecutive_throttles(&self) -> u32 {
+        self.consecutive_throttles
+    }
+}
+
+/// A mutating call AWS was asked to make, with the exact parameters it was given
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum AwsCall {
+    CreateVolume {
+        availability_zone: String,
+        size_gib: SizeGib,
+        vol_type: String,
+        encrypted: bool,
+        iops: Option<u64>,
+        throughput: Option<u64>,
+        /// Tags of the request's tag specification, including Name
+        tags: BTreeMap<String, String>,
+    },
+    AttachVolume { volume_id: String, device: String },
+    DeleteVolume { volume_id: String },
+    DetachVolume { volume_id: String },
+    ModifyVolume { volume_id: String, size_gib: SizeGib },
+    ModifyVolumeType { volume_id: String, vol_type: String, iops: u64 },
+    SetDeleteOnTermination { device: String, delete_on_termination: bool },
+    PublishEvent { topic_arn: String, message: String },
+    TagInstance { instance_id: String, key: String, value: String },
+    TagVolume { volume_id: String, key: String, value: String },
+}
+
+impl AwsCall {
+    /// Makes the call again against another client
+    pub fn replay(self, aws: &mut dyn AWS) -> Result<(), Box<GenericAWSError>> {
+        match self {
+            AwsCall::CreateVolume {
+                size_gib, vol_type, encrypted, iops, throughput, tags, ..
+            } => {
+                let name = tags.get("Name").cloned();
+                aws.request_ebs_volume(size_gib, vol_type, encrypted, iops, throughput, name)?;
+            }
+            AwsCall::AttachVolume { volume_id, device } => {
+                aws.attach_ebs_volume(volume_id, device)?;
+            }
+            AwsCall::DeleteVolume { volume_id } => aws.delete_ebs_volume(volume_id)?,
+            AwsCall::DetachVolume { volume_id } => aws.detach_ebs_volume(volume_id)?,
+            AwsCall::ModifyVolume { volume_id, size_gib } => {
+                aws.modify_volume_size(volume_id, size_gib)?
+            }
+            AwsCall::ModifyVolumeType { volume_id, vol_type, iops } => {
+                aws.modify_volume_type(volume_id, vol_type, iops)?
+            }
+            AwsCall::SetDeleteOnTermination { device, delete_on_termination } => {
+                aws.set_delete_on_termination(device, delete_on_termination)?
+            }
+            AwsCall::PublishEvent { topic_arn, message } => aws.publish_event(topic_arn, message)?,
+            AwsCall::TagInstance { instance_id, key, value } => {
+                aws.tag_instance(instance_id, key, value)?
+            }
+            AwsCall::TagVolume { volume_id, key, value } => aws.tag_volume(volume_id, key, value)?,
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a client and records every mutating call before passing it on
+///
+/// Reads go straight to the wrapped client. Wrapping a MockAWS gives a dry run whose
+/// log can be compared against golden expectations or replayed elsewhere.
+pub struct RecordingAWS {
+    inner: Box<dyn AWS>,
+    /// Calls made so far, shared so tests can inspect them after boxing the client
+    pub calls: Arc<Mutex<Vec<AwsCall>>>,
+    /// Cost-allocation tags created volumes are tagged with, as by ConcreteAWS
+    pub cost_allocation_tags: BTreeMap<String, String>,
+}
+
+impl RecordingAWS {
+    pub fn new(inner: Box<dyn AWS>) -> RecordingAWS {
+        RecordingAWS {
+            inner,
+            calls: Arc::new(Mutex::new(Vec::new())),
+            cost_allocation_tags: BTreeMap::new(),
+        }
+    }
+
+    fn record(&self, call: AwsCall) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    /// The recorded calls as JSON lines, one call per line
+    pub fn to_log(calls: &[AwsCall]) -> Result<String, serde_json::Error> {
+        let mut log = String::new();
+        for call in calls {
+            log.push_str(&serde_json::to_string(call)?);
+            log.push('\n');
+        }
+        Ok(log)
+    }
+
+    /// Parses a log written by to_log
+    pub fn from_log(log: &str) -> Result<Vec<AwsCall>, serde_json::Error> {
+        log.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect()
+    }
+}
+
+impl AWS for RecordingAWS {
+    fn request_ebs_volume(
+        &mut self, size: SizeGib, vol_type: String,
+        encrypted: bool, iops: Option<u64>, throughput: Option<u64>, name: Option<String>,
+    ) -> Result<String, Box<GenericAWSError>> {
+        // Recorded as ConcreteAWS sends it, which needs the instance identity first
+        let identity = self.inner.instance_identity()?;
+        let tags = volume_tags(&identity.instance_id, name.as_deref(), &self.cost_allocation_tags);
+        self.record(AwsCall::CreateVolume {
+            availability_zone: identity.availability_zone,
+            size_gib: size,
+            vol_type: vol_type.clone(),
+            encrypted,
+            iops,
+            throughput,
+            tags: tags.into_iter().collect(),
+        });
+        self.inner.request_ebs_volume(size, vol_type, encrypted, iops, throughput, name)
+    }
+
+    fn volume_state(&mut self, volume_id: String) -> Result<String, Box<GenericAWSError>> {
+        self.inner.volume_state(volume_id)
+    }
+
+    fn attach_ebs_volume(
+        &mut self, volume_id: String, device: String,
+    ) -> Result<String, Box<GenericAWSError>> {
+        self.record(AwsCall::AttachVolume { volume_id: volume_id.clone(), device: device.clone() });
+        self.inner.attach_ebs_volume(volume_id, device)
+    }
+
+    fn get_managed_ebs_volumes(&mut self) -> Result<Vec<ManagedVolume>, Box<GenericAWSError>> {
+        self.inner.get_managed_ebs_volumes()
+    }
+
+    fn count_mounted_ebs_volumes(&mut self) -> Result<u32, Box<GenericAWSError>> {
+        self.inner.count_mounted_ebs_volumes()
+    }
+
+    fn account_storage_gib(&mut self, vol_type: String) -> Result<SizeGib, Box<GenericAWSError>> {
+        self.inner.account_storage_gib(vol_type)
+    }
+
+    fn ebs_encryption_by_default(&mut self) -> Result<bool, Box<GenericAWSError>> {
+        self.inner.ebs_encryption_by_default()
+    }
+
+    fn delete_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::DeleteVolume { volume_id: volume_id.clone() });
+        self.inner.delete_ebs_volume(volume_id)
+    }
+
+    fn detach_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::DetachVolume { volume_id: volume_id.clone() });
+        self.inner.detach_ebs_volume(volume_id)
+    }
+
+    fn modify_volume_size(
+        &mut self, volume_id: String, size: SizeGib,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::ModifyVolume { volume_id: volume_id.clone(), size_gib: size });
+        self.inner.modify_volume_size(volume_id, size)
+    }
+
+    fn modify_volume_type(
+        &mut self, volume_id: String, vol_type: String, iops: u64,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::ModifyVolumeType {
+            volume_id: volume_id.clone(),
+            vol_type: vol_type.clone(),
+            iops,
+        });
+        self.inner.modify_volume_type(volume_id, vol_type, iops)
+    }
+
+    fn volume_modification_state(
+        &mut self, volume_id: String,
+    ) -> Result<Option<ModificationState>, Box<GenericAWSError>> {
+        self.inner.volume_modification_state(volume_id)
+    }
+
+    fn set_delete_on_termination(
+        &mut self, device: String, flag: bool,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::SetDeleteOnTermination {
+            device: device.clone(),
+            delete_on_termination: flag,
+        });
+        self.inner.set_delete_on_termination(device, flag)
+    }
+
+    fn instance_identity(&mut self) -> Result<InstanceIdentity, Box<GenericAWSError>> {
+        self.inner.instance_identity()
+    }
+
+    fn termination_notice(&mut self) -> Option<String> {
+        self.inner.termination_notice()
+    }
+
+    fn publish_event(
+        &mut self, topic_arn: String, message: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::PublishEvent {
+            topic_arn: topic_arn.clone(),
+            message: message.clone(),
+        });
+        self.inner.publish_event(topic_arn, message)
+    }
+
+    fn tag_instance(
+        &mut self, instance_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::TagInstance {
+            instance_id: instance_id.clone(),
+            key: key.clone(),
+            value: value.clone(),
+        });
+        self.inner.tag_instance(instance_id, key, value)
+    }
+
+    fn tag_volume(
+        &mut self, volume_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.record(AwsCall::TagVolume {
+            volume_id: volume_id.clone(),
+            key: key.clone(),
+            value: value.clone(),
+        });
+        self.inner.tag_volume(volume_id, key, value)
+    }
+
+    fn probe_permission(
+        &mut self, action: Ec2Action, identity: Option<&InstanceIdentity>,
+    ) -> PermissionProbe {
+        self.inner.probe_permission(action, identity)
+    }
+
+    fn consecutive_throttles(&self) -> u32 {
+        self.inner.consecutive_throttles()
+    }
+}
+
+/// Wraps a client and reuses its list of managed volumes for up to ttl, so the lookups
+/// of a reconcile share one DescribeVolumes call
+///
+/// Any mutating call drops the cached list, so it never hides a change made here.
+pub struct CachingAWS {
+    inner: Box<dyn AWS>,
+    ttl: Duration,
+    clock: Box<dyn Clock>,
+    cached: Option<(std::time::Instant, Vec<ManagedVolume>)>,
+}
+
+impl CachingAWS {
+    pub fn new(inner: Box<dyn AWS>, ttl: Duration, clock: Box<dyn Clock>) -> CachingAWS {
+        CachingAWS { inner, ttl, clock, cached: None }
+    }
+
+    fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
+
+impl AWS for CachingAWS {
+    fn request_ebs_volume(
+        &mut self, size: SizeGib, vol_type: String,
+        encrypted: bool, iops: Option<u64>, throughput: Option<u64>, name: Option<String>,
+    ) -> Result<String, Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.request_ebs_volume(size, vol_type, encrypted, iops, throughput, name)
+    }
+
+    fn request_ebs_volumes(
+        &mut self, requests: Vec<VolumeRequest>,
+    ) -> Vec<Result<String, Box<GenericAWSError>>> {
+        self.invalidate();
+        self.inner.request_ebs_volumes(requests)
+    }
+
+    fn volume_state(&mut self, volume_id: String) -> Result<String, Box<GenericAWSError>> {
+        self.inner.volume_state(volume_id)
+    }
+
+    fn attach_ebs_volume(
+        &mut self, volume_id: String, device: String,
+    ) -> Result<String, Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.attach_ebs_volume(volume_id, device)
+    }
+
+    fn get_managed_ebs_volumes(&mut self) -> Result<Vec<ManagedVolume>, Box<GenericAWSError>> {
+        let now = self.clock.now();
+        if let Some((fetched_at, volumes)) = &self.cached {
+            if now.duration_since(*fetched_at) < self.ttl {
+                return Ok(volumes.clone());
+            }
+        }
+        let volumes = self.inner.get_managed_ebs_volumes()?;
+        self.cached = Some((now, volumes.clone()));
+        Ok(volumes)
+    }
+
+    fn count_mounted_ebs_volumes(&mut self) -> Result<u32, Box<GenericAWSError>> {
+        self.inner.count_mounted_ebs_volumes()
+    }
+
+    fn account_storage_gib(&mut self, vol_type: String) -> Result<SizeGib, Box<GenericAWSError>> {
+        self.inner.account_storage_gib(vol_type)
+    }
+
+    fn ebs_encryption_by_default(&mut self) -> Result<bool, Box<GenericAWSError>> {
+        self.inner.ebs_encryption_by_default()
+    }
+
+    fn delete_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.delete_ebs_volume(volume_id)
+    }
+
+    fn detach_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.detach_ebs_volume(volume_id)
+    }
+
+    fn modify_volume_size(
+        &mut self, volume_id: String, size: SizeGib,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.modify_volume_size(volume_id, size)
+    }
+
+    fn modify_volume_type(
+        &mut self, volume_id: String, vol_type: String, iops: u64,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.modify_volume_type(volume_id, vol_type, iops)
+    }
+
+    fn volume_modification_state(
+        &mut self, volume_id: String,
+    ) -> Result<Option<ModificationState>, Box<GenericAWSError>> {
+        self.inner.volume_modification_state(volume_id)
+    }
+
+    fn set_delete_on_termination(
+        &mut self, device: String, flag: bool,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.set_delete_on_termination(device, flag)
+    }
+
+    fn instance_identity(&mut self) -> Result<InstanceIdentity, Box<GenericAWSError>> {
+        self.inner.instance_identity()
+    }
+
+    fn termination_notice(&mut self) -> Option<String> {
+        self.inner.termination_notice()
+    }
+
+    fn publish_event(
+        &mut self, topic_arn: String, message: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.inner.publish_event(topic_arn, message)
+    }
+
+    fn tag_instance(
+        &mut self, instance_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.inner.tag_instance(instance_id, key, value)
+    }
+
+    fn tag_volume(
+        &mut self, volume_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        self.invalidate();
+        self.inner.tag_volume(volume_id, key, value)
+    }
+
+    fn probe_permission(
+        &mut self, action: Ec2Action, identity: Option<&InstanceIdentity>,
+    ) -> PermissionProbe {
+        self.inner.probe_permission(action, identity)
+    }
+
+    fn consecutive_throttles(&self) -> u32 {
+        self.inner.consecutive_throttles()
+    }
+}
+
+/// Caps the EC2 API calls in flight at once, shared by every client of the process
+#[derive(Clone)]
+pub struct ApiLimiter {
+    max: usize,
+    in_flight: Arc<(Mutex<usize>, std::sync::Condvar)>,
+}
+
+/// Held for the duration of a call, giving its slots back when dropped
+pub struct ApiPermit {
+    in_flight: Arc<(Mutex<usize>, std::sync::Condvar)>,
+    slots: usize,
+}
+
+impl ApiLimiter {
+    pub fn new(max: usize) -> ApiLimiter {
+        let in_flight = Arc::new((Mutex::new(0), std::sync::Condvar::new()));
+        ApiLimiter { max: max.max(1), in_flight }
+    }
+
+    /// Waits until fewer than max calls are in flight
+    pub fn acquire(&self) -> ApiPermit {
+        self.acquire_many(1)
+    }
+
+    /// Waits until slots more calls fit under max, taking them all at once; slots is
+    /// capped at max
+    pub fn acquire_many(&self, slots: usize) -> ApiPermit {
+        let slots = slots.min(self.max);
+        let (count, freed) = &*self.in_flight;
+        let mut count = freed
+            .wait_while(count.lock().unwrap(), |count| *count + slots > self.max)
+            .unwrap();
+        *count += slots;
+        ApiPermit { in_flight: self.in_flight.clone(), slots }
+    }
+}
+
+impl Drop for ApiPermit {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.in_flight;
+        *count.lock().unwrap() -= self.slots;
+        freed.notify_all();
+    }
+}
+
+/// Wraps a client so each call waits for a slot of the limiter
+pub struct LimitedAWS {
+    inner: Box<dyn AWS>,
+    limiter: ApiLimiter,
+}
+
+impl LimitedAWS {
+    pub fn new(inner: Box<dyn AWS>, limiter: ApiLimiter) -> LimitedAWS {
+        LimitedAWS { inner, limiter }
+    }
+}
+
+impl AWS for LimitedAWS {
+    fn request_ebs_volume(
+        &mut self, size: SizeGib, vol_type: String,
+        encrypted: bool, iops: Option<u64>, throughput: Option<u64>, name: Option<String>,
+    ) -> Result<String, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.request_ebs_volume(size, vol_type, encrypted, iops, throughput, name)
+    }
+
+    fn request_ebs_volumes(
+        &mut self, requests: Vec<VolumeRequest>,
+    ) -> Vec<Result<String, Box<GenericAWSError>>> {
+        // Sent in groups of at most max, each request of a group holding a slot
+        let mut results = Vec::new();
+        for group in requests.chunks(self.limiter.max) {
+            let _permit = self.limiter.acquire_many(group.len());
+            results.extend(self.inner.request_ebs_volumes(group.to_vec()));
         }
-        Ok("/dev/test".to_string())
+        results
+    }
+
+    fn volume_state(&mut self, volume_id: String) -> Result<String, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.volume_state(volume_id)
+    }
+
+    fn attach_ebs_volume(
+        &mut self, volume_id: String, device: String,
+    ) -> Result<String, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.attach_ebs_volume(volume_id, device)
+    }
+
+    fn get_managed_ebs_volumes(&mut self) -> Result<Vec<ManagedVolume>, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.get_managed_ebs_volumes()
+    }
+
+    fn count_mounted_ebs_volumes(&mut self) -> Result<u32, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.count_mounted_ebs_volumes()
+    }
+
+    fn account_storage_gib(&mut self, vol_type: String) -> Result<SizeGib, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.account_storage_gib(vol_type)
+    }
+
+    fn ebs_encryption_by_default(&mut self) -> Result<bool, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.ebs_encryption_by_default()
+    }
+
+    fn delete_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.delete_ebs_volume(volume_id)
+    }
+
+    fn detach_ebs_volume(&mut self, volume_id: String) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.detach_ebs_volume(volume_id)
+    }
+
+    fn modify_volume_size(
+        &mut self, volume_id: String, size: SizeGib,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.modify_volume_size(volume_id, size)
+    }
+
+    fn modify_volume_type(
+        &mut self, volume_id: String, vol_type: String, iops: u64,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.modify_volume_type(volume_id, vol_type, iops)
+    }
+
+    fn volume_modification_state(
+        &mut self, volume_id: String,
+    ) -> Result<Option<ModificationState>, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.volume_modification_state(volume_id)
+    }
+
+    fn set_delete_on_termination(
+        &mut self, device: String, flag: bool,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.set_delete_on_termination(device, flag)
+    }
+
+    fn instance_identity(&mut self) -> Result<InstanceIdentity, Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.instance_identity()
+    }
+
+    fn termination_notice(&mut self) -> Option<String> {
+        self.inner.termination_notice()
+    }
+
+    fn publish_event(
+        &mut self, topic_arn: String, message: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.publish_event(topic_arn, message)
+    }
+
+    fn tag_instance(
+        &mut self, instance_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.tag_instance(instance_id, key, value)
+    }
+
+    fn tag_volume(
+        &mut self, volume_id: String, key: String, value: String,
+    ) -> Result<(), Box<GenericAWSError>> {
+        let _permit = self.limiter.acquire();
+        self.inner.tag_volume(volume_id, key, value)
+    }
+
+    fn probe_permission(
+        &mut self, action: Ec2Action, identity: Option<&InstanceIdentity>,
+    ) -> PermissionProbe {
+        let _permit = self.limiter.acquire();
+        self.inner.probe_permission(action, identity)
+    }
+
+    fn consecutive_throttles(&self) -> u32 {
+        self.inner.consecutive_throttles()
     }
 }
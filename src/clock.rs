@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of time and sleeps, so timing behavior can be tested without waiting
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+    /// Wall-clock time in Unix seconds, for timestamps kept across restarts
+    fn unix_secs(&self) -> u64;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_secs(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Clock whose time only moves on sleep or advance
+///
+/// Clones share the same time, so tests can keep a handle after boxing it.
+#[derive(Clone)]
+pub struct MockClock {
+    start: Instant,
+    start_unix_secs: u64,
+    elapsed: Arc<Mutex<Duration>>,
+    /// Every sleep requested so far
+    pub sleeps: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock {
+            start: Instant::now(),
+            start_unix_secs: SystemClock.unix_secs(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+            sleeps: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn unix_secs(&self) -> u64 {
+        self.start_unix_secs + self.elapsed.lock().unwrap().as_secs()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap().push(duration);
+        self.advance(duration);
+    }
+}
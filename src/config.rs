@@ -1,60 +1,1232 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use figment::{Figment, Metadata, Profile, Provider};
+use figment::providers::Serialized;
+use figment::value::{Dict, Map, Value};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::size::SizeGib;
+
+/// Environment variable selecting the active profile, like --profile
+pub const PROFILE_ENV: &str = "EBS_AUTOSCALE_PROFILE";
+
+/// Prefix of the instance tags read as config keys, e.g.
+/// ebs-autoscale:limits.max_ebs_volume_count
+pub const CONFIG_TAG_PREFIX: &str = "ebs-autoscale:";
+
+/// Settings masked when the config is logged, as they identify accounts and roles or,
+/// for notifier and status_token, hold credentials
+pub const REDACTED_FIELDS: &[&str] =
+    &["assume_role_arn", "sns_topic_arn", "aws_profile", "notifier", "status_token"];
+const REDACTED: &str = "<redacted>";
+
+/// Highest gp3 throughput, in MiB/s, allowed per provisioned IOPS
+pub const GP3_MAX_THROUGHPUT_PER_IOPS: f64 = 0.25;
+/// Most IOPS a gp3 volume can be provisioned with
+pub const GP3_MAX_IOPS: u64 = 16000;
+/// Most throughput, in MiB/s, a gp3 volume can be provisioned with
+pub const GP3_MAX_THROUGHPUT: u64 = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Gp3ThroughputRatioError {
+    pub iops: u64,
+    pub throughput: u64,
+}
+
+impl Error for Gp3ThroughputRatioError {}
+
+impl fmt::Display for Gp3ThroughputRatioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "gp3 throughput of {}MiB/s needs at least {} IOPS (max {} MiB/s per IOPS), got {}",
+            self.throughput,
+            self.throughput * 4,
+            GP3_MAX_THROUGHPUT_PER_IOPS,
+            self.iops
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownConfigKeysError {
+    pub keys: Vec<String>,
+}
+
+impl Error for UnknownConfigKeysError {}
+
+impl fmt::Display for UnknownConfigKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown config keys: {}", self.keys.join(", "))
+    }
+}
+
+/// A setting needs a cargo feature this binary was built without
+#[derive(Debug, Clone)]
+pub struct FeatureNotBuiltError {
+    pub setting: &'static str,
+    pub feature: &'static str,
+}
+
+impl Error for FeatureNotBuiltError {}
+
+impl fmt::Display for FeatureNotBuiltError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} needs a build with the {} feature", self.setting, self.feature)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownProfileError {
+    pub profile: String,
+}
+
+impl Error for UnknownProfileError {}
+
+impl fmt::Display for UnknownProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No [profiles.{}] table in the config", self.profile)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Limits {
     pub initial_utilization_threshold: u32,
-    pub min_ebs_volume_size: u32,
-    pub max_ebs_volume_size: u32,
-    pub max_logical_volume_size: u32,
-    pub max_ebs_volume_count: u32
+    pub min_ebs_volume_size: SizeGib,
+    pub max_ebs_volume_size: SizeGib,
+    pub max_logical_volume_size: SizeGib,
+    pub max_ebs_volume_count: u32,
+    /// Projected monthly cost cap, in USD, for the managed volumes of this instance
+    pub max_monthly_cost_usd: Option<f64>,
+    /// Room, in GiB despite the name, left below max_logical_volume_size for manual
+    /// intervention: scaling stops once a new volume would take the logical volume into it
+    pub logical_size_headroom_gb: SizeGib,
+    /// Account EBS storage quota, in GiB, for the configured volume type, as shown in
+    /// Service Quotas (e.g. "Storage for General Purpose SSD (gp3) volumes")
+    pub account_storage_quota_gib: Option<SizeGib>,
+    /// Share of account_storage_quota_gib, in percent, past which scaling is refused
+    pub account_quota_max_percent: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct MissingVolumeSettingError {
+    pub vol_type: String,
+    pub setting: &'static str,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Error for MissingVolumeSettingError {}
+
+impl fmt::Display for MissingVolumeSettingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} volumes need an explicit volume.{}", self.vol_type, self.setting)
+    }
+}
+
+/// EBS volume types, with the defaults applied when the config leaves settings out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeType {
+    Gp2,
+    Gp3,
+    Io1,
+    Io2,
+    St1,
+    Sc1,
+    Standard,
+}
+
+impl VolumeType {
+    pub fn parse(vol_type: &str) -> Option<VolumeType> {
+        match vol_type {
+            "gp2" => Some(VolumeType::Gp2),
+            "gp3" => Some(VolumeType::Gp3),
+            "io1" => Some(VolumeType::Io1),
+            "io2" => Some(VolumeType::Io2),
+            "st1" => Some(VolumeType::St1),
+            "sc1" => Some(VolumeType::Sc1),
+            "standard" => Some(VolumeType::Standard),
+            _ => None,
+        }
+    }
+
+    /// Baseline IOPS, for types where IOPS can be set
+    pub fn default_iops(&self) -> Option<u64> {
+        match self {
+            VolumeType::Gp3 => Some(3000),
+            _ => None,
+        }
+    }
+
+    /// Baseline throughput in MiB/s, for types where throughput can be set
+    pub fn default_throughput(&self) -> Option<u64> {
+        match self {
+            VolumeType::Gp3 => Some(125),
+            _ => None,
+        }
+    }
+
+    /// Provisioned IOPS types have no sane default and must be set explicitly
+    pub fn requires_iops(&self) -> bool {
+        matches!(self, VolumeType::Io1 | VolumeType::Io2)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Volume {
     pub vol_type: String,
     pub encrypted: bool,
+    /// Provisioned IOPS, for volume types that take it
+    ///
+    /// Default: None (3000 for gp3, required for io1/io2)
+    pub iops: Option<u64>,
+    /// Throughput in MiB/s
+    ///
+    /// Default: None (125 for gp3)
+    pub throughput: Option<u64>,
+}
+
+impl Volume {
+    /// Copy with the defaults of the volume type filled in for unset settings
+    pub fn with_type_defaults(&self) -> Result<Volume, Box<dyn Error>> {
+        let mut volume = self.clone();
+        if let Some(vol_type) = VolumeType::parse(&self.vol_type) {
+            if vol_type.requires_iops() && self.iops.is_none() {
+                return Err(Box::new(MissingVolumeSettingError {
+                    vol_type: self.vol_type.clone(),
+                    setting: "iops",
+                }));
+            }
+            volume.iops = self.iops.or(vol_type.default_iops());
+            volume.throughput = self.throughput.or(vol_type.default_throughput());
+        }
+        Ok(volume)
+    }
+
+    /// Rejects settings AWS would refuse at create time
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let volume = self.with_type_defaults()?;
+        if let (Some(iops), Some(throughput)) = (volume.iops, volume.throughput) {
+            if volume.vol_type == "gp3"
+                && throughput as f64 > iops as f64 * GP3_MAX_THROUGHPUT_PER_IOPS
+            {
+                return Err(Box::new(Gp3ThroughputRatioError { iops, throughput }));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IOPS and throughput each new volume must deliver, whatever its type
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct PerformanceTarget {
+    pub iops: u64,
+    /// In MiB/s
     pub throughput: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone)]
+pub struct UnreachablePerformanceTargetError {
+    pub target: PerformanceTarget,
+}
+
+impl Error for UnreachablePerformanceTargetError {}
+
+impl fmt::Display for UnreachablePerformanceTargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "performance_target of {} IOPS and {}MiB/s is above the gp3 maximum of {} IOPS \
+             and {}MiB/s",
+            self.target.iops, self.target.throughput, GP3_MAX_IOPS, GP3_MAX_THROUGHPUT
+        )
+    }
+}
+
+/// Volume settings for new volumes once the logical volume has from_volume_count volumes
+///
+/// Unset settings come from the global volume config. Setting vol_type also drops the
+/// global iops and throughput, as they rarely suit another type.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct VolumeTier {
+    pub from_volume_count: u32,
+    pub vol_type: Option<String>,
+    pub iops: Option<u64>,
+    pub throughput: Option<u64>,
+    /// Size of each new volume instead of the growth_strategy size
+    pub size_gib: Option<SizeGib>,
+}
+
+impl VolumeTier {
+    /// base with the settings of the tier applied
+    pub fn apply(&self, base: &Volume) -> Volume {
+        let mut volume = base.clone();
+        if let Some(vol_type) = &self.vol_type {
+            volume.vol_type = vol_type.clone();
+            volume.iops = None;
+            volume.throughput = None;
+        }
+        volume.iops = self.iops.or(volume.iops);
+        volume.throughput = self.throughput.or(volume.throughput);
+        volume
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeTierOrderError {
+    pub from_volume_count: u32,
+    pub previous: u32,
+}
+
+impl Error for VolumeTierOrderError {}
+
+impl fmt::Display for VolumeTierOrderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "volume_tiers must be in increasing from_volume_count order, got {} after {}",
+            self.from_volume_count, self.previous
+        )
+    }
+}
+
+/// Placeholders allowed in name_tag_template
+pub const NAME_TAG_PLACEHOLDERS: [&str; 4] = ["instance_id", "mountpoint", "seq", "timestamp"];
+
+#[derive(Debug, Clone)]
+pub struct UnknownPlaceholderError {
+    pub placeholder: String,
+}
+
+impl Error for UnknownPlaceholderError {}
+
+impl fmt::Display for UnknownPlaceholderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown placeholder {{{}}} in name_tag_template, expected one of {}",
+            self.placeholder,
+            NAME_TAG_PLACEHOLDERS.join(", ")
+        )
+    }
+}
+
+/// Directories searched for mount_unit, in systemd's order of precedence
+pub const MOUNT_UNIT_DIRS: &[&str] =
+    &["/etc/systemd/system", "/run/systemd/system", "/usr/lib/systemd/system"];
+
+/// The device and mountpoint of a systemd mount unit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountUnit {
+    /// What=, the device mounted
+    pub what: String,
+    /// Where=, the mountpoint
+    pub mount_where: String,
+}
+
+/// A value of the wrong type or out of range, e.g. EBS_AUTOSCALE_DETECTION_INTERVAL=600
+#[derive(Debug, Clone)]
+pub struct InvalidConfigValueError {
+    /// Key as set in its source, such as the environment variable name
+    pub key: String,
+    pub value: String,
+    pub expected: String,
+    /// e.g. `EBS_AUTOSCALE_` environment variable(s)
+    pub source: String,
+}
+
+impl Error for InvalidConfigValueError {}
+
+impl fmt::Display for InvalidConfigValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid value {} for {} in {} - expected {}",
+            self.value, self.key, self.source, self.expected
+        )
+    }
+}
+
+/// Readable range of the integer types serde names in its errors
+fn describe_expected(expected: &str) -> String {
+    let range = match expected {
+        "u8" => "0 to 255",
+        "u16" => "0 to 65535",
+        "u32" => "0 to 4294967295",
+        "u64" => "0 to 18446744073709551615",
+        _ => return expected.to_string(),
+    };
+    format!("a whole number from {}", range)
+}
+
+/// Variable an environment key was read from, as figment names prefixed environment
+/// sources "`PREFIX_` environment variable(s)" and interpolates keys without the prefix
+fn env_var_name(source: &str, key: String) -> String {
+    match source.strip_prefix('`').and_then(|rest| rest.split_once('`')) {
+        Some((prefix, _)) if !key.starts_with(prefix) => format!("{}{}", prefix, key),
+        _ => key,
+    }
+}
+
+/// Extracts the config, reporting values of the wrong type or out of range with the key
+/// and value instead of the raw deserialization error
+pub fn extract(figment: &Figment) -> Result<Config, Box<dyn Error>> {
+    let err = match figment.extract::<Config>() {
+        Ok(config) => return Ok(config),
+        Err(err) => err,
+    };
+    let (actual, expected) = match &err.kind {
+        figment::error::Kind::InvalidType(actual, expected)
+        | figment::error::Kind::InvalidValue(actual, expected) => (actual, expected),
+        _ => return Err(Box::new(err)),
+    };
+    let path: Vec<&str> = err.path.iter().map(String::as_str).collect();
+    let (key, source) = match (&err.metadata, &err.profile) {
+        (Some(metadata), Some(profile)) => {
+            let key = metadata.interpolate(profile, &path);
+            (env_var_name(&metadata.name, key), metadata.name.to_string())
+        }
+        (Some(metadata), None) => (path.join("."), metadata.name.to_string()),
+        _ => (path.join("."), "the config".to_string()),
+    };
+    Err(Box::new(InvalidConfigValueError {
+        key,
+        value: actual.to_string(),
+        expected: describe_expected(expected),
+        source,
+    }))
+}
+
+#[derive(Debug, Clone)]
+pub struct MountUnitError {
+    pub unit: String,
+    pub reason: String,
+}
+
+impl Error for MountUnitError {}
+
+impl fmt::Display for MountUnitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid mount unit {}: {}", self.unit, self.reason)
+    }
+}
+
+impl MountUnit {
+    /// Reads What= and Where= from the [Mount] section of a unit file
+    ///
+    /// As with systemd, a later assignment overrides an earlier one. What= must be a
+    /// block device, as network and virtual filesystems cannot be grown with volumes.
+    pub fn parse(unit: &str, contents: &str) -> Result<MountUnit, MountUnitError> {
+        let mut section = "";
+        let (mut what, mut mount_where) = (None, None);
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = &line[1..line.len() - 1];
+                continue;
+            }
+            if section != "Mount" {
+                continue;
+            }
+            match line.split_once('=').map(|(key, value)| (key.trim(), value.trim())) {
+                Some(("What", value)) => what = Some(value.to_string()),
+                Some(("Where", value)) => mount_where = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        let missing = |key: &str| MountUnitError {
+            unit: unit.to_string(),
+            reason: format!("no {}= in the [Mount] section", key),
+        };
+        let what = what.filter(|what| !what.is_empty()).ok_or_else(|| missing("What"))?;
+        if !is_block_device_spec(&what) {
+            return Err(MountUnitError {
+                unit: unit.to_string(),
+                reason: format!("What={} is not a block device", what),
+            });
+        }
+        Ok(MountUnit {
+            what,
+            mount_where: mount_where
+                .filter(|mount_where| !mount_where.is_empty())
+                .ok_or_else(|| missing("Where"))?,
+        })
+    }
+
+    /// Loads the unit from the first of dirs that has it
+    pub fn load(unit: &str, dirs: &[&str]) -> Result<MountUnit, Box<dyn Error>> {
+        for dir in dirs {
+            match std::fs::read_to_string(std::path::Path::new(dir).join(unit)) {
+                Ok(contents) => return Ok(MountUnit::parse(unit, &contents)?),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(Box::new(MountUnitError {
+            unit: unit.to_string(),
+            reason: format!("not found in {}", dirs.join(", ")),
+        }))
+    }
+}
+
+/// Whether a What= names a block device: a /dev path or a UUID=, LABEL=, PARTUUID= or
+/// PARTLABEL= lookup
+fn is_block_device_spec(what: &str) -> bool {
+    what.starts_with("/dev/")
+        || ["UUID=", "LABEL=", "PARTUUID=", "PARTLABEL="]
+            .iter()
+            .any(|prefix| what.strip_prefix(prefix).is_some_and(|value| !value.is_empty()))
+}
+
+/// Replaces each `{placeholder}` in template with its value
+pub fn expand_name_tag(
+    template: &str,
+    values: &[(&str, String)],
+) -> Result<String, UnknownPlaceholderError> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after.find('}').ok_or_else(|| UnknownPlaceholderError {
+            placeholder: after.to_string(),
+        })?;
+        let placeholder = &after[..end];
+        let value = values.iter()
+            .find(|(key, _)| *key == placeholder)
+            .map(|(_, value)| value)
+            .ok_or_else(|| UnknownPlaceholderError { placeholder: placeholder.to_string() })?;
+        name.push_str(value);
+        rest = &after[end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Rejects templates with placeholders that would not be expanded
+pub fn validate_name_tag_template(template: &str) -> Result<(), UnknownPlaceholderError> {
+    let values = NAME_TAG_PLACEHOLDERS.map(|placeholder| (placeholder, String::new()));
+    expand_name_tag(template, &values).map(|_| ())
+}
+
+/// What to do once the logical volume reaches `max_logical_volume_size`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum OnMaxSize {
+    /// Return an error on every tick
+    Error,
+    /// Stop attempting to scale until the config is reloaded
+    Quiesce,
+    /// Stop the daemon
+    Exit,
+    /// Emit a critical alert once and keep running
+    AlertAndContinue,
+}
+
+/// Promotion of gp3 volumes to io2 while the workload keeps needing more IOPS
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Io2Promotion {
+    /// IOPS utilization, in percent of the provisioned IOPS, fed to the status endpoint
+    /// with POST /iops-utilization/<percent>, from which a volume is promoted. The
+    /// utilization must be fed again at least every 5 minutes to keep counting.
+    pub iops_utilization_threshold: u32,
+    /// How long the utilization must stay at or above the threshold, in seconds
+    pub sustained_secs: u64,
+    /// IOPS provisioned on promoted volumes
+    pub iops: u64,
+}
+
+/// What to do with a volume attached for a scale-up when the filesystem cannot be grown
+/// onto it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum OnExpandFailure {
+    /// Keep it attached for an operator to grow the filesystem, and send a critical alert
+    LeaveAndAlert,
+    /// Detach and delete it, so it is not billed for nothing. A device that already
+    /// joined the filesystem is left attached and alerted on instead.
+    DetachAndDelete,
+}
+
+/// How long the loop waits between ticks, before any throttling backoff
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum PollingStrategy {
+    /// Every detection_interval seconds
+    Fixed,
+    /// From max_secs with an empty disk down to min_secs once utilization reaches the
+    /// threshold, so ticks are more frequent near a scaling decision
+    Adaptive { min_secs: u64, max_secs: u64 },
+}
+
+impl PollingStrategy {
+    /// Interval for the last sampled utilization, detection_interval being the Fixed one
+    pub fn interval(
+        &self,
+        detection_interval: Duration,
+        utilization_percent: Option<u32>,
+        threshold_percent: u32,
+    ) -> Duration {
+        let (min_secs, max_secs) = match *self {
+            PollingStrategy::Fixed => return detection_interval,
+            PollingStrategy::Adaptive { min_secs, max_secs } => (min_secs, max_secs),
+        };
+        let utilization = match utilization_percent {
+            Some(utilization) if threshold_percent > 0 => utilization,
+            _ => return Duration::from_secs(max_secs),
+        };
+        let closeness = (f64::from(utilization) / f64::from(threshold_percent)).min(1.0);
+        let range = max_secs.saturating_sub(min_secs) as f64;
+        let secs = max_secs as f64 - range * closeness;
+        Duration::from_secs_f64(secs).max(Duration::from_secs(min_secs))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdaptivePollingRangeError {
+    pub min_secs: u64,
+    pub max_secs: u64,
+}
+
+impl Error for AdaptivePollingRangeError {}
+
+impl fmt::Display for AdaptivePollingRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Adaptive polling needs 0 < min_secs <= max_secs, got min_secs {} and max_secs {}",
+            self.min_secs, self.max_secs
+        )
+    }
+}
+
+/// How the size of each new volume is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum GrowthStrategy {
+    /// Size grows with the number of attached volumes
+    Tiered,
+    /// Every new volume has the same size, in GiB despite the name
+    FixedGb(SizeGib),
+    /// Each new volume is a percentage of the current logical volume size
+    PercentOfCurrent(u8),
+}
+
+/// How more space is added to the logical volume
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ScaleMode {
+    /// Attach a new volume and add it to the filesystem
+    AddVolume,
+    /// Grow an attached volume with ModifyVolume, adding a new one only once every
+    /// volume is at max_ebs_volume_size
+    GrowVolume,
+}
+
+/// Where operators are notified of scale, limit and utilization events
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum NotifierConfig {
+    /// POST each event as JSON to a URL
+    Webhook(String),
+    /// Post each event as a message to a Slack incoming webhook URL
+    Slack(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     /// Ensure that EBS volumes are deleted on termination
     ///
     /// By default, this is true. If you prefer to keep it safe, turn this config to false
     pub ensure_ebs_deleted_on_term: bool,
+    /// Volumes never deleted on termination, e.g. the base data volume, even when
+    /// they carry the managed-by tag
+    ///
+    /// Default: empty
+    pub protected_volume_ids: Vec<String>,
     /// Detection interval, in seconds
     ///
     /// Default: 2 seconds
     pub detection_interval: u8,
+    /// Cap for the detection interval while AWS calls are throttled, in seconds
+    ///
+    /// The interval doubles with each throttled tick in a row. Default: 60 seconds
+    pub max_detection_interval_secs: u64,
+    /// Whether ticks are every detection_interval or adapt to utilization
+    ///
+    /// Default: Fixed
+    pub polling_strategy: PollingStrategy,
+    /// How long the managed volumes listed by DescribeVolumes are reused, in milliseconds,
+    /// so the lookups within a reconcile share one call
+    ///
+    /// Dropped after any change made to the volumes. Default: 0 (disabled)
+    pub describe_cache_ttl_ms: u64,
 
     pub mountpoint: String,
 
     pub limits: Limits,
-
+    /// Filesystem at the mountpoint, checked against the mounted one on startup
+    ///
+    /// Set to "" to detect it from the mounted filesystem. Default: btrfs
     pub fs_type: String,
+    /// Manage the mountpoint even when it is mounted read-only, which preflight refuses
+    /// otherwise as a read-only filesystem cannot be grown
+    ///
+    /// Default: false
+    pub allow_readonly: bool,
+    /// Reserved-block percentage set with tune2fs after growing an ext4 filesystem,
+    /// instead of keeping the 5% ext4 reserves for root
+    ///
+    /// Default: None (unchanged)
+    pub ext4_reserved_blocks_percent: Option<u8>,
+    /// Helper the filesystem commands run through when the process itself is not root,
+    /// e.g. ["sudo", "-n"] or a setuid wrapper allowed to run only btrfs, growpart,
+    /// resize2fs, xfs_growfs and tune2fs
+    ///
+    /// Default: empty (run the commands directly)
+    pub privileged_command: Vec<String>,
+    /// Tags every created volume carries from CreateVolume on, for cost allocation
+    ///
+    /// Default: empty
+    pub cost_allocation_tags: BTreeMap<String, String>,
+    /// Keys of cost_allocation_tags that must be set, or preflight fails
+    ///
+    /// Default: empty
+    pub required_cost_allocation_tags: Vec<String>,
 
     pub volume: Volume,
+    /// Per-tier overrides of volume and the growth_strategy size, e.g. gp3 for the first
+    /// volumes and st1 once there are 4
+    ///
+    /// Default: empty (volume and growth_strategy for every volume)
+    pub volume_tiers: Vec<VolumeTier>,
+    /// Performance new volumes must meet, as gp3 volumes with the cheapest IOPS and
+    /// throughput that do, in place of the type and performance of volume and volume_tiers
+    ///
+    /// Default: None (volume settings as configured)
+    pub performance_target: Option<PerformanceTarget>,
+    /// Behavior when the maximum logical volume size is reached
+    ///
+    /// Default: Error
+    pub on_max_size: OnMaxSize,
+    /// Behavior when the filesystem cannot be grown onto a volume attached for a scale-up
+    ///
+    /// Default: LeaveAndAlert
+    pub on_expand_failure: OnExpandFailure,
+    /// How the size of each new volume is chosen, clamped to min/max_ebs_volume_size
+    ///
+    /// Default: Tiered
+    pub growth_strategy: GrowthStrategy,
+    /// Whether space is added with new volumes or by growing attached ones
+    ///
+    /// Default: AddVolume
+    pub scale_mode: ScaleMode,
+    /// Keep adding volumes within a tick until utilization drops below the threshold
+    ///
+    /// Bounded by max_ebs_volume_count. Default: false
+    pub scale_until_below_threshold: bool,
+    /// Most volumes created in a single tick, the rest waits for the next tick, 0 for no limit
+    ///
+    /// Default: 0
+    pub max_volumes_per_tick: u32,
+    /// Create the volumes a tick needs as one batch: all are requested and attached
+    /// before waiting for their devices, instead of one after the other
+    ///
+    /// Sized from the projected utilization, up to max_volumes_per_tick. Needs
+    /// scale_until_below_threshold. Default: false
+    pub concurrent_volume_creation: bool,
+    /// Directory for state that must survive restarts
+    ///
+    /// Default: /var/lib/ebs-autoscale-rust
+    pub state_dir: String,
+    /// How long shutdown waits for a running btrfs balance before cancelling it
+    ///
+    /// Default: 30 seconds
+    pub balance_shutdown_timeout_secs: u64,
+    /// Pause between a confirmed attach and expanding the filesystem, in milliseconds
+    ///
+    /// Default: 0
+    pub post_attach_settle_ms: u64,
+    /// How long to wait for the block device of an attached volume to appear under /dev,
+    /// which can lag behind AttachVolume on NVMe instances
+    ///
+    /// Default: 30 seconds
+    pub device_appearance_timeout_secs: u64,
+    /// Times a device reporting another volume id than the attached volume is checked
+    /// again, as when two attaches happen close together, before giving up instead of
+    /// growing the filesystem onto the wrong device
+    ///
+    /// Default: 3
+    pub device_mismatch_retries: u32,
+    /// How long a created volume may stay in creating before it is deleted and the scale
+    /// up fails
+    ///
+    /// Default: 300 seconds
+    pub volume_create_timeout_secs: u64,
+    /// After growing a volume, wait for its modification to complete instead of only
+    /// until the new size is usable (optimizing)
+    ///
+    /// Default: false
+    pub wait_for_modification_completed: bool,
+    /// How long to wait for a volume modification before giving up
+    ///
+    /// Default: 300 seconds
+    pub modification_timeout_secs: u64,
+    /// Re-attach managed volumes found detached out-of-band
+    ///
+    /// Default: false (only alert)
+    pub reattach_detached_volumes: bool,
+    /// SNS topic receiving a JSON message on each scale or limit event
+    ///
+    /// Default: None (no events)
+    pub sns_topic_arn: Option<String>,
+    /// Notifier receiving the same events as the SNS topic
+    ///
+    /// Default: None (no notifications)
+    pub notifier: Option<NotifierConfig>,
+    /// Collect utilization without scaling, re-read on config reload (SIGHUP)
+    ///
+    /// Default: false
+    pub paused: bool,
+    /// Address for the HTTP status endpoint, e.g. 127.0.0.1:9810
+    ///
+    /// Default: None (disabled)
+    pub status_addr: Option<String>,
+    /// Bearer token the status endpoint requires for POST /pause, /resume and
+    /// /iops-utilization; without one they are only accepted from loopback
+    ///
+    /// Default: None
+    pub status_token: Option<String>,
+    /// StatsD agent receiving the /metrics gauges in DogStatsD format over UDP,
+    /// e.g. 127.0.0.1:8125
+    ///
+    /// Default: None (disabled)
+    pub statsd_addr: Option<String>,
+    /// Tags added to each StatsD metric besides mountpoint, e.g. env:prod
+    ///
+    /// Default: []
+    pub statsd_tags: Vec<String>,
+    /// Named AWS profile used to build the EC2 client
+    ///
+    /// Default: None (default credential chain)
+    pub aws_profile: Option<String>,
+    /// Role assumed via STS for the EC2 calls
+    ///
+    /// Default: None
+    pub assume_role_arn: Option<String>,
+    /// Region override, required along with availability_zone when IMDS is unreachable
+    ///
+    /// Default: None (from IMDS)
+    pub region: Option<String>,
+    /// Endpoint of the EC2 API instead of the regional one, for partitions such as
+    /// GovCloud or China, used along with region
+    ///
+    /// Default: None (regional endpoint)
+    pub ec2_endpoint_url: Option<String>,
+    /// Availability zone override for new volumes
+    ///
+    /// Default: None (from IMDS)
+    pub availability_zone: Option<String>,
+    /// File receiving one utilization record per tick, as CSV if it ends in .csv, else JSONL
+    ///
+    /// Default: None (disabled)
+    pub utilization_log_path: Option<String>,
+    /// Size at which the utilization log is rotated to `<path>.1`
+    ///
+    /// Default: 10 MiB
+    pub utilization_log_max_bytes: u64,
+    /// Append-only JSONL file recording every volume create, attach, modify and delete
+    /// and filesystem expansion, with its parameters and outcome
+    ///
+    /// Default: None (disabled)
+    pub audit_log_path: Option<String>,
+    /// File the exit report, a JSON summary of the run, is written to on exit, besides
+    /// stdout
+    ///
+    /// Default: None (stdout only)
+    pub exit_report_path: Option<String>,
+    /// Create, format and mount the first volume when nothing is mounted at the mountpoint
+    ///
+    /// Default: false
+    pub bootstrap_if_missing: bool,
+    /// Add bootstrapped filesystems to /etc/fstab, by UUID, so they remount after a reboot
+    ///
+    /// Default: true
+    pub persist_mounts: bool,
+    /// Run as a pure monitoring agent: collect utilization for status and the utilization
+    /// log, but never evaluate or log a scaling decision
+    ///
+    /// Unlike a dry run, which reports the actions it would take, nothing about scaling is
+    /// computed. Unlike `paused`, it also skips bootstrap and resuming filesystem work.
+    ///
+    /// Default: false
+    pub observe_only: bool,
+    /// Most EC2 API calls in flight at once, to stay under the account's API rate limits
+    ///
+    /// Default: 4
+    pub max_concurrent_aws_calls: usize,
+    /// Upper bound for the whole cleanup on termination, after which the daemon exits anyway
+    ///
+    /// Default: 60 seconds
+    pub termination_timeout_secs: u64,
+    /// Instance tag updated with the autoscale status, e.g. ebs-autoscale-status=ok:3vols:420GB
+    ///
+    /// Default: None (disabled)
+    pub status_tag_key: Option<String>,
+    /// Minimum time between two updates of the status tag
+    ///
+    /// Default: 300 seconds
+    pub status_tag_interval_secs: u64,
+    /// Ticks may take this many times the longest detection interval before the loop is
+    /// considered hung, 0 to disable the watchdog
+    ///
+    /// Default: 10
+    pub watchdog_interval_multiplier: u32,
+    /// Abort the process when the watchdog fires, so a supervisor can restart it
+    ///
+    /// Default: false (only log)
+    pub watchdog_abort: bool,
+    /// Devices or mount paths ignored on disk scans and never used for new volumes, with
+    /// everything below them; a trailing * matches any suffix, e.g. /dev/loop* or
+    /// /var/lib/docker
+    ///
+    /// Default: empty
+    pub exclude_devices: Vec<String>,
+    /// Utilization percentage that raises a critical alert once exceeded for
+    /// alert_utilization_duration_secs, independent of scaling
+    ///
+    /// Default: None (disabled)
+    pub alert_utilization_threshold: Option<u32>,
+    /// How long utilization must stay at or above alert_utilization_threshold before alerting
+    ///
+    /// Default: 300 seconds
+    pub alert_utilization_duration_secs: u64,
+    /// File pre-allocated at startup and deleted once, with a critical alert, when
+    /// utilization reaches panic_reserve_threshold_percent while scaling fails
+    ///
+    /// Default: None (disabled)
+    pub panic_reserve_path: Option<String>,
+    /// Size of the panic reserve file in MiB
+    ///
+    /// Default: 1024
+    pub panic_reserve_size_mib: u64,
+    /// Utilization percentage at which the panic reserve is released
+    ///
+    /// Default: 98
+    pub panic_reserve_threshold_percent: u32,
+    /// Inode utilization percentage that also triggers scaling, checked after bytes
+    ///
+    /// ext4 does not gain inodes from more space, so inode pressure there is only logged.
+    ///
+    /// Default: None (disabled)
+    pub inode_utilization_threshold: Option<u32>,
+    /// Also scale below the utilization threshold when, at the fill rate between the last
+    /// two samples, the disk would be full within this many seconds
+    ///
+    /// Default: None (disabled)
+    pub fill_rate_horizon_secs: Option<u64>,
+    /// Points below the triggering threshold utilization may drop to, when re-read right
+    /// before creating a volume, and still scale
+    ///
+    /// Default: 2
+    pub no_longer_needed_margin_percent: u32,
+    /// Seconds the device name of a detached volume is not handed out again, as AWS may
+    /// still consider it busy
+    ///
+    /// Default: 30
+    pub device_reuse_quarantine_secs: u64,
+    /// Log the request id of failed AWS calls, which AWS support asks for; the errors
+    /// carry it either way
+    ///
+    /// Default: true
+    pub log_aws_request_ids: bool,
+    /// Promote gp3 volumes to io2 once the fed IOPS utilization stays high, one volume
+    /// at a time and respecting the ModifyVolume cooldown
+    ///
+    /// Default: None (disabled)
+    pub io2_promotion: Option<Io2Promotion>,
+    /// Name tag of created volumes, with {instance_id}, {mountpoint}, {seq} (position of the
+    /// volume in the logical volume) and {timestamp} (Unix seconds) expanded,
+    /// e.g. data-{instance_id}-{seq}
+    ///
+    /// Default: None (no Name tag)
+    pub name_tag_template: Option<String>,
+    /// Refuse to start when the config files have keys no setting reads, such as a
+    /// misspelled detection_intervl, instead of silently using the default
+    ///
+    /// Default: false
+    pub strict_config: bool,
+    /// Read settings from the instance tags prefixed with ebs-autoscale:, e.g.
+    /// ebs-autoscale:mountpoint=/mnt/data, through IMDS (instance metadata tags must be
+    /// enabled). Set in the config file or environment; tag values take precedence
+    /// over both, so a fleet can be configured by tagging
+    ///
+    /// Default: false
+    pub config_from_instance_tags: bool,
+    /// systemd mount unit, e.g. mnt-data.mount, whose Where= replaces mountpoint
+    ///
+    /// Looked up in MOUNT_UNIT_DIRS. Default: None
+    pub mount_unit: Option<String>,
+}
+
+impl Config {
+    /// Replaces mountpoint with the Where= of mount_unit, looked up in dirs, returning
+    /// the unit if one is set
+    pub fn apply_mount_unit(
+        &mut self, dirs: &[&str],
+    ) -> Result<Option<MountUnit>, Box<dyn Error>> {
+        let name = match &self.mount_unit {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let unit = MountUnit::load(name, dirs)?;
+        self.mountpoint = unit.mount_where.clone();
+        Ok(Some(unit))
+    }
+
+    /// Rejects settings that would only fail once scaling starts
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.volume.validate()?;
+        for (idx, tier) in self.volume_tiers.iter().enumerate() {
+            if let Some(previous) = idx.checked_sub(1).map(|idx| &self.volume_tiers[idx]) {
+                if tier.from_volume_count <= previous.from_volume_count {
+                    return Err(Box::new(VolumeTierOrderError {
+                        from_volume_count: tier.from_volume_count,
+                        previous: previous.from_volume_count,
+                    }));
+                }
+            }
+            tier.apply(&self.volume).validate()?;
+        }
+        // gp3 is always a candidate, so any target within its limits can be met
+        if let Some(target) = self.performance_target {
+            if target.iops > GP3_MAX_IOPS || target.throughput > GP3_MAX_THROUGHPUT {
+                return Err(Box::new(UnreachablePerformanceTargetError { target }));
+            }
+        }
+        if let Some(template) = &self.name_tag_template {
+            validate_name_tag_template(template)?;
+        }
+        if let PollingStrategy::Adaptive { min_secs, max_secs } = self.polling_strategy {
+            if min_secs == 0 || min_secs > max_secs {
+                return Err(Box::new(AdaptivePollingRangeError { min_secs, max_secs }));
+            }
+        }
+        if cfg!(not(feature = "sns")) && self.sns_topic_arn.is_some() {
+            return Err(Box::new(FeatureNotBuiltError { setting: "sns_topic_arn", feature: "sns" }));
+        }
+        Ok(())
+    }
+
+    /// Last tier that applies with volume_count volumes already in the logical volume
+    pub fn tier_for(&self, volume_count: u32) -> Option<&VolumeTier> {
+        self.volume_tiers.iter().rev().find(|tier| tier.from_volume_count <= volume_count)
+    }
+
+    /// Settings of the next volume with volume_count volumes already in the logical volume
+    pub fn volume_for(&self, volume_count: u32) -> Volume {
+        match self.tier_for(volume_count) {
+            Some(tier) => tier.apply(&self.volume),
+            None => self.volume.clone(),
+        }
+    }
+
+    /// Pretty JSON of the config with the REDACTED_FIELDS that are set masked, for logging
+    pub fn to_redacted_json(&self) -> Result<String, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(fields) = value.as_object_mut() {
+            for field in REDACTED_FIELDS {
+                if let Some(setting) = fields.get_mut(*field).filter(|setting| !setting.is_null()) {
+                    *setting = serde_json::Value::from(REDACTED);
+                }
+            }
+        }
+        serde_json::to_string_pretty(&value)
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             ensure_ebs_deleted_on_term: true,
+            protected_volume_ids: Vec::new(),
             detection_interval: 2,
+            max_detection_interval_secs: 60,
+            polling_strategy: PollingStrategy::Fixed,
+            describe_cache_ttl_ms: 0,
             mountpoint: "/dev/xvdba".to_string(),
             limits: Limits {
                 initial_utilization_threshold: 80,
-                min_ebs_volume_size: 10,
-                max_ebs_volume_size: 1000,
-                max_logical_volume_size: 1000,
-                max_ebs_volume_count: 100
+                min_ebs_volume_size: SizeGib(10),
+                max_ebs_volume_size: SizeGib(1000),
+                max_logical_volume_size: SizeGib(1000),
+                max_ebs_volume_count: 100,
+                max_monthly_cost_usd: None,
+                logical_size_headroom_gb: SizeGib::ZERO,
+                account_storage_quota_gib: None,
+                account_quota_max_percent: 90,
             },
             volume: Volume {
                 vol_type: "gp3".to_string(),
                 encrypted: true,
-                throughput: 1000,
+                iops: None,
+                throughput: None,
             },
-            fs_type: "btrfs".to_string()
+            volume_tiers: Vec::new(),
+            performance_target: None,
+            fs_type: "btrfs".to_string(),
+            allow_readonly: false,
+            ext4_reserved_blocks_percent: None,
+            privileged_command: Vec::new(),
+            cost_allocation_tags: BTreeMap::new(),
+            required_cost_allocation_tags: Vec::new(),
+            on_max_size: OnMaxSize::Error,
+            on_expand_failure: OnExpandFailure::LeaveAndAlert,
+            growth_strategy: GrowthStrategy::Tiered,
+            scale_mode: ScaleMode::AddVolume,
+            scale_until_below_threshold: false,
+            max_volumes_per_tick: 0,
+            concurrent_volume_creation: false,
+            state_dir: "/var/lib/ebs-autoscale-rust".to_string(),
+            balance_shutdown_timeout_secs: 30,
+            post_attach_settle_ms: 0,
+            device_appearance_timeout_secs: 30,
+            device_mismatch_retries: 3,
+            volume_create_timeout_secs: 300,
+            wait_for_modification_completed: false,
+            modification_timeout_secs: 300,
+            reattach_detached_volumes: false,
+            sns_topic_arn: None,
+            notifier: None,
+            paused: false,
+            status_addr: None,
+            status_token: None,
+            statsd_addr: None,
+            statsd_tags: Vec::new(),
+            aws_profile: None,
+            assume_role_arn: None,
+            region: None,
+            ec2_endpoint_url: None,
+            availability_zone: None,
+            utilization_log_path: None,
+            utilization_log_max_bytes: 10 * 1024 * 1024,
+            audit_log_path: None,
+            exit_report_path: None,
+            bootstrap_if_missing: false,
+            persist_mounts: true,
+            observe_only: false,
+            max_concurrent_aws_calls: 4,
+            termination_timeout_secs: 60,
+            status_tag_key: None,
+            status_tag_interval_secs: 300,
+            watchdog_interval_multiplier: 10,
+            watchdog_abort: false,
+            exclude_devices: Vec::new(),
+            alert_utilization_threshold: None,
+            alert_utilization_duration_secs: 300,
+            panic_reserve_path: None,
+            panic_reserve_size_mib: 1024,
+            panic_reserve_threshold_percent: 98,
+            inode_utilization_threshold: None,
+            fill_rate_horizon_secs: None,
+            no_longer_needed_margin_percent: 2,
+            device_reuse_quarantine_secs: 30,
+            log_aws_request_ids: true,
+            config_from_instance_tags: false,
+            io2_promotion: None,
+            name_tag_template: None,
+            strict_config: false,
+            mount_unit: None,
+        }
+    }
+}
+
+/// Config keys from tags prefixed with CONFIG_TAG_PREFIX, with dotted keys for nested
+/// settings and values parsed like environment variables
+pub struct TagProvider {
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Provider for TagProvider {
+    fn metadata(&self) -> Metadata {
+        Metadata::named("instance tags")
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        let mut figment = Figment::new();
+        for (key, value) in &self.tags {
+            if let Some(key) = key.strip_prefix(CONFIG_TAG_PREFIX) {
+                let value: Value = value.parse().expect("infallible");
+                figment = figment.merge(Serialized::default(key, value));
+            }
+        }
+        figment.data()
+    }
+}
+
+/// Overlays the `[profiles.<profile>]` table onto the rest of the config
+///
+/// Without a profile, the profiles tables are ignored.
+pub fn select_profile(
+    figment: Figment,
+    profile: Option<&str>,
+) -> Result<Figment, UnknownProfileError> {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return Ok(figment),
+    };
+    let key = format!("profiles.{}", profile);
+    if figment.find_value(&key).is_err() {
+        return Err(UnknownProfileError { profile: profile.to_string() });
+    }
+    let overlay = figment.focus(&key);
+    Ok(figment.merge(overlay))
+}
+
+/// Errors on keys of the config sources that no setting reads, including keys in
+/// `[profiles.<profile>]` tables
+pub fn reject_unknown_keys(figment: &Figment) -> Result<(), Box<dyn Error>> {
+    let known = serde_json::to_value(Config::default())?;
+    let provided: serde_json::Value = figment.extract()?;
+    let mut keys = Vec::new();
+    if let Some(provided) = provided.as_object() {
+        for (key, value) in provided {
+            if key != "profiles" {
+                collect_unknown_keys(&known, key, value, key, &mut keys);
+                continue;
+            }
+            for (profile, table) in value.as_object().into_iter().flatten() {
+                for (key, value) in table.as_object().into_iter().flatten() {
+                    let path = format!("profiles.{}.{}", profile, key);
+                    collect_unknown_keys(&known, key, value, &path, &mut keys);
+                }
+            }
+        }
+    }
+    if keys.is_empty() {
+        return Ok(());
+    }
+    Err(Box::new(UnknownConfigKeysError { keys }))
+}
+
+/// Settings holding a map, whose keys are user data rather than setting names
+const MAP_SETTINGS: &[&str] = &["cost_allocation_tags"];
+
+/// Adds path to keys if known has no key, recursing into tables like [limits] but not
+/// into the maps of MAP_SETTINGS
+fn collect_unknown_keys(
+    known: &serde_json::Value,
+    key: &str,
+    value: &serde_json::Value,
+    path: &str,
+    keys: &mut Vec<String>,
+) {
+    let known = match known.get(key) {
+        Some(known) => known,
+        None => return keys.push(path.to_string()),
+    };
+    if MAP_SETTINGS.contains(&key) {
+        return;
+    }
+    if let (Some(_), Some(table)) = (known.as_object(), value.as_object()) {
+        for (key, value) in table {
+            collect_unknown_keys(known, key, value, &format!("{}.{}", path, key), keys);
         }
     }
 }
@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::config::{self, PerformanceTarget, Volume};
+use crate::size::SizeGib;
+
+#[derive(Debug, Clone)]
+pub struct UnknownVolumeTypeError {
+    pub vol_type: String,
+}
+
+impl Error for UnknownVolumeTypeError {}
+
+impl fmt::Display for UnknownVolumeTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No pricing known for volume type {}", self.vol_type)
+    }
+}
+
+/// Monthly USD prices for an EBS volume type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumePricing {
+    pub per_gib: f64,
+    /// Price per provisioned IOPS above included_iops
+    pub per_iops: f64,
+    /// Price per provisioned MiB/s above included_throughput
+    pub per_mibps: f64,
+    pub included_iops: u64,
+    pub included_throughput: u64,
+}
+
+/// Static us-east-1 on-demand pricing
+pub fn pricing(vol_type: &str) -> Result<VolumePricing, UnknownVolumeTypeError> {
+    let flat = |per_gib| VolumePricing {
+        per_gib,
+        per_iops: 0.0,
+        per_mibps: 0.0,
+        included_iops: 0,
+        included_throughput: 0,
+    };
+    match vol_type {
+        "gp3" => Ok(VolumePricing {
+            per_gib: 0.08,
+            per_iops: 0.005,
+            per_mibps: 0.04,
+            included_iops: 3000,
+            included_throughput: 125,
+        }),
+        "gp2" => Ok(flat(0.10)),
+        "io1" | "io2" => Ok(VolumePricing {
+            per_gib: 0.125,
+            per_iops: 0.065,
+            ..flat(0.0)
+        }),
+        "st1" => Ok(flat(0.045)),
+        "sc1" => Ok(flat(0.015)),
+        "standard" => Ok(flat(0.05)),
+        _ => Err(UnknownVolumeTypeError { vol_type: vol_type.to_string() }),
+    }
+}
+
+/// Projected monthly cost of a single volume
+pub fn monthly_cost(
+    vol_type: &str,
+    size_gib: SizeGib,
+    iops: Option<u64>,
+    throughput: Option<u64>,
+) -> Result<f64, UnknownVolumeTypeError> {
+    let price = pricing(vol_type)?;
+    let extra_iops = iops.unwrap_or(0).saturating_sub(price.included_iops);
+    let extra_throughput = throughput.unwrap_or(0).saturating_sub(price.included_throughput);
+    Ok(
+        size_gib.as_u64() as f64 * price.per_gib
+            + extra_iops as f64 * price.per_iops
+            + extra_throughput as f64 * price.per_mibps
+    )
+}
+
+/// Cheapest gp3 volume meeting target, keeping encryption from volume
+///
+/// The gp3 baseline of 3000 IOPS and 125MiB/s is used when it meets target, otherwise
+/// just enough IOPS and throughput are provisioned. Types whose performance depends on
+/// size or access pattern, like gp2 and st1, are never picked.
+pub fn cheapest_volume(volume: &Volume, target: PerformanceTarget) -> Volume {
+    // Throughput needs a minimum of IOPS on gp3
+    let min_iops = (target.throughput as f64 / config::GP3_MAX_THROUGHPUT_PER_IOPS).ceil() as u64;
+    Volume {
+        vol_type: "gp3".to_string(),
+        encrypted: volume.encrypted,
+        iops: Some(target.iops.max(min_iops).max(3000)),
+        throughput: Some(target.throughput.max(125)),
+    }
+}
@@ -1,7 +1,24 @@
+#[cfg(target_os = "linux")]
 use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
 use sysinfo::Disks;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use log::warn;
+
+use crate::clock::{Clock, SystemClock};
+use crate::size::SizeGib;
+
+/// Prefix for the device names handed to AttachVolume
+pub const DEVICE_PREFIX: &str = "/dev/xvd";
 
 #[derive(Debug, Clone)]
 pub struct MountPointNotFoundError;
@@ -25,62 +42,539 @@ impl fmt::Display for NoMoreDeviceNamesAvailableError {
     }
 }
 
-pub trait DiskMgr {
-    fn new_disks(&mut self);
-    fn save_disk_list(&mut self);
+/// Refreshing the disk list failed or found no disks at all
+#[derive(Debug, Clone)]
+pub struct DiskListError;
+
+impl Error for DiskListError {}
+
+impl fmt::Display for DiskListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Disk list is empty - /proc/mounts or /sys may not be readable")
+    }
+}
+
+/// Volume id of an EBS NVMe serial, e.g. vol-0123456789abcdef0 for vol0123456789abcdef0
+pub fn volume_id_from_serial(serial: &str) -> Option<String> {
+    let id = serial.trim().strip_prefix("vol")?;
+    let id = id.strip_prefix('-').unwrap_or(id);
+    if id.is_empty() {
+        return None;
+    }
+    Some(format!("vol-{}", id))
+}
+
+/// Lists the block devices currently present on the host
+pub trait DeviceLister: Send {
+    fn list_devices(&self) -> Result<Vec<String>, io::Error>;
+}
+
+#[cfg(target_os = "linux")]
+/// Lists the entries under /dev
+pub struct SysDeviceLister;
+
+#[cfg(target_os = "linux")]
+impl DeviceLister for SysDeviceLister {
+    fn list_devices(&self) -> Result<Vec<String>, io::Error> {
+        let mut devices = Vec::new();
+        for entry in std::fs::read_dir("/dev")? {
+            devices.push(entry?.path().to_string_lossy().into_owned());
+        }
+        Ok(devices)
+    }
+}
+
+pub struct MockDeviceLister {
+    pub devices: Vec<String>,
+}
+
+impl DeviceLister for MockDeviceLister {
+    fn list_devices(&self) -> Result<Vec<String>, io::Error> {
+        Ok(self.devices.clone())
+    }
+}
+
+/// A mounted filesystem as listed by sysinfo, sizes in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskInfo {
+    pub name: String,
+    pub mount_point: String,
+    pub total_space: u64,
+    pub available_space: u64,
+}
+
+/// Loads the mounted filesystems, rescanning them on every call
+pub trait DiskLister: Send {
+    fn list_disks(&mut self) -> Vec<DiskInfo>;
+}
+
+#[cfg(target_os = "linux")]
+pub struct SysinfoDiskLister {
+    disks: Disks,
+}
+
+#[cfg(target_os = "linux")]
+impl Default for SysinfoDiskLister {
+    fn default() -> Self {
+        SysinfoDiskLister { disks: Disks::new() }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DiskLister for SysinfoDiskLister {
+    fn list_disks(&mut self) -> Vec<DiskInfo> {
+        self.disks.refresh_list();
+        self.disks.list()
+            .iter()
+            .map(|disk| DiskInfo {
+                name: disk.name().to_string_lossy().into_owned(),
+                mount_point: disk.mount_point().to_string_lossy().into_owned(),
+                total_space: disk.total_space(),
+                available_space: disk.available_space(),
+            })
+            .collect()
+    }
+}
+
+pub struct MockDiskLister {
+    pub disks: Vec<DiskInfo>,
+    /// Times the disks were listed
+    pub refreshes: Arc<AtomicUsize>,
+}
+
+impl DiskLister for MockDiskLister {
+    fn list_disks(&mut self) -> Vec<DiskInfo> {
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+        self.disks.clone()
+    }
+}
+
+/// Usage of the filesystem at a mountpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MountUsage {
+    pub usage_percent: u32,
+    pub size: SizeGib,
+}
+
+/// Usage of every mountpoint from a single disk refresh
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskSnapshot {
+    pub mounts: BTreeMap<String, MountUsage>,
+    /// Disks listed, excluded ones included, to tell an empty list from a missing mount
+    pub disk_count: usize,
+}
+
+/// Percent of total used, 0 for an empty disk rather than dividing by zero
+fn usage_percent(total: u64, available: u64) -> u32 {
+    let used = u128::from(total.saturating_sub(available));
+    (used * 100).checked_div(u128::from(total)).unwrap_or(0) as u32
+}
+
+impl DiskSnapshot {
+    /// Indexes the disks not excluded by filter by mountpoint, the first disk listed
+    /// for a mountpoint winning
+    pub fn new(disks: &[DiskInfo], filter: &DeviceFilter) -> DiskSnapshot {
+        let mut mounts = BTreeMap::new();
+        for disk in disks.iter().filter(|disk| filter.allows(&disk.name, &disk.mount_point)) {
+            mounts.entry(disk.mount_point.clone()).or_insert_with(|| MountUsage {
+                usage_percent: usage_percent(disk.total_space, disk.available_space),
+                size: SizeGib::from_bytes(disk.total_space),
+            });
+        }
+        DiskSnapshot { mounts, disk_count: disks.len() }
+    }
+
+    pub fn get(&self, mountpoint: &str) -> Result<MountUsage, MountPointNotFoundError> {
+        self.mounts.get(mountpoint).copied().ok_or(MountPointNotFoundError)
+    }
+}
+
+/// Devices and mount paths ignored on enumeration, matched as whole names or as a
+/// directory and what is below it, or by prefix when ending with *
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceFilter {
+    excludes: Vec<String>,
+}
+
+impl DeviceFilter {
+    pub fn new(excludes: Vec<String>) -> DeviceFilter {
+        DeviceFilter { excludes }
+    }
+
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.excludes.iter().any(|exclude| match exclude.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path
+                .strip_prefix(exclude.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        })
+    }
+
+    /// Whether a disk, by device and mount path, takes part in usage lookups
+    pub fn allows(&self, device: &str, mount_path: &str) -> bool {
+        !self.is_excluded(device) && !self.is_excluded(mount_path)
+    }
+}
+
+/// Candidate device names, in allocation order: xvdb..xvdz, then xvdba..xvdzz
+pub fn candidate_device_names(prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = ('b'..='z')
+        .map(|c| format!("{}{}", prefix, c))
+        .collect();
+    for first in 'b'..='z' {
+        for second in 'a'..='z' {
+            names.push(format!("{}{}{}", prefix, first, second));
+        }
+    }
+    names
+}
+
+/// Picks the next free device name, resuming after the last one handed out
+///
+/// Wraps around to the start of the sequence once the end is reached, so names
+/// freed by deleted volumes are eventually reused. A released name is skipped for the
+/// quarantine, as AWS may still consider it busy right after the detach.
+pub struct DeviceAllocator {
+    candidates: Vec<String>,
+    cursor: usize,
+    quarantine: Duration,
+    /// Names released within the quarantine, and when
+    released: BTreeMap<String, Instant>,
+    clock: Box<dyn Clock>,
+}
+
+impl DeviceAllocator {
+    pub fn new(prefix: &str) -> DeviceAllocator {
+        DeviceAllocator::with_quarantine(prefix, Duration::ZERO, Box::new(SystemClock))
+    }
+
+    pub fn with_quarantine(
+        prefix: &str,
+        quarantine: Duration,
+        clock: Box<dyn Clock>,
+    ) -> DeviceAllocator {
+        DeviceAllocator {
+            candidates: candidate_device_names(prefix),
+            cursor: 0,
+            quarantine,
+            released: BTreeMap::new(),
+            clock,
+        }
+    }
+
+    /// Marks device as freed by a detached volume, starting its quarantine
+    pub fn release(&mut self, device: &str) {
+        if !self.quarantine.is_zero() {
+            self.released.insert(device.to_string(), self.clock.now());
+        }
+    }
+
+    pub fn next_free(&mut self, used: &[String]) -> Result<String, NoMoreDeviceNamesAvailableError> {
+        self.next_free_filtered(used, &DeviceFilter::default())
+    }
+
+    /// Like next_free, never handing out names excluded by filter
+    pub fn next_free_filtered(
+        &mut self,
+        used: &[String],
+        filter: &DeviceFilter,
+    ) -> Result<String, NoMoreDeviceNamesAvailableError> {
+        let now = self.clock.now();
+        let quarantine = self.quarantine;
+        self.released.retain(|_, released| now.duration_since(*released) < quarantine);
+        let used: HashSet<&str> = used.iter().map(|dev| dev.as_str()).collect();
+        let count = self.candidates.len();
+        for offset in 0..count {
+            let idx = (self.cursor + offset) % count;
+            let candidate = self.candidates[idx].as_str();
+            if !used.contains(candidate)
+                && !filter.is_excluded(candidate)
+                && !self.released.contains_key(candidate)
+            {
+                self.cursor = (idx + 1) % count;
+                return Ok(self.candidates[idx].clone());
+            }
+        }
+        Err(NoMoreDeviceNamesAvailableError)
+    }
+
+    /// Hands out count distinct names at once, e.g. for volumes attached concurrently
+    pub fn next_free_many(
+        &mut self,
+        used: &[String],
+        filter: &DeviceFilter,
+        count: usize,
+    ) -> Result<Vec<String>, NoMoreDeviceNamesAvailableError> {
+        let mut used = used.to_vec();
+        let mut names = Vec::new();
+        for _ in 0..count {
+            let name = self.next_free_filtered(&used, filter)?;
+            used.push(name.clone());
+            names.push(name);
+        }
+        Ok(names)
+    }
+}
+
+/// Allocator behind a lock, so disk managers sharing one never hand out the same
+/// device name
+pub type SharedDeviceAllocator = Arc<Mutex<DeviceAllocator>>;
+
+pub fn shared_device_allocator() -> SharedDeviceAllocator {
+    Arc::new(Mutex::new(DeviceAllocator::new(DEVICE_PREFIX)))
+}
+
+/// Percentage of inodes in use, 0 for filesystems without a fixed inode count (e.g. btrfs)
+pub fn inode_usage_percent(files: u64, free: u64) -> u32 {
+    if files == 0 {
+        return 0;
+    }
+    (files.saturating_sub(free).saturating_mul(100) / files) as u32
+}
+
+/// Whether mountpoint is mounted read-only, from the contents of /proc/mounts
+///
+/// The last matching entry wins, as for fs_type_from_mounts.
+pub fn read_only_from_mounts(mounts: &str, mountpoint: &str) -> Option<bool> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mounted_at = fields.nth(1)?;
+            let options = fields.nth(1)?;
+            (mounted_at == mountpoint).then(|| options.split(',').any(|option| option == "ro"))
+        })
+        .next_back()
+}
+
+/// Filesystem type mounted at mountpoint, from the contents of /proc/mounts
+///
+/// The last matching entry wins, since later mounts shadow earlier ones.
+pub fn fs_type_from_mounts(mounts: &str, mountpoint: &str) -> Option<String> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _device = fields.next()?;
+            let mounted_at = fields.next()?;
+            let fs_type = fields.next()?;
+            (mounted_at == mountpoint).then(|| fs_type.to_string())
+        })
+        .next_back()
+}
+
+pub trait DiskMgr: Send {
+    /// Replaces the disk list with a freshly loaded one
+    fn new_disks(&mut self) -> Result<(), DiskListError>;
+    /// Refreshes the disk list, failing rather than keeping an empty list
+    fn save_disk_list(&mut self) -> Result<(), DiskListError>;
     /// Returns the usage percentage for a mountpoint
     fn disk_usage_percent(&mut self, mountpoint: String) -> Result<u32, MountPointNotFoundError>;
+    /// Returns the inode usage percentage for a mountpoint
+    fn inode_usage_percent(&mut self, mountpoint: String) -> Result<u32, MountPointNotFoundError>;
     /// Total size for a mountpoint
-    fn disk_size(&mut self, mountpoint: String) -> Result<u64, MountPointNotFoundError>;
+    fn disk_size(&mut self, mountpoint: String) -> Result<SizeGib, MountPointNotFoundError>;
+    /// Type of the filesystem mounted at a mountpoint, e.g. btrfs
+    fn filesystem_type(&mut self, mountpoint: String) -> Result<String, MountPointNotFoundError>;
+    /// Whether a mountpoint is mounted read-only
+    fn is_read_only(&mut self, mountpoint: String) -> Result<bool, MountPointNotFoundError>;
     fn get_next_logical_device(&mut self) -> Result<String, NoMoreDeviceNamesAvailableError>;
+    /// Next count free device names, allocated under a single lock so they are distinct
+    fn get_next_logical_devices(
+        &mut self, count: usize,
+    ) -> Result<Vec<String>, NoMoreDeviceNamesAvailableError>;
+    /// Whether the block device is present on the host
+    fn device_exists(&mut self, device: String) -> bool;
+    /// Volume id the device reports, from its NVMe serial on Nitro instances; None when
+    /// it reports none, as on xen instances
+    fn device_volume_id(&mut self, device: String) -> Option<String>;
+    /// Usage of every mountpoint as of the last refresh, without rescanning the disks
+    fn snapshot(&mut self) -> DiskSnapshot;
+    /// Quarantines the device of a detached volume before its name is handed out again
+    fn release_device(&mut self, device: String);
 }
 
-struct ConcreteDiskMgr {
-    disks: Disks,
+#[cfg(target_os = "linux")]
+/// Disk manager answering usage lookups from the snapshot taken on the last refresh,
+/// so the disks are scanned once per tick however many lookups follow
+pub struct ConcreteDiskMgr {
+    disk_lister: Box<dyn DiskLister>,
+    snapshot: DiskSnapshot,
+    lister: Box<dyn DeviceLister>,
+    allocator: SharedDeviceAllocator,
+    filter: DeviceFilter,
 }
 
+#[cfg(target_os = "linux")]
+impl ConcreteDiskMgr {
+    pub fn new(filter: DeviceFilter) -> ConcreteDiskMgr {
+        ConcreteDiskMgr::with_allocator(
+            Box::new(SysDeviceLister),
+            shared_device_allocator(),
+            filter,
+        )
+    }
+
+    pub fn with_lister(lister: Box<dyn DeviceLister>) -> ConcreteDiskMgr {
+        ConcreteDiskMgr::with_allocator(lister, shared_device_allocator(), DeviceFilter::default())
+    }
+
+    pub fn with_allocator(
+        lister: Box<dyn DeviceLister>,
+        allocator: SharedDeviceAllocator,
+        filter: DeviceFilter,
+    ) -> ConcreteDiskMgr {
+        ConcreteDiskMgr::with_disk_lister(
+            Box::new(SysinfoDiskLister::default()),
+            lister,
+            allocator,
+            filter,
+        )
+    }
+
+    pub fn with_disk_lister(
+        disk_lister: Box<dyn DiskLister>,
+        lister: Box<dyn DeviceLister>,
+        allocator: SharedDeviceAllocator,
+        filter: DeviceFilter,
+    ) -> ConcreteDiskMgr {
+        let mut diskmgr = ConcreteDiskMgr {
+            disk_lister,
+            snapshot: DiskSnapshot::default(),
+            lister,
+            allocator,
+            filter,
+        };
+        diskmgr.refresh();
+        diskmgr
+    }
+
+    fn refresh(&mut self) {
+        self.snapshot = DiskSnapshot::new(&self.disk_lister.list_disks(), &self.filter);
+    }
+
+    fn check_disk_list(&self) -> Result<(), DiskListError> {
+        if self.snapshot.disk_count == 0 {
+            warn!("sysinfo returned no disks");
+            return Err(DiskListError);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
 impl DiskMgr for ConcreteDiskMgr {
-    fn new_disks(&mut self) {
-        self.disks = Disks::new()
+    fn new_disks(&mut self) -> Result<(), DiskListError> {
+        self.snapshot = DiskSnapshot::default();
+        self.refresh();
+        self.check_disk_list()
     }
 
-    fn save_disk_list(&mut self) {
-        self.disks.refresh_list();
+    fn save_disk_list(&mut self) -> Result<(), DiskListError> {
+        self.refresh();
+        self.check_disk_list()
     }
 
     fn disk_usage_percent(&mut self, mountpoint: String) -> Result<u32, MountPointNotFoundError> {
-        let mt_path = Path::new(&mountpoint);
-        for disk in self.disks.list() {
-            if disk.mount_point() == mt_path {
-                return Ok(
-                    (disk.total_space() / disk.available_space() * 100)
-                        .try_into().unwrap()
-                )
-            }
-        };
-        Err(MountPointNotFoundError)
+        Ok(self.snapshot.get(&mountpoint)?.usage_percent)
     }
 
-    fn disk_size(&mut self, mountpoint: String) -> Result<u64, MountPointNotFoundError> {
-        let mt_path = Path::new(&mountpoint);
-        for disk in self.disks.list() {
-            if disk.mount_point() == mt_path {
-                return Ok(disk.total_space())
-            }
-        };
-        Err(MountPointNotFoundError)
+    fn inode_usage_percent(&mut self, mountpoint: String) -> Result<u32, MountPointNotFoundError> {
+        self.snapshot.get(&mountpoint)?;
+        let path = CString::new(mountpoint).map_err(|_| MountPointNotFoundError)?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            warn!("statvfs failed - {}", io::Error::last_os_error());
+            return Err(MountPointNotFoundError);
+        }
+        Ok(inode_usage_percent(stat.f_files as u64, stat.f_ffree as u64))
+    }
+
+    fn disk_size(&mut self, mountpoint: String) -> Result<SizeGib, MountPointNotFoundError> {
+        Ok(self.snapshot.get(&mountpoint)?.size)
+    }
+
+    fn filesystem_type(&mut self, mountpoint: String) -> Result<String, MountPointNotFoundError> {
+        let mounts = std::fs::read_to_string("/proc/mounts").map_err(|e| {
+            warn!("Could not read /proc/mounts - {}", e);
+            MountPointNotFoundError
+        })?;
+        fs_type_from_mounts(&mounts, &mountpoint).ok_or(MountPointNotFoundError)
+    }
+
+    fn is_read_only(&mut self, mountpoint: String) -> Result<bool, MountPointNotFoundError> {
+        let mounts = std::fs::read_to_string("/proc/mounts").map_err(|e| {
+            warn!("Could not read /proc/mounts - {}", e);
+            MountPointNotFoundError
+        })?;
+        read_only_from_mounts(&mounts, &mountpoint).ok_or(MountPointNotFoundError)
     }
 
     fn get_next_logical_device(&mut self) -> Result<String, NoMoreDeviceNamesAvailableError> {
-        Ok("/dev/test".to_string())
+        let used = self.lister.list_devices().map_err(|e| {
+            warn!("Could not list devices - {}", e);
+            NoMoreDeviceNamesAvailableError
+        })?;
+        self.allocator.lock().unwrap().next_free_filtered(&used, &self.filter)
+    }
+
+    fn get_next_logical_devices(
+        &mut self, count: usize,
+    ) -> Result<Vec<String>, NoMoreDeviceNamesAvailableError> {
+        let used = self.lister.list_devices().map_err(|e| {
+            warn!("Could not list devices - {}", e);
+            NoMoreDeviceNamesAvailableError
+        })?;
+        self.allocator.lock().unwrap().next_free_many(&used, &self.filter, count)
+    }
+
+    fn device_exists(&mut self, device: String) -> bool {
+        Path::new(&device).exists()
+    }
+
+    fn device_volume_id(&mut self, device: String) -> Option<String> {
+        // /dev/xvdb is a udev symlink to the nvme device on Nitro instances
+        let name = std::fs::canonicalize(device).ok()?.file_name()?.to_str()?.to_string();
+        if !name.starts_with("nvme") {
+            return None;
+        }
+        let serial = std::fs::read_to_string(format!("/sys/block/{}/device/serial", name)).ok()?;
+        volume_id_from_serial(&serial)
+    }
+
+    fn snapshot(&mut self) -> DiskSnapshot {
+        self.snapshot.clone()
+    }
+
+    fn release_device(&mut self, device: String) {
+        self.allocator.lock().unwrap().release(&device)
     }
 }
 
 pub struct MockDiskMgr {
     pub disks: Vec<String>,
     pub utilization_percentage: u32,
+    /// In GiB
     pub total_disk_size: u64,
-    pub sim_no_more_device_names: bool
+    pub sim_no_more_device_names: bool,
+    /// Refreshing the disk list finds no disks
+    pub sim_empty_disk_list: bool,
+    /// Nothing is mounted at the mountpoint
+    pub sim_missing_mountpoint: bool,
+    /// Utilization values returned first, one per call, before utilization_percentage
+    pub pending_utilization: Vec<u32>,
+    /// Hands out device names instead of always returning /dev/test
+    pub allocator: Option<SharedDeviceAllocator>,
+    pub mounted_fs_type: String,
+    /// Whether the mountpoint is mounted read-only
+    pub read_only: bool,
+    pub inode_utilization_percentage: u32,
+    /// Times device_exists reports a device missing before it appears
+    pub device_appears_after: u32,
+    /// Volume ids device_volume_id reports, one per call, before reporting none
+    pub device_volume_ids: Vec<String>,
+    /// Mountpoint reported by snapshot
+    pub mountpoint: String,
 }
 
 impl Default for MockDiskMgr {
@@ -90,31 +584,124 @@ impl Default for MockDiskMgr {
             utilization_percentage: 10,
             total_disk_size: 100,
             sim_no_more_device_names: false,
+            sim_empty_disk_list: false,
+            sim_missing_mountpoint: false,
+            pending_utilization: Vec::new(),
+            allocator: None,
+            mounted_fs_type: "btrfs".to_string(),
+            read_only: false,
+            inode_utilization_percentage: 0,
+            device_appears_after: 0,
+            device_volume_ids: Vec::new(),
+            mountpoint: "/dev/xvdba".to_string(),
         }
     }
 }
 
 impl DiskMgr for MockDiskMgr {
-    fn new_disks(&mut self) {
-        self.disks = Vec::new()
+    fn new_disks(&mut self) -> Result<(), DiskListError> {
+        self.disks = Vec::new();
+        self.save_disk_list()
     }
 
-    fn save_disk_list(&mut self) {
+    fn save_disk_list(&mut self) -> Result<(), DiskListError> {
+        if self.sim_empty_disk_list {
+            self.disks = Vec::new();
+            return Err(DiskListError);
+        }
         self.disks = vec!["test".to_string()];
+        Ok(())
     }
 
     fn disk_usage_percent(&mut self, _mountpoint: String) -> Result<u32, MountPointNotFoundError> {
+        if self.sim_missing_mountpoint {
+            return Err(MountPointNotFoundError)
+        }
+        if !self.pending_utilization.is_empty() {
+            return Ok(self.pending_utilization.remove(0))
+        }
         Ok(self.utilization_percentage)
     }
 
-    fn disk_size(&mut self, _mountpoint: String) -> Result<u64, MountPointNotFoundError> {
-        Ok(self.total_disk_size)
+    fn inode_usage_percent(&mut self, _mountpoint: String) -> Result<u32, MountPointNotFoundError> {
+        if self.sim_missing_mountpoint {
+            return Err(MountPointNotFoundError)
+        }
+        Ok(self.inode_utilization_percentage)
+    }
+
+    fn disk_size(&mut self, _mountpoint: String) -> Result<SizeGib, MountPointNotFoundError> {
+        if self.sim_missing_mountpoint {
+            return Err(MountPointNotFoundError)
+        }
+        Ok(SizeGib(self.total_disk_size))
+    }
+
+    fn filesystem_type(&mut self, _mountpoint: String) -> Result<String, MountPointNotFoundError> {
+        if self.sim_missing_mountpoint {
+            return Err(MountPointNotFoundError)
+        }
+        Ok(self.mounted_fs_type.clone())
+    }
+
+    fn is_read_only(&mut self, _mountpoint: String) -> Result<bool, MountPointNotFoundError> {
+        if self.sim_missing_mountpoint {
+            return Err(MountPointNotFoundError)
+        }
+        Ok(self.read_only)
     }
 
     fn get_next_logical_device(&mut self) -> Result<String, NoMoreDeviceNamesAvailableError> {
         if self.sim_no_more_device_names {
             return Err(NoMoreDeviceNamesAvailableError)
         }
+        if let Some(allocator) = &self.allocator {
+            return allocator.lock().unwrap().next_free(&[]);
+        }
         Ok("/dev/test".to_string())
     }
+
+    fn get_next_logical_devices(
+        &mut self, count: usize,
+    ) -> Result<Vec<String>, NoMoreDeviceNamesAvailableError> {
+        if self.sim_no_more_device_names {
+            return Err(NoMoreDeviceNamesAvailableError)
+        }
+        if let Some(allocator) = &self.allocator {
+            return allocator.lock().unwrap().next_free_many(&[], &DeviceFilter::default(), count);
+        }
+        Ok(vec!["/dev/test".to_string(); count])
+    }
+
+    fn device_exists(&mut self, _device: String) -> bool {
+        if self.device_appears_after > 0 {
+            self.device_appears_after -= 1;
+            return false;
+        }
+        true
+    }
+
+    fn device_volume_id(&mut self, _device: String) -> Option<String> {
+        if self.device_volume_ids.is_empty() {
+            return None;
+        }
+        Some(self.device_volume_ids.remove(0))
+    }
+
+    fn snapshot(&mut self) -> DiskSnapshot {
+        let mut snapshot = DiskSnapshot { disk_count: self.disks.len(), ..DiskSnapshot::default() };
+        if !self.sim_missing_mountpoint {
+            snapshot.mounts.insert(self.mountpoint.clone(), MountUsage {
+                usage_percent: self.utilization_percentage,
+                size: SizeGib(self.total_disk_size),
+            });
+        }
+        snapshot
+    }
+
+    fn release_device(&mut self, device: String) {
+        if let Some(allocator) = &self.allocator {
+            allocator.lock().unwrap().release(&device);
+        }
+    }
 }
@@ -0,0 +1,70 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct DrainState {
+    requested: bool,
+    in_flight: bool,
+}
+
+/// Coordinates shutdown with the detection loop, shared between the loop and main
+///
+/// Once shutdown is requested no new reconcile starts, and wait blocks until the one in
+/// flight finishes, so a volume is never left created but unattached.
+#[derive(Clone, Default)]
+pub struct Drain {
+    state: Arc<(Mutex<DrainState>, Condvar)>,
+}
+
+/// Marks a reconcile in flight until dropped
+pub struct InFlight {
+    drain: Drain,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let (state, changed) = &*self.drain.state;
+        state.lock().unwrap().in_flight = false;
+        changed.notify_all();
+    }
+}
+
+impl Drain {
+    /// Stops new reconciles and wakes the loop if it is sleeping
+    pub fn request(&self) {
+        let (state, changed) = &*self.state;
+        state.lock().unwrap().requested = true;
+        changed.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.0.lock().unwrap().requested
+    }
+
+    /// Starts a reconcile, None once shutdown was requested
+    pub fn begin(&self) -> Option<InFlight> {
+        let mut state = self.state.0.lock().unwrap();
+        if state.requested {
+            return None;
+        }
+        state.in_flight = true;
+        Some(InFlight { drain: self.clone() })
+    }
+
+    /// Sleeps for interval, returning early when shutdown is requested
+    pub fn sleep(&self, interval: Duration) {
+        let (state, changed) = &*self.state;
+        let state = state.lock().unwrap();
+        let _ = changed.wait_timeout_while(state, interval, |state| !state.requested);
+    }
+
+    /// Waits for the reconcile in flight to finish, false if it did not by the deadline
+    pub fn wait(&self, deadline: Instant) -> bool {
+        let (state, changed) = &*self.state;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (state, _) = changed
+            .wait_timeout_while(state.lock().unwrap(), timeout, |state| state.in_flight)
+            .unwrap();
+        !state.in_flight
+    }
+}
@@ -0,0 +1,33 @@
+use serde::Serialize;
+
+use crate::size::SizeGib;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ScaleUp,
+    ScaleDown,
+    LimitReached,
+    HighUtilization,
+    HighUtilizationCleared,
+    /// A volume was attached but the filesystem could not be grown onto it
+    ExpandFailed,
+    /// The panic reserve file was deleted to free space
+    PanicReserveReleased,
+}
+
+/// Something the autoscaler did (or could not do), published for external consumers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AutoscaleEvent {
+    pub event: EventKind,
+    /// Unknown when IMDS is bypassed with region/AZ overrides
+    pub instance_id: Option<String>,
+    pub mountpoint: String,
+    pub volume_id: Option<String>,
+    pub size_gib: Option<SizeGib>,
+    /// Logical volume size after the event
+    pub total_size_gib: SizeGib,
+    /// Only set on utilization alerts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utilization_percent: Option<u32>,
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use log::error;
+use serde::Serialize;
+
+use crate::size::SizeGib;
+use crate::status::StatusSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    /// Stopped on a termination signal
+    Graceful,
+    Error,
+}
+
+/// Summary of a run, emitted as one JSON line when the daemon exits
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExitReport {
+    pub reason: ExitReason,
+    pub error: Option<String>,
+    /// Managed volumes as of the last tick, None if none ran
+    pub volume_count: Option<u32>,
+    pub total_size_gib: Option<SizeGib>,
+    /// Successful mutating actions of this run by action, e.g. create_volume
+    pub actions: BTreeMap<String, u64>,
+    /// Whether draining filesystem work and deleting volumes on termination succeeded,
+    /// None when the daemon stopped on an error before cleaning up
+    pub cleanup_succeeded: Option<bool>,
+}
+
+impl ExitReport {
+    /// Report of a run ending with result, the cleanup outcome on a graceful exit
+    pub fn new(snapshot: &StatusSnapshot, result: &Result<bool, Box<dyn Error>>) -> ExitReport {
+        let (reason, error, cleanup_succeeded) = match result {
+            Ok(cleanup_succeeded) => (ExitReason::Graceful, None, Some(*cleanup_succeeded)),
+            Err(e) => (ExitReason::Error, Some(e.to_string()), None),
+        };
+        ExitReport {
+            reason,
+            error,
+            volume_count: snapshot.volume_count,
+            total_size_gib: snapshot.total_size_gib,
+            actions: snapshot.actions.clone(),
+            cleanup_succeeded,
+        }
+    }
+
+    /// Prints the report as a JSON line, also writing it to path if set
+    pub fn emit(&self, path: Option<&str>) -> Result<String, Box<dyn Error>> {
+        let line = serde_json::to_string(self)?;
+        println!("{}", line);
+        if let Some(path) = path {
+            fs::write(path, format!("{}\n", line))?;
+        }
+        Ok(line)
+    }
+}
+
+/// Emits the report of a run ending with result, also writing it to path if set, and
+/// returns its JSON line
+///
+/// A report that cannot be written is only logged, so it never hides the exit error.
+pub fn report(
+    snapshot: &StatusSnapshot,
+    result: &Result<bool, Box<dyn Error>>,
+    path: Option<&str>,
+) -> Option<String> {
+    match ExitReport::new(snapshot, result).emit(path) {
+        Ok(line) => Some(line),
+        Err(e) => {
+            error!("Could not write the exit report - {}", e);
+            None
+        }
+    }
+}
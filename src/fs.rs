@@ -1,5 +1,17 @@
 use std::error::Error;
 use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+
+#[cfg(target_os = "linux")]
+const FSTAB_PATH: &str = "/etc/fstab";
 
 #[derive(Debug, Clone)]
 pub struct GenericFSError;
@@ -12,37 +24,582 @@ impl fmt::Display for GenericFSError {
     }
 }
 
-pub trait FS {
-    fn expand_volume(&self, dev: String) -> Result<bool, Box<GenericFSError>>;
+/// A filesystem tool is not installed
+#[derive(Debug, Clone)]
+pub struct FilesystemToolMissingError {
+    pub tool: String,
+}
+
+impl Error for FilesystemToolMissingError {}
+
+impl fmt::Display for FilesystemToolMissingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not installed", self.tool)
+    }
+}
+
+/// A device could not be added to the filesystem, so it is not part of it
+#[derive(Debug, Clone)]
+pub struct DeviceNotAddedError {
+    pub device: String,
+}
+
+impl Error for DeviceNotAddedError {}
+
+impl fmt::Display for DeviceNotAddedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} could not be added to the filesystem", self.device)
+    }
+}
+
+/// The mountpoint could not be unmounted, so its devices must stay attached
+#[derive(Debug, Clone)]
+pub struct UnmountFailedError {
+    pub mountpoint: String,
+    pub stderr: String,
+}
+
+impl Error for UnmountFailedError {}
+
+impl fmt::Display for UnmountFailedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not unmount {} - {}", self.mountpoint, self.stderr.trim())
+    }
+}
+
+/// Output of an external command
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs external commands, so filesystem tooling can be mocked in tests
+pub trait CommandRunner: Send {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput, io::Error>;
+}
+
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput, io::Error> {
+        let output = Command::new(program).args(args).output()?;
+        Ok(CommandOutput {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// File taking up disk space from startup, deleted to buy time when the disk is
+/// critically full and scaling fails
+#[derive(Debug, Clone, PartialEq)]
+pub struct PanicReserve {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+}
+
+impl PanicReserve {
+    /// Creates the file with all its blocks allocated, keeping one that is already there
+    pub fn allocate(&self) -> Result<(), io::Error> {
+        if fs::metadata(&self.path).is_ok_and(|metadata| metadata.len() >= self.size_bytes) {
+            return Ok(());
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.path)?;
+        let len = libc::off_t::try_from(self.size_bytes)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // set_len would leave a sparse file that reserves nothing
+        let err = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        Ok(())
+    }
+
+    /// Deletes the file, returning false if it was not there
+    pub fn release(&self) -> Result<bool, io::Error> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs commands through a narrowly-scoped privileged helper, e.g. `sudo -n` or a setuid
+/// wrapper, so the filesystem tools get root without the whole process running as root
+pub struct PrivilegedCommandRunner {
+    /// Helper program and its arguments, put before every command
+    pub helper: Vec<String>,
+    pub inner: Box<dyn CommandRunner>,
+}
+
+impl CommandRunner for PrivilegedCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput, io::Error> {
+        let (helper, helper_args) = match self.helper.split_first() {
+            Some(helper) => helper,
+            None => return self.inner.run(program, args),
+        };
+        let mut command: Vec<&str> = helper_args.iter().map(String::as_str).collect();
+        command.push(program);
+        command.extend_from_slice(args);
+        self.inner.run(helper, &command)
+    }
+}
+
+pub struct MockCommandRunner {
+    /// Commands run so far, as "program arg1 arg2"
+    pub calls: Arc<Mutex<Vec<String>>>,
+    /// Commands, as "program arg1 arg2", that exit with a failure
+    pub failing: Vec<String>,
+    /// Stdout of commands, as ("program arg1 arg2", stdout), empty for the others
+    pub outputs: Vec<(String, String)>,
+    /// Programs that are not installed
+    pub missing: Vec<String>,
+}
+
+impl Default for MockCommandRunner {
+    fn default() -> Self {
+        MockCommandRunner {
+            calls: Arc::new(Mutex::new(Vec::new())),
+            failing: Vec::new(),
+            outputs: Vec::new(),
+            missing: Vec::new(),
+        }
+    }
+}
+
+impl CommandRunner for MockCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput, io::Error> {
+        let mut call = vec![program];
+        call.extend_from_slice(args);
+        let call = call.join(" ");
+        self.calls.lock().unwrap().push(call.clone());
+        if self.missing.iter().any(|missing| missing == program) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        let stdout = self.outputs.iter()
+            .find(|(command, _)| *command == call)
+            .map(|(_, stdout)| stdout.clone())
+            .unwrap_or_default();
+        Ok(CommandOutput {
+            success: !self.failing.contains(&call),
+            stdout,
+            stderr: String::new(),
+        })
+    }
+}
+
+/// Runs a filesystem tool, naming it in the error if it is not installed
+fn run_tool(
+    runner: &dyn CommandRunner,
+    program: &str,
+    args: &[&str],
+) -> Result<CommandOutput, Box<dyn Error>> {
+    runner.run(program, args).map_err(|e| -> Box<dyn Error> {
+        if e.kind() == io::ErrorKind::NotFound {
+            error!("{} is not installed", program);
+            return Box::new(FilesystemToolMissingError { tool: program.to_string() });
+        }
+        error!("Could not run {} - {}", program, e);
+        Box::new(GenericFSError)
+    })
+}
+
+/// Tools needed to grow a filesystem type
+pub fn required_tools(fs_type: &str) -> &'static [&'static str] {
+    match fs_type {
+        "btrfs" => &["btrfs"],
+        "ext4" => &["resize2fs"],
+        "xfs" => &["xfs_growfs"],
+        _ => &[],
+    }
+}
+
+/// Checks the tools needed for fs_type are installed, whatever their exit status
+pub fn check_tools(runner: &dyn CommandRunner, fs_type: &str) -> Result<(), Box<dyn Error>> {
+    for tool in required_tools(fs_type) {
+        run_tool(runner, tool, &["--version"])?;
+    }
+    Ok(())
+}
+
+/// Marker file recording that a btrfs balance was started but did not finish
+///
+/// Written before the balance starts and removed once it completes, so a balance
+/// cancelled on shutdown (or killed along with the process) is redone on next start.
+pub struct RebalanceState {
+    path: PathBuf,
+}
+
+impl RebalanceState {
+    pub fn new(state_dir: &str) -> RebalanceState {
+        RebalanceState {
+            path: Path::new(state_dir).join("rebalance-pending"),
+        }
+    }
+
+    pub fn mark_pending(&self, mountpoint: &str) -> Result<(), io::Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, mountpoint)
+    }
+
+    pub fn clear(&self) -> Result<(), io::Error> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Mountpoint of the pending balance, if any
+    pub fn pending(&self) -> Option<String> {
+        fs::read_to_string(&self.path).ok()
+    }
+}
+
+/// Runs a metadata balance, keeping the pending marker until it completes
+pub fn run_balance(
+    runner: &dyn CommandRunner,
+    state: &RebalanceState,
+    mountpoint: &str,
+) -> Result<bool, Box<dyn Error>> {
+    state.mark_pending(mountpoint).map_err(|e| {
+        error!("Could not record pending balance - {}", e);
+        Box::new(GenericFSError)
+    })?;
+    let output = run_tool(runner, "btrfs", &["balance", "start", "-m", mountpoint])?;
+    if !output.success {
+        warn!("btrfs balance on {} did not finish - {}", mountpoint, output.stderr);
+        return Err(Box::new(GenericFSError));
+    }
+    state.clear().map_err(|e| {
+        error!("Could not clear pending balance - {}", e);
+        Box::new(GenericFSError)
+    })?;
+    Ok(true)
+}
+
+/// Waits up to timeout for a pending balance to finish, cancelling it afterwards
+///
+/// Returns true if the balance had to be cancelled. The pending marker is kept
+/// so the balance is redone on next start.
+pub fn drain_rebalance(
+    runner: &dyn CommandRunner,
+    state: &RebalanceState,
+    timeout: Duration,
+) -> Result<bool, io::Error> {
+    let deadline = Instant::now() + timeout;
+    while state.pending().is_some() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mountpoint = match state.pending() {
+        Some(mountpoint) => mountpoint,
+        None => return Ok(false),
+    };
+    info!("Cancelling btrfs balance on {}", mountpoint);
+    let output = runner.run("btrfs", &["balance", "cancel", &mountpoint])?;
+    if !output.success {
+        warn!("Could not cancel btrfs balance on {} - {}", mountpoint, output.stderr);
+    }
+    Ok(true)
+}
+
+/// Unmounts mountpoint, so its devices can be detached
+pub fn unmount(runner: &dyn CommandRunner, mountpoint: &str) -> Result<(), Box<dyn Error>> {
+    let output = runner.run("umount", &[mountpoint])?;
+    if !output.success {
+        return Err(Box::new(UnmountFailedError {
+            mountpoint: mountpoint.to_string(),
+            stderr: output.stderr,
+        }));
+    }
+    Ok(())
+}
+
+/// btrfs devid of dev, from the output of `btrfs filesystem show`
+pub fn devid_from_show(show: &str, dev: &str) -> Option<String> {
+    show.lines()
+        .filter(|line| line.split_whitespace().last() == Some(dev))
+        .find_map(|line| {
+            let mut fields = line.split_whitespace().skip_while(|field| *field != "devid");
+            fields.next()?;
+            fields.next().map(str::to_string)
+        })
+}
+
+/// Grows a filesystem into the extra space of dev, after dev was grown in place
+pub fn grow_filesystem(
+    runner: &dyn CommandRunner,
+    fs_type: &str,
+    dev: &str,
+    mountpoint: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let output = match fs_type {
+        "btrfs" => {
+            let show = run_tool(runner, "btrfs", &["filesystem", "show", mountpoint])?;
+            let devid = devid_from_show(&show.stdout, dev).ok_or_else(|| {
+                error!("{} is not part of the filesystem at {}", dev, mountpoint);
+                Box::new(GenericFSError)
+            })?;
+            let size = format!("{}:max", devid);
+            run_tool(runner, "btrfs", &["filesystem", "resize", &size, mountpoint])?
+        }
+        "ext4" => run_tool(runner, "resize2fs", &[dev])?,
+        "xfs" => run_tool(runner, "xfs_growfs", &[mountpoint])?,
+        _ => {
+            error!("Growing {} filesystems is not supported", fs_type);
+            return Err(Box::new(GenericFSError));
+        }
+    };
+    if !output.success {
+        error!("Could not grow {} into {} - {}", mountpoint, dev, output.stderr);
+        return Err(Box::new(GenericFSError));
+    }
+    info!("Grew {} into the new space of {}", mountpoint, dev);
+    Ok(true)
+}
+
+/// Sets the reserved-block percentage of an ext4 filesystem, returning false for
+/// other filesystems
+pub fn set_reserved_blocks(
+    runner: &dyn CommandRunner,
+    fs_type: &str,
+    dev: &str,
+    percent: u8,
+) -> Result<bool, Box<dyn Error>> {
+    if fs_type != "ext4" {
+        return Ok(false);
+    }
+    let output = run_tool(runner, "tune2fs", &["-m", &percent.to_string(), dev])?;
+    if !output.success {
+        error!("Could not set reserved blocks of {} to {}% - {}", dev, percent, output.stderr);
+        return Err(Box::new(GenericFSError));
+    }
+    info!("Set reserved blocks of {} to {}%", dev, percent);
+    Ok(true)
+}
+
+/// Formats a new device and mounts it
+pub fn bootstrap_filesystem(
+    runner: &dyn CommandRunner,
+    fs_type: &str,
+    dev: &str,
+    mountpoint: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let commands: [(&str, Vec<&str>); 3] = [
+        ("mkfs", vec!["-t", fs_type, dev]),
+        ("mkdir", vec!["-p", mountpoint]),
+        ("mount", vec!["-t", fs_type, dev, mountpoint]),
+    ];
+    for (program, args) in commands.iter() {
+        let output = run_tool(runner, program, args)?;
+        if !output.success {
+            error!("{} {} failed - {}", program, args.join(" "), output.stderr);
+            return Err(Box::new(GenericFSError));
+        }
+    }
+    info!("Created {} filesystem on {} at {}", fs_type, dev, mountpoint);
+    Ok(true)
+}
+
+/// Filesystem UUID of a device, from blkid
+pub fn filesystem_uuid(
+    runner: &dyn CommandRunner,
+    dev: &str,
+) -> Result<String, Box<dyn Error>> {
+    let output = run_tool(runner, "blkid", &["-s", "UUID", "-o", "value", dev])?;
+    let uuid = output.stdout.trim();
+    if !output.success || uuid.is_empty() {
+        error!("Could not read the filesystem UUID of {} - {}", dev, output.stderr);
+        return Err(Box::new(GenericFSError));
+    }
+    Ok(uuid.to_string())
+}
+
+/// Adds an fstab entry for the filesystem on dev, by UUID since device names can
+/// change across reboots
+pub fn persist_mount_by_uuid(
+    runner: &dyn CommandRunner,
+    fs_type: &str,
+    dev: &str,
+    mountpoint: &str,
+    fstab: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    let uuid = filesystem_uuid(runner, dev)?;
+    persist_mount(fstab, fs_type, &format!("UUID={}", uuid), mountpoint).map_err(|e| {
+        error!("Could not add {} to {} - {}", mountpoint, fstab.display(), e);
+        Box::new(GenericFSError)
+    })?;
+    Ok(true)
+}
+
+/// Adds an fstab entry for the mountpoint, unless one is already there
+fn persist_mount(fstab: &Path, fs_type: &str, source: &str, mountpoint: &str) -> Result<(), io::Error> {
+    let contents = match fs::read_to_string(fstab) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let already_mounted = contents
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(mountpoint));
+    if already_mounted {
+        return Ok(());
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(fstab)?;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        writeln!(file)?;
+    }
+    // nofail keeps the instance booting if the volume is gone
+    writeln!(file, "{} {} {} defaults,nofail 0 0", source, mountpoint, fs_type)
+}
+
+pub trait FS: Send {
+    fn expand_volume(&self, dev: String) -> Result<bool, Box<dyn Error>>;
+    /// Finishes work interrupted by a previous shutdown, i.e. a cancelled balance
+    fn resume_pending_work(&self) -> Result<bool, Box<dyn Error>>;
+    /// Grows the filesystem into a device that was grown in place
+    fn grow_device(&self, dev: String) -> Result<bool, Box<dyn Error>>;
+    /// Creates and mounts the filesystem on the first device of the mountpoint
+    fn create_filesystem(&self, dev: String) -> Result<bool, Box<dyn Error>>;
+    /// Adds the filesystem on dev to fstab, so it is mounted again after a reboot
+    fn persist_mount(&self, dev: String) -> Result<bool, Box<dyn Error>>;
+    /// Fails with FilesystemToolMissingError if a tool needed to grow the filesystem is missing
+    fn check_tools(&self) -> Result<(), Box<dyn Error>>;
+    /// Switches to a filesystem type detected at startup
+    fn set_fs_type(&mut self, fs_type: String);
 }
 
+#[cfg(target_os = "linux")]
 pub struct ConcreteFS {
-    pub fs_type: String
+    pub fs_type: String,
+    pub mountpoint: String,
+    pub runner: Box<dyn CommandRunner>,
+    pub rebalance: RebalanceState,
+    /// Reserved-block percentage applied after growing ext4, if set
+    pub reserved_blocks_percent: Option<u8>,
 }
 
+#[cfg(target_os = "linux")]
 impl FS for ConcreteFS {
-    fn expand_volume(&self, _dev: String) -> Result<bool, Box<GenericFSError>> {
+    fn expand_volume(&self, dev: String) -> Result<bool, Box<dyn Error>> {
+        if self.fs_type != "btrfs" {
+            error!("Expanding {} filesystems is not supported", self.fs_type);
+            return Err(Box::new(DeviceNotAddedError { device: dev }));
+        }
+        let output = run_tool(
+            self.runner.as_ref(), "btrfs", &["device", "add", &dev, &self.mountpoint]
+        )?;
+        if !output.success {
+            error!("Could not add {} to {} - {}", dev, self.mountpoint, output.stderr);
+            return Err(Box::new(DeviceNotAddedError { device: dev }));
+        }
+        run_balance(self.runner.as_ref(), &self.rebalance, &self.mountpoint)
+    }
+
+    fn resume_pending_work(&self) -> Result<bool, Box<dyn Error>> {
+        match self.rebalance.pending() {
+            Some(mountpoint) => {
+                info!("Resuming interrupted btrfs balance on {}", mountpoint);
+                run_balance(self.runner.as_ref(), &self.rebalance, &mountpoint)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn grow_device(&self, dev: String) -> Result<bool, Box<dyn Error>> {
+        grow_filesystem(self.runner.as_ref(), &self.fs_type, &dev, &self.mountpoint)?;
+        if let Some(percent) = self.reserved_blocks_percent {
+            set_reserved_blocks(self.runner.as_ref(), &self.fs_type, &dev, percent)?;
+        }
         Ok(true)
     }
+
+    fn create_filesystem(&self, dev: String) -> Result<bool, Box<dyn Error>> {
+        bootstrap_filesystem(self.runner.as_ref(), &self.fs_type, &dev, &self.mountpoint)
+    }
+
+    fn persist_mount(&self, dev: String) -> Result<bool, Box<dyn Error>> {
+        persist_mount_by_uuid(
+            self.runner.as_ref(),
+            &self.fs_type,
+            &dev,
+            &self.mountpoint,
+            Path::new(FSTAB_PATH),
+        )
+    }
+
+    fn check_tools(&self) -> Result<(), Box<dyn Error>> {
+        check_tools(self.runner.as_ref(), &self.fs_type)
+    }
+
+    fn set_fs_type(&mut self, fs_type: String) {
+        self.fs_type = fs_type;
+    }
 }
 
 pub struct MockFS {
     pub simulate_fs_err: bool,
+    /// Only persisting the mount fails
+    pub simulate_persist_err: bool,
+    /// Devices the filesystem cannot be expanded onto
+    pub failing_devices: Vec<String>,
 }
 
 impl Default for MockFS {
     fn default() -> Self {
         MockFS {
             simulate_fs_err: false,
+            simulate_persist_err: false,
+            failing_devices: Vec::new(),
         }
     }
 }
 
 impl FS for MockFS {
-    fn expand_volume(&self, _dev: String) -> Result<bool, Box<GenericFSError>> {
+    fn expand_volume(&self, dev: String) -> Result<bool, Box<dyn Error>> {
+        if self.simulate_fs_err || self.failing_devices.contains(&dev) {
+            return Err(Box::new(DeviceNotAddedError { device: dev }))
+        }
+        Ok(true)
+    }
+
+    fn resume_pending_work(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(false)
+    }
+
+    fn grow_device(&self, _dev: String) -> Result<bool, Box<dyn Error>> {
         if self.simulate_fs_err {
             return Err(Box::new(GenericFSError))
         }
         Ok(true)
     }
+
+    fn create_filesystem(&self, _dev: String) -> Result<bool, Box<dyn Error>> {
+        if self.simulate_fs_err {
+            return Err(Box::new(GenericFSError))
+        }
+        Ok(true)
+    }
+
+    fn persist_mount(&self, _dev: String) -> Result<bool, Box<dyn Error>> {
+        if self.simulate_fs_err || self.simulate_persist_err {
+            return Err(Box::new(GenericFSError))
+        }
+        Ok(true)
+    }
+
+    fn check_tools(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn set_fs_type(&mut self, _fs_type: String) {}
 }
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+
+const CSV_HEADER: &str = "timestamp,utilization_percent,volume_count,logical_size_gb\n";
+
+/// Utilization observed on a single tick
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UtilizationRecord {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub utilization_percent: u32,
+    pub volume_count: u32,
+    pub logical_size_gb: u64,
+}
+
+impl UtilizationRecord {
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{}\n",
+            self.timestamp, self.utilization_percent, self.volume_count, self.logical_size_gb
+        )
+    }
+
+    fn to_jsonl(&self) -> String {
+        format!("{}\n", serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Append-only utilization history, CSV if the path ends in .csv and JSONL otherwise
+///
+/// Once a write would take the file over max_bytes, it is rotated to `<path>.1`,
+/// replacing the previous rotation, so at most twice max_bytes is kept.
+pub struct UtilizationLog {
+    path: PathBuf,
+    max_bytes: u64,
+    csv: bool,
+}
+
+impl UtilizationLog {
+    pub fn new(path: &str, max_bytes: u64) -> UtilizationLog {
+        let path = PathBuf::from(path);
+        let csv = path.extension().is_some_and(|ext| ext == "csv");
+        UtilizationLog { path, max_bytes, csv }
+    }
+
+    /// Path the current file is rotated to
+    pub fn rotated_path(&self) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        PathBuf::from(rotated)
+    }
+
+    pub fn append(&self, record: &UtilizationRecord) -> Result<(), io::Error> {
+        let line = if self.csv { record.to_csv() } else { record.to_jsonl() };
+        let current_size = fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        if current_size > 0 && current_size + line.len() as u64 > self.max_bytes {
+            fs::rename(&self.path, self.rotated_path())?;
+        }
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if self.csv && file.metadata()?.len() == 0 {
+            file.write_all(CSV_HEADER.as_bytes())?;
+        }
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// AWS allows one ModifyVolume per volume in this period
+pub const MODIFY_VOLUME_COOLDOWN_SECS: u64 = 6 * 60 * 60;
+
+/// When each volume was last grown with ModifyVolume, persisted in the state dir
+/// so the cooldown survives restarts
+pub struct VolumeModifications {
+    path: PathBuf,
+}
+
+impl VolumeModifications {
+    pub fn new(state_dir: &str) -> VolumeModifications {
+        VolumeModifications {
+            path: Path::new(state_dir).join("volume-modifications.json"),
+        }
+    }
+
+    /// Last modification of each volume, in seconds since the Unix epoch
+    pub fn load(&self) -> Result<BTreeMap<String, u64>, io::Error> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records a modification at now, dropping entries whose cooldown is over
+    pub fn record(&self, volume_id: &str, now: u64) -> Result<(), io::Error> {
+        let mut modified = self.load().unwrap_or_default();
+        modified.retain(|_, at| now.saturating_sub(*at) < MODIFY_VOLUME_COOLDOWN_SECS);
+        modified.insert(volume_id.to_string(), now);
+        self.save(&modified)
+    }
+
+    /// Replaces every recorded modification
+    pub fn save(&self, modified: &BTreeMap<String, u64>) -> Result<(), io::Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = serde_json::to_string(modified)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&self.path, contents)
+    }
+
+    /// Volumes still in their cooldown at now, with when it ends
+    pub fn in_cooldown(&self, now: u64) -> Result<BTreeMap<String, u64>, io::Error> {
+        Ok(self.load()?
+            .into_iter()
+            .map(|(volume_id, at)| (volume_id, at.saturating_add(MODIFY_VOLUME_COOLDOWN_SECS)))
+            .filter(|(_, until)| *until > now)
+            .collect())
+    }
+}
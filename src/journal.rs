@@ -0,0 +1,139 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use log::{Level, Log, Metadata, Record};
+
+use crate::event::{AutoscaleEvent, EventKind};
+
+/// Socket of the journald native protocol
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "ebs-autoscale-rust";
+
+/// Journal fields, in the order they are sent
+pub type Fields = Vec<(&'static str, String)>;
+
+/// syslog priority for a log level, as expected in PRIORITY
+pub fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+pub fn log_fields(record: &Record) -> Fields {
+    vec![
+        ("MESSAGE", record.args().to_string()),
+        ("PRIORITY", priority(record.level()).to_string()),
+        ("SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER.to_string()),
+        ("TARGET", record.target().to_string()),
+    ]
+}
+
+/// Fields of an autoscale event: limits, utilization alerts, failed expansions and released
+/// panic reserves are critical (2), everything else a notice (5)
+pub(crate) fn event_fields(event: &AutoscaleEvent) -> Fields {
+    let (name, priority) = match event.event {
+        EventKind::ScaleUp => ("scale_up", 5),
+        EventKind::ScaleDown => ("scale_down", 5),
+        EventKind::LimitReached => ("limit_reached", 2),
+        EventKind::HighUtilization => ("high_utilization", 2),
+        EventKind::HighUtilizationCleared => ("high_utilization_cleared", 5),
+        EventKind::ExpandFailed => ("expand_failed", 2),
+        EventKind::PanicReserveReleased => ("panic_reserve_released", 2),
+    };
+    let mut fields = vec![
+        ("MESSAGE", format!("{} on {}", name, event.mountpoint)),
+        ("PRIORITY", priority.to_string()),
+        ("SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER.to_string()),
+        ("EVENT", name.to_string()),
+        ("MOUNTPOINT", event.mountpoint.clone()),
+        ("TOTAL_SIZE_GIB", event.total_size_gib.as_u64().to_string()),
+    ];
+    if let Some(volume_id) = &event.volume_id {
+        fields.push(("VOLUME_ID", volume_id.clone()));
+    }
+    if let Some(size_gib) = event.size_gib {
+        fields.push(("SIZE_GIB", size_gib.as_u64().to_string()));
+    }
+    if let Some(instance_id) = &event.instance_id {
+        fields.push(("INSTANCE_ID", instance_id.clone()));
+    }
+    if let Some(utilization) = event.utilization_percent {
+        fields.push(("UTILIZATION_PERCENT", utilization.to_string()));
+    }
+    fields
+}
+
+/// Serializes fields in the journald native protocol
+///
+/// Values with newlines use the length-prefixed binary form.
+pub fn encode(fields: &Fields) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    for (key, value) in fields {
+        datagram.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    }
+    datagram
+}
+
+/// Whether journald is listening on this host
+pub fn available() -> bool {
+    Path::new(JOURNAL_SOCKET).exists()
+}
+
+fn send(fields: &Fields) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(&encode(fields), JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+/// Sends an autoscale event to the journal, ignoring hosts without journald
+pub(crate) fn send_event(event: &AutoscaleEvent) {
+    if available() {
+        let _ = send(&event_fields(event));
+    }
+}
+
+/// Logger writing each record to the journal with structured fields
+pub struct JournalLogger {
+    /// Only used to filter, so the journal takes the same directives as env_logger
+    filter: env_logger::Logger,
+}
+
+impl JournalLogger {
+    /// Installs the logger, or returns false when journald is unavailable
+    pub fn init(filter: env_logger::Logger) -> bool {
+        if !available() {
+            return false;
+        }
+        let level = filter.filter();
+        if log::set_boxed_logger(Box::new(JournalLogger { filter })).is_err() {
+            return false;
+        }
+        log::set_max_level(level);
+        true
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            let _ = send(&log_fields(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
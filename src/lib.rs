@@ -2,325 +2,7393 @@ pub mod config;
 mod fs;
 mod aws;
 mod disk;
+mod cost;
+mod clock;
+mod event;
+mod history;
+pub mod notify;
+pub mod status;
+pub mod watchdog;
+pub mod drain;
+pub mod audit;
+pub mod lock;
+pub mod size;
+pub mod predict;
+pub mod migrate;
+pub mod exit;
+pub mod trace;
+#[cfg(feature = "journald")]
+pub mod journal;
 
-use std::io;
+/// Created by main so every manager of the process shares it
+pub use aws::ApiLimiter;
+
+use std::collections::BTreeMap;
 use std::error::Error;
-use log::info;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use serde::Serialize;
+use size::SizeGib;
 
 use std::fmt;
 
+/// How often to check for the block device of an attached volume
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const VOLUME_CREATE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MODIFICATION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Failed reconciles in a row after which /ready reports not ready again
+const MAX_CONSECUTIVE_RECONCILE_ERRORS: u32 = 3;
+/// How long a fed IOPS utilization counts for io2 promotion without a newer one
+const IOPS_SAMPLE_MAX_AGE: Duration = Duration::from_secs(300);
+
 #[derive(Debug, Clone)]
 pub struct MaxEBSCountExceededError;
 
-impl Error for MaxEBSCountExceededError {}
+impl Error for MaxEBSCountExceededError {}
+
+impl fmt::Display for MaxEBSCountExceededError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Maximum number of EBS volumes exceeded")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaxLogicalVolumeSizeExceededError;
+
+impl Error for MaxLogicalVolumeSizeExceededError {}
+
+impl fmt::Display for MaxLogicalVolumeSizeExceededError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Maximum logical volume size exceeded")
+    }
+}
+
+/// Utilization dropped back below the threshold that triggered scaling before a volume
+/// was created
+#[derive(Debug, Clone)]
+pub struct ScaleNoLongerNeededError {
+    pub utilization_percent: u32,
+    pub threshold_percent: u32,
+}
+
+impl Error for ScaleNoLongerNeededError {}
+
+impl fmt::Display for ScaleNoLongerNeededError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Utilization dropped to {}%, below the {}% threshold that triggered scaling",
+            self.utilization_percent, self.threshold_percent
+        )
+    }
+}
+
+/// A new volume would take the logical volume past the soft ceiling left by
+/// logical_size_headroom_gb, below the hard max_logical_volume_size
+#[derive(Debug, Clone)]
+pub struct LogicalSizeHeadroomReachedError {
+    pub projected_gib: SizeGib,
+    pub soft_ceiling_gib: SizeGib,
+}
+
+impl Error for LogicalSizeHeadroomReachedError {}
+
+impl fmt::Display for LogicalSizeHeadroomReachedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Scaling would take the logical volume to {}, past the soft ceiling of {}",
+            self.projected_gib, self.soft_ceiling_gib
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnsupportedPlatformError;
+
+impl Error for UnsupportedPlatformError {}
+
+impl fmt::Display for UnsupportedPlatformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ebs autoscaler only runs on Linux - use the mocks to test elsewhere")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaxMonthlyCostExceededError {
+    pub projected_cost_usd: f64,
+}
+
+impl Error for MaxMonthlyCostExceededError {}
+
+impl fmt::Display for MaxMonthlyCostExceededError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Maximum monthly cost exceeded - projected ${:.2}", self.projected_cost_usd)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccountQuotaNearlyExhaustedError {
+    pub vol_type: String,
+    pub used_gib: SizeGib,
+    pub requested_gib: SizeGib,
+    pub quota_gib: SizeGib,
+}
+
+impl Error for AccountQuotaNearlyExhaustedError {}
+
+impl fmt::Display for AccountQuotaNearlyExhaustedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Account EBS quota nearly exhausted - {} of {} used, {} more requested, quota is {}",
+            self.used_gib, self.vol_type, self.requested_gib, self.quota_gib
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImdsUnavailableError;
+
+impl Error for ImdsUnavailableError {}
+
+impl fmt::Display for ImdsUnavailableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Could not reach the EC2 instance metadata service (IMDS) - \
+             set region and availability_zone in the config when running off EC2 or with IMDS disabled"
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MissingCostAllocationTagsError {
+    pub keys: Vec<String>,
+}
+
+impl Error for MissingCostAllocationTagsError {}
+
+impl fmt::Display for MissingCostAllocationTagsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Required cost allocation tags {} are not set in cost_allocation_tags",
+            self.keys.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MissingPermissionError {
+    pub action: &'static str,
+}
+
+impl Error for MissingPermissionError {}
+
+impl fmt::Display for MissingPermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Missing IAM permission {} - grant it to the instance role or the configured credentials",
+            self.action
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TerminationTimeoutError {
+    /// Volumes whose deletion did not finish in time
+    pub remaining: Vec<String>,
+}
+
+impl Error for TerminationTimeoutError {}
+
+impl fmt::Display for TerminationTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Termination cleanup timed out - volumes not deleted: {}",
+            self.remaining.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FsTypeMismatchError {
+    pub configured: String,
+    pub detected: String,
+}
+
+impl Error for FsTypeMismatchError {}
+
+impl fmt::Display for FsTypeMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "fs_type is {} but the mountpoint has a {} filesystem - fix fs_type or set it to \"\" to detect it",
+            self.configured, self.detected
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SizeOverflowError;
+
+impl Error for SizeOverflowError {}
+
+impl fmt::Display for SizeOverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Volume size arithmetic overflowed")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadOnlyMountError {
+    pub mountpoint: String,
+}
+
+impl Error for ReadOnlyMountError {}
+
+impl fmt::Display for ReadOnlyMountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is mounted read-only and cannot be grown - remount it read-write or set \
+             allow_readonly",
+            self.mountpoint
+        )
+    }
+}
+
+/// The block device of an attached volume did not show up in time
+#[derive(Debug, Clone)]
+pub struct DeviceAppearanceTimeoutError {
+    pub device: String,
+    pub timeout: Duration,
+}
+
+impl Error for DeviceAppearanceTimeoutError {}
+
+impl fmt::Display for DeviceAppearanceTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Volume is attached but {} did not appear within {}s",
+            self.device,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+/// A created volume was still creating at volume_create_timeout_secs
+#[derive(Debug, Clone)]
+pub struct VolumeCreateTimeoutError {
+    pub volume_id: String,
+    pub timeout: Duration,
+}
+
+impl Error for VolumeCreateTimeoutError {}
+
+impl fmt::Display for VolumeCreateTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Volume {} was still creating after {}s",
+            self.volume_id,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+/// The device of an attached volume kept reporting another volume
+#[derive(Debug, Clone)]
+pub struct AttachedDeviceMismatchError {
+    pub device: String,
+    pub volume_id: String,
+    pub found: String,
+}
+
+impl Error for AttachedDeviceMismatchError {}
+
+impl fmt::Display for AttachedDeviceMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is volume {} rather than the attached {} - not growing onto it",
+            self.device, self.found, self.volume_id
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeModificationError {
+    pub volume_id: String,
+    /// Last state seen, None if the modification timed out before any
+    pub state: Option<aws::ModificationState>,
+}
+
+impl Error for VolumeModificationError {}
+
+impl fmt::Display for VolumeModificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.state {
+            Some(aws::ModificationState::Failed) => {
+                write!(f, "Modification of volume {} failed", self.volume_id)
+            }
+            state => write!(
+                f,
+                "Modification of volume {} did not finish in time - last state {:?}",
+                self.volume_id, state
+            ),
+        }
+    }
+}
+
+/// Why a tick did not scale
+///
+/// The ModifyVolume cooldown and throttling backoff never skip a tick: volumes in
+/// their cooldown are left out of growing, so a new volume is added instead, and
+/// throttling only spaces out the ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    BelowThreshold,
+    /// Scaling is paused by config or from the status endpoint
+    Paused,
+    /// Shutdown was requested, so no volume is created
+    Draining,
+}
+
+impl SkipReason {
+    /// Exit code of --once when the tick skipped for this reason, each reason having
+    /// its own so callers can tell them apart from success (0) and errors (1)
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            SkipReason::BelowThreshold => 3,
+            SkipReason::Paused => 4,
+            SkipReason::Draining => 5,
+        }
+    }
+}
+
+/// Result of a single autoscaling tick
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReconcileOutcome {
+    NoActionNeeded(SkipReason),
+    ScaledUp,
+    /// Max logical size was reached and scaling is stopped until config reload
+    Quiesced,
+    /// Max logical size was reached and the alert was already emitted
+    MaxSizeReached,
+    /// The next volume would enter the headroom below max logical size
+    HeadroomReached,
+    /// Utilization dropped below the threshold again before a volume was created
+    NoLongerNeeded,
+    /// Max logical size was reached and the daemon should stop
+    Exit,
+    /// Utilization was collected in observe-only mode
+    Observed,
+}
+
+impl ReconcileOutcome {
+    /// Exit code of --once for this outcome: the skip reason's code, 0 otherwise
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ReconcileOutcome::NoActionNeeded(reason) => reason.exit_code(),
+            _ => 0,
+        }
+    }
+
+    /// Name reported as last_outcome by /status, the skip reason being reported separately
+    pub fn name(&self) -> String {
+        match self {
+            ReconcileOutcome::NoActionNeeded(SkipReason::Paused) => "Paused".to_string(),
+            ReconcileOutcome::NoActionNeeded(_) => "NoActionNeeded".to_string(),
+            outcome => format!("{:?}", outcome),
+        }
+    }
+}
+
+/// What the next tick would do, as computed by a report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+pub enum NextAction {
+    None,
+    AddVolume { size_gib: SizeGib, vol_type: String },
+    Blocked { reason: String },
+    Paused,
+    ObserveOnly,
+    Quiesced,
+}
+
+/// Current reconciliation view, computed without changing anything
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReconcileReport {
+    pub mountpoint: String,
+    /// Unknown when nothing is mounted at the mountpoint
+    pub utilization_percent: Option<u32>,
+    pub threshold_percent: u32,
+    pub mounted_volume_count: u32,
+    pub managed_volumes: Vec<aws::ManagedVolume>,
+    pub logical_size_gb: Option<SizeGib>,
+    pub limits: config::Limits,
+    pub next_action: NextAction,
+    /// Whether new volumes end up encrypted, by volume.encrypted or the account's
+    /// encryption by default; unknown when the account setting could not be read
+    pub encrypted: Option<bool>,
+}
+
+/// How much more the logical volume can grow before each limit stops it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RemainingCapacity {
+    /// Size left below the soft ceiling of max_logical_volume_size less
+    /// logical_size_headroom_gb, and below the account quota when one is set
+    pub by_size_gib: SizeGib,
+    /// Volumes left below max_ebs_volume_count
+    pub by_count: u32,
+    /// Monthly cost in USD left below max_monthly_cost_usd, None without a cost cap
+    pub by_cost: Option<f64>,
+}
+
+/// A projected scale-up, as computed by a plan
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedStep {
+    /// Mounted volumes after this step
+    pub volume_count: u32,
+    pub size_gib: SizeGib,
+    /// Logical volume size after this step
+    pub total_size_gib: SizeGib,
+}
+
+/// Managed volumes found already attached on startup, e.g. when resuming from a warm pool
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistingVolumes {
+    pub volume_count: u32,
+    pub logical_size_gib: SizeGib,
+    pub devices: Vec<String>,
+}
+
+/// Volume created and added to the logical volume by a scale-up
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddedVolume {
+    pub volume_id: String,
+    pub device: String,
+    pub size_gib: SizeGib,
+}
+
+pub struct EBSManager {
+    config: config::Config,
+    diskmgr: Box<dyn disk::DiskMgr>,
+    aws: Box<dyn aws::AWS>,
+    fs: Box<dyn fs::FS>,
+    clock: Box<dyn clock::Clock>,
+    /// From IMDS during preflight, unset when bypassed by overrides
+    instance_id: Option<String>,
+    status: status::SharedStatus,
+    quiesced: bool,
+    max_size_alerted: bool,
+    limit_event_sent: bool,
+    utilization_log: Option<history::UtilizationLog>,
+    audit_log: Option<audit::AuditLog>,
+    statsd: Option<status::StatsdSink>,
+    /// Last status tag value pushed, and when
+    last_status_tag: Option<(String, Instant)>,
+    /// When utilization last rose to the alert threshold
+    high_utilization_since: Option<Instant>,
+    /// When the fed IOPS utilization last rose to the io2 promotion threshold
+    high_iops_since: Option<Instant>,
+    /// IOPS utilization samples seen so far, and when the last new one arrived
+    iops_samples_seen: (u64, Option<Instant>),
+    /// Reconciles that failed in a row
+    consecutive_reconcile_errors: u32,
+    /// Previous disk utilization sample, for the fill rate
+    last_utilization_sample: Option<(Instant, u32)>,
+    /// Utilization change in percent per second between the last two samples
+    fill_rate: Option<f64>,
+    /// Threshold utilization crossed at the last check, re-checked before creating a volume
+    scale_threshold: Option<u32>,
+    /// Sizes new volumes instead of growth_strategy when set
+    size_predictor: Option<Box<dyn predict::SizePredictor>>,
+    utilization_alerted: bool,
+    /// Whether the panic reserve file was already deleted
+    panic_reserve_released: bool,
+    /// Whether the remaining capacity must be computed again, as the limits or the
+    /// volumes may have changed since it was
+    remaining_capacity_stale: bool,
+    /// Gates evaluated by the reconcile in progress, while tracing one
+    trace: Option<trace::DecisionTrace>,
+    /// Attached managed volumes, once rebuilt by reconcile_existing_volumes
+    attached_volume_count: Option<u32>,
+    /// The account's EBS encryption by default, once read during preflight
+    encryption_by_default: Option<bool>,
+    notifier: Box<dyn notify::Notifier>,
+    /// Shutdown coordination of the daemon loop, unset outside of it
+    drain: Option<drain::Drain>,
+}
+
+impl EBSManager {
+    pub fn new(
+        conf: config::Config,
+        disks: Box<dyn disk::DiskMgr>,
+        aws_cli: Box<dyn aws::AWS>,
+        fs_lib: Box<dyn fs::FS>,
+        clock_src: Box<dyn clock::Clock>,
+    ) -> Box<EBSManager> {
+        let utilization_log = utilization_log_for(&conf);
+        let audit_log = audit_log_for(&conf);
+        let statsd = statsd_sink_for(&conf);
+        let notifier = notify::from_config(&conf);
+        Box::new(Self {
+            config: conf,
+            diskmgr: disks,
+            aws: aws_cli,
+            fs: fs_lib,
+            clock: clock_src,
+            instance_id: None,
+            status: status::SharedStatus::default(),
+            quiesced: false,
+            max_size_alerted: false,
+            limit_event_sent: false,
+            utilization_log,
+            audit_log,
+            statsd,
+            last_status_tag: None,
+            high_utilization_since: None,
+            high_iops_since: None,
+            iops_samples_seen: (0, None),
+            consecutive_reconcile_errors: 0,
+            last_utilization_sample: None,
+            fill_rate: None,
+            scale_threshold: None,
+            size_predictor: None,
+            utilization_alerted: false,
+            panic_reserve_released: false,
+            remaining_capacity_stale: true,
+            trace: None,
+            attached_volume_count: None,
+            encryption_by_default: None,
+            notifier,
+            drain: None,
+        })
+    }
+
+    /// Builds an EBSManager backed by the real disk, AWS and filesystem implementations
+    ///
+    /// The limiter is created once per process and shared by every manager.
+    #[cfg(target_os = "linux")]
+    pub fn from_config(
+        conf: config::Config, limiter: aws::ApiLimiter,
+    ) -> Result<Box<EBSManager>, Box<dyn Error>> {
+        let aws_cli = aws::LimitedAWS::new(Box::new(aws::ConcreteAWS::new(&conf)?), limiter);
+        let fs_lib = fs::ConcreteFS {
+            fs_type: conf.fs_type.clone(),
+            mountpoint: conf.mountpoint.clone(),
+            runner: command_runner_for(&conf),
+            rebalance: fs::RebalanceState::new(&conf.state_dir),
+            reserved_blocks_percent: conf.ext4_reserved_blocks_percent,
+        };
+        let aws_cli: Box<dyn aws::AWS> = match conf.describe_cache_ttl_ms {
+            0 => Box::new(aws_cli),
+            ttl_ms => Box::new(aws::CachingAWS::new(
+                Box::new(aws_cli),
+                Duration::from_millis(ttl_ms),
+                Box::new(clock::SystemClock),
+            )),
+        };
+        let allocator = disk::DeviceAllocator::with_quarantine(
+            disk::DEVICE_PREFIX,
+            Duration::from_secs(conf.device_reuse_quarantine_secs),
+            Box::new(clock::SystemClock),
+        );
+        let diskmgr = disk::ConcreteDiskMgr::with_allocator(
+            Box::new(disk::SysDeviceLister),
+            std::sync::Arc::new(Mutex::new(allocator)),
+            disk::DeviceFilter::new(conf.exclude_devices.clone()),
+        );
+        Ok(EBSManager::new(
+            conf,
+            Box::new(diskmgr),
+            aws_cli,
+            Box::new(fs_lib),
+            Box::new(clock::SystemClock),
+        ))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_config(
+        _conf: config::Config, _limiter: aws::ApiLimiter,
+    ) -> Result<Box<EBSManager>, Box<dyn Error>> {
+        Err(Box::new(UnsupportedPlatformError))
+    }
+
+    /// Skips reconciles started once shutdown is requested on drain
+    pub fn set_drain(&mut self, drain: drain::Drain) {
+        self.drain = Some(drain);
+    }
+
+    /// Handle shared with the status endpoint
+    pub fn status_handle(&self) -> status::SharedStatus {
+        self.status.clone()
+    }
+
+    /// Paused by config or from the status endpoint
+    pub fn is_paused(&self) -> bool {
+        self.config.paused || self.status.is_paused()
+    }
+
+    /// Replaces the running config, clearing any quiescent state
+    pub fn reload_config(&mut self, conf: config::Config) {
+        match conf.to_redacted_json() {
+            Ok(json) => info!("Reloading config - effective config {}", json),
+            Err(e) => warn!("Reloading config - could not serialize it - {}", e),
+        }
+        self.utilization_log = utilization_log_for(&conf);
+        self.audit_log = audit_log_for(&conf);
+        self.statsd = statsd_sink_for(&conf);
+        self.notifier = notify::from_config(&conf);
+        self.config = conf;
+        self.quiesced = false;
+        self.max_size_alerted = false;
+        self.limit_event_sent = false;
+        self.remaining_capacity_stale = true;
+    }
+
+    /// Finishes filesystem work interrupted by a previous shutdown
+    pub fn resume_filesystem_work(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.config.observe_only {
+            return Ok(false);
+        }
+        self.fs.resume_pending_work()
+    }
+
+    /// Rebuilds the volume accounting from managed volumes already attached to this
+    /// instance, so an instance started from a warm pool resumes instead of assuming
+    /// a clean slate
+    pub fn reconcile_existing_volumes(&mut self) -> Result<Option<ExistingVolumes>, Box<dyn Error>> {
+        let instance_id = match self.own_instance_id() {
+            Some(instance_id) => instance_id,
+            None => return Ok(None),
+        };
+        let attached: Vec<aws::ManagedVolume> = self.aws.get_managed_ebs_volumes()?
+            .into_iter()
+            .filter(|volume| {
+                volume.state == "in-use" && volume.device.is_some()
+                    && volume.is_attached_to(&instance_id)
+            })
+            .collect();
+        if attached.is_empty() {
+            // A clean slate, counted from zero rather than by count_mounted_ebs_volumes
+            self.attached_volume_count = Some(0);
+            return Ok(None);
+        }
+        let existing = ExistingVolumes {
+            volume_count: attached.len().try_into()?,
+            logical_size_gib: total_size_gib(attached.iter().map(|volume| volume.size_gib))?,
+            devices: attached.into_iter().filter_map(|volume| volume.device).collect(),
+        };
+        info!(
+            "Resuming with {} managed volumes ({}) already attached to {}",
+            existing.volume_count, existing.logical_size_gib, self.config.mountpoint
+        );
+        self.attached_volume_count = Some(existing.volume_count);
+        Ok(Some(existing))
+    }
+
+    /// Volumes making up the logical volume, as rebuilt on startup or reported by AWS
+    fn mounted_volume_count(&mut self) -> Result<u32, Box<dyn Error>> {
+        match self.attached_volume_count {
+            Some(count) => Ok(count),
+            None => Ok(self.aws.count_mounted_ebs_volumes()?),
+        }
+    }
+
+    pub fn power_on_self_test(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.check_cost_allocation_tags()?;
+        let identity = self.check_instance_identity()?;
+        self.check_permissions(identity.as_ref())?;
+        if self.needs_bootstrap() {
+            // bootstrap_if_missing creates the filesystem these checks inspect
+            info!(
+                "Nothing mounted at {} yet - skipping the mount checks until it is bootstrapped",
+                self.config.mountpoint
+            );
+        } else {
+            self.check_filesystem_type()?;
+            if !self.config.observe_only {
+                self.check_read_only()?;
+            }
+        }
+        if !self.config.observe_only {
+            self.fs.check_tools()?;
+        }
+        self.check_encryption_by_default();
+        self.status.set_preflight_passed();
+        Ok(true)
+    }
+
+    /// Fails when a required cost-allocation tag is missing or empty, as volumes could
+    /// not be tagged at creation
+    fn check_cost_allocation_tags(&self) -> Result<(), Box<dyn Error>> {
+        let tags = &self.config.cost_allocation_tags;
+        let missing: Vec<String> = self.config.required_cost_allocation_tags.iter()
+            .filter(|key| tags.get(*key).is_none_or(|value| value.is_empty()))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(Box::new(MissingCostAllocationTagsError { keys: missing }));
+        }
+        Ok(())
+    }
+
+    /// Fails on the first EC2 action the credentials are denied; inconclusive
+    /// checks are only logged, so a throttled probe does not block startup
+    fn check_permissions(
+        &mut self,
+        identity: Option<&aws::InstanceIdentity>,
+    ) -> Result<(), Box<dyn Error>> {
+        let actions: &[aws::Ec2Action] = if self.config.observe_only {
+            &[aws::Ec2Action::DescribeVolumes]
+        } else {
+            &[
+                aws::Ec2Action::DescribeVolumes,
+                aws::Ec2Action::CreateVolume,
+                aws::Ec2Action::AttachVolume,
+            ]
+        };
+        for action in actions {
+            match self.aws.probe_permission(*action, identity) {
+                aws::PermissionProbe::Allowed => {}
+                aws::PermissionProbe::Denied => {
+                    return Err(Box::new(MissingPermissionError { action: action.iam_name() }));
+                }
+                aws::PermissionProbe::Inconclusive(reason) => {
+                    warn!("Could not check {} - {}", action.iam_name(), reason);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns when the account encrypts new volumes although volume.encrypted is false,
+    /// as AWS then ignores the setting; a failed lookup is only logged
+    fn check_encryption_by_default(&mut self) {
+        let by_default = match self.aws.ebs_encryption_by_default() {
+            Ok(by_default) => by_default,
+            Err(e) => {
+                warn!("Could not read the account's EBS encryption by default - {}", e);
+                return;
+            }
+        };
+        self.encryption_by_default = Some(by_default);
+        if by_default && !self.config.volume.encrypted {
+            warn!(
+                "EBS encryption by default is enabled for the account - new volumes will be \
+                 encrypted although volume.encrypted is false"
+            );
+        }
+        info!(
+            "New volumes will be {}",
+            if self.effective_encryption() == Some(true) { "encrypted" } else { "unencrypted" }
+        );
+    }
+
+    /// Whether new volumes end up encrypted, None while the account setting is unknown
+    pub fn effective_encryption(&self) -> Option<bool> {
+        if self.config.volume.encrypted {
+            return Some(true);
+        }
+        self.encryption_by_default
+    }
+
+    /// Checks fs_type against the mounted filesystem, or adopts it when fs_type is ""
+    fn check_filesystem_type(&mut self) -> Result<(), Box<dyn Error>> {
+        let detected = self.diskmgr.filesystem_type(self.config.mountpoint.clone())?;
+        if self.config.fs_type.is_empty() {
+            info!("Detected {} filesystem at {}", detected, self.config.mountpoint);
+            self.fs.set_fs_type(detected.clone());
+            self.config.fs_type = detected;
+            return Ok(());
+        }
+        if self.config.fs_type != detected {
+            return Err(Box::new(FsTypeMismatchError {
+                configured: self.config.fs_type.clone(),
+                detected,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Refuses a read-only mountpoint unless allow_readonly is set
+    fn check_read_only(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.diskmgr.is_read_only(self.config.mountpoint.clone())? {
+            return Ok(());
+        }
+        if !self.config.allow_readonly {
+            let mountpoint = self.config.mountpoint.clone();
+            return Err(Box::new(ReadOnlyMountError { mountpoint }));
+        }
+        warn!("{} is mounted read-only - managing it anyway", self.config.mountpoint);
+        Ok(())
+    }
+
+    /// Fails fast when IMDS is unreachable and there are no region/AZ overrides
+    fn check_instance_identity(&mut self) -> Result<Option<aws::InstanceIdentity>, Box<dyn Error>> {
+        if self.config.region.is_some() && self.config.availability_zone.is_some() {
+            return Ok(None);
+        }
+        match self.aws.instance_identity() {
+            Ok(identity) => {
+                info!("Running on {} in {}", identity.instance_id, identity.availability_zone);
+                self.instance_id = Some(identity.instance_id.clone());
+                Ok(Some(identity))
+            }
+            Err(_e) => Err(Box::new(ImdsUnavailableError)),
+        }
+    }
+
+    /// Id of the instance this runs on, read from IMDS the first time it is needed
+    fn own_instance_id(&mut self) -> Option<String> {
+        if self.instance_id.is_none() {
+            self.instance_id = self.aws.instance_identity().ok()
+                .map(|identity| identity.instance_id);
+        }
+        self.instance_id.clone()
+    }
+
+    /// Managed volumes belonging to this instance, every managed volume when the
+    /// instance is unknown so that limits are never undercounted
+    fn own_volumes(&mut self) -> Result<Vec<aws::ManagedVolume>, Box<dyn Error>> {
+        let volumes = self.aws.get_managed_ebs_volumes()?;
+        Ok(match self.own_instance_id() {
+            Some(instance_id) => volumes.into_iter()
+                .filter(|volume| volume.belongs_to(&instance_id))
+                .collect(),
+            None => volumes,
+        })
+    }
+
+    pub fn need_more_space(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.scale_threshold = None;
+        self.diskmgr.save_disk_list()?;
+        let dev_count = self.mounted_volume_count()?;
+        let threshold = self.calc_threshold(dev_count).unwrap();
+        let disk_utilization = self.diskmgr.disk_usage_percent(
+            self.config.mountpoint.clone()
+        )?;
+        self.status.update(|snapshot| snapshot.utilization_percent = Some(disk_utilization));
+        self.sample_utilization(disk_utilization);
+        let filling_fast = self.filling_within_horizon(disk_utilization);
+
+        self.trace_gate(
+            "utilization_threshold",
+            format!("utilization {}% / threshold {}%", disk_utilization, threshold),
+            disk_utilization >= threshold,
+        );
+        if disk_utilization >= threshold {
+            info!("Low disk space - adding more disks");
+            self.scale_threshold = Some(threshold);
+            return Ok(true);
+        }
+        if let Some(horizon) = self.config.fill_rate_horizon_secs {
+            let detail = format!("fill rate {:?}%/s / horizon {}s", self.fill_rate, horizon);
+            self.trace_gate("fill_rate", detail, filling_fast);
+        }
+        if filling_fast {
+            return Ok(true);
+        }
+        self.low_on_inodes()
+
+    }
+
+    /// Re-reads utilization right before creating a volume, erroring when it dropped
+    /// below the threshold that triggered scaling by more than no_longer_needed_margin_percent
+    fn check_still_needed(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(threshold) = self.scale_threshold else {
+            return Ok(());
+        };
+        self.diskmgr.save_disk_list()?;
+        let utilization = self.diskmgr.disk_usage_percent(self.config.mountpoint.clone())?;
+        if utilization >= threshold.saturating_sub(self.config.no_longer_needed_margin_percent) {
+            return Ok(());
+        }
+        Err(Box::new(ScaleNoLongerNeededError {
+            utilization_percent: utilization,
+            threshold_percent: threshold,
+        }))
+    }
+
+    /// Keeps utilization as the latest sample, updating the fill rate from the previous one
+    fn sample_utilization(&mut self, utilization: u32) {
+        let now = self.clock.now();
+        let previous = self.last_utilization_sample.replace((now, utilization));
+        if let Some((sampled_at, previous_utilization)) = previous {
+            let elapsed = now.duration_since(sampled_at).as_secs_f64();
+            self.fill_rate = (elapsed > 0.0).then(|| {
+                (f64::from(utilization) - f64::from(previous_utilization)) / elapsed
+            });
+        }
+    }
+
+    /// Whether, at the rate utilization rose since the previous sample, the disk is full
+    /// within fill_rate_horizon_secs
+    fn filling_within_horizon(&self, utilization: u32) -> bool {
+        let (horizon, rate) = match (self.config.fill_rate_horizon_secs, self.fill_rate) {
+            (Some(horizon), Some(rate)) if rate > 0.0 => (horizon, rate),
+            _ => return false,
+        };
+        let secs_to_full = f64::from(100u32.saturating_sub(utilization)) / rate;
+        if secs_to_full >= horizon as f64 {
+            return false;
+        }
+        info!(
+            "Disk is filling at {:.2}%/s and full in {:.0}s - adding more disks",
+            rate, secs_to_full
+        );
+        true
+    }
+
+    /// Whether inode utilization is over inode_utilization_threshold and more space helps
+    fn low_on_inodes(&mut self) -> Result<bool, Box<dyn Error>> {
+        let threshold = match self.config.inode_utilization_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(false),
+        };
+        let inode_utilization = self.diskmgr.inode_usage_percent(self.config.mountpoint.clone())?;
+        let detail = format!("inodes {}% / threshold {}%", inode_utilization, threshold);
+        let low = inode_utilization >= threshold && self.config.fs_type != "ext4";
+        self.trace_gate("inode_threshold", detail, low);
+        if inode_utilization < threshold {
+            return Ok(false);
+        }
+        if self.config.fs_type == "ext4" {
+            warn!(
+                "Inode utilization on {} is {}% - not scaling, adding space to ext4 does not add inodes",
+                self.config.mountpoint, inode_utilization
+            );
+            return Ok(false);
+        }
+        info!("Low on inodes ({}%) - adding more disks", inode_utilization);
+        Ok(true)
+    }
+
+    pub fn add_more_space(&mut self, dev_count: u32) -> Result<AddedVolume, Box<dyn Error>> {
+        let max_count = self.config.limits.max_ebs_volume_count;
+        let detail = format!("{} volumes / max {}", dev_count, max_count);
+        self.trace_gate("max_ebs_volume_count", detail, dev_count < max_count);
+        if dev_count >= max_count {
+            return Err(Box::new(MaxEBSCountExceededError));
+        }
+        let cur_size = self.diskmgr.disk_size(self.config.mountpoint.clone())?;
+        let max_size = self.config.limits.max_logical_volume_size;
+        let detail = format!("{} / max {}", cur_size, max_size);
+        self.trace_gate("max_logical_volume_size", detail, cur_size < max_size);
+        if cur_size >= max_size {
+           return Err(Box::new(MaxLogicalVolumeSizeExceededError));
+        }
+        let new_size = self.calc_new_size(dev_count, cur_size);
+        let headroom = self.check_headroom(cur_size, new_size);
+        self.traced("headroom", headroom)?;
+        let new_volumes = [(self.volume_spec(0)?, new_size)];
+        let quota = self.check_account_quota(&new_volumes);
+        self.traced("account_quota", quota)?;
+        let managed_volumes = self.own_volumes()?;
+        let cost = self.check_monthly_cost(&managed_volumes, &new_volumes);
+        self.traced("monthly_cost", cost)?;
+        // https://github.com/awslabs/amazon-ebs-autoscale/blob/8c8ac28914bf9302e4f5821ba99daac6e7fc05eb/bin/create-ebs-volume#L223
+        let total_created_volumes_size = total_size_gib(
+            managed_volumes.iter().map(|volume| volume.size_gib)
+        )?;
+        let detail = format!("{} managed / max {}", total_created_volumes_size, max_size);
+        let under_max = total_created_volumes_size < max_size;
+        self.trace_gate("managed_volumes_size", detail, under_max);
+        if !under_max {
+            return Err(Box::new(MaxLogicalVolumeSizeExceededError));
+        }
+        // TODO - check AWS payload to filter by this
+        // https://github.com/awslabs/amazon-ebs-autoscale/blob/8c8ac28914bf9302e4f5821ba99daac6e7fc05eb/bin/create-ebs-volume#L192
+        /*let attached_volumes_count = created_volumes
+            .filter(
+                |ebs_data|
+                ebs_data.as_ref()
+                        .map_or(false, true)
+            );
+        if attached_volumes_count >= self.config.limits.max_ebs_volume_count ||
+            created_volumes.len() >= self.config.limits.max_ebs_volume_count
+        {
+            return Err(Box::new(MaxEBSCountExceededError))
+        }*/
+        if self.config.scale_mode == config::ScaleMode::GrowVolume {
+            if let Some(volume) = self.volume_to_grow(growable_volumes(managed_volumes)) {
+                return self.grow_volume(volume, new_size, cur_size);
+            }
+            info!(
+                "No volume of {} can be grown now - adding a new volume",
+                self.config.mountpoint
+            );
+        }
+        let still_needed = self.check_still_needed();
+        self.traced("still_needed", still_needed)?;
+        info!(
+            "Will extend volume {} by {}",
+            self.config.mountpoint.clone(),
+            new_size
+        );
+        let scale_event_id = new_scale_event_id();
+        let added = self.create_and_attach_volume(new_size, Some(&scale_event_id))?;
+        self.expand_onto(&added, cur_size)?;
+        self.publish_event(
+            event::EventKind::ScaleUp,
+            Some(&added),
+            checked_size_add(cur_size, added.size_gib)?,
+        );
+        Ok(added)
+    }
+
+    /// Adds the volumes needed to drop below the threshold as one batch, up to
+    /// max_volumes_per_tick
+    ///
+    /// Limits are checked for every volume of the batch before creating any, the batch
+    /// stopping before the first volume add_more_space would refuse. The volumes are
+    /// created at once, as many in flight as the API limiter allows, then attached one
+    /// after another; the waits for their devices overlap, as every volume is attached
+    /// before waiting for any. If creating or attaching one fails, the other volumes are
+    /// still added to the filesystem and the error returned afterwards.
+    pub fn add_more_space_concurrently(
+        &mut self,
+        dev_count: u32,
+    ) -> Result<Vec<AddedVolume>, Box<dyn Error>> {
+        let budget = match self.config.max_volumes_per_tick {
+            0 => self.config.limits.max_ebs_volume_count,
+            budget => budget,
+        };
+        let steps = self.plan(budget);
+        let needed = self.volumes_needed(&steps)?;
+        if needed < 2 {
+            return Ok(vec![self.add_more_space(dev_count)?]);
+        }
+        let managed_volumes = self.own_volumes()?;
+        let needed = self.volumes_within_limits(dev_count, &steps[..needed], &managed_volumes)?;
+        let steps = &steps[..needed];
+        let cur_size = self.diskmgr.disk_size(self.config.mountpoint.clone())?;
+        let batch_size = total_size_gib(steps.iter().map(|step| step.size_gib))?;
+        let mut specs = Vec::new();
+        for (pending, step) in steps.iter().enumerate() {
+            specs.push((self.volume_spec(pending.try_into()?)?, step.size_gib));
+        }
+        let quota = self.check_account_quota(&specs);
+        self.traced("account_quota", quota)?;
+        let cost = self.check_monthly_cost(&managed_volumes, &specs);
+        self.traced("monthly_cost", cost)?;
+        let still_needed = self.check_still_needed();
+        self.traced("still_needed", still_needed)?;
+        info!(
+            "Will extend volume {} by {} with {} volumes",
+            self.config.mountpoint, batch_size, needed
+        );
+        let mut requests = Vec::new();
+        for (pending, step) in steps.iter().enumerate() {
+            requests.push(self.volume_request(step.size_gib, pending.try_into()?)?);
+        }
+        let devices = self.diskmgr.get_next_logical_devices(needed)?;
+        let scale_event_id = new_scale_event_id();
+        let created = self.aws.request_ebs_volumes(requests.clone());
+        let mut attached = Vec::new();
+        let mut failure = None;
+        for ((request, created), dev) in requests.iter().zip(created).zip(devices) {
+            let volume_id = match self.finish_created(request, created, Some(&scale_event_id)) {
+                Ok(volume_id) => volume_id,
+                Err(err) => {
+                    // The name allocated for a volume never created
+                    self.diskmgr.release_device(dev);
+                    failure.get_or_insert(err);
+                    continue;
+                }
+            };
+            match self.attach_created(volume_id, dev) {
+                Ok((volume_id, dev)) => attached.push((volume_id, dev, request.size)),
+                Err(err) => {
+                    failure.get_or_insert(err);
+                }
+            }
+        }
+        let mut added = Vec::new();
+        for (volume_id, dev, size) in attached {
+            match self.incorporate_volume(volume_id, dev, size) {
+                Ok(volume) => added.push(volume),
+                Err(err) => {
+                    failure.get_or_insert(err);
+                }
+            }
+        }
+        self.settle_after_attach();
+        let mut total_size = cur_size;
+        let mut expanded = Vec::new();
+        for volume in added {
+            if let Err(err) = self.expand_onto(&volume, total_size) {
+                failure.get_or_insert(err);
+                continue;
+            }
+            total_size = checked_size_add(total_size, volume.size_gib)?;
+            self.publish_event(event::EventKind::ScaleUp, Some(&volume), total_size);
+            expanded.push(volume);
+        }
+        if let Some(err) = failure {
+            warn!("Added {} of {} volumes to {}", expanded.len(), needed, self.config.mountpoint);
+            return Err(err);
+        }
+        Ok(expanded)
+    }
+
+    /// Requests a volume and attaches it as dev, deleting it again if it cannot be
+    /// attached so it does not leak
+    ///
+    /// dev is released if no volume ends up attached to it.
+    fn request_and_attach(
+        &mut self,
+        size: SizeGib,
+        pending: u32,
+        dev: String,
+        scale_event_id: Option<&str>,
+    ) -> Result<(String, String), Box<dyn Error>> {
+        let volume_id = match self.request_volume(size, pending, scale_event_id) {
+            Ok(volume_id) => volume_id,
+            Err(err) => {
+                self.diskmgr.release_device(dev);
+                return Err(err);
+            }
+        };
+        self.attach_created(volume_id, dev)
+    }
+
+    /// Attaches a created volume as dev, deleting it and releasing dev if it cannot be
+    /// attached so it does not leak
+    fn attach_created(
+        &mut self, volume_id: String, dev: String,
+    ) -> Result<(String, String), Box<dyn Error>> {
+        match self.attach_volume(volume_id.clone(), dev.clone()) {
+            Ok(dev) => Ok((volume_id, dev)),
+            Err(err) => {
+                self.diskmgr.release_device(dev);
+                let params = serde_json::json!({ "volume_id": volume_id });
+                let deleted = self.aws.delete_ebs_volume(volume_id.clone());
+                self.audit("delete_volume", params, &deleted);
+                if let Err(e) = deleted {
+                    error!("CRITICAL: could not delete unattached {} - {}", volume_id, e);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Planned volumes of a batch add_more_space would add one after another, checking
+    /// the volume count, logical size, headroom and managed volumes size limits for each
+    ///
+    /// Fails as add_more_space does when not even the first volume can be added.
+    fn volumes_within_limits(
+        &mut self,
+        dev_count: u32,
+        steps: &[PlannedStep],
+        managed_volumes: &[aws::ManagedVolume],
+    ) -> Result<usize, Box<dyn Error>> {
+        let limits = self.config.limits.clone();
+        let mut logical_size = self.diskmgr.disk_size(self.config.mountpoint.clone())?;
+        let mut managed_size = total_size_gib(managed_volumes.iter().map(|volume| volume.size_gib))?;
+        for (idx, step) in steps.iter().enumerate() {
+            let count = dev_count.saturating_add(idx.try_into()?);
+            let refused: Option<Box<dyn Error>> = if count >= limits.max_ebs_volume_count {
+                Some(Box::new(MaxEBSCountExceededError))
+            } else if logical_size >= limits.max_logical_volume_size
+                || managed_size >= limits.max_logical_volume_size
+            {
+                Some(Box::new(MaxLogicalVolumeSizeExceededError))
+            } else {
+                self.check_headroom(logical_size, step.size_gib).err().map(Into::into)
+            };
+            if let Some(err) = refused {
+                if idx == 0 {
+                    return Err(err);
+                }
+                info!(
+                    "Adding {} of {} volumes to {} - {}",
+                    idx, steps.len(), self.config.mountpoint, err
+                );
+                return Ok(idx);
+            }
+            logical_size = checked_size_add(logical_size, step.size_gib)?;
+            managed_size = checked_size_add(managed_size, step.size_gib)?;
+        }
+        Ok(steps.len())
+    }
+
+    /// Planned volumes needed for the projected utilization to drop below the threshold,
+    /// all of them if it never does
+    fn volumes_needed(&mut self, steps: &[PlannedStep]) -> Result<usize, Box<dyn Error>> {
+        let cur_size = self.diskmgr.disk_size(self.config.mountpoint.clone())?;
+        let utilization = self.diskmgr.disk_usage_percent(self.config.mountpoint.clone())?;
+        let used_gib = cur_size.percent(utilization.into()).as_u64();
+        for (idx, step) in steps.iter().enumerate() {
+            let threshold = u64::from(self.calc_threshold(step.volume_count).unwrap());
+            let total_gib = step.total_size_gib.as_u64();
+            if used_gib.saturating_mul(100) < threshold.saturating_mul(total_gib) {
+                return Ok(idx + 1);
+            }
+        }
+        Ok(steps.len())
+    }
+
+    /// Volume attached to this instance with the most room left below
+    /// max_ebs_volume_size, skipping volumes in their ModifyVolume cooldown
+    ///
+    /// volumes should already be filtered by growable_volumes.
+    fn volume_to_grow(&mut self, volumes: Vec<aws::ManagedVolume>) -> Option<aws::ManagedVolume> {
+        let max_size = self.config.limits.max_ebs_volume_size;
+        let instance_id = self.own_instance_id()?;
+        let candidates: Vec<&aws::ManagedVolume> = volumes.iter()
+            .filter(|volume| {
+                volume.state == "in-use" && volume.device.is_some() && volume.size_gib < max_size
+                    && volume.is_attached_to(&instance_id)
+            })
+            .collect();
+        self.modifiable(candidates, "growing")
+            .into_iter()
+            .min_by_key(|volume| volume.size_gib)
+    }
+
+    /// Volumes ModifyVolume can change now, i.e. neither in their cooldown nor with a
+    /// modification still running; action names the change in logs
+    fn modifiable(
+        &mut self, volumes: Vec<&aws::ManagedVolume>, action: &str,
+    ) -> Vec<aws::ManagedVolume> {
+        let now = self.clock.unix_secs();
+        let cooldowns = history::VolumeModifications::new(&self.config.state_dir)
+            .in_cooldown(now)
+            .unwrap_or_else(|e| {
+                warn!("Could not read volume modification history - {}", e);
+                BTreeMap::new()
+            });
+        self.status.update(|snapshot| snapshot.modify_cooldown_until = cooldowns.clone());
+        if !volumes.is_empty() {
+            let cooling: Vec<&str> = volumes.iter()
+                .map(|volume| volume.volume_id.as_str())
+                .filter(|volume_id| cooldowns.contains_key(*volume_id))
+                .collect();
+            let detail = format!("{} of {} volumes in cooldown", cooling.len(), volumes.len());
+            self.trace_gate("modify_cooldown", detail, cooling.len() < volumes.len());
+        }
+        volumes.into_iter()
+            .filter(|volume| match cooldowns.get(&volume.volume_id) {
+                Some(until) => {
+                    info!(
+                        "Volume {} was modified less than 6 hours ago - not {} it until {}",
+                        volume.volume_id, action, until
+                    );
+                    false
+                }
+                None => true,
+            })
+            .filter(|volume| {
+                // AWS refuses a modification while the previous one is still running
+                match self.aws.volume_modification_state(volume.volume_id.clone()) {
+                    Ok(Some(state)) if state.in_progress() => {
+                        info!(
+                            "Volume {} is still {:?} - not {} it",
+                            volume.volume_id, state, action
+                        );
+                        false
+                    }
+                    Ok(_) => true,
+                    Err(e) => {
+                        warn!("Could not check modifications of {} - {}", volume.volume_id, e);
+                        false
+                    }
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Promotes a gp3 volume to io2 once the fed IOPS utilization stayed at or above
+    /// io2_promotion's threshold for long enough
+    ///
+    /// The tool cannot measure IOPS demand itself, so nothing is promoted until a
+    /// utilization is fed to the status endpoint. A fed utilization not refreshed
+    /// within IOPS_SAMPLE_MAX_AGE no longer counts.
+    fn check_io2_promotion(&mut self) -> Result<(), Box<dyn Error>> {
+        let promotion = match self.config.io2_promotion {
+            Some(promotion) if !self.config.observe_only && !self.is_paused() => promotion,
+            _ => return Ok(()),
+        };
+        let now = self.clock.now();
+        let fed = self.status.iops_samples_fed();
+        if fed != self.iops_samples_seen.0 {
+            self.iops_samples_seen = (fed, Some(now));
+        }
+        let fresh = self.iops_samples_seen.1
+            .is_some_and(|fed_at| now.duration_since(fed_at) <= IOPS_SAMPLE_MAX_AGE);
+        let utilization = self.status.snapshot().iops_utilization_percent.filter(|_| fresh);
+        match utilization {
+            Some(utilization) if utilization >= promotion.iops_utilization_threshold => {
+                let since = *self.high_iops_since.get_or_insert(now);
+                if now.duration_since(since) < Duration::from_secs(promotion.sustained_secs) {
+                    return Ok(());
+                }
+            }
+            _ => {
+                self.high_iops_since = None;
+                return Ok(());
+            }
+        }
+        let instance_id = match self.own_instance_id() {
+            Some(instance_id) => instance_id,
+            None => return Ok(()),
+        };
+        let volumes = growable_volumes(self.aws.get_managed_ebs_volumes()?);
+        let gp3: Vec<&aws::ManagedVolume> = volumes.iter()
+            .filter(|volume| {
+                volume.state == "in-use" && volume.vol_type == "gp3"
+                    && volume.is_attached_to(&instance_id)
+            })
+            .collect();
+        let volume = match self.modifiable(gp3, "promoting").into_iter().next() {
+            Some(volume) => volume,
+            None => return Ok(()),
+        };
+        info!(
+            "IOPS utilization has been at or above {}% for {}s - promoting {} to io2 with {} IOPS",
+            promotion.iops_utilization_threshold,
+            promotion.sustained_secs,
+            volume.volume_id,
+            promotion.iops
+        );
+        let promoted = self.aws.modify_volume_type(
+            volume.volume_id.clone(), "io2".to_string(), promotion.iops,
+        );
+        self.audit(
+            "modify_volume_type",
+            serde_json::json!({
+                "volume_id": volume.volume_id, "vol_type": "io2", "iops": promotion.iops
+            }),
+            &promoted,
+        );
+        promoted?;
+        history::VolumeModifications::new(&self.config.state_dir)
+            .record(&volume.volume_id, self.clock.unix_secs())?;
+        // Demand is measured afresh against the promoted volume
+        self.high_iops_since = None;
+        Ok(())
+    }
+
+    /// Polls the modification of a volume until its new size is usable, or until it
+    /// completes with wait_for_modification_completed
+    fn wait_for_modification(&mut self, volume_id: &str) -> Result<(), VolumeModificationError> {
+        let timeout = Duration::from_secs(self.config.modification_timeout_secs);
+        let started = self.clock.now();
+        let mut last_state = None;
+        loop {
+            // A failed check says nothing about the modification, so keep polling
+            match self.aws.volume_modification_state(volume_id.to_string()) {
+                Ok(state) => {
+                    match state {
+                        None | Some(aws::ModificationState::Completed) => return Ok(()),
+                        Some(aws::ModificationState::Optimizing)
+                            if !self.config.wait_for_modification_completed => return Ok(()),
+                        Some(aws::ModificationState::Failed) => {
+                            return Err(VolumeModificationError {
+                                volume_id: volume_id.to_string(),
+                                state,
+                            });
+                        }
+                        _ => {}
+                    }
+                    last_state = state;
+                }
+                Err(e) => warn!("Could not check the modification of {} - {}", volume_id, e),
+            }
+            if self.clock.now().duration_since(started) >= timeout {
+                return Err(VolumeModificationError {
+                    volume_id: volume_id.to_string(),
+                    state: last_state,
+                });
+            }
+            self.clock.sleep(MODIFICATION_POLL_INTERVAL);
+        }
+    }
+
+    /// Grows an attached volume by up to size with ModifyVolume, then the filesystem
+    fn grow_volume(
+        &mut self, volume: aws::ManagedVolume, size: SizeGib, cur_size: SizeGib,
+    ) -> Result<AddedVolume, Box<dyn Error>> {
+        let max_size = self.config.limits.max_ebs_volume_size;
+        let target_size = volume.size_gib.saturating_add(size).min(max_size);
+        let device = volume.device.clone().unwrap_or_default();
+        info!(
+            "Growing volume {} ({}) from {} to {}",
+            volume.volume_id, device, volume.size_gib, target_size
+        );
+        let modified = self.aws.modify_volume_size(volume.volume_id.clone(), target_size);
+        self.audit(
+            "modify_volume",
+            serde_json::json!({ "volume_id": volume.volume_id, "size_gib": target_size }),
+            &modified,
+        );
+        modified?;
+        let now = self.clock.unix_secs();
+        if let Err(e) = history::VolumeModifications::new(&self.config.state_dir)
+            .record(&volume.volume_id, now)
+        {
+            warn!("Could not record the modification of {} - {}", volume.volume_id, e);
+        }
+        self.wait_for_modification(&volume.volume_id)?;
+        let started = self.clock.now();
+        let grown = self.fs.grow_device(device.clone());
+        self.record_fs_grow_duration(started);
+        self.audit("grow_filesystem", serde_json::json!({ "device": device }), &grown);
+        grown?;
+        let added = AddedVolume {
+            volume_id: volume.volume_id,
+            device,
+            size_gib: target_size.saturating_sub(volume.size_gib),
+        };
+        self.publish_event(
+            event::EventKind::ScaleUp,
+            Some(&added),
+            checked_size_add(cur_size, added.size_gib)?,
+        );
+        Ok(added)
+    }
+
+    /// Creates a volume of size and attaches it to the next free device
+    fn create_and_attach_volume(
+        &mut self,
+        size: SizeGib,
+        scale_event_id: Option<&str>,
+    ) -> Result<AddedVolume, Box<dyn Error>> {
+        let dev = self.diskmgr.get_next_logical_device()?;
+        let (volume_id, dev) = self.request_and_attach(size, 0, dev, scale_event_id)?;
+        let added = match self.incorporate_volume(volume_id.clone(), dev.clone(), size) {
+            Ok(added) => added,
+            Err(err) => {
+                self.discard_attached(&volume_id, dev);
+                return Err(err);
+            }
+        };
+        self.settle_after_attach();
+        Ok(added)
+    }
+
+    /// Detaches and deletes a volume that never joined the filesystem, so it does not
+    /// leak, returning whether it was removed
+    fn discard_attached(&mut self, volume_id: &str, dev: String) -> bool {
+        warn!("Detaching and deleting {} ({}) as it could not be used", volume_id, dev);
+        match self.remove_volume(volume_id.to_string(), dev) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("CRITICAL: could not remove unused {} - {}", volume_id, e);
+                false
+            }
+        }
+    }
+
+    /// Requests a volume of size, pending being the volumes requested before it
+    /// in the same batch, tagged with the id of the scale event creating it if any
+    fn request_volume(
+        &mut self,
+        size: SizeGib,
+        pending: u32,
+        scale_event_id: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        let request = self.volume_request(size, pending)?;
+        let created = self.aws.request_ebs_volume(
+            request.size,
+            request.vol_type.clone(),
+            request.encrypted,
+            request.iops,
+            request.throughput,
+            request.name.clone(),
+        );
+        self.finish_created(&request, created, scale_event_id)
+    }
+
+    /// The CreateVolume request for a volume of size, pending being the volumes
+    /// requested before it in the same batch
+    fn volume_request(
+        &mut self, size: SizeGib, pending: u32,
+    ) -> Result<aws::VolumeRequest, Box<dyn Error>> {
+        let volume = self.volume_spec(pending)?;
+        Ok(aws::VolumeRequest {
+            size,
+            vol_type: volume.vol_type,
+            encrypted: volume.encrypted,
+            iops: volume.iops,
+            throughput: volume.throughput,
+            name: self.volume_name(pending)?,
+        })
+    }
+
+    /// Spec of the next volume, pending being the volumes requested before it in the
+    /// same batch: that of its tier, made the cheapest meeting performance_target if set
+    fn volume_spec(&mut self, pending: u32) -> Result<config::Volume, Box<dyn Error>> {
+        let count = self.mounted_volume_count()? + pending;
+        let mut volume = self.config.volume_for(count);
+        if let Some(target) = self.config.performance_target {
+            volume = cost::cheapest_volume(&volume, target);
+        }
+        volume.validate()?;
+        volume.with_type_defaults()
+    }
+
+    /// Audits the CreateVolume call of request, then waits for the volume to be created
+    /// and tags it with the id of its scale event if any
+    fn finish_created(
+        &mut self,
+        request: &aws::VolumeRequest,
+        created: Result<String, Box<aws::GenericAWSError>>,
+        scale_event_id: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        let params = serde_json::json!({
+            "size_gib": request.size,
+            "vol_type": request.vol_type,
+            "encrypted": request.encrypted,
+            "iops": request.iops,
+            "throughput": request.throughput,
+            "name": request.name,
+            "scale_event_id": scale_event_id,
+        });
+        self.audit("create_volume", params, &created);
+        let volume_id = created?;
+        self.wait_until_created(&volume_id)?;
+        if let Some(scale_event_id) = scale_event_id {
+            self.tag_scale_event(&volume_id, scale_event_id);
+        }
+        Ok(volume_id)
+    }
+
+    /// Waits for a volume to leave creating, deleting it once volume_create_timeout_secs
+    /// passes so a stuck volume does not leak
+    ///
+    /// A state that cannot be read is checked again until the timeout, as the volume
+    /// exists either way.
+    fn wait_until_created(&mut self, volume_id: &str) -> Result<(), Box<dyn Error>> {
+        let timeout = Duration::from_secs(self.config.volume_create_timeout_secs);
+        let started = self.clock.now();
+        loop {
+            match self.aws.volume_state(volume_id.to_string()) {
+                Ok(state) if state != "creating" => return Ok(()),
+                Ok(_) => {}
+                Err(e) => warn!("Could not check whether {} is created - {}", volume_id, e),
+            }
+            if self.clock.now().duration_since(started) >= timeout {
+                warn!("{} is stuck creating - deleting it", volume_id);
+                let params = serde_json::json!({ "volume_id": volume_id });
+                let deleted = self.aws.delete_ebs_volume(volume_id.to_string());
+                self.audit("delete_volume", params, &deleted);
+                if let Err(e) = deleted {
+                    error!("Could not delete stuck volume {} - {}", volume_id, e);
+                }
+                return Err(Box::new(VolumeCreateTimeoutError {
+                    volume_id: volume_id.to_string(),
+                    timeout,
+                }));
+            }
+            self.clock.sleep(VOLUME_CREATE_POLL_INTERVAL);
+        }
+    }
+
+    /// Tags a created volume with the id shared by the resources of its scale event,
+    /// only warning on failure as the volume is still usable
+    fn tag_scale_event(&mut self, volume_id: &str, scale_event_id: &str) {
+        let tagged = self.aws.tag_volume(
+            volume_id.to_string(),
+            aws::SCALE_EVENT_ID_TAG.to_string(),
+            scale_event_id.to_string(),
+        );
+        if let Err(e) = tagged {
+            warn!(
+                "Could not tag {} with {} {} - {}",
+                volume_id, aws::SCALE_EVENT_ID_TAG, scale_event_id, e
+            );
+        }
+    }
+
+    /// Attaches a volume at dev, returning the device AWS attached it as
+    fn attach_volume(&mut self, volume_id: String, dev: String) -> Result<String, Box<dyn Error>> {
+        let params = serde_json::json!({ "volume_id": volume_id, "device": dev });
+        let attached = self.aws.attach_ebs_volume(volume_id, dev);
+        self.audit("attach_volume", params, &attached);
+        Ok(attached?)
+    }
+
+    /// Grows the filesystem onto a newly attached device
+    fn expand_filesystem(&mut self, device: String) -> Result<bool, Box<dyn Error>> {
+        let params = serde_json::json!({ "device": device, "mountpoint": self.config.mountpoint });
+        let started = self.clock.now();
+        let expanded = self.fs.expand_volume(device);
+        self.record_fs_grow_duration(started);
+        self.audit("expand_filesystem", params, &expanded);
+        expanded
+    }
+
+    /// Keeps how long the filesystem grow begun at started took, for the status and
+    /// ebs_autoscale_fs_grow_duration_seconds
+    fn record_fs_grow_duration(&self, started: Instant) {
+        let secs = self.clock.now().duration_since(started).as_secs_f64();
+        let fs_type = self.config.fs_type.clone();
+        info!("Growing the {} filesystem on {} took {:.1}s", fs_type, self.config.mountpoint, secs);
+        self.status.update(|snapshot| {
+            snapshot.fs_grow_duration_seconds.insert(fs_type, secs);
+        });
+    }
+
+    /// Grows the filesystem onto a volume added by a scale-up, applying on_expand_failure
+    /// when it cannot be; cur_size is the logical volume size before the volume
+    fn expand_onto(
+        &mut self, added: &AddedVolume, cur_size: SizeGib,
+    ) -> Result<(), Box<dyn Error>> {
+        let err = match self.expand_filesystem(added.device.clone()) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        // Once the device joined the filesystem, removing it would lose data
+        let joined = !err.is::<fs::DeviceNotAddedError>();
+        match self.config.on_expand_failure {
+            config::OnExpandFailure::DetachAndDelete if joined => {
+                error!(
+                    "CRITICAL: could not grow {} onto {} ({}) - it is already part of the \
+                     filesystem, leaving it attached - {}",
+                    self.config.mountpoint, added.volume_id, added.device, err
+                );
+                self.publish_event(event::EventKind::ExpandFailed, Some(added), cur_size);
+            }
+            config::OnExpandFailure::LeaveAndAlert => {
+                error!(
+                    "CRITICAL: could not grow {} onto {} ({}) - leaving it attached - {}",
+                    self.config.mountpoint, added.volume_id, added.device, err
+                );
+                self.publish_event(event::EventKind::ExpandFailed, Some(added), cur_size);
+            }
+            config::OnExpandFailure::DetachAndDelete => {
+                warn!(
+                    "Could not grow {} onto {} ({}) - detaching and deleting it - {}",
+                    self.config.mountpoint, added.volume_id, added.device, err
+                );
+                match self.remove_volume(added.volume_id.clone(), added.device.clone()) {
+                    Ok(()) => {
+                        if let Some(count) = self.attached_volume_count.as_mut() {
+                            *count = count.saturating_sub(1);
+                        }
+                    }
+                    Err(e) => error!(
+                        "CRITICAL: could not remove {} after the failed expansion - {}",
+                        added.volume_id, e
+                    ),
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// Detaches and deletes a volume attached as device
+    fn remove_volume(&mut self, volume_id: String, device: String) -> Result<(), Box<dyn Error>> {
+        let params = serde_json::json!({ "volume_id": volume_id });
+        let detached = self.aws.detach_ebs_volume(volume_id.clone());
+        self.audit("detach_volume", params.clone(), &detached);
+        detached?;
+        self.diskmgr.release_device(device);
+        let deleted = self.aws.delete_ebs_volume(volume_id);
+        self.audit("delete_volume", params, &deleted);
+        Ok(deleted?)
+    }
+
+    /// Appends the outcome of a mutating action to the audit log, if enabled, and
+    /// counts it for the exit report if it succeeded
+    fn audit<T: Serialize, E: fmt::Display>(
+        &self, action: &str, params: serde_json::Value, result: &Result<T, E>,
+    ) {
+        if result.is_ok() {
+            self.status.update(|snapshot| {
+                *snapshot.actions.entry(action.to_string()).or_default() += 1;
+            });
+        }
+        if let Some(log) = &self.audit_log {
+            log.record(action, params, result);
+        }
+    }
+
+    /// Waits for the device of an attached volume and marks it for deletion on termination
+    fn incorporate_volume(
+        &mut self, volume_id: String, dev: String, size: SizeGib,
+    ) -> Result<AddedVolume, Box<dyn Error>> {
+        self.wait_for_device(&dev, &volume_id)?;
+        self.aws.set_delete_on_termination(dev.clone(), self.config.ensure_ebs_deleted_on_term)?;
+        self.tag_delete_on_term(&volume_id, None)?;
+        if let Some(count) = self.attached_volume_count.as_mut() {
+            *count += 1;
+        }
+        Ok(AddedVolume {
+            volume_id,
+            device: dev,
+            size_gib: size,
+        })
+    }
+
+    /// Tags an attached volume for deletion on termination, unless the describe data
+    /// already fetched for it shows the tag
+    fn tag_delete_on_term(
+        &mut self, volume_id: &str, existing: Option<&aws::ManagedVolume>,
+    ) -> Result<(), Box<dyn Error>> {
+        if existing.is_some_and(|volume| volume.is_tagged_delete_on_term()) {
+            return Ok(());
+        }
+        Ok(self.aws.tag_volume(
+            volume_id.to_string(),
+            aws::DELETE_ON_TERM_TAG.to_string(),
+            aws::DELETE_ON_TERM_TAG_VALUE.to_string(),
+        )?)
+    }
+
+    /// Whether the mountpoint has no filesystem yet and bootstrapping is enabled
+    pub fn needs_bootstrap(&mut self) -> bool {
+        self.config.bootstrap_if_missing
+            && !self.config.observe_only
+            && self.diskmgr.disk_size(self.config.mountpoint.clone()).is_err()
+    }
+
+    /// Creates, formats and mounts the first volume when nothing is mounted at the mountpoint
+    pub fn bootstrap_if_missing(&mut self) -> Result<Option<AddedVolume>, Box<dyn Error>> {
+        if !self.needs_bootstrap() {
+            return Ok(None);
+        }
+        let size = self.calc_new_size(0, SizeGib::ZERO);
+        info!(
+            "Nothing mounted at {} - bootstrapping a {} {} filesystem",
+            self.config.mountpoint, size, self.config.fs_type
+        );
+        let managed_volumes = self.own_volumes()?;
+        let spec = self.volume_spec(0)?;
+        self.check_monthly_cost(&managed_volumes, &[(spec, size)])?;
+        let added = self.create_and_attach_volume(size, None)?;
+        if let Err(err) = self.fs.create_filesystem(added.device.clone()) {
+            let removed = self.discard_attached(&added.volume_id, added.device.clone());
+            if let Some(count) = self.attached_volume_count.as_mut().filter(|_| removed) {
+                *count = count.saturating_sub(1);
+            }
+            return Err(err);
+        }
+        if self.config.persist_mounts {
+            // The filesystem is already mounted, so the volume stays attached
+            if let Err(err) = self.fs.persist_mount(added.device.clone()) {
+                error!(
+                    "CRITICAL: {} ({}) is mounted at {} but could not be persisted - {}",
+                    added.volume_id, added.device, self.config.mountpoint, err
+                );
+                return Err(err);
+            }
+        }
+        self.publish_event(event::EventKind::ScaleUp, Some(&added), added.size_gib);
+        Ok(Some(added))
+    }
+
+    /// Alerts on managed volumes of this instance detached out-of-band, re-attaching
+    /// them if configured
+    ///
+    /// Returns the ids of the detached volumes.
+    fn reconcile_attachments(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let instance_id = match self.own_instance_id() {
+            Some(instance_id) => instance_id,
+            None => return Ok(Vec::new()),
+        };
+        let detached: Vec<aws::ManagedVolume> = self.aws.get_managed_ebs_volumes()?
+            .into_iter()
+            .filter(|volume| volume.state == "available" && volume.belongs_to(&instance_id))
+            .collect();
+        for volume in &detached {
+            error!(
+                "CRITICAL: managed volume {} is detached - logical volume on {} is degraded",
+                volume.volume_id,
+                self.config.mountpoint
+            );
+            if self.config.reattach_detached_volumes {
+                let dev = self.diskmgr.get_next_logical_device()?;
+                info!("Re-attaching volume {} as {}", volume.volume_id, dev);
+                let dev = self.attach_volume(volume.volume_id.clone(), dev)?;
+                self.aws.set_delete_on_termination(
+                    dev.clone(), self.config.ensure_ebs_deleted_on_term,
+                )?;
+                self.tag_delete_on_term(&volume.volume_id, Some(volume))?;
+            }
+        }
+        Ok(detached.into_iter().map(|volume| volume.volume_id).collect())
+    }
+
+    /// Publishes an event to the SNS topic, if configured
+    ///
+    /// Failures are only logged, publishing never blocks scaling.
+    fn publish_event(
+        &mut self,
+        kind: event::EventKind,
+        volume: Option<&AddedVolume>,
+        total_size_gib: SizeGib,
+    ) {
+        let autoscale_event = event::AutoscaleEvent {
+            event: kind,
+            instance_id: self.instance_id.clone(),
+            mountpoint: self.config.mountpoint.clone(),
+            volume_id: volume.map(|volume| volume.volume_id.clone()),
+            size_gib: volume.map(|volume| volume.size_gib),
+            total_size_gib,
+            utilization_percent: None,
+        };
+        self.send_event(autoscale_event);
+    }
+
+    fn send_event(&mut self, autoscale_event: event::AutoscaleEvent) {
+        #[cfg(feature = "journald")]
+        journal::send_event(&autoscale_event);
+        if let Err(e) = self.notifier.notify(autoscale_event.clone()) {
+            warn!("Could not notify {:?} - {}", autoscale_event.event, e);
+        }
+        let topic_arn = match &self.config.sns_topic_arn {
+            Some(topic_arn) => topic_arn.clone(),
+            None => return,
+        };
+        let kind = autoscale_event.event;
+        let message = match serde_json::to_string(&autoscale_event) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Could not serialize event {:?} - {}", autoscale_event, e);
+                return;
+            }
+        };
+        if let Err(e) = self.aws.publish_event(topic_arn, message) {
+            warn!("Could not publish event {:?} - {}", kind, e);
+        }
+    }
+
+    /// Refuses a volume that would take the logical volume into the configured headroom
+    fn check_headroom(
+        &self, cur_size: SizeGib, new_size: SizeGib,
+    ) -> Result<(), LogicalSizeHeadroomReachedError> {
+        let limits = &self.config.limits;
+        if limits.logical_size_headroom_gb == SizeGib::ZERO {
+            return Ok(());
+        }
+        let soft_ceiling_gib = limits.max_logical_volume_size
+            .saturating_sub(limits.logical_size_headroom_gb);
+        let projected_gib = cur_size.saturating_add(new_size);
+        if projected_gib > soft_ceiling_gib {
+            return Err(LogicalSizeHeadroomReachedError { projected_gib, soft_ceiling_gib });
+        }
+        Ok(())
+    }
+
+    /// Name tag for the next volume, from name_tag_template
+    fn volume_name(&mut self, pending: u32) -> Result<Option<String>, Box<dyn Error>> {
+        let template = match &self.config.name_tag_template {
+            Some(template) => template.clone(),
+            None => return Ok(None),
+        };
+        let values = [
+            ("instance_id", self.instance_id.clone().unwrap_or_else(|| "unknown".to_string())),
+            ("mountpoint", self.config.mountpoint.clone()),
+            ("seq", (self.mounted_volume_count()? + pending + 1).to_string()),
+            ("timestamp", self.clock.unix_secs().to_string()),
+        ];
+        Ok(Some(config::expand_name_tag(&template, &values)?))
+    }
+
+    /// Waits for the block device of an attached volume to appear on the host, and to
+    /// be that volume when the device reports a volume id
+    ///
+    /// Without a reported volume id (xen), the device AWS reported for the attachment
+    /// is trusted.
+    fn wait_for_device(&mut self, dev: &str, volume_id: &str) -> Result<(), Box<dyn Error>> {
+        let timeout = Duration::from_secs(self.config.device_appearance_timeout_secs);
+        let started = self.clock.now();
+        let mut mismatches = 0;
+        loop {
+            if self.diskmgr.device_exists(dev.to_string()) {
+                match self.diskmgr.device_volume_id(dev.to_string()) {
+                    Some(found) if found != volume_id => {
+                        mismatches += 1;
+                        if mismatches > self.config.device_mismatch_retries {
+                            return Err(Box::new(AttachedDeviceMismatchError {
+                                device: dev.to_string(),
+                                volume_id: volume_id.to_string(),
+                                found,
+                            }));
+                        }
+                        warn!("{} is {} rather than {} - checking again", dev, found, volume_id);
+                    }
+                    _ => return Ok(()),
+                }
+            } else if self.clock.now().duration_since(started) >= timeout {
+                return Err(Box::new(DeviceAppearanceTimeoutError {
+                    device: dev.to_string(),
+                    timeout,
+                }));
+            }
+            self.clock.sleep(DEVICE_POLL_INTERVAL);
+        }
+    }
+
+    /// Gives the new device time to become visible to the filesystem tools
+    fn settle_after_attach(&self) {
+        if self.config.post_attach_settle_ms > 0 {
+            self.clock.sleep(Duration::from_millis(self.config.post_attach_settle_ms));
+        }
+    }
+
+    /// Refuses to scale if the new volumes would push the managed volumes over the cost
+    /// cap, each new volume priced with the spec it will be created with
+    fn check_monthly_cost(
+        &self,
+        volumes: &[aws::ManagedVolume],
+        new_volumes: &[(config::Volume, SizeGib)],
+    ) -> Result<(), Box<dyn Error>> {
+        let max_cost = match self.config.limits.max_monthly_cost_usd {
+            Some(max_cost) => max_cost,
+            None => return Ok(()),
+        };
+        let mut projected_cost = monthly_cost_of(volumes)?;
+        for (volume, size) in new_volumes {
+            projected_cost +=
+                cost::monthly_cost(&volume.vol_type, *size, volume.iops, volume.throughput)?;
+        }
+        if projected_cost > max_cost {
+            return Err(Box::new(MaxMonthlyCostExceededError {
+                projected_cost_usd: projected_cost,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Refuses to scale when the new volumes would take the account close to its storage
+    /// quota, each counted against the quota of the type it will be created with
+    fn check_account_quota(
+        &mut self, new_volumes: &[(config::Volume, SizeGib)],
+    ) -> Result<(), Box<dyn Error>> {
+        let quota_gib = match self.config.limits.account_storage_quota_gib {
+            Some(quota_gib) => quota_gib,
+            None => return Ok(()),
+        };
+        let mut requested = BTreeMap::new();
+        for (volume, size) in new_volumes {
+            let total = requested.entry(volume.vol_type.clone()).or_insert(SizeGib::ZERO);
+            *total = checked_size_add(*total, *size)?;
+        }
+        let max_percent = u64::from(self.config.limits.account_quota_max_percent);
+        let max_gib = quota_gib.percent(max_percent);
+        for (vol_type, requested_gib) in requested {
+            let used_gib = self.aws.account_storage_gib(vol_type.clone())?;
+            let projected_gib = checked_size_add(used_gib, requested_gib)?;
+            if projected_gib > max_gib {
+                return Err(Box::new(AccountQuotaNearlyExhaustedError {
+                    vol_type,
+                    used_gib,
+                    requested_gib,
+                    quota_gib,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Room left to grow under each configured limit, without changing anything
+    pub fn remaining_capacity(&mut self) -> Result<RemainingCapacity, Box<dyn Error>> {
+        let limits = self.config.limits.clone();
+        let cur_size = self.diskmgr.disk_size(self.config.mountpoint.clone())?;
+        let soft_ceiling_gib = limits.max_logical_volume_size
+            .saturating_sub(limits.logical_size_headroom_gb);
+        let mut by_size_gib = soft_ceiling_gib.saturating_sub(cur_size);
+        if let Some(quota_gib) = limits.account_storage_quota_gib {
+            let used_gib = self.aws.account_storage_gib(self.config.volume.vol_type.clone())?;
+            let max_gib = quota_gib.percent(limits.account_quota_max_percent.into());
+            by_size_gib = by_size_gib.min(max_gib.saturating_sub(used_gib));
+        }
+        let by_count = limits.max_ebs_volume_count.saturating_sub(self.mounted_volume_count()?);
+        let by_cost = match limits.max_monthly_cost_usd {
+            Some(max_cost) => {
+                let volumes = self.own_volumes()?;
+                Some((max_cost - monthly_cost_of(&volumes)?).max(0.0))
+            }
+            None => None,
+        };
+        Ok(RemainingCapacity { by_size_gib, by_count, by_cost })
+    }
+
+    /// Captures the state needed to resume managing the filesystem on another instance
+    pub fn export_state(&mut self) -> Result<migrate::DaemonState, Box<dyn Error>> {
+        let managed_volumes = self.own_volumes()?;
+        Ok(migrate::DaemonState {
+            version: migrate::STATE_VERSION,
+            mountpoint: self.config.mountpoint.clone(),
+            managed_volumes,
+            volume_count: self.mounted_volume_count()?,
+            volume_modifications: history::VolumeModifications::new(&self.config.state_dir)
+                .load()?,
+            rebalance_pending: fs::RebalanceState::new(&self.config.state_dir).pending(),
+        })
+    }
+
+    /// Restores an exported state into the state dir, replacing the local one
+    pub fn import_state(&mut self, state: &migrate::DaemonState) -> Result<(), Box<dyn Error>> {
+        migrate::check_version(state.version)?;
+        if state.mountpoint != self.config.mountpoint {
+            warn!("Importing the state of {} for {}", state.mountpoint, self.config.mountpoint);
+        }
+        let known: Vec<String> = self.aws.get_managed_ebs_volumes()?
+            .into_iter()
+            .map(|volume| volume.volume_id)
+            .collect();
+        for volume in &state.managed_volumes {
+            if !known.contains(&volume.volume_id) {
+                warn!("Volume {} of the imported state is not a managed volume", volume.volume_id);
+            }
+        }
+        history::VolumeModifications::new(&self.config.state_dir)
+            .save(&state.volume_modifications)?;
+        let rebalance = fs::RebalanceState::new(&self.config.state_dir);
+        match state.rebalance_pending {
+            Some(_) => rebalance.mark_pending(&self.config.mountpoint)?,
+            None => rebalance.clear()?,
+        }
+        info!(
+            "Imported the state of {} with {} managed volumes",
+            state.mountpoint, state.managed_volumes.len()
+        );
+        Ok(())
+    }
+
+    /// Describes what a tick would see and do, without scaling or attaching anything
+    pub fn report(&mut self) -> Result<ReconcileReport, Box<dyn Error>> {
+        let mounted_volume_count = self.mounted_volume_count()?;
+        let threshold_percent = self.calc_threshold(mounted_volume_count).unwrap();
+        let utilization_percent = self.diskmgr
+            .disk_usage_percent(self.config.mountpoint.clone())
+            .ok();
+        let logical_size_gb = self.diskmgr.disk_size(self.config.mountpoint.clone()).ok();
+        let managed_volumes = self.own_volumes()?;
+        let limits = &self.config.limits;
+        let next_action = if self.config.observe_only {
+            NextAction::ObserveOnly
+        } else if self.is_paused() {
+            NextAction::Paused
+        } else if self.quiesced {
+            NextAction::Quiesced
+        } else {
+            match (utilization_percent, logical_size_gb) {
+                (Some(utilization), Some(size)) if utilization >= threshold_percent => {
+                    if mounted_volume_count >= limits.max_ebs_volume_count {
+                        NextAction::Blocked { reason: MaxEBSCountExceededError.to_string() }
+                    } else if size >= limits.max_logical_volume_size {
+                        NextAction::Blocked {
+                            reason: MaxLogicalVolumeSizeExceededError.to_string(),
+                        }
+                    } else {
+                        let size_gib = self.calc_new_size(mounted_volume_count, size);
+                        match self.check_headroom(size, size_gib) {
+                            Ok(()) => NextAction::AddVolume {
+                                size_gib,
+                                vol_type: self.config.volume_for(mounted_volume_count).vol_type,
+                            },
+                            Err(e) => NextAction::Blocked { reason: e.to_string() },
+                        }
+                    }
+                }
+                (Some(_), Some(_)) => NextAction::None,
+                _ => NextAction::Blocked {
+                    reason: format!("Nothing mounted at {}", self.config.mountpoint),
+                },
+            }
+        };
+        if self.encryption_by_default.is_none() {
+            self.encryption_by_default = self.aws.ebs_encryption_by_default().ok();
+        }
+        Ok(ReconcileReport {
+            encrypted: self.effective_encryption(),
+            mountpoint: self.config.mountpoint.clone(),
+            utilization_percent,
+            threshold_percent,
+            mounted_volume_count,
+            managed_volumes,
+            logical_size_gb,
+            limits: self.config.limits.clone(),
+            next_action,
+        })
+    }
+
+    /// Next scale-ups if utilization stays high, at most steps of them
+    ///
+    /// Stops before the first step add_more_space would refuse for the volume count,
+    /// logical size or headroom limits, and is empty when the volume count cannot be
+    /// read. Nothing is created or attached.
+    pub fn plan(&mut self, steps: u32) -> Vec<PlannedStep> {
+        let limits = self.config.limits.clone();
+        let Ok(mounted) = self.mounted_volume_count() else {
+            return Vec::new();
+        };
+        let mut total_size_gib = self.diskmgr.disk_size(self.config.mountpoint.clone())
+            .unwrap_or_default();
+        let mut plan = Vec::new();
+        for volume_count in (mounted..).take(steps as usize) {
+            if volume_count >= limits.max_ebs_volume_count
+                || total_size_gib >= limits.max_logical_volume_size
+            {
+                break;
+            }
+            let size_gib = self.calc_new_size(volume_count, total_size_gib);
+            if self.check_headroom(total_size_gib, size_gib).is_err() {
+                break;
+            }
+            total_size_gib = match checked_size_add(total_size_gib, size_gib) {
+                Ok(total) => total,
+                Err(_) => break,
+            };
+            plan.push(PlannedStep { volume_count: volume_count + 1, size_gib, total_size_gib });
+        }
+        plan
+    }
+
+    /// Reconciles once, recording each gate evaluated on the way with its values
+    pub fn reconcile_traced(
+        &mut self,
+    ) -> (Result<ReconcileOutcome, Box<dyn Error>>, trace::DecisionTrace) {
+        self.trace = Some(trace::DecisionTrace::default());
+        let outcome = self.reconcile();
+        let mut trace = self.trace.take().unwrap_or_default();
+        trace.outcome = Some(match &outcome {
+            Ok(outcome) => outcome.name(),
+            Err(e) => format!("Error: {}", e),
+        });
+        (outcome, trace)
+    }
+
+    /// Records a gate when tracing the reconcile in progress
+    fn trace_gate(&mut self, name: &'static str, detail: String, passed: bool) {
+        if let Some(trace) = &mut self.trace {
+            trace.record(name, detail, passed);
+        }
+    }
+
+    /// Records the result of a limit check as a gate, passing it through
+    fn traced<E: Into<Box<dyn Error>>>(
+        &mut self, name: &'static str, result: Result<(), E>,
+    ) -> Result<(), Box<dyn Error>> {
+        let result = result.map_err(Into::into);
+        let detail = match &result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.trace_gate(name, detail, result.is_ok());
+        result
+    }
+
+    /// Runs a single autoscaling tick: checks utilization and adds space if needed
+    pub fn reconcile(&mut self) -> Result<ReconcileOutcome, Box<dyn Error>> {
+        let outcome = self.run_reconcile();
+        let paused = self.is_paused();
+        let last_outcome = match &outcome {
+            Ok(outcome) => outcome.name(),
+            Err(e) => format!("Error: {}", e),
+        };
+        let skip_reason = match &outcome {
+            Ok(ReconcileOutcome::NoActionNeeded(reason)) => Some(*reason),
+            _ => None,
+        };
+        if outcome.is_ok() {
+            self.consecutive_reconcile_errors = 0;
+            self.status.set_reconciled(true);
+        } else {
+            self.consecutive_reconcile_errors += 1;
+            if self.consecutive_reconcile_errors >= MAX_CONSECUTIVE_RECONCILE_ERRORS {
+                self.status.set_reconciled(false);
+            }
+        }
+        self.status.update(|snapshot| {
+            snapshot.paused = paused;
+            snapshot.last_outcome = Some(last_outcome);
+            snapshot.skip_reason = skip_reason;
+        });
+        if let Err(e) = self.record_utilization() {
+            warn!("Could not write utilization log - {}", e);
+        }
+        if let Err(e) = self.update_status_tag(&outcome) {
+            warn!("Could not update status tag - {}", e);
+        }
+        if let Err(e) = self.check_utilization_alert() {
+            warn!("Could not check utilization alert - {}", e);
+        }
+        if let Err(e) = self.check_io2_promotion() {
+            warn!("Could not check io2 promotion - {}", e);
+        }
+        let scaling_failed = matches!(
+            outcome,
+            Err(_)
+                | Ok(ReconcileOutcome::Quiesced)
+                | Ok(ReconcileOutcome::MaxSizeReached)
+                | Ok(ReconcileOutcome::HeadroomReached)
+                | Ok(ReconcileOutcome::Exit)
+        );
+        if let Err(e) = self.check_panic_reserve(scaling_failed) {
+            warn!("Could not check the panic reserve - {}", e);
+        }
+        let volume_count = self.mounted_volume_count().ok();
+        let total_size_gib = self.diskmgr.disk_size(self.config.mountpoint.clone()).ok();
+        self.status.update(|snapshot| {
+            snapshot.volume_count = volume_count;
+            snapshot.total_size_gib = total_size_gib;
+        });
+        // Computing it lists every volume of the account, so only when it may have moved;
+        // a failed tick may still have added volumes
+        let volumes_changed = matches!(outcome, Ok(ReconcileOutcome::ScaledUp) | Err(_));
+        if self.remaining_capacity_stale || volumes_changed {
+            match self.remaining_capacity() {
+                Ok(capacity) => {
+                    self.remaining_capacity_stale = false;
+                    self.status.update(|snapshot| {
+                        snapshot.remaining_capacity = Some(capacity);
+                    });
+                }
+                Err(e) => warn!("Could not compute the remaining capacity - {}", e),
+            }
+        }
+        let interval = self.detection_interval();
+        if interval > self.polling_interval() {
+            warn!("AWS calls are throttled - checking every {}s", interval.as_secs());
+        }
+        self.status.update(|snapshot| snapshot.effective_interval_secs = Some(interval.as_secs()));
+        if let Some(statsd) = &self.statsd {
+            statsd.send(&self.status.snapshot());
+        }
+        outcome
+    }
+
+    /// Time until the next tick from polling_strategy, before throttling backoff
+    pub fn polling_interval(&self) -> Duration {
+        let threshold = self.calc_threshold(self.attached_volume_count.unwrap_or_default());
+        self.config.polling_strategy.interval(
+            Duration::from_secs(self.config.detection_interval.into()),
+            self.last_utilization_sample.map(|(_, utilization)| utilization),
+            threshold.unwrap(),
+        )
+    }
+
+    /// Time until the next tick, lengthened while AWS calls are throttled
+    pub fn detection_interval(&self) -> Duration {
+        backoff_interval(
+            self.polling_interval(),
+            self.aws.consecutive_throttles(),
+            Duration::from_secs(self.config.max_detection_interval_secs),
+        )
+    }
+
+    /// Tags the instance with the outcome, when it changed and the last update is old enough
+    ///
+    /// Never in observe-only mode, which changes nothing in AWS.
+    fn update_status_tag(
+        &mut self, outcome: &Result<ReconcileOutcome, Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (key, instance_id) = match (&self.config.status_tag_key, &self.instance_id) {
+            (Some(key), Some(instance_id)) if !self.config.observe_only => {
+                (key.clone(), instance_id.clone())
+            }
+            _ => return Ok(()),
+        };
+        let now = self.clock.now();
+        let interval = Duration::from_secs(self.config.status_tag_interval_secs);
+        if let Some((_, updated_at)) = &self.last_status_tag {
+            if now.duration_since(*updated_at) < interval {
+                return Ok(());
+            }
+        }
+        let value = status_tag_value(
+            outcome,
+            self.mounted_volume_count()?,
+            self.diskmgr.disk_size(self.config.mountpoint.clone()).unwrap_or_default(),
+        );
+        if matches!(&self.last_status_tag, Some((last_value, _)) if last_value == &value) {
+            return Ok(());
+        }
+        self.aws.tag_instance(instance_id, key, value.clone())?;
+        self.last_status_tag = Some((value, now));
+        Ok(())
+    }
+
+    /// Alerts once utilization stays at or above alert_utilization_threshold for
+    /// alert_utilization_duration_secs, and again when it drops back below
+    fn check_utilization_alert(&mut self) -> Result<(), Box<dyn Error>> {
+        let threshold = match self.config.alert_utilization_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let utilization = self.diskmgr.disk_usage_percent(self.config.mountpoint.clone())?;
+        let now = self.clock.now();
+        if utilization < threshold {
+            self.high_utilization_since = None;
+            if self.utilization_alerted {
+                info!(
+                    "Utilization on {} is back below {}% ({}%)",
+                    self.config.mountpoint, threshold, utilization
+                );
+                self.utilization_alerted = false;
+                self.send_utilization_event(event::EventKind::HighUtilizationCleared, utilization);
+            }
+        } else {
+            let since = *self.high_utilization_since.get_or_insert(now);
+            let duration = Duration::from_secs(self.config.alert_utilization_duration_secs);
+            if !self.utilization_alerted && now.duration_since(since) >= duration {
+                error!(
+                    "CRITICAL: utilization on {} has been at or above {}% for {}s ({}%)",
+                    self.config.mountpoint, threshold, duration.as_secs(), utilization
+                );
+                self.utilization_alerted = true;
+                self.send_utilization_event(event::EventKind::HighUtilization, utilization);
+            }
+        }
+        let alerted = self.utilization_alerted;
+        self.status.update(|snapshot| snapshot.high_utilization_alert = alerted);
+        Ok(())
+    }
+
+    fn panic_reserve(&self) -> Option<fs::PanicReserve> {
+        self.config.panic_reserve_path.as_ref().map(|path| fs::PanicReserve {
+            path: path.into(),
+            size_bytes: self.config.panic_reserve_size_mib.saturating_mul(1024 * 1024),
+        })
+    }
+
+    /// Creates the panic reserve file, if configured
+    pub fn allocate_panic_reserve(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(reserve) = self.panic_reserve() {
+            reserve.allocate()?;
+            info!(
+                "Reserved {} MiB in {}",
+                self.config.panic_reserve_size_mib, reserve.path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Deletes the panic reserve once, when the disk is critically full and scaling failed
+    fn check_panic_reserve(&mut self, scaling_failed: bool) -> Result<(), Box<dyn Error>> {
+        let reserve = match self.panic_reserve() {
+            Some(reserve) if scaling_failed && !self.panic_reserve_released => reserve,
+            _ => return Ok(()),
+        };
+        let utilization = self.diskmgr.disk_usage_percent(self.config.mountpoint.clone())?;
+        if utilization < self.config.panic_reserve_threshold_percent {
+            return Ok(());
+        }
+        reserve.release()?;
+        self.panic_reserve_released = true;
+        error!(
+            "CRITICAL: {} is {}% full and could not be scaled - deleted the panic reserve {}",
+            self.config.mountpoint, utilization, reserve.path.display()
+        );
+        self.send_utilization_event(event::EventKind::PanicReserveReleased, utilization);
+        Ok(())
+    }
+
+    fn send_utilization_event(&mut self, kind: event::EventKind, utilization: u32) {
+        let autoscale_event = event::AutoscaleEvent {
+            event: kind,
+            instance_id: self.instance_id.clone(),
+            mountpoint: self.config.mountpoint.clone(),
+            volume_id: None,
+            size_gib: None,
+            total_size_gib: self.diskmgr.disk_size(self.config.mountpoint.clone())
+                .unwrap_or_default(),
+            utilization_percent: Some(utilization),
+        };
+        self.send_event(autoscale_event);
+    }
+
+    /// Keeps utilization fresh in status, without evaluating the threshold
+    fn observe_utilization(&mut self) -> Result<(), Box<dyn Error>> {
+        self.diskmgr.save_disk_list()?;
+        let utilization = self.diskmgr.disk_usage_percent(self.config.mountpoint.clone())?;
+        self.status.update(|snapshot| snapshot.utilization_percent = Some(utilization));
+        Ok(())
+    }
+
+    /// Appends the utilization seen this tick to the utilization log, if enabled
+    fn record_utilization(&mut self) -> Result<(), Box<dyn Error>> {
+        let utilization_percent = match self.status.snapshot().utilization_percent {
+            Some(utilization) if self.utilization_log.is_some() => utilization,
+            _ => return Ok(()),
+        };
+        let record = history::UtilizationRecord {
+            timestamp: self.clock.unix_secs(),
+            utilization_percent,
+            volume_count: self.mounted_volume_count()?,
+            logical_size_gb: self.diskmgr.disk_size(self.config.mountpoint.clone())?.as_u64(),
+        };
+        if let Some(log) = &self.utilization_log {
+            log.append(&record)?;
+        }
+        Ok(())
+    }
+
+    fn run_reconcile(&mut self) -> Result<ReconcileOutcome, Box<dyn Error>> {
+        let draining = self.drain.as_ref().is_some_and(drain::Drain::is_requested);
+        self.trace_gate("drain", format!("shutdown requested {}", draining), !draining);
+        if draining {
+            return Ok(ReconcileOutcome::NoActionNeeded(SkipReason::Draining));
+        }
+        let observe_only = self.config.observe_only;
+        self.trace_gate("observe_only", format!("observe_only {}", observe_only), !observe_only);
+        if observe_only {
+            self.observe_utilization()?;
+            return Ok(ReconcileOutcome::Observed);
+        }
+        let paused = self.is_paused();
+        self.trace_gate("paused", format!("paused {}", paused), !paused);
+        if paused {
+            self.observe_utilization()?;
+            return Ok(ReconcileOutcome::NoActionNeeded(SkipReason::Paused));
+        }
+        let quiesced = self.quiesced;
+        self.trace_gate("quiesced", format!("quiesced {}", quiesced), !quiesced);
+        if quiesced {
+            return Ok(ReconcileOutcome::Quiesced);
+        }
+        self.reconcile_attachments()?;
+        if !self.need_more_space()? {
+            return Ok(ReconcileOutcome::NoActionNeeded(SkipReason::BelowThreshold));
+        }
+        let dev_count = self.mounted_volume_count()?;
+        let concurrent = self.config.concurrent_volume_creation
+            && self.config.scale_until_below_threshold;
+        let first = if concurrent {
+            self.add_more_space_concurrently(dev_count).map(|added| added.len())
+        } else {
+            self.add_more_space(dev_count).map(|_| 1)
+        };
+        let mut added: u32 = match first {
+            Ok(added) => added.try_into()?,
+            Err(e) if e.is::<MaxLogicalVolumeSizeExceededError>() => {
+                return self.on_max_size_reached(e);
+            }
+            Err(e) if e.is::<LogicalSizeHeadroomReachedError>() => {
+                info!("{} - leaving the headroom for manual intervention", e);
+                return Ok(ReconcileOutcome::HeadroomReached);
+            }
+            Err(e) if e.is::<ScaleNoLongerNeededError>() => {
+                info!("{} - not adding a volume", e);
+                return Ok(ReconcileOutcome::NoLongerNeeded);
+            }
+            Err(e) => return Err(e),
+        };
+        let budget = self.config.max_volumes_per_tick;
+        // A concurrent batch is already sized to drop below the threshold
+        while self.config.scale_until_below_threshold && !concurrent {
+            if budget > 0 && added >= budget {
+                info!("Created {} volumes this tick - deferring any more to the next tick", added);
+                break;
+            }
+            if !self.need_more_space()? {
+                break;
+            }
+            if let Err(e) = self.add_more_space(dev_count + added) {
+                warn!("Stopped scaling after {} volumes - {}", added, e);
+                break;
+            }
+            added += 1;
+        }
+        Ok(ReconcileOutcome::ScaledUp)
+    }
+
+    fn on_max_size_reached(
+        &mut self,
+        err: Box<dyn Error>
+    ) -> Result<ReconcileOutcome, Box<dyn Error>> {
+        if !self.limit_event_sent {
+            let total_size = self.diskmgr.disk_size(self.config.mountpoint.clone())
+                .unwrap_or_default();
+            self.publish_event(event::EventKind::LimitReached, None, total_size);
+            self.limit_event_sent = true;
+        }
+        match self.config.on_max_size {
+            config::OnMaxSize::Error => Err(err),
+            config::OnMaxSize::Quiesce => {
+                warn!("{} - no more scaling until config is reloaded", err);
+                self.quiesced = true;
+                Ok(ReconcileOutcome::Quiesced)
+            }
+            config::OnMaxSize::Exit => {
+                error!("{} - stopping ebs autoscaler", err);
+                Ok(ReconcileOutcome::Exit)
+            }
+            config::OnMaxSize::AlertAndContinue => {
+                if !self.max_size_alerted {
+                    error!("CRITICAL: {} on {}", err, self.config.mountpoint);
+                    self.max_size_alerted = true;
+                }
+                Ok(ReconcileOutcome::MaxSizeReached)
+            }
+        }
+    }
+
+    /// Utilization threshold with dev_count volumes in the logical volume
+    ///
+    /// 0 volumes is a filesystem not yet on managed volumes (e.g. before bootstrap),
+    /// which scales like the first volumes.
+    fn calc_threshold(&self, dev_count: u32) -> Option<u32> {
+        match dev_count {
+            0..=3 => Some(self.config.limits.initial_utilization_threshold),
+            4..=6 => Some(80),
+            _ => Some(90),
+        }
+    }
+
+    /// Replaces growth_strategy and the size_gib of volume tiers with predictor to size
+    /// new volumes
+    pub fn set_size_predictor(&mut self, predictor: Box<dyn predict::SizePredictor>) {
+        self.size_predictor = Some(predictor);
+    }
+
+    /// Size from growth_strategy, replaced by the size_gib of the volume tier that applies
+    fn configured_size(&self, context: &predict::SizeContext) -> SizeGib {
+        let tier = self.config.tier_for(context.volume_count);
+        if let Some(size) = tier.and_then(|tier| tier.size_gib) {
+            return size;
+        }
+        let predictor = predict::StrategySizePredictor {
+            strategy: self.config.growth_strategy,
+            max_ebs_volume_size: self.config.limits.max_ebs_volume_size,
+        };
+        predict::SizePredictor::predict(&predictor, context)
+    }
+
+    /// Size of the next volume to add
+    ///
+    /// Tiers follow https://github.com/awslabs/amazon-ebs-autoscale/blob/8c8ac28914bf9302e4f5821ba99daac6e7fc05eb/bin/ebs-autoscale#L123
+    /// The size_gib of the volume tier that applies, if any, replaces the strategy size.
+    fn calc_new_size(&self, dev_count: u32, cur_size: SizeGib) -> SizeGib {
+        let limits = &self.config.limits;
+        let context = predict::SizeContext {
+            current_size: cur_size,
+            volume_count: dev_count,
+            utilization_percent: self.last_utilization_sample.map(|(_, utilization)| utilization),
+            fill_rate: self.fill_rate,
+        };
+        let new_size = match &self.size_predictor {
+            Some(predictor) => predictor.predict(&context),
+            None => self.configured_size(&context),
+        };
+        new_size
+            .max(limits.min_ebs_volume_size)
+            .min(limits.max_ebs_volume_size)
+    }
+}
+
+/// Tags of this instance, for config::TagProvider
+pub fn instance_tags() -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    aws::instance_tags()
+}
+
+/// Runs filesystem commands, through privileged_command if set
+fn command_runner_for(conf: &config::Config) -> Box<dyn fs::CommandRunner> {
+    if conf.privileged_command.is_empty() {
+        return Box::new(fs::SystemCommandRunner);
+    }
+    Box::new(fs::PrivilegedCommandRunner {
+        helper: conf.privileged_command.clone(),
+        inner: Box::new(fs::SystemCommandRunner),
+    })
+}
+
+/// Deletes the managed volumes attached to this instance once IMDS announces its
+/// termination, giving up once timeout elapses
+///
+/// A plain stop or restart of the daemon deletes nothing: the volumes are marked
+/// DeleteOnTermination when attached, so EC2 deletes them with the instance. Returns
+/// the deleted volumes. Volumes that could not be deleted are logged. The calls wait
+/// for the limiter shared with the managers, as from_config's do.
+pub fn delete_volumes_on_termination(
+    conf: &config::Config,
+    limiter: aws::ApiLimiter,
+    timeout: Duration,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let aws_cli = aws::LimitedAWS::new(Box::new(aws::ConcreteAWS::new(conf)?), limiter);
+    delete_managed_volumes(
+        Box::new(aws_cli),
+        command_runner_for(conf),
+        conf.mountpoint.clone(),
+        conf.protected_volume_ids.clone(),
+        audit_log_for(conf),
+        timeout,
+    )
+}
+
+/// Volumes to delete on termination: only those created by this tool and attached to
+/// this instance, never a protected one
+fn volumes_to_delete(
+    volumes: Vec<aws::ManagedVolume>, instance_id: &str, protected: &[String],
+) -> Vec<String> {
+    volumes
+        .into_iter()
+        .filter(|volume| volume.is_attached_to(instance_id))
+        .filter(|volume| {
+            if !volume.is_managed() {
+                warn!(
+                    "Not deleting {} - it was not created by ebs-autoscale-rust",
+                    volume.volume_id
+                );
+                return false;
+            }
+            if protected.contains(&volume.volume_id) {
+                info!("Not deleting protected volume {}", volume.volume_id);
+                return false;
+            }
+            true
+        })
+        .map(|volume| volume.volume_id)
+        .collect()
+}
+
+enum CleanupProgress {
+    Found(Vec<String>),
+    Deleted(String, bool),
+}
+
+/// Deletes on a worker thread, so a hanging call cannot hold up termination
+///
+/// Only with a termination notice, after unmounting mountpoint and detaching each
+/// volume, as a mounted volume cannot be deleted.
+fn delete_managed_volumes(
+    mut aws_cli: Box<dyn aws::AWS>,
+    runner: Box<dyn fs::CommandRunner>,
+    mountpoint: String,
+    protected: Vec<String>,
+    audit_log: Option<audit::AuditLog>,
+    timeout: Duration,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        match aws_cli.termination_notice() {
+            Some(notice) => info!("Instance is terminating ({}) - deleting its volumes", notice),
+            None => {
+                info!("No termination notice - leaving the volumes to DeleteOnTermination");
+                return;
+            }
+        }
+        let instance_id = match aws_cli.instance_identity() {
+            Ok(identity) => identity.instance_id,
+            Err(e) => {
+                error!("Could not tell which volumes belong to this instance - {}", e);
+                return;
+            }
+        };
+        let volume_ids: Vec<String> = match aws_cli.get_managed_ebs_volumes() {
+            Ok(volumes) => volumes_to_delete(volumes, &instance_id, &protected),
+            Err(e) => {
+                error!("Could not list volumes to delete - {}", e);
+                return;
+            }
+        };
+        if tx.send(CleanupProgress::Found(volume_ids.clone())).is_err() || volume_ids.is_empty() {
+            return;
+        }
+        if let Err(e) = fs::unmount(runner.as_ref(), &mountpoint) {
+            error!("{} - not deleting its volumes", e);
+            return;
+        }
+        for volume_id in volume_ids {
+            let params = serde_json::json!({ "volume_id": volume_id });
+            // Waits until the volume is detached
+            let result = aws_cli.detach_ebs_volume(volume_id.clone());
+            if let Some(log) = &audit_log {
+                log.record("detach_volume", params.clone(), &result);
+            }
+            let result = result.and_then(|()| aws_cli.delete_ebs_volume(volume_id.clone()));
+            if let Some(log) = &audit_log {
+                log.record("delete_volume", params, &result);
+            }
+            let deleted = result.is_ok();
+            if tx.send(CleanupProgress::Deleted(volume_id, deleted)).is_err() {
+                return;
+            }
+        }
+    });
+    let mut remaining = Vec::new();
+    let mut deleted = Vec::new();
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(CleanupProgress::Found(volume_ids)) => remaining = volume_ids,
+            Ok(CleanupProgress::Deleted(volume_id, true)) => {
+                info!("Deleted volume {}", volume_id);
+                remaining.retain(|remaining_id| remaining_id != &volume_id);
+                deleted.push(volume_id);
+            }
+            Ok(CleanupProgress::Deleted(volume_id, false)) => {
+                warn!("Could not delete volume {}", volume_id);
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let err = TerminationTimeoutError { remaining };
+                error!("{}", err);
+                return Err(Box::new(err));
+            }
+        }
+    }
+    if !remaining.is_empty() {
+        error!("Volumes not deleted on termination: {}", remaining.join(", "));
+    }
+    Ok(deleted)
+}
+
+/// Monthly cost of volumes, in USD
+fn monthly_cost_of(volumes: &[aws::ManagedVolume]) -> Result<f64, Box<dyn Error>> {
+    let mut total = 0.0;
+    for volume in volumes {
+        total += cost::monthly_cost(
+            &volume.vol_type,
+            volume.size_gib,
+            volume.iops,
+            volume.throughput,
+        )?;
+    }
+    Ok(total)
+}
+
+/// Id tagged on every resource created by one scale event
+fn new_scale_event_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Sum of volume sizes, failing instead of wrapping on overflow
+fn total_size_gib<I: IntoIterator<Item = SizeGib>>(sizes: I) -> Result<SizeGib, SizeOverflowError> {
+    sizes.into_iter()
+        .try_fold(SizeGib::ZERO, |total, size| total.checked_add(size))
+        .ok_or(SizeOverflowError)
+}
+
+fn checked_size_add(size: SizeGib, added: SizeGib) -> Result<SizeGib, SizeOverflowError> {
+    size.checked_add(added).ok_or(SizeOverflowError)
+}
+
+/// Doubles base for each throttled call in a row, up to cap
+fn backoff_interval(base: Duration, consecutive_throttles: u32, cap: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(consecutive_throttles);
+    base.saturating_mul(factor).min(cap.max(base))
+}
+
+/// Status tag value, e.g. ok:3vols:420GB
+fn status_tag_value(
+    outcome: &Result<ReconcileOutcome, Box<dyn Error>>,
+    volume_count: u32,
+    size: SizeGib,
+) -> String {
+    let state = match outcome {
+        Ok(ReconcileOutcome::NoActionNeeded(SkipReason::Paused)) => "paused",
+        Ok(ReconcileOutcome::Observed) => "observe",
+        Ok(ReconcileOutcome::Quiesced)
+        | Ok(ReconcileOutcome::MaxSizeReached)
+        | Ok(ReconcileOutcome::HeadroomReached)
+        | Ok(ReconcileOutcome::Exit) => "max-size",
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    format!("{}:{}vols:{}GB", state, volume_count, size.as_u64())
+}
+
+fn utilization_log_for(conf: &config::Config) -> Option<history::UtilizationLog> {
+    conf.utilization_log_path
+        .as_ref()
+        .map(|path| history::UtilizationLog::new(path, conf.utilization_log_max_bytes))
+}
+
+fn audit_log_for(conf: &config::Config) -> Option<audit::AuditLog> {
+    conf.audit_log_path.as_deref().map(audit::AuditLog::new)
+}
+
+fn statsd_sink_for(conf: &config::Config) -> Option<status::StatsdSink> {
+    let addr = conf.statsd_addr.as_ref()?;
+    let mut tags = vec![format!("mountpoint:{}", conf.mountpoint)];
+    tags.extend(conf.statsd_tags.iter().cloned());
+    status::StatsdSink::new(addr, tags)
+        .map_err(|e| warn!("Could not open a StatsD socket - {}", e))
+        .ok()
+}
+
+/// Lets an in-flight btrfs balance finish on shutdown, cancelling it after the timeout
+///
+/// btrfs runs through privileged_command, as it does when growing the filesystem.
+pub fn drain_filesystem_work(conf: &config::Config) -> Result<bool, Box<dyn Error>> {
+    let state = fs::RebalanceState::new(&conf.state_dir);
+    Ok(fs::drain_rebalance(
+        command_runner_for(conf).as_ref(),
+        &state,
+        Duration::from_secs(conf.balance_shutdown_timeout_secs),
+    )?)
+}
+
+/// Managed volumes ModifyVolume is safe to use on, skipping Multi-Attach ones
+fn growable_volumes(volumes: Vec<aws::ManagedVolume>) -> Vec<aws::ManagedVolume> {
+    volumes.into_iter()
+        .filter(|volume| {
+            if volume.multi_attach_enabled {
+                warn!(
+                    "Skipping volume {} - Multi-Attach volumes are not safe to grow from a single node",
+                    volume.volume_id
+                );
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::disk::DiskMgr;
+    use crate::fs::{CommandRunner, FS};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use figment::{Figment, providers::{Env, Format, Toml, Serialized}};
+    use std::cell::RefCell;
+
+    thread_local! {
+        static CAPTURED_LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records log messages per thread, so parallel tests do not see each other's logs
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS.with(|logs| logs.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger;
+
+    /// Messages logged on this thread while running f
+    fn capture_logs<F: FnOnce()>(f: F) -> Vec<String> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            let _ = log::set_logger(&CAPTURE_LOGGER);
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        CAPTURED_LOGS.with(|logs| logs.borrow_mut().clear());
+        f();
+        CAPTURED_LOGS.with(|logs| logs.take())
+    }
+
+    struct Context {
+        ebs_manager: Box<EBSManager>,
+    }
+
+    /// Default config with an inline TOML document merged on top
+    fn config_from_toml(toml: &str) -> Result<config::Config, Box<dyn Error>> {
+        Ok(Figment::from(Serialized::defaults(config::Config::default()))
+            .merge(Toml::string(toml))
+            .extract()?)
+    }
+
+    fn setup(
+        mock_diskmgr: disk::MockDiskMgr,
+        mock_aws: aws::MockAWS,
+        mock_fs: fs::MockFS,
+    ) -> Result<Context, Box<dyn Error>> {
+        setup_with_config(mock_diskmgr, mock_aws, mock_fs, None)
+    }
+
+    /// Like setup, with a config replacing the defaults
+    fn setup_with_config(
+        mock_diskmgr: disk::MockDiskMgr,
+        mock_aws: aws::MockAWS,
+        mock_fs: fs::MockFS,
+        config: Option<config::Config>,
+    ) -> Result<Context, Box<dyn Error>> {
+        let config = config.unwrap_or_default();
+        let mock_diskmgr : Box<dyn disk::DiskMgr> = Box::new(mock_diskmgr);
+        let mock_aws = Box::new(mock_aws);
+        let mock_fs = Box::new(mock_fs);
+        Ok(Context {
+            ebs_manager: EBSManager::new(
+                config,
+                mock_diskmgr,
+                mock_aws,
+                mock_fs,
+                Box::new(clock::MockClock::default()),
+            ),
+        })
+    }
+
+    #[test]
+    fn test_power_on_self_test() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        assert_eq!(ctx.ebs_manager.power_on_self_test()?, true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_need_more_space_false() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        assert_eq!(ctx.ebs_manager.need_more_space()?, false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_need_more_space_true() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(disk::MockDiskMgr {
+            disks: vec!["test".to_string()],
+            utilization_percentage: 95,
+            total_disk_size: 100,
+            sim_no_more_device_names: false,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
+        assert_eq!(ctx.ebs_manager.need_more_space()?, true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_no_available_dev_name() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(disk::MockDiskMgr {
+            disks: vec!["test".to_string()],
+            utilization_percentage: 95,
+            total_disk_size: 100,
+            sim_no_more_device_names: true,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS {
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        });
+        assert!(ctx.ebs_manager.add_more_space(101).is_err());
+        // No volume is created without a device name to attach it as
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_attach_failure_deletes_volume() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS {
+            fail_attach: true,
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        });
+        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_max_ebs_count() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        assert!(ctx.ebs_manager.add_more_space(101).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_max_logical_size() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(disk::MockDiskMgr {
+            disks: vec!["test".to_string()],
+            utilization_percentage: 10,
+            total_disk_size: 1000,
+            sim_no_more_device_names: false,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
+        assert!(ctx.ebs_manager.add_more_space(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_aws_err() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                simulate_aws_err: true,
+                mounted_devices_count: 1,
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_aws_error_carries_request_id() {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                simulate_aws_err: true,
+                request_id: Some("4a1c7e2d-0b5f-4c1e-9d3a-2f6b8e7c1d90".to_string()),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(
+            err.to_string().contains("(request id 4a1c7e2d-0b5f-4c1e-9d3a-2f6b8e7c1d90)"),
+            "{}",
+            err
+        );
+
+        struct ServiceError;
+
+        impl fmt::Display for ServiceError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "UnauthorizedOperation")
+            }
+        }
+
+        impl aws_sdk_ec2::operation::RequestId for ServiceError {
+            fn request_id(&self) -> Option<&str> {
+                Some("req-1")
+            }
+        }
+
+        let logs = capture_logs(|| {
+            let err = aws::call_failed("DescribeVolumes", &ServiceError, true);
+            assert_eq!(
+                err.to_string(),
+                "Error calling AWS API DescribeVolumes (request id req-1)"
+            );
+        });
+        assert!(logs.iter().any(|line| line.contains("failed (request id req-1)")));
+    }
+
+    #[test]
+    fn test_add_more_space_logical_volume_err() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS {
+                simulate_fs_err: true,
+                ..fs::MockFS::default()
+            }
+        ).unwrap();
+        assert!(ctx.ebs_manager.add_more_space(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_expand_failure_leaves_and_alerts() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            mock_aws,
+            fs::MockFS { simulate_fs_err: true, ..fs::MockFS::default() },
+        )?;
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:1:ebs".to_string());
+        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 0);
+        let events = published_events(&calls);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "expand_failed");
+        assert_eq!(events[0]["volume_id"], "vol-test");
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_expand_failure_detaches_and_deletes() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            mock_aws,
+            fs::MockFS { simulate_fs_err: true, ..fs::MockFS::default() },
+        )?;
+        ctx.ebs_manager.config.on_expand_failure = config::OnExpandFailure::DetachAndDelete;
+        ctx.ebs_manager.attached_volume_count = Some(1);
+        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+        assert_eq!(
+            calls.lock().unwrap().iter()
+                .filter(|call| !call.starts_with("tag_") && !call.starts_with("set_"))
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![
+                "request_ebs_volume 150 gp3".to_string(),
+                "attach_ebs_volume vol-test /dev/test".to_string(),
+                "detach_ebs_volume vol-test".to_string(),
+                "delete_ebs_volume vol-test".to_string(),
+            ]
+        );
+        // Back to the count before the scale-up
+        assert_eq!(ctx.ebs_manager.attached_volume_count, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_failure_keeps_joined_device() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        // The device is added, then the balance fails
+        ctx.ebs_manager.fs = Box::new(fs::ConcreteFS {
+            fs_type: "btrfs".to_string(),
+            mountpoint: "/mnt/data".to_string(),
+            runner: Box::new(fs::MockCommandRunner {
+                failing: vec!["btrfs balance start -m /mnt/data".to_string()],
+                ..fs::MockCommandRunner::default()
+            }),
+            rebalance: fs::RebalanceState::new(&test_state_dir("expand-failure-joined")),
+            reserved_blocks_percent: None,
+        });
+        ctx.ebs_manager.config.on_expand_failure = config::OnExpandFailure::DetachAndDelete;
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:1:ebs".to_string());
+        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 0);
+        assert_eq!(published_events(&calls)[0]["event"], "expand_failed");
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        assert!(ctx.ebs_manager.add_more_space(10).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_calc_threshold() {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        ctx.ebs_manager.config.limits.initial_utilization_threshold = 70;
+        for dev_count in [0, 1, 2, 3] {
+            assert_eq!(ctx.ebs_manager.calc_threshold(dev_count), Some(70));
+        }
+        for dev_count in [4, 5, 6] {
+            assert_eq!(ctx.ebs_manager.calc_threshold(dev_count), Some(80));
+        }
+        for dev_count in [7, 8, 9, 10] {
+            assert_eq!(ctx.ebs_manager.calc_threshold(dev_count), Some(90));
+        }
+        assert_eq!(ctx.ebs_manager.calc_threshold(11), Some(90));
+        assert_eq!(ctx.ebs_manager.calc_threshold(u32::MAX), Some(90));
+    }
+
+    #[test]
+    fn test_calc_new_size() {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        for dev_count in [0, 1, 2, 3] {
+            assert_eq!(ctx.ebs_manager.calc_new_size(dev_count, SizeGib(100)), SizeGib(150));
+        }
+        for dev_count in [4, 5, 6] {
+            assert_eq!(ctx.ebs_manager.calc_new_size(dev_count, SizeGib(100)), SizeGib(300));
+        }
+        for dev_count in [7, 8, 9, 10] {
+            assert_eq!(ctx.ebs_manager.calc_new_size(dev_count, SizeGib(100)), SizeGib(1000));
+        }
+        ctx.ebs_manager.config.limits.max_ebs_volume_size = SizeGib(1500);
+        assert_eq!(ctx.ebs_manager.calc_new_size(11, SizeGib(100)), SizeGib(1500));
+        ctx.ebs_manager.config.limits.max_ebs_volume_size = SizeGib(200);
+        assert_eq!(ctx.ebs_manager.calc_new_size(4, SizeGib(100)), SizeGib(200));
+    }
+
+    #[test]
+    fn test_calc_new_size_fixed() {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        ctx.ebs_manager.config.growth_strategy = config::GrowthStrategy::FixedGb(SizeGib(50));
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(100)), SizeGib(50));
+        assert_eq!(ctx.ebs_manager.calc_new_size(11, SizeGib(100)), SizeGib(50));
+        ctx.ebs_manager.config.growth_strategy = config::GrowthStrategy::FixedGb(SizeGib(5));
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(100)), SizeGib(10));
+        ctx.ebs_manager.config.growth_strategy = config::GrowthStrategy::FixedGb(SizeGib(5000));
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(100)), SizeGib(1000));
+    }
+
+    #[test]
+    fn test_calc_new_size_percent_of_current() {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        ctx.ebs_manager.config.growth_strategy = config::GrowthStrategy::PercentOfCurrent(20);
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(500)), SizeGib(100));
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(10)), SizeGib(10));
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(50000)), SizeGib(1000));
+    }
+
+    fn setup_at_max_size(on_max_size: config::OnMaxSize) -> Context {
+        let config = config::Config {
+            on_max_size,
+            ..config::Config::default()
+        };
+        setup_with_config(disk::MockDiskMgr {
+            disks: vec!["test".to_string()],
+            utilization_percentage: 95,
+            total_disk_size: 1000,
+            sim_no_more_device_names: false,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default(), Some(config)).unwrap()
+    }
+
+    #[test]
+    fn test_reconcile_no_action_needed() -> Result<(), Box<dyn Error>> {
+        // Mock-only, runs on any OS
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            mock_aws,
+            fs::MockFS::default()
+        ).unwrap();
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::BelowThreshold)
+        );
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(target_os = "linux"))]
+    fn test_from_config_unsupported_platform() {
+        let limiter = aws::ApiLimiter::new(1);
+        let err = EBSManager::from_config(config::Config::default(), limiter).err().unwrap();
+        assert!(err.is::<UnsupportedPlatformError>());
+    }
+
+    #[test]
+    fn test_reconcile_scaled_up() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(disk::MockDiskMgr {
+            disks: vec!["test".to_string()],
+            utilization_percentage: 95,
+            total_disk_size: 100,
+            sim_no_more_device_names: false,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_on_max_size_error() {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::Error);
+        for _ in 0..2 {
+            let err = ctx.ebs_manager.reconcile().unwrap_err();
+            assert!(err.is::<MaxLogicalVolumeSizeExceededError>());
+        }
+    }
+
+    #[test]
+    fn test_reconcile_on_max_size_quiesce() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::Quiesce);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::Quiesced);
+        assert!(ctx.ebs_manager.quiesced);
+        // Stays quiesced even if there is room again
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            disks: vec!["test".to_string()],
+            utilization_percentage: 95,
+            total_disk_size: 100,
+            sim_no_more_device_names: false,
+            ..disk::MockDiskMgr::default()
+        });
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::Quiesced);
+        ctx.ebs_manager.reload_config(config::Config::default());
+        assert!(!ctx.ebs_manager.quiesced);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_on_max_size_exit() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::Exit);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::Exit);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_on_max_size_alert_and_continue() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::AlertAndContinue);
+        assert!(!ctx.ebs_manager.max_size_alerted);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::MaxSizeReached);
+        assert!(ctx.ebs_manager.max_size_alerted);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::MaxSizeReached);
+        assert!(!ctx.ebs_manager.quiesced);
+        Ok(())
+    }
+
+    #[test]
+    fn test_credential_source_default_chain() {
+        let conf = config::Config::default();
+        let source = aws::CredentialSource::from_config(&conf);
+        assert_eq!(source, aws::CredentialSource::DefaultChain);
+        assert_eq!(source.profile(), None);
+    }
+
+    #[test]
+    fn test_credential_source_profile() {
+        let mut conf = config::Config::default();
+        conf.aws_profile = Some("autoscaler".to_string());
+        let source = aws::CredentialSource::from_config(&conf);
+        assert_eq!(source, aws::CredentialSource::Profile("autoscaler".to_string()));
+        assert_eq!(source.profile(), Some("autoscaler"));
+    }
+
+    #[test]
+    fn test_credential_source_assume_role() {
+        let mut conf = config::Config::default();
+        conf.aws_profile = Some("autoscaler".to_string());
+        conf.assume_role_arn = Some("arn:aws:iam::123456789012:role/ebs".to_string());
+        let source = aws::CredentialSource::from_config(&conf);
+        assert_eq!(source, aws::CredentialSource::AssumeRole {
+            role_arn: "arn:aws:iam::123456789012:role/ebs".to_string(),
+            profile: Some("autoscaler".to_string()),
+        });
+        assert_eq!(source.profile(), Some("autoscaler"));
+    }
+
+    #[test]
+    fn test_candidate_device_names() {
+        let names = disk::candidate_device_names(disk::DEVICE_PREFIX);
+        assert_eq!(names.len(), 25 + 25 * 26);
+        assert_eq!(names[0], "/dev/xvdb");
+        assert_eq!(names[24], "/dev/xvdz");
+        assert_eq!(names[25], "/dev/xvdba");
+        assert_eq!(names[names.len() - 1], "/dev/xvdzz");
+    }
+
+    #[test]
+    fn test_device_allocator_first_free() {
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        assert_eq!(allocator.next_free(&[]).unwrap(), "/dev/xvdb");
+    }
+
+    #[test]
+    fn test_device_allocator_skips_used() {
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        let used = vec!["/dev/xvdb".to_string(), "/dev/xvdc".to_string()];
+        assert_eq!(allocator.next_free(&used).unwrap(), "/dev/xvdd");
+    }
+
+    /// Candidate names picked by indices, plus arbitrary names that may or may not be
+    /// candidates, e.g. /dev/xvda or /dev/nvme
+    fn used_device_names() -> impl proptest::strategy::Strategy<Value = Vec<String>> {
+        use proptest::strategy::Strategy;
+        let count = disk::candidate_device_names(disk::DEVICE_PREFIX).len();
+        let subset = proptest::prop_oneof![
+            proptest::collection::btree_set(0..count, 0..=count),
+            // Nearly every name used, to reach exhaustion
+            proptest::collection::btree_set(0..count, 0..3).prop_map(move |free| {
+                (0..count)
+                    .filter(|idx| !free.contains(idx))
+                    .collect::<std::collections::BTreeSet<usize>>()
+            }),
+        ];
+        (subset, proptest::collection::vec("/dev/(xvd|nvme)[a-z]{0,2}", 0..5)).prop_map(
+            |(indices, extra)| {
+                let names = disk::candidate_device_names(disk::DEVICE_PREFIX);
+                indices.into_iter().map(|idx| names[idx].clone()).chain(extra).collect()
+            },
+        )
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_device_allocator_first_free_of_any_used(used in used_device_names()) {
+            let names = disk::candidate_device_names(disk::DEVICE_PREFIX);
+            let first_free = names.iter().find(|name| !used.contains(*name));
+            let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+            // Exhausted exactly when no candidate is free
+            let name = allocator.next_free(&used).ok();
+            proptest::prop_assert_eq!(name.as_ref(), first_free);
+        }
+
+        #[test]
+        fn test_device_allocator_drains_in_sequence(used in used_device_names()) {
+            let names = disk::candidate_device_names(disk::DEVICE_PREFIX);
+            let free: Vec<&String> = names.iter().filter(|name| !used.contains(*name)).collect();
+            let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+            let mut used = used;
+            for expected in free {
+                let name = allocator.next_free(&used);
+                proptest::prop_assert_eq!(name.as_ref().ok(), Some(expected));
+                used.push(expected.clone());
+            }
+            proptest::prop_assert!(allocator.next_free(&used).is_err());
+        }
+    }
+
+    #[test]
+    fn test_device_allocator_resumes_after_last() {
+        // A name handed out but not yet visible on the host is not reused
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        assert_eq!(allocator.next_free(&[]).unwrap(), "/dev/xvdb");
+        assert_eq!(allocator.next_free(&[]).unwrap(), "/dev/xvdc");
+    }
+
+    #[test]
+    fn test_device_allocator_two_letter_names() {
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        let used: Vec<String> = ('b'..='z')
+            .map(|c| format!("/dev/xvd{}", c))
+            .collect();
+        assert_eq!(allocator.next_free(&used).unwrap(), "/dev/xvdba");
+    }
+
+    #[test]
+    fn test_device_allocator_wraparound() {
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        let candidates = disk::candidate_device_names(disk::DEVICE_PREFIX);
+        let used: Vec<String> = candidates.iter()
+            .filter(|dev| dev.as_str() != "/dev/xvdzz")
+            .cloned()
+            .collect();
+        assert_eq!(allocator.next_free(&used).unwrap(), "/dev/xvdzz");
+        let used: Vec<String> = candidates.iter()
+            .filter(|dev| dev.as_str() != "/dev/xvdc")
+            .cloned()
+            .collect();
+        assert_eq!(allocator.next_free(&used).unwrap(), "/dev/xvdc");
+    }
+
+    #[test]
+    fn test_device_allocator_exhausted() {
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        let used = disk::candidate_device_names(disk::DEVICE_PREFIX);
+        assert!(allocator.next_free(&used).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_concrete_diskmgr_next_logical_device() {
+        let mut diskmgr = disk::ConcreteDiskMgr::with_lister(
+            Box::new(disk::MockDeviceLister {
+                devices: vec![
+                    "/dev/xvda".to_string(),
+                    "/dev/xvdb".to_string(),
+                    "/dev/xvdb1".to_string(),
+                    "/dev/nvme0n1".to_string(),
+                ],
+            })
+        );
+        assert_eq!(diskmgr.get_next_logical_device().unwrap(), "/dev/xvdc");
+        assert_eq!(diskmgr.get_next_logical_device().unwrap(), "/dev/xvdd");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_concrete_diskmgr_single_refresh_serves_lookups() {
+        let gib = 1 << 30;
+        let disk = |name: &str, mount_point: &str, total: u64, available: u64| disk::DiskInfo {
+            name: name.to_string(),
+            mount_point: mount_point.to_string(),
+            total_space: total * gib,
+            available_space: available * gib,
+        };
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let mut diskmgr = disk::ConcreteDiskMgr::with_disk_lister(
+            Box::new(disk::MockDiskLister {
+                disks: vec![
+                    disk("/dev/nvme0n1p1", "/", 8, 4),
+                    disk("/dev/mapper/data", "/mnt/data", 100, 50),
+                    disk("/dev/mapper/logs", "/mnt/logs", 300, 100),
+                    disk("/dev/loop0", "/snap/core", 1, 1),
+                ],
+                refreshes: refreshes.clone(),
+            }),
+            Box::new(disk::MockDeviceLister { devices: Vec::new() }),
+            disk::shared_device_allocator(),
+            disk::DeviceFilter::new(vec!["/dev/loop*".to_string()]),
+        );
+        assert_eq!(refreshes.load(Ordering::Relaxed), 1);
+        diskmgr.save_disk_list().unwrap();
+        assert_eq!(refreshes.load(Ordering::Relaxed), 2);
+
+        let snapshot = diskmgr.snapshot();
+        assert_eq!(
+            snapshot.mounts.keys().collect::<Vec<_>>(),
+            vec!["/", "/mnt/data", "/mnt/logs"]
+        );
+        for mountpoint in ["/mnt/data", "/mnt/logs"] {
+            let usage = snapshot.get(mountpoint).unwrap();
+            assert_eq!(
+                diskmgr.disk_usage_percent(mountpoint.to_string()).unwrap(),
+                usage.usage_percent
+            );
+            assert_eq!(diskmgr.disk_size(mountpoint.to_string()).unwrap(), usage.size);
+        }
+        assert_eq!(snapshot.get("/mnt/logs").unwrap().size, SizeGib(300));
+        assert!(diskmgr.disk_size("/snap/core".to_string()).is_err());
+        // Every lookup came from the last refresh
+        assert_eq!(refreshes.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_disk_snapshot_usage_of_full_disk() {
+        let disk = |mount_point: &str, total: u64, available: u64| disk::DiskInfo {
+            name: "/dev/mapper/data".to_string(),
+            mount_point: mount_point.to_string(),
+            total_space: total,
+            available_space: available,
+        };
+        let snapshot = disk::DiskSnapshot::new(
+            &[disk("/mnt/full", 100, 0), disk("/mnt/half", 100, 50), disk("/mnt/empty", 0, 0)],
+            &disk::DeviceFilter::default(),
+        );
+        assert_eq!(snapshot.get("/mnt/full").unwrap().usage_percent, 100);
+        assert_eq!(snapshot.get("/mnt/half").unwrap().usage_percent, 50);
+        assert_eq!(snapshot.get("/mnt/empty").unwrap().usage_percent, 0);
+    }
+
+    #[test]
+    fn test_device_filter() {
+        let filter = disk::DeviceFilter::new(vec![
+            "/dev/loop*".to_string(),
+            "/var/lib/docker".to_string(),
+            "/dev/xvdb".to_string(),
+        ]);
+        assert!(filter.is_excluded("/dev/loop0"));
+        assert!(filter.is_excluded("/dev/xvdb"));
+        assert!(!filter.is_excluded("/dev/xvdba"));
+        assert!(!filter.is_excluded("/dev/xvdc"));
+        assert!(filter.is_excluded("/var/lib/docker/overlay2"));
+        assert!(!filter.is_excluded("/var/lib/docker-data"));
+        assert!(filter.allows("/dev/xvdc", "/mnt/data"));
+        assert!(!filter.allows("overlay", "/var/lib/docker/overlay2/merged"));
+        assert!(!filter.allows("/dev/loop3", "/snap/core/1"));
+    }
+
+    #[test]
+    fn test_device_allocator_skips_excluded() {
+        let filter = disk::DeviceFilter::new(vec![
+            "/dev/xvdc".to_string(),
+            "/dev/xvdd".to_string(),
+        ]);
+        let mut allocator = disk::DeviceAllocator::new(disk::DEVICE_PREFIX);
+        let used = vec!["/dev/xvdb".to_string(), "/dev/loop0".to_string()];
+        assert_eq!(allocator.next_free_filtered(&used, &filter).unwrap(), "/dev/xvde");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_concrete_diskmgr_skips_excluded_devices() {
+        let mut diskmgr = disk::ConcreteDiskMgr::with_allocator(
+            Box::new(disk::MockDeviceLister {
+                devices: vec![
+                    "/dev/loop0".to_string(),
+                    "/dev/xvdb".to_string(),
+                ],
+            }),
+            disk::shared_device_allocator(),
+            disk::DeviceFilter::new(vec!["/dev/xvdc".to_string()]),
+        );
+        assert_eq!(diskmgr.get_next_logical_device().unwrap(), "/dev/xvdd");
+    }
+
+    #[test]
+    fn test_reconcile_single_volume_per_tick_by_default() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            pending_utilization: vec![95, 85, 50],
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default()).unwrap();
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_scale_until_below_threshold() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            // One small volume only brings utilization down to 85%, each scale-up
+            // re-reads it before creating the volume
+            pending_utilization: vec![95, 95, 85, 85, 50],
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default()).unwrap();
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 2);
+        Ok(())
+    }
+
+    fn managed_volume(volume_id: &str, multi_attach_enabled: bool) -> aws::ManagedVolume {
+        aws::ManagedVolume {
+            volume_id: volume_id.to_string(),
+            size_gib: SizeGib(10),
+            vol_type: "gp3".to_string(),
+            state: "in-use".to_string(),
+            iops: Some(3000),
+            throughput: Some(125),
+            device: Some("/dev/xvdb".to_string()),
+            instance_id: Some("i-0123456789abcdef0".to_string()),
+            multi_attach_enabled,
+            tags: [
+                (aws::MANAGED_BY_TAG, aws::MANAGED_BY_TAG_VALUE),
+                (aws::OWNER_TAG, "i-0123456789abcdef0"),
+                (aws::DELETE_ON_TERM_TAG, aws::DELETE_ON_TERM_TAG_VALUE),
+            ]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_growable_volumes_skips_multi_attach() -> Result<(), Box<dyn Error>> {
+        let volumes = vec![
+            managed_volume("vol-single", false),
+            managed_volume("vol-shared", true),
+        ];
+        assert_eq!(growable_volumes(volumes.clone()), vec![managed_volume("vol-single", false)]);
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-shared", true)],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.state_dir = test_state_dir("multi-attach");
+        ctx.ebs_manager.config.scale_mode = config::ScaleMode::GrowVolume;
+        // The shared volume is not grown, a volume is added instead
+        assert!(ctx.ebs_manager.add_more_space(1).is_ok());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    fn assert_cost(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_monthly_cost_per_volume_type() -> Result<(), Box<dyn Error>> {
+        assert_cost(cost::monthly_cost("gp3", SizeGib(100), None, None)?, 8.0);
+        // Baseline IOPS and throughput are included in the gp3 price
+        assert_cost(cost::monthly_cost("gp3", SizeGib(100), Some(3000), Some(125))?, 8.0);
+        assert_cost(cost::monthly_cost("gp3", SizeGib(100), Some(4000), Some(250))?, 18.0);
+        assert_cost(cost::monthly_cost("gp2", SizeGib(100), None, Some(250))?, 10.0);
+        assert_cost(cost::monthly_cost("io1", SizeGib(100), Some(1000), None)?, 77.5);
+        assert_cost(cost::monthly_cost("io2", SizeGib(100), Some(1000), None)?, 77.5);
+        assert_cost(cost::monthly_cost("st1", SizeGib(1000), None, None)?, 45.0);
+        assert_cost(cost::monthly_cost("sc1", SizeGib(1000), None, None)?, 15.0);
+        assert_cost(cost::monthly_cost("standard", SizeGib(100), None, None)?, 5.0);
+        assert!(cost::monthly_cost("gp9", SizeGib(100), None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_max_monthly_cost() -> Result<(), Box<dyn Error>> {
+        let mut volume = managed_volume("vol-existing", false);
+        volume.size_gib = SizeGib(100);
+        let mut other_instance = managed_volume("vol-other", false);
+        other_instance.size_gib = SizeGib(1000);
+        other_instance.instance_id = Some("i-0fedcba9876543210".to_string());
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                managed_volumes: vec![volume, other_instance],
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        // Existing volume is $8, a new 150GB gp3 at baseline IOPS and throughput is $12.
+        // The volume of another instance does not count.
+        ctx.ebs_manager.config.limits.max_monthly_cost_usd = Some(15.0);
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(err.is::<MaxMonthlyCostExceededError>());
+        ctx.ebs_manager.config.limits.max_monthly_cost_usd = Some(25.0);
+        assert!(ctx.ebs_manager.add_more_space(1).is_ok());
+
+        // Priced as the io2 volume of its tier rather than as the gp3 base volume
+        ctx.ebs_manager.config.volume_tiers = vec![config::VolumeTier {
+            from_volume_count: 1,
+            vol_type: Some("io2".to_string()),
+            iops: Some(3000),
+            throughput: None,
+            size_gib: None,
+        }];
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(err.is::<MaxMonthlyCostExceededError>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gp3_default_baseline_is_valid() {
+        assert!(config::Config::default().volume.validate().is_ok());
+    }
+
+    #[test]
+    fn test_gp3_throughput_ratio() {
+        let mut volume = config::Config::default().volume;
+        volume.iops = Some(4000);
+        volume.throughput = Some(1000);
+        assert!(volume.validate().is_ok());
+        volume.iops = Some(3000);
+        let err = volume.validate().unwrap_err();
+        assert!(err.is::<config::Gp3ThroughputRatioError>());
+        assert!(err.to_string().contains("at least 4000 IOPS"));
+        // Other volume types have their own limits
+        volume.vol_type = "io2".to_string();
+        assert!(volume.validate().is_ok());
+    }
+
+    #[test]
+    fn test_add_more_space_rejects_gp3_over_ratio() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        ctx.ebs_manager.config.volume.throughput = Some(1000);
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(err.is::<config::Gp3ThroughputRatioError>());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+    }
+
+    #[test]
+    fn test_utilization_log_records_ticks() -> Result<(), Box<dyn Error>> {
+        let dir = test_state_dir("utilization-log");
+        let path = format!("{}/utilization.jsonl", dir);
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                pending_utilization: vec![10, 20, 30],
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let mut conf = config::Config::default();
+        conf.utilization_log_path = Some(path.clone());
+        // Room for two records per file
+        conf.utilization_log_max_bytes = 200;
+        ctx.ebs_manager.reload_config(conf);
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        for _ in 0..3 {
+            ctx.ebs_manager.reconcile()?;
+            clock.advance(Duration::from_secs(60));
+        }
+        let read_records = |path: &str| -> Vec<serde_json::Value> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let rotated = read_records(&format!("{}.1", path));
+        let current = read_records(&path);
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(current.len(), 1);
+        assert_eq!(rotated[0]["utilization_percent"], 10);
+        assert_eq!(rotated[1]["utilization_percent"], 20);
+        assert_eq!(current[0]["utilization_percent"], 30);
+        assert_eq!(current[0]["volume_count"], 1);
+        assert_eq!(current[0]["logical_size_gb"], 100);
+        // Timestamps come from the manager's clock
+        let timestamp = |record: &serde_json::Value| record["timestamp"].as_u64().unwrap();
+        assert_eq!(timestamp(&current[0]), clock.unix_secs() - 60);
+        assert_eq!(timestamp(&current[0]) - timestamp(&rotated[0]), 120);
+        Ok(())
+    }
+
+    #[test]
+    fn test_utilization_log_csv() -> Result<(), Box<dyn Error>> {
+        let dir = test_state_dir("utilization-csv");
+        let log = history::UtilizationLog::new(&format!("{}/utilization.csv", dir), 1024);
+        let record = history::UtilizationRecord {
+            timestamp: 1700000000,
+            utilization_percent: 42,
+            volume_count: 3,
+            logical_size_gb: 450,
+        };
+        log.append(&record)?;
+        log.append(&record)?;
+        let contents = std::fs::read_to_string(format!("{}/utilization.csv", dir))?;
+        assert_eq!(
+            contents,
+            "timestamp,utilization_percent,volume_count,logical_size_gb\n\
+             1700000000,42,3,450\n\
+             1700000000,42,3,450\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrap_decision() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                sim_missing_mountpoint: true,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        // Disabled by default
+        assert!(!ctx.ebs_manager.needs_bootstrap());
+        assert!(ctx.ebs_manager.power_on_self_test().is_err());
+        ctx.ebs_manager.config.bootstrap_if_missing = true;
+        assert!(ctx.ebs_manager.needs_bootstrap());
+        // The mount checks wait for the bootstrap
+        assert!(ctx.ebs_manager.power_on_self_test()?);
+
+        // An existing filesystem is only ever grown
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        ctx.ebs_manager.config.bootstrap_if_missing = true;
+        assert!(!ctx.ebs_manager.needs_bootstrap());
+        assert_eq!(ctx.ebs_manager.bootstrap_if_missing()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrap_creates_first_volume() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                sim_missing_mountpoint: true,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        )?;
+        ctx.ebs_manager.config.bootstrap_if_missing = true;
+        let added = ctx.ebs_manager.bootstrap_if_missing()?.unwrap();
+        assert_eq!(added.size_gib, SizeGib(150));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "request_ebs_volume 150 gp3".to_string(),
+                "attach_ebs_volume vol-test /dev/test".to_string(),
+                "set_delete_on_termination /dev/test true".to_string(),
+                "tag_volume vol-test delete-on-termination true".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrap_removes_volume_when_mkfs_fails() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                sim_missing_mountpoint: true,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS { simulate_fs_err: true, ..fs::MockFS::default() },
+        ).unwrap();
+        ctx.ebs_manager.config.bootstrap_if_missing = true;
+        assert!(ctx.ebs_manager.bootstrap_if_missing().is_err());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 1);
+    }
+
+    #[test]
+    fn test_bootstrap_keeps_mounted_volume_when_persisting_fails() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                sim_missing_mountpoint: true,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS { simulate_persist_err: true, ..fs::MockFS::default() },
+        ).unwrap();
+        ctx.ebs_manager.config.bootstrap_if_missing = true;
+        ctx.ebs_manager.config.persist_mounts = true;
+        let logs = capture_logs(|| {
+            assert!(ctx.ebs_manager.bootstrap_if_missing().is_err());
+        });
+        assert!(logs.iter().any(|log| log.contains("CRITICAL: vol-test (/dev/test) is mounted")));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 0);
+    }
+
+    #[test]
+    fn test_bootstrap_filesystem_commands() -> Result<(), Box<dyn Error>> {
+        let runner = fs::MockCommandRunner::default();
+        assert!(fs::bootstrap_filesystem(&runner, "btrfs", "/dev/xvdb", "/mnt/data").unwrap());
+        assert_eq!(
+            *runner.calls.lock().unwrap(),
+            vec![
+                "mkfs -t btrfs /dev/xvdb".to_string(),
+                "mkdir -p /mnt/data".to_string(),
+                "mount -t btrfs /dev/xvdb /mnt/data".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_persist_mount_by_uuid() -> Result<(), Box<dyn Error>> {
+        let dir = test_state_dir("bootstrap");
+        std::fs::create_dir_all(&dir)?;
+        let fstab = std::path::Path::new(&dir).join("fstab");
+        std::fs::write(&fstab, "/dev/xvda1 / ext4 defaults 0 1")?;
+        let runner = fs::MockCommandRunner {
+            outputs: vec![(
+                "blkid -s UUID -o value /dev/xvdb".to_string(),
+                "0b2a5d3c-6f1e-4c8e-9d7a-2f4b1e3c5a6d\n".to_string(),
+            )],
+            ..fs::MockCommandRunner::default()
+        };
+        assert!(fs::persist_mount_by_uuid(&runner, "btrfs", "/dev/xvdb", "/mnt/data", &fstab).unwrap());
+        // Persisting again does not duplicate the fstab entry
+        assert!(fs::persist_mount_by_uuid(&runner, "btrfs", "/dev/xvdb", "/mnt/data", &fstab).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&fstab)?,
+            "/dev/xvda1 / ext4 defaults 0 1\n\
+             UUID=0b2a5d3c-6f1e-4c8e-9d7a-2f4b1e3c5a6d /mnt/data btrfs defaults,nofail 0 0\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_persist_mount_without_uuid() {
+        let fstab = std::path::Path::new(&test_state_dir("bootstrap-no-uuid")).join("fstab");
+        let runner = fs::MockCommandRunner::default();
+        assert!(fs::persist_mount_by_uuid(&runner, "btrfs", "/dev/xvdb", "/mnt/data", &fstab).is_err());
+        assert!(!fstab.exists());
+    }
+
+    #[test]
+    fn test_bootstrap_stops_when_mkfs_fails() {
+        let runner = fs::MockCommandRunner {
+            failing: vec!["mkfs -t btrfs /dev/xvdb".to_string()],
+            ..fs::MockCommandRunner::default()
+        };
+        assert!(fs::bootstrap_filesystem(&runner, "btrfs", "/dev/xvdb", "/mnt/data").is_err());
+        assert_eq!(runner.calls.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_observe_only_makes_no_scaling_decisions() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                utilization_percentage: 95,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        ctx.ebs_manager.config.observe_only = true;
+        ctx.ebs_manager.config.status_tag_key = Some("ebs-autoscale-status".to_string());
+        ctx.ebs_manager.power_on_self_test().unwrap();
+        calls.lock().unwrap().clear();
+        let logs = capture_logs(|| {
+            assert_eq!(ctx.ebs_manager.reconcile().unwrap(), ReconcileOutcome::Observed);
+        });
+        assert_eq!(logs, Vec::<String>::new());
+        // The mock records every mutating call, the status tag included
+        assert_eq!(*calls.lock().unwrap(), Vec::<String>::new());
+        assert_eq!(ctx.ebs_manager.status_handle().snapshot().utilization_percent, Some(95));
+
+        // The same tick outside observe-only logs the decision
+        ctx.ebs_manager.config.observe_only = false;
+        let logs = capture_logs(|| {
+            ctx.ebs_manager.reconcile().unwrap();
+        });
+        assert!(logs.iter().any(|log| log.contains("adding more disks")));
+    }
+
+    #[test]
+    fn test_delete_managed_volumes() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![
+                managed_volume("vol-a", false),
+                managed_volume("vol-b", false),
+            ],
+            termination_notice: Some("spot interruption".to_string()),
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        };
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let runner = fs::MockCommandRunner { calls: commands.clone(), ..Default::default() };
+        let deleted = delete_managed_volumes(
+            Box::new(mock_aws), Box::new(runner), "/mnt/data".to_string(),
+            Vec::new(), None, Duration::from_secs(5),
+        )?;
+        assert_eq!(deleted, vec!["vol-a".to_string(), "vol-b".to_string()]);
+        assert_eq!(*commands.lock().unwrap(), vec!["umount /mnt/data".to_string()]);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 2);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_managed_volumes_needs_termination_notice() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        };
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let runner = fs::MockCommandRunner { calls: commands.clone(), ..Default::default() };
+        let deleted = delete_managed_volumes(
+            Box::new(mock_aws), Box::new(runner), "/mnt/data".to_string(),
+            Vec::new(), None, Duration::from_secs(5),
+        )?;
+        assert!(deleted.is_empty());
+        assert!(commands.lock().unwrap().is_empty());
+        assert!(calls.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_managed_volumes_keeps_volumes_if_unmount_fails() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            termination_notice: Some("Auto Scaling termination".to_string()),
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        };
+        let runner = fs::MockCommandRunner {
+            failing: vec!["umount /mnt/data".to_string()],
+            ..Default::default()
+        };
+        let deleted = delete_managed_volumes(
+            Box::new(mock_aws), Box::new(runner), "/mnt/data".to_string(),
+            Vec::new(), None, Duration::from_secs(5),
+        )?;
+        assert!(deleted.is_empty());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_managed_volumes_spares_base_volume() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut base_volume = managed_volume("vol-base", false);
+        base_volume.tags.clear();
+        let mut other_instance = managed_volume("vol-other", false);
+        other_instance.instance_id = Some("i-0fedcba9876543210".to_string());
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![
+                base_volume,
+                managed_volume("vol-a", false),
+                managed_volume("vol-protected", false),
+                other_instance,
+            ],
+            termination_notice: Some("spot interruption".to_string()),
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        };
+        let deleted = delete_managed_volumes(
+            Box::new(mock_aws),
+            Box::new(fs::MockCommandRunner::default()),
+            "/mnt/data".to_string(),
+            vec!["vol-protected".to_string()],
+            None,
+            Duration::from_secs(5),
+        )?;
+        assert_eq!(deleted, vec!["vol-a".to_string()]);
+        assert_eq!(*calls.lock().unwrap(), vec![
+            "detach_ebs_volume vol-a".to_string(),
+            "delete_ebs_volume vol-a".to_string(),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_managed_volumes_times_out() {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![
+                managed_volume("vol-a", false),
+                managed_volume("vol-b", false),
+            ],
+            delete_delay: Duration::from_secs(30),
+            termination_notice: Some("spot interruption".to_string()),
+            ..aws::MockAWS::default()
+        };
+        let started = std::time::Instant::now();
+        let err = delete_managed_volumes(
+            Box::new(mock_aws), Box::new(fs::MockCommandRunner::default()),
+            "/mnt/data".to_string(), Vec::new(), None, Duration::from_millis(50),
+        ).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let err = err.downcast_ref::<TerminationTimeoutError>().unwrap();
+        assert_eq!(err.remaining, vec!["vol-a".to_string(), "vol-b".to_string()]);
+    }
+
+    #[test]
+    fn test_fs_type_from_mounts() {
+        let mounts = "/dev/nvme0n1p1 / xfs rw,noatime 0 0\n\
+                      /dev/xvdb /mnt/data ext4 rw 0 0\n\
+                      /dev/xvdc /mnt/data btrfs rw,relatime 0 0\n";
+        assert_eq!(disk::fs_type_from_mounts(mounts, "/"), Some("xfs".to_string()));
+        assert_eq!(disk::fs_type_from_mounts(mounts, "/mnt/data"), Some("btrfs".to_string()));
+        assert_eq!(disk::fs_type_from_mounts(mounts, "/mnt/other"), None);
+    }
+
+    #[test]
+    fn test_read_only_mount_refused_unless_allowed() -> Result<(), Box<dyn Error>> {
+        let mounts = "/dev/nvme0n1p1 / xfs rw,noatime 0 0\n\
+                      /dev/xvdb /mnt/data btrfs ro,relatime 0 0\n\
+                      /dev/xvdc /mnt/other ext4 rw,errors=remount-ro 0 0\n";
+        assert_eq!(disk::read_only_from_mounts(mounts, "/"), Some(false));
+        assert_eq!(disk::read_only_from_mounts(mounts, "/mnt/data"), Some(true));
+        assert_eq!(disk::read_only_from_mounts(mounts, "/mnt/other"), Some(false));
+        assert_eq!(disk::read_only_from_mounts(mounts, "/mnt/missing"), None);
+
+        let mut ctx = setup(
+            disk::MockDiskMgr { read_only: true, ..disk::MockDiskMgr::default() },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let err = ctx.ebs_manager.power_on_self_test().unwrap_err();
+        assert!(err.is::<ReadOnlyMountError>());
+
+        ctx.ebs_manager.config.allow_readonly = true;
+        assert!(ctx.ebs_manager.power_on_self_test()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filesystem_type_agrees() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        assert!(ctx.ebs_manager.power_on_self_test()?);
+        assert_eq!(ctx.ebs_manager.config.fs_type, "btrfs");
+        Ok(())
+    }
+
+    #[test]
+    fn test_filesystem_type_disagrees() {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                mounted_fs_type: "xfs".to_string(),
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        let err = ctx.ebs_manager.power_on_self_test().unwrap_err();
+        let err = err.downcast_ref::<FsTypeMismatchError>().unwrap();
+        assert_eq!(err.configured, "btrfs");
+        assert_eq!(err.detected, "xfs");
+    }
+
+    #[test]
+    fn test_filesystem_type_auto_detect() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                mounted_fs_type: "xfs".to_string(),
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        ctx.ebs_manager.config.fs_type = String::new();
+        assert!(ctx.ebs_manager.power_on_self_test()?);
+        assert_eq!(ctx.ebs_manager.config.fs_type, "xfs");
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_tag_value() {
+        assert_eq!(
+            status_tag_value(&Ok(ReconcileOutcome::ScaledUp), 3, SizeGib(420)),
+            "ok:3vols:420GB"
+        );
+        assert_eq!(
+            status_tag_value(
+                &Ok(ReconcileOutcome::NoActionNeeded(SkipReason::Paused)), 1, SizeGib(100)
+            ),
+            "paused:1vols:100GB"
+        );
+        assert_eq!(
+            status_tag_value(&Ok(ReconcileOutcome::HeadroomReached), 4, SizeGib(900)),
+            "max-size:4vols:900GB"
+        );
+        let err: Box<dyn Error> = Box::new(MaxEBSCountExceededError);
+        assert_eq!(status_tag_value(&Err(err), 2, SizeGib(0)), "error:2vols:0GB");
+    }
+
+    #[test]
+    fn test_status_tag_is_throttled() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        )?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.status_tag_key = Some("ebs-autoscale-status".to_string());
+        ctx.ebs_manager.power_on_self_test()?;
+
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["tag_instance i-0123456789abcdef0 ebs-autoscale-status ok:1vols:100GB".to_string()]
+        );
+        // Changed, but too soon after the last update
+        ctx.ebs_manager.status.set_paused(true);
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "tag_instance"), 1);
+
+        clock.advance(Duration::from_secs(300));
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "tag_instance"), 2);
+        assert!(calls.lock().unwrap()[1].ends_with("paused:1vols:100GB"));
+
+        // Unchanged values are not pushed again
+        clock.advance(Duration::from_secs(300));
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "tag_instance"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_interval() {
+        let base = Duration::from_secs(2);
+        let cap = Duration::from_secs(60);
+        assert_eq!(backoff_interval(base, 0, cap), base);
+        assert_eq!(backoff_interval(base, 3, cap), Duration::from_secs(16));
+        assert_eq!(backoff_interval(base, 40, cap), cap);
+    }
+
+    #[test]
+    fn test_detection_interval_backs_off_while_throttled() {
+        let throttling = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                throttling: throttling.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        let mut intervals = Vec::new();
+        for _ in 0..6 {
+            assert!(ctx.ebs_manager.reconcile().is_err());
+            intervals.push(ctx.ebs_manager.detection_interval().as_secs());
+        }
+        assert_eq!(intervals, vec![4, 8, 16, 32, 60, 60]);
+        assert_eq!(
+            ctx.ebs_manager.status_handle().snapshot().effective_interval_secs,
+            Some(60)
+        );
+
+        throttling.store(false, Ordering::Relaxed);
+        ctx.ebs_manager.reconcile().unwrap();
+        assert_eq!(ctx.ebs_manager.detection_interval(), Duration::from_secs(2));
+        assert_eq!(
+            ctx.ebs_manager.status_handle().snapshot().effective_interval_secs,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_total_size_gib() {
+        // Well past u32::MAX
+        let sizes = vec![SizeGib(u64::from(u32::MAX)); 1000];
+        assert_eq!(total_size_gib(sizes).unwrap(), SizeGib(u64::from(u32::MAX) * 1000));
+        assert!(total_size_gib(vec![SizeGib(u64::MAX), SizeGib(1)]).is_err());
+        assert!(checked_size_add(SizeGib(u64::MAX), SizeGib(1)).is_err());
+        assert_eq!(checked_size_add(SizeGib(100), SizeGib(150)).unwrap(), SizeGib(250));
+    }
+
+    #[test]
+    fn test_add_more_space_size_overflow() {
+        let mut huge = managed_volume("vol-huge", false);
+        huge.size_gib = SizeGib(u64::MAX);
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                managed_volumes: vec![huge, managed_volume("vol-small", false)],
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(err.is::<SizeOverflowError>());
+    }
+
+    #[test]
+    fn test_add_more_space_managed_size_at_max() {
+        let mut volume = managed_volume("vol-big", false);
+        volume.size_gib = SizeGib(1000);
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                managed_volumes: vec![volume],
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(err.is::<MaxLogicalVolumeSizeExceededError>());
+    }
+
+    #[test]
+    fn test_setup_with_inline_config() -> Result<(), Box<dyn Error>> {
+        let config = config_from_toml(r#"
+            scale_until_below_threshold = true
+
+            [limits]
+            max_ebs_volume_count = 3
+        "#)?;
+        assert_eq!(config.limits.max_ebs_volume_count, 3);
+        // Fields left out keep their defaults
+        assert_eq!(config.limits.max_logical_volume_size, SizeGib(1000));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup_with_config(
+            disk::MockDiskMgr {
+                utilization_percentage: 95,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default(),
+            Some(config),
+        )?;
+        // One volume is mounted, so only two more fit under the count limit
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_report() -> Result<(), Box<dyn Error>> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                utilization_percentage: 95,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS {
+                managed_volumes: vec![managed_volume("vol-a", false)],
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        )?;
+        let report = ctx.ebs_manager.report()?;
+        assert_eq!(report.utilization_percent, Some(95));
+        assert_eq!(report.threshold_percent, 80);
+        assert_eq!(report.mounted_volume_count, 1);
+        assert_eq!(report.logical_size_gb, Some(SizeGib(100)));
+        assert_eq!(report.managed_volumes, vec![managed_volume("vol-a", false)]);
+        assert_eq!(
+            report.next_action,
+            NextAction::AddVolume { size_gib: SizeGib(150), vol_type: "gp3".to_string() }
+        );
+        // Nothing was created or attached
+        assert!(calls.lock().unwrap().is_empty());
+
+        let json = serde_json::to_value(&report)?;
+        assert_eq!(json["next_action"]["action"], "add_volume");
+        assert_eq!(json["managed_volumes"][0]["volume_id"], "vol-a");
+        assert_eq!(json["limits"]["max_ebs_volume_count"], 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_next_action() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        assert_eq!(ctx.ebs_manager.report()?.next_action, NextAction::None);
+
+        let mut ctx = setup_at_max_size(config::OnMaxSize::Error);
+        assert_eq!(
+            ctx.ebs_manager.report()?.next_action,
+            NextAction::Blocked { reason: "Maximum logical volume size exceeded".to_string() }
+        );
+        ctx.ebs_manager.status.set_paused(true);
+        assert_eq!(ctx.ebs_manager.report()?.next_action, NextAction::Paused);
+
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                sim_missing_mountpoint: true,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let report = ctx.ebs_manager.report()?;
+        assert_eq!(report.utilization_percent, None);
+        assert!(matches!(report.next_action, NextAction::Blocked { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_type_defaults() -> Result<(), Box<dyn Error>> {
+        let volume = |vol_type: &str, iops: Option<u64>| config::Volume {
+            vol_type: vol_type.to_string(),
+            encrypted: true,
+            iops,
+            throughput: None,
+        };
+        let gp3 = volume("gp3", None).with_type_defaults()?;
+        assert_eq!((gp3.iops, gp3.throughput), (Some(3000), Some(125)));
+        // Explicit settings win over the defaults
+        let gp3 = volume("gp3", Some(6000)).with_type_defaults()?;
+        assert_eq!((gp3.iops, gp3.throughput), (Some(6000), Some(125)));
+        let gp2 = volume("gp2", None).with_type_defaults()?;
+        assert_eq!((gp2.iops, gp2.throughput), (None, None));
+
+        let err = volume("io2", None).with_type_defaults().unwrap_err();
+        let err = err.downcast_ref::<config::MissingVolumeSettingError>().unwrap();
+        assert_eq!(err.setting, "iops");
+        let io2 = volume("io2", Some(8000)).with_type_defaults()?;
+        assert_eq!((io2.iops, io2.throughput), (Some(8000), None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_io2_requires_iops() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                calls: calls.clone(),
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        ctx.ebs_manager.config.volume.vol_type = "io2".to_string();
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        assert!(err.is::<config::MissingVolumeSettingError>());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+    }
+
+    #[test]
+    fn test_watchdog_timeout() {
+        let mut conf = config::Config::default();
+        assert_eq!(watchdog::timeout(&conf), Some(Duration::from_secs(600)));
+        conf.watchdog_interval_multiplier = 0;
+        assert_eq!(watchdog::timeout(&conf), None);
+    }
+
+    #[test]
+    fn test_watchdog_fires_on_hung_reconcile() {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                utilization_percentage: 95,
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        ).unwrap();
+        // The settle sleep stands in for an AWS call that never returns
+        ctx.ebs_manager.clock = Box::new(clock::SystemClock);
+        ctx.ebs_manager.config.post_attach_settle_ms = 60_000;
+        let heartbeat = watchdog::Heartbeat::default();
+        let loop_heartbeat = heartbeat.clone();
+        let mut ebs_manager = ctx.ebs_manager;
+        std::thread::spawn(move || {
+            let _ = ebs_manager.reconcile();
+            loop_heartbeat.beat();
+        });
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        watchdog::spawn(
+            heartbeat,
+            Duration::from_millis(50),
+            Duration::from_millis(10),
+            move |elapsed| {
+                let _ = tx.send(elapsed);
+            },
+        );
+        let elapsed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(elapsed > Duration::from_millis(50));
+        // Fires once per stall
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_drain_waits_for_reconcile_in_flight() {
+        let (mut ctx, calls) = setup_needing_space();
+        // The settle sleep holds the reconcile between attaching and finishing
+        ctx.ebs_manager.clock = Box::new(clock::SystemClock);
+        ctx.ebs_manager.config.post_attach_settle_ms = 300;
+        let drain = drain::Drain::default();
+        let in_flight = drain.begin().unwrap();
+        let mut ebs_manager = ctx.ebs_manager;
+        let handle = std::thread::spawn(move || {
+            let result = ebs_manager.reconcile().map_err(|e| e.to_string());
+            drop(in_flight);
+            result
+        });
+
+        drain.request();
+        assert!(drain.is_requested());
+        assert!(drain.begin().is_none());
+        assert!(!drain.wait(std::time::Instant::now()));
+        assert!(drain.wait(std::time::Instant::now() + Duration::from_secs(5)));
+        // The reconcile ran to completion rather than stopping between create and attach
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 1);
+
+        // Sleeping returns straight away once shutdown is requested
+        let started = std::time::Instant::now();
+        drain.sleep(Duration::from_secs(5));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_high_utilization_alert_fires_and_clears() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_needing_space();
+        let mock_clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(mock_clock.clone());
+        ctx.ebs_manager.config.paused = true;
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:123456789012:ebs".to_string());
+        ctx.ebs_manager.config.alert_utilization_threshold = Some(90);
+        ctx.ebs_manager.config.alert_utilization_duration_secs = 300;
+
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::Paused)
+        );
+        mock_clock.advance(Duration::from_secs(299));
+        ctx.ebs_manager.reconcile()?;
+        assert!(published_events(&calls).is_empty());
+        assert!(!ctx.ebs_manager.status_handle().snapshot().high_utilization_alert);
+
+        mock_clock.advance(Duration::from_secs(1));
+        let logs = capture_logs(|| { ctx.ebs_manager.reconcile().unwrap(); });
+        assert!(logs.iter().any(|log| log.starts_with("CRITICAL: utilization on")));
+        mock_clock.advance(Duration::from_secs(600));
+        ctx.ebs_manager.reconcile()?;
+        let events = published_events(&calls);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "high_utilization");
+        assert_eq!(events[0]["utilization_percent"], 95);
+        assert!(ctx.ebs_manager.status_handle().snapshot().high_utilization_alert);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            utilization_percentage: 50,
+            ..disk::MockDiskMgr::default()
+        });
+        ctx.ebs_manager.reconcile()?;
+        let events = published_events(&calls);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["event"], "high_utilization_cleared");
+        assert!(!ctx.ebs_manager.status_handle().snapshot().high_utilization_alert);
+        assert!(ctx.ebs_manager.high_utilization_since.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_high_utilization_alert_resets_on_dip() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_needing_space();
+        let mock_clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(mock_clock.clone());
+        ctx.ebs_manager.config.observe_only = true;
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:123456789012:ebs".to_string());
+        ctx.ebs_manager.config.alert_utilization_threshold = Some(90);
+
+        ctx.ebs_manager.reconcile()?;
+        mock_clock.advance(Duration::from_secs(200));
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            utilization_percentage: 85,
+            ..disk::MockDiskMgr::default()
+        });
+        ctx.ebs_manager.reconcile()?;
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            ..disk::MockDiskMgr::default()
+        });
+        ctx.ebs_manager.reconcile()?;
+        mock_clock.advance(Duration::from_secs(200));
+        ctx.ebs_manager.reconcile()?;
+        assert!(published_events(&calls).is_empty());
+        assert!(!ctx.ebs_manager.utilization_alerted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reattach_skips_existing_delete_on_term_tag() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_with_detached_volume();
+        ctx.ebs_manager.config.reattach_detached_volumes = true;
+        ctx.ebs_manager.reconcile_attachments()?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "tag_volume"), 0);
+
+        let mut untagged = managed_volume("vol-untagged", false);
+        untagged.state = "available".to_string();
+        untagged.instance_id = None;
+        untagged.tags.remove(aws::DELETE_ON_TERM_TAG);
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS {
+            managed_volumes: vec![untagged],
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        });
+        ctx.ebs_manager.reconcile_attachments()?;
+        assert!(calls.lock().unwrap()
+            .contains(&"tag_volume vol-untagged delete-on-termination true".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_delete_on_termination_follows_config() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            mock_aws,
+            fs::MockFS::default()
+        )?;
+        let added = ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(added.device, "/dev/test");
+        assert_eq!(
+            calls.lock().unwrap().iter()
+                .filter(|call| call.starts_with("set_delete_on_termination "))
+                .collect::<Vec<_>>(),
+            vec!["set_delete_on_termination /dev/test true"]
+        );
+
+        ctx.ebs_manager.config.ensure_ebs_deleted_on_term = false;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert!(calls.lock().unwrap().contains(&"set_delete_on_termination /dev/test false".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_existing_volumes_on_restart() -> Result<(), Box<dyn Error>> {
+        let mut detached = managed_volume("vol-detached", false);
+        detached.state = "available".to_string();
+        detached.device = None;
+        detached.instance_id = None;
+        let mut other_instance = managed_volume("vol-other", false);
+        other_instance.instance_id = Some("i-0fedcba9876543210".to_string());
+        let mut attached = Vec::new();
+        for (volume_id, device) in [("vol-a", "/dev/xvdb"), ("vol-b", "/dev/xvdc"), ("vol-c", "/dev/xvdd")] {
+            let mut volume = managed_volume(volume_id, false);
+            volume.size_gib = SizeGib(150);
+            volume.device = Some(device.to_string());
+            attached.push(volume);
+        }
+        let mock_aws = aws::MockAWS {
+            mounted_devices_count: 1,
+            managed_volumes: attached.into_iter().chain([detached, other_instance]).collect(),
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            total_disk_size: 450,
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(2000);
+
+        assert_eq!(ctx.ebs_manager.reconcile_existing_volumes()?, Some(ExistingVolumes {
+            volume_count: 3,
+            logical_size_gib: SizeGib(450),
+            devices: vec![
+                "/dev/xvdb".to_string(),
+                "/dev/xvdc".to_string(),
+                "/dev/xvdd".to_string(),
+            ],
+        }));
+        assert_eq!(ctx.ebs_manager.report()?.mounted_volume_count, 3);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        assert_eq!(ctx.ebs_manager.report()?.mounted_volume_count, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_existing_volumes_clean_slate() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        assert_eq!(ctx.ebs_manager.reconcile_existing_volumes()?, None);
+        assert_eq!(ctx.ebs_manager.report()?.mounted_volume_count, 0);
+        // The volumes added afterwards are counted from there
+        ctx.ebs_manager.add_more_space(0)?;
+        assert_eq!(ctx.ebs_manager.report()?.mounted_volume_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_slow_device() -> Result<(), Box<dyn Error>> {
+        let mock_clock = clock::MockClock::default();
+        let mut ctx = setup(disk::MockDiskMgr {
+            device_appears_after: 5,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default())?;
+        ctx.ebs_manager.clock = Box::new(mock_clock.clone());
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(*mock_clock.sleeps.lock().unwrap(), vec![DEVICE_POLL_INTERVAL; 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_appearance_timeout() {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            device_appears_after: u32::MAX,
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default()).unwrap();
+        ctx.ebs_manager.config.device_appearance_timeout_secs = 10;
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        let err = err.downcast_ref::<DeviceAppearanceTimeoutError>().unwrap();
+        assert_eq!(err.device, "/dev/test");
+        assert_eq!(err.timeout, Duration::from_secs(10));
+        // AWS reported the attach, only the device was late
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "set_delete_on_termination"), 0);
+        // The volume never joined the filesystem, so it is removed
+        assert_eq!(aws::MockAWS::count_calls(&calls, "detach_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 1);
+    }
+
+    #[test]
+    fn test_inode_usage_percent() {
+        assert_eq!(disk::inode_usage_percent(1000, 1000), 0);
+        assert_eq!(disk::inode_usage_percent(1000, 50), 95);
+        assert_eq!(disk::inode_usage_percent(1000, 0), 100);
+        // btrfs reports no fixed inode count
+        assert_eq!(disk::inode_usage_percent(0, 0), 0);
+    }
+
+    #[test]
+    fn test_need_more_space_on_inodes() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 10,
+            inode_utilization_percentage: 95,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default())?;
+        assert!(!ctx.ebs_manager.need_more_space()?);
+        ctx.ebs_manager.config.inode_utilization_threshold = Some(96);
+        assert!(!ctx.ebs_manager.need_more_space()?);
+        ctx.ebs_manager.config.inode_utilization_threshold = Some(90);
+        assert!(ctx.ebs_manager.need_more_space()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inode_pressure_on_ext4_only_warns() {
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 10,
+            inode_utilization_percentage: 95,
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
+        ctx.ebs_manager.config.inode_utilization_threshold = Some(90);
+        ctx.ebs_manager.config.fs_type = "ext4".to_string();
+        let logs = capture_logs(|| assert!(!ctx.ebs_manager.need_more_space().unwrap()));
+        assert!(logs.iter().any(|log| log.contains("adding space to ext4 does not add inodes")));
+    }
+
+    #[test]
+    fn test_plan_stops_at_max_logical_size() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        assert_eq!(ctx.ebs_manager.plan(10), vec![
+            PlannedStep { volume_count: 2, size_gib: SizeGib(150), total_size_gib: SizeGib(250) },
+            PlannedStep { volume_count: 3, size_gib: SizeGib(150), total_size_gib: SizeGib(400) },
+            PlannedStep { volume_count: 4, size_gib: SizeGib(150), total_size_gib: SizeGib(550) },
+            PlannedStep { volume_count: 5, size_gib: SizeGib(300), total_size_gib: SizeGib(850) },
+            PlannedStep { volume_count: 6, size_gib: SizeGib(300), total_size_gib: SizeGib(1150) },
+        ]);
+        assert_eq!(ctx.ebs_manager.plan(2).len(), 2);
+        assert!(calls.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_stops_at_max_count() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        ctx.ebs_manager.config.limits.max_ebs_volume_count = 3;
+        let plan = ctx.ebs_manager.plan(10);
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan.last().unwrap().volume_count, 3);
+
+        ctx.ebs_manager.config.limits.max_ebs_volume_count = 1;
+        assert!(ctx.ebs_manager.plan(10).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_profile() -> Result<(), Box<dyn Error>> {
+        let figment = Figment::from(Serialized::defaults(config::Config::default()))
+            .merge(Toml::string(r#"
+                mountpoint = "/mnt/data"
+
+                [limits]
+                max_ebs_volume_count = 5
+
+                [profiles.prod]
+                detection_interval = 10
+
+                [profiles.prod.limits]
+                max_ebs_volume_count = 50
+            "#));
+        let base: config::Config = config::select_profile(figment.clone(), None)?.extract()?;
+        assert_eq!(base.limits.max_ebs_volume_count, 5);
+        assert_eq!(base.detection_interval, 2);
+
+        let prod: config::Config = config::select_profile(figment.clone(), Some("prod"))?.extract()?;
+        assert_eq!(prod.limits.max_ebs_volume_count, 50);
+        assert_eq!(prod.detection_interval, 10);
+        // Everything else comes from the base config
+        assert_eq!(prod.mountpoint, "/mnt/data");
+        assert_eq!(prod.limits.max_logical_volume_size, SizeGib(1000));
+
+        let err = config::select_profile(figment, Some("staging")).unwrap_err();
+        assert_eq!(err.profile, "staging");
+        Ok(())
+    }
+
+    #[test]
+    fn test_need_more_space_empty_disk_list() {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            sim_empty_disk_list: true,
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default()).unwrap();
+        let err = ctx.ebs_manager.need_more_space().unwrap_err();
+        assert!(err.is::<disk::DiskListError>());
+        // Nothing is scaled on an empty disk list, even with stale utilization
+        assert!(ctx.ebs_manager.reconcile().unwrap_err().is::<disk::DiskListError>());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+    }
+
+    #[test]
+    fn test_expand_name_tag() -> Result<(), Box<dyn Error>> {
+        let values = [
+            ("instance_id", "i-0123".to_string()),
+            ("mountpoint", "/mnt/data".to_string()),
+            ("seq", "3".to_string()),
+            ("timestamp", "1700000000".to_string()),
+        ];
+        assert_eq!(config::expand_name_tag("data-{instance_id}-{seq}", &values)?, "data-i-0123-3");
+        assert_eq!(config::expand_name_tag("{mountpoint}@{timestamp}", &values)?, "/mnt/data@1700000000");
+        assert_eq!(config::expand_name_tag("plain", &values)?, "plain");
+        Ok(())
+    }
+
+    #[test]
+    fn test_name_tag_template_rejects_unknown_placeholder() {
+        assert!(config::validate_name_tag_template("data-{instance_id}-{seq}").is_ok());
+        let err = config::validate_name_tag_template("data-{hostname}").unwrap_err();
+        assert_eq!(err.placeholder, "hostname");
+        assert!(config::validate_name_tag_template("data-{seq").is_err());
+
+        let mut conf = config::Config::default();
+        conf.name_tag_template = Some("data-{volume}".to_string());
+        assert!(conf.validate().unwrap_err().is::<config::UnknownPlaceholderError>());
+    }
+
+    #[test]
+    fn test_created_volume_name_tag() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.power_on_self_test()?;
+        ctx.ebs_manager.config.name_tag_template = Some("data-{instance_id}-{seq}".to_string());
+        ctx.ebs_manager.add_more_space(1)?;
+        assert!(calls.lock().unwrap().contains(
+            &"request_ebs_volume 150 gp3 data-i-0123456789abcdef0-2".to_string()
+        ));
+
+        // The timestamp comes from the manager's clock
+        let clock = clock::MockClock::default();
+        clock.advance(Duration::from_secs(86400));
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.name_tag_template = Some("data-{timestamp}".to_string());
+        ctx.ebs_manager.add_more_space(1)?;
+        let expected = format!("request_ebs_volume 150 gp3 data-{}", clock.unix_secs());
+        assert!(calls.lock().unwrap().contains(&expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile_stops_at_soft_ceiling() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_needing_space();
+        ctx.ebs_manager.config.limits.logical_size_headroom_gb = SizeGib(800);
+        // 100GB mounted, 150GB more would pass the 200GB soft ceiling
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        let err = err.downcast_ref::<LogicalSizeHeadroomReachedError>().unwrap();
+        assert_eq!(err.projected_gib, SizeGib(250));
+        assert_eq!(err.soft_ceiling_gib, SizeGib(200));
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::HeadroomReached);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+
+        assert!(matches!(ctx.ebs_manager.report()?.next_action, NextAction::Blocked { .. }));
+        assert!(ctx.ebs_manager.plan(5).is_empty());
+
+        ctx.ebs_manager.config.limits.logical_size_headroom_gb = SizeGib(750);
+        assert_eq!(ctx.ebs_manager.plan(5).len(), 1);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_ceiling_still_errors_with_headroom() {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::Error);
+        ctx.ebs_manager.config.limits.logical_size_headroom_gb = SizeGib(100);
+        let err = ctx.ebs_manager.reconcile().unwrap_err();
+        assert!(err.is::<MaxLogicalVolumeSizeExceededError>());
+    }
+
+    #[test]
+    fn test_skip_reasons() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::BelowThreshold)
+        );
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
+        assert_eq!(snapshot.skip_reason, Some(SkipReason::BelowThreshold));
+        assert_eq!(serde_json::to_value(&snapshot)?["skip_reason"], "below_threshold");
+
+        ctx.ebs_manager.status_handle().set_paused(true);
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::Paused)
+        );
+        assert_eq!(ctx.ebs_manager.status_handle().snapshot().skip_reason, Some(SkipReason::Paused));
+
+        ctx.ebs_manager.status_handle().set_paused(false);
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            ..disk::MockDiskMgr::default()
+        });
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(ctx.ebs_manager.status_handle().snapshot().skip_reason, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_once_exit_code_below_threshold() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let outcome = ctx.ebs_manager.reconcile()?;
+        assert_eq!(outcome, ReconcileOutcome::NoActionNeeded(SkipReason::BelowThreshold));
+        assert_eq!(outcome.exit_code(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_once_exit_code_paused() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, _) = setup_needing_space();
+        ctx.ebs_manager.status_handle().set_paused(true);
+        let outcome = ctx.ebs_manager.reconcile()?;
+        assert_eq!(outcome, ReconcileOutcome::NoActionNeeded(SkipReason::Paused));
+        assert_eq!(outcome.exit_code(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_once_exit_code_draining() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, _) = setup_needing_space();
+        let drain = drain::Drain::default();
+        ctx.ebs_manager.set_drain(drain.clone());
+        drain.request();
+        let outcome = ctx.ebs_manager.reconcile()?;
+        assert_eq!(outcome, ReconcileOutcome::NoActionNeeded(SkipReason::Draining));
+        assert_eq!(outcome.exit_code(), 5);
+        // Scaling is not a skip, so it exits successfully
+        assert_eq!(ReconcileOutcome::ScaledUp.exit_code(), 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "journald")]
+    #[test]
+    fn test_journal_fields() {
+        let autoscale_event = event::AutoscaleEvent {
+            event: event::EventKind::ScaleUp,
+            instance_id: None,
+            mountpoint: "/mnt/data".to_string(),
+            volume_id: Some("vol-test".to_string()),
+            size_gib: Some(SizeGib(150)),
+            total_size_gib: SizeGib(250),
+            utilization_percent: None,
+        };
+        assert_eq!(journal::event_fields(&autoscale_event), vec![
+            ("MESSAGE", "scale_up on /mnt/data".to_string()),
+            ("PRIORITY", "5".to_string()),
+            ("SYSLOG_IDENTIFIER", "ebs-autoscale-rust".to_string()),
+            ("EVENT", "scale_up".to_string()),
+            ("MOUNTPOINT", "/mnt/data".to_string()),
+            ("TOTAL_SIZE_GIB", "250".to_string()),
+            ("VOLUME_ID", "vol-test".to_string()),
+            ("SIZE_GIB", "150".to_string()),
+        ]);
+
+        let fields = journal::log_fields(
+            &log::Record::builder()
+                .args(format_args!("Low disk space"))
+                .level(log::Level::Warn)
+                .target("ebs_autoscale_rust")
+                .build()
+        );
+        assert_eq!(fields[0], ("MESSAGE", "Low disk space".to_string()));
+        assert_eq!(fields[1], ("PRIORITY", "4".to_string()));
+
+        let encoded = journal::encode(&vec![
+            ("MESSAGE", "one\ntwo".to_string()),
+            ("PRIORITY", "2".to_string()),
+        ]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&7u64.to_le_bytes());
+        expected.extend_from_slice(b"one\ntwo\nPRIORITY=2\n");
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_reconcile_max_volumes_per_tick() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            // Three volumes are needed before utilization drops, each scale-up
+            // re-reads it before creating the volume
+            pending_utilization: vec![95, 95, 95, 95, 95, 95, 50],
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        ctx.ebs_manager.config.max_volumes_per_tick = 1;
+        for tick in 1..=3 {
+            assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+            assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), tick);
+        }
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::BelowThreshold)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_more_space_near_account_quota() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            account_storage_gib: 700,
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.limits.account_storage_quota_gib = Some(SizeGib(1000));
+        // 700GiB used + 150GiB is within 90% of the quota
+        ctx.ebs_manager.add_more_space(1)?;
+
+        ctx.ebs_manager.config.limits.account_quota_max_percent = 80;
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        let err = err.downcast_ref::<AccountQuotaNearlyExhaustedError>().unwrap();
+        assert_eq!(
+            (err.used_gib, err.requested_gib, err.quota_gib),
+            (SizeGib(700), SizeGib(150), SizeGib(1000))
+        );
+        assert_eq!(err.vol_type, "gp3");
+        assert!(err.to_string().starts_with("Account EBS quota nearly exhausted"));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+
+        // Checked against the quota of the type the volume's tier creates
+        ctx.ebs_manager.config.volume_tiers = vec![config::VolumeTier {
+            from_volume_count: 1,
+            vol_type: Some("st1".to_string()),
+            iops: None,
+            throughput: None,
+            size_gib: None,
+        }];
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        let err = err.downcast_ref::<AccountQuotaNearlyExhaustedError>().unwrap();
+        assert_eq!(err.vol_type, "st1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_filesystem_tool_is_named() {
+        let runner = fs::MockCommandRunner {
+            missing: vec!["btrfs".to_string()],
+            ..fs::MockCommandRunner::default()
+        };
+        let state = fs::RebalanceState::new(&test_state_dir("missing-tool"));
+        let err = fs::run_balance(&runner, &state, "/mnt/data").unwrap_err();
+        assert_eq!(err.downcast_ref::<fs::FilesystemToolMissingError>().unwrap().tool, "btrfs");
+        assert!(err.to_string().contains("btrfs"));
+
+        let err = fs::check_tools(&runner, "btrfs").unwrap_err();
+        assert!(err.is::<fs::FilesystemToolMissingError>());
+        assert!(fs::check_tools(&runner, "ext4").is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_volume_creation() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            total_disk_size: 2000,
+            allocator: Some(disk::shared_device_allocator()),
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.sns_topic_arn =
+            Some("arn:aws:sns:us-east-1:123456789012:ebs-autoscale".to_string());
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(10000);
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        ctx.ebs_manager.config.concurrent_volume_creation = true;
+        // 1900GB used needs three 150GB volumes to drop below 80%
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 3);
+        let attached: Vec<String> = calls.lock().unwrap()
+            .iter()
+            .filter_map(|call| call.strip_prefix("attach_ebs_volume vol-test "))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(attached, vec!["/dev/xvdb", "/dev/xvdc", "/dev/xvdd"]);
+        // Every volume is attached before the first is incorporated
+        let first_incorporated = calls.lock().unwrap()
+            .iter()
+            .position(|call| call.starts_with("set_delete_on_termination"))
+            .unwrap();
+        let last_attach = calls.lock().unwrap()
+            .iter()
+            .rposition(|call| call.starts_with("attach_ebs_volume"))
+            .unwrap();
+        assert!(last_attach < first_incorporated);
+        let totals: Vec<u64> = published_events(&calls)
+            .iter()
+            .map(|event| event["total_size_gib"].as_u64().unwrap())
+            .collect();
+        assert_eq!(totals, vec![2150, 2300, 2450]);
+
+        // The batch is capped by max_volumes_per_tick
+        calls.lock().unwrap().clear();
+        ctx.ebs_manager.config.max_volumes_per_tick = 2;
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_volume_creation_attach_failure() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS { attaches_before_failure: Some(1), ..Default::default() };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            total_disk_size: 2000,
+            allocator: Some(disk::shared_device_allocator()),
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.sns_topic_arn =
+            Some("arn:aws:sns:us-east-1:123456789012:ebs-autoscale".to_string());
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(10000);
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        ctx.ebs_manager.config.concurrent_volume_creation = true;
+        assert!(ctx.ebs_manager.add_more_space_concurrently(1).is_err());
+        // Every volume is requested up front, and the ones that cannot be attached deleted
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 3);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "delete_ebs_volume"), 2);
+        // The first is still added to the filesystem
+        assert_eq!(aws::MockAWS::count_calls(&calls, "set_delete_on_termination"), 1);
+        let totals: Vec<u64> = published_events(&calls)
+            .iter()
+            .map(|event| event["total_size_gib"].as_u64().unwrap())
+            .collect();
+        assert_eq!(totals, vec![2150]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_volume_creation_checks_managed_size() -> Result<(), Box<dyn Error>> {
+        let mut volume = managed_volume("vol-big", false);
+        volume.size_gib = SizeGib(9800);
+        let mock_aws = aws::MockAWS { managed_volumes: vec![volume], ..Default::default() };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            total_disk_size: 2000,
+            allocator: Some(disk::shared_device_allocator()),
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(10000);
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        ctx.ebs_manager.config.concurrent_volume_creation = true;
+        // The third 150GB volume would start with 10100GB managed, so it is left out
+        assert_eq!(ctx.ebs_manager.add_more_space_concurrently(1)?.len(), 2);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 2);
+
+        // Nothing is created when not even the first volume fits
+        calls.lock().unwrap().clear();
+        let mut volume = managed_volume("vol-big", false);
+        volume.size_gib = SizeGib(10000);
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS {
+            managed_volumes: vec![volume],
+            calls: calls.clone(),
+            ..Default::default()
+        });
+        let err = ctx.ebs_manager.add_more_space_concurrently(1).unwrap_err();
+        assert!(err.is::<MaxLogicalVolumeSizeExceededError>());
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_volume_creation_expand_failure() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            total_disk_size: 2000,
+            allocator: Some(disk::shared_device_allocator()),
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS {
+            failing_devices: vec!["/dev/xvdb".to_string()],
+            ..fs::MockFS::default()
+        })?;
+        ctx.ebs_manager.config.sns_topic_arn =
+            Some("arn:aws:sns:us-east-1:123456789012:ebs-autoscale".to_string());
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(10000);
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        ctx.ebs_manager.config.concurrent_volume_creation = true;
+        ctx.ebs_manager.config.max_volumes_per_tick = 2;
+        let logs = capture_logs(|| {
+            assert!(ctx.ebs_manager.add_more_space_concurrently(1).is_err());
+        });
+        // The second volume is still expanded onto after the first fails
+        let events: Vec<(String, u64)> = published_events(&calls)
+            .iter()
+            .map(|event| {
+                (event["event"].as_str().unwrap().to_string(),
+                 event["total_size_gib"].as_u64().unwrap())
+            })
+            .collect();
+        assert_eq!(events, vec![
+            ("expand_failed".to_string(), 2000),
+            ("scale_up".to_string(), 2150),
+        ]);
+        assert!(logs.iter().any(|log| log.contains("Added 1 of 2 volumes")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_second_instance_cannot_lock_state() -> Result<(), Box<dyn Error>> {
+        let state_dir = test_state_dir("state-lock");
+        assert!(lock::lock_path(&state_dir, "/mnt/data").ends_with("mnt-data.lock"));
+        assert!(lock::lock_path(&state_dir, "/").ends_with("root.lock"));
+        assert!(lock::lock_path(&state_dir, "/mnt/data-x").ends_with(r"mnt-data\x2dx.lock"));
+        assert_ne!(
+            lock::lock_path(&state_dir, "/mnt/data-x"),
+            lock::lock_path(&state_dir, "/mnt/data/x")
+        );
+
+        let first = lock::StateLock::acquire(&state_dir, "/mnt/data")?;
+        let err = lock::StateLock::acquire(&state_dir, "/mnt/data").err().unwrap();
+        assert_eq!(err.downcast_ref::<lock::StateLockedError>().unwrap().path, first.path());
+        // Other mountpoints have their own lock
+        lock::StateLock::acquire(&state_dir, "/mnt/other")?;
+        drop(first);
+        lock::StateLock::acquire(&state_dir, "/mnt/data")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_json_is_redacted() -> Result<(), Box<dyn Error>> {
+        let mut conf = config::Config::default();
+        conf.assume_role_arn = Some("arn:aws:iam::123456789012:role/ebs-autoscale".to_string());
+        conf.sns_topic_arn = Some("arn:aws:sns:us-east-1:123456789012:ebs-autoscale".to_string());
+        let json = conf.to_redacted_json()?;
+        assert!(!json.contains("123456789012"));
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(value["assume_role_arn"], "<redacted>");
+        assert_eq!(value["sns_topic_arn"], "<redacted>");
+        // Unset settings stay null, so it is clear they are not configured
+        assert!(value["aws_profile"].is_null());
+        assert_eq!(value["mountpoint"], conf.mountpoint.as_str());
+        Ok(())
+    }
+
+    fn scale_up_event() -> event::AutoscaleEvent {
+        event::AutoscaleEvent {
+            event: event::EventKind::ScaleUp,
+            instance_id: Some("i-0123456789abcdef0".to_string()),
+            mountpoint: "/mnt/data".to_string(),
+            volume_id: Some("vol-test".to_string()),
+            size_gib: Some(SizeGib(150)),
+            total_size_gib: SizeGib(250),
+            utilization_percent: None,
+        }
+    }
+
+    #[test]
+    fn test_webhook_notifier_payload() -> Result<(), Box<dyn Error>> {
+        let transport = notify::MockTransport::default();
+        let posts = transport.posts.clone();
+        let notifier = notify::WebhookNotifier {
+            url: "https://hooks.example.com/ebs".to_string(),
+            transport: Box::new(transport),
+        };
+        notify::Notifier::notify(&notifier, scale_up_event())?;
+        let posts = posts.lock().unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].0, "https://hooks.example.com/ebs");
+        let payload: serde_json::Value = serde_json::from_str(&posts[0].1)?;
+        assert_eq!(payload, serde_json::json!({
+            "event": "scale_up",
+            "instance_id": "i-0123456789abcdef0",
+            "mountpoint": "/mnt/data",
+            "volume_id": "vol-test",
+            "size_gib": 150,
+            "total_size_gib": 250,
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_slack_notifier_payload() -> Result<(), Box<dyn Error>> {
+        let payload: serde_json::Value =
+            serde_json::from_str(&notify::SlackNotifier::payload(&scale_up_event())?)?;
+        assert_eq!(payload, serde_json::json!({
+            "text": "Added 150GiB volume vol-test to /mnt/data on i-0123456789abcdef0, now 250GiB",
+        }));
+        let mut limit_event = scale_up_event();
+        limit_event.event = event::EventKind::LimitReached;
+        let payload: serde_json::Value =
+            serde_json::from_str(&notify::SlackNotifier::payload(&limit_event)?)?;
+        assert_eq!(
+            payload["text"],
+            ":warning: /mnt/data on i-0123456789abcdef0 reached its size limit at 250GiB"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale_up_notifies() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let transport = notify::MockTransport::default();
+        let posts = transport.posts.clone();
+        ctx.ebs_manager.notifier = Box::new(notify::SlackNotifier {
+            webhook_url: "https://hooks.slack.com/services/T0/B0/x".to_string(),
+            transport: Box::new(transport),
+        });
+        ctx.ebs_manager.add_more_space(1)?;
+        let posts = posts.lock().unwrap();
+        assert_eq!(posts.len(), 1);
+        assert!(posts[0].1.contains("Added 150GiB volume vol-test"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_volume_mode() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.state_dir = test_state_dir("grow-volume");
+
+        // AddVolume attaches a new volume even when an attached one could grow
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 0);
+
+        calls.lock().unwrap().clear();
+        ctx.ebs_manager.config.scale_mode = config::ScaleMode::GrowVolume;
+        let added = ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(*calls.lock().unwrap(), vec!["modify_volume_size vol-a 160".to_string()]);
+        assert_eq!(added, AddedVolume {
+            volume_id: "vol-a".to_string(),
+            device: "/dev/xvdb".to_string(),
+            size_gib: SizeGib(150),
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_volume_mode_at_max_size() -> Result<(), Box<dyn Error>> {
+        let mut volume = managed_volume("vol-a", false);
+        volume.size_gib = SizeGib(1000);
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![volume],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.state_dir = test_state_dir("grow-volume-max");
+        ctx.ebs_manager.config.scale_mode = config::ScaleMode::GrowVolume;
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(5000);
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_volume_mode_skips_other_instances() -> Result<(), Box<dyn Error>> {
+        let mut volume = managed_volume("vol-other", false);
+        volume.instance_id = Some("i-0fedcba9876543210".to_string());
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![volume],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.state_dir = test_state_dir("grow-volume-other-instance");
+        ctx.ebs_manager.config.scale_mode = config::ScaleMode::GrowVolume;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_btrfs_device() -> Result<(), Box<dyn Error>> {
+        let show = "Label: none  uuid: 4a1b\n\
+                    \tTotal devices 2 FS bytes used 1.00GiB\n\
+                    \tdevid    1 size 10.00GiB used 2.00GiB path /dev/xvdb\n\
+                    \tdevid    2 size 150.00GiB used 1.00GiB path /dev/xvdc\n";
+        assert_eq!(fs::devid_from_show(show, "/dev/xvdc"), Some("2".to_string()));
+        assert_eq!(fs::devid_from_show(show, "/dev/xvdz"), None);
+
+        let runner = fs::MockCommandRunner {
+            outputs: vec![("btrfs filesystem show /mnt/data".to_string(), show.to_string())],
+            ..fs::MockCommandRunner::default()
+        };
+        assert!(fs::grow_filesystem(&runner, "btrfs", "/dev/xvdc", "/mnt/data")?);
+        assert_eq!(*runner.calls.lock().unwrap(), vec![
+            "btrfs filesystem show /mnt/data".to_string(),
+            "btrfs filesystem resize 2:max /mnt/data".to_string(),
+        ]);
+        assert!(fs::grow_filesystem(&runner, "btrfs", "/dev/xvdz", "/mnt/data").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_volume_mode_in_modify_cooldown() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        let state_dir = test_state_dir("modify-cooldown");
+        ctx.ebs_manager.config.state_dir = state_dir.clone();
+        ctx.ebs_manager.config.scale_mode = config::ScaleMode::GrowVolume;
+        let now = clock.unix_secs();
+        history::VolumeModifications::new(&state_dir).record("vol-a", now - 60)?;
+
+        ctx.ebs_manager.trace = Some(trace::DecisionTrace::default());
+        ctx.ebs_manager.add_more_space(1)?;
+        let trace = ctx.ebs_manager.trace.take().unwrap();
+        let gate = trace.gates.iter().find(|gate| gate.name == "modify_cooldown").unwrap();
+        assert_eq!((gate.detail.as_str(), gate.passed), ("1 of 1 volumes in cooldown", false));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        let cooldowns = ctx.ebs_manager.status_handle().snapshot().modify_cooldown_until;
+        let until = now - 60 + history::MODIFY_VOLUME_COOLDOWN_SECS;
+        assert_eq!(cooldowns.get("vol-a"), Some(&until));
+
+        // Once the cooldown is over the volume is grown again
+        clock.advance(Duration::from_secs(history::MODIFY_VOLUME_COOLDOWN_SECS));
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 1);
+        let modified = history::VolumeModifications::new(&state_dir).load()?;
+        assert_eq!(modified["vol-a"], clock.unix_secs());
+        Ok(())
+    }
+
+    #[test]
+    fn test_io2_promotion_after_sustained_iops_utilization() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![
+                aws::ManagedVolume {
+                    vol_type: "io2".to_string(),
+                    ..managed_volume("vol-io2", false)
+                },
+                managed_volume("vol-a", false),
+            ],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.state_dir = test_state_dir("io2-promotion");
+        ctx.ebs_manager.config.io2_promotion = Some(config::Io2Promotion {
+            iops_utilization_threshold: 90,
+            sustained_secs: 600,
+            iops: 16000,
+        });
+        let status = ctx.ebs_manager.status_handle();
+        let promotions = || aws::MockAWS::count_calls(&calls, "modify_volume_type");
+
+        // Nothing is promoted without a fed metric
+        ctx.ebs_manager.reconcile()?;
+        clock.advance(Duration::from_secs(3600));
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(promotions(), 0);
+
+        assert_eq!(status::route("POST", "/iops-utilization/high", &status).status, 400);
+        assert_eq!(status::route("POST", "/iops-utilization/95", &status).status, 200);
+        assert_eq!(status.snapshot().iops_utilization_percent, Some(95));
+        ctx.ebs_manager.reconcile()?;
+        clock.advance(Duration::from_secs(300));
+        status::route("POST", "/iops-utilization/95", &status);
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(promotions(), 0);
+        clock.advance(Duration::from_secs(300));
+        status::route("POST", "/iops-utilization/95", &status);
+        ctx.ebs_manager.reconcile()?;
+        assert!(calls.lock().unwrap().contains(&"modify_volume_type vol-a io2 16000".to_string()));
+        assert_eq!(promotions(), 1);
+
+        // vol-a is in its ModifyVolume cooldown and the other volume is already io2
+        ctx.ebs_manager.reconcile()?;
+        clock.advance(Duration::from_secs(600));
+        status::route("POST", "/iops-utilization/95", &status);
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(promotions(), 1);
+        assert!(status.snapshot().modify_cooldown_until.contains_key("vol-a"));
+
+        // Dropping below the threshold restarts the sustained period
+        status::route("POST", "/iops-utilization/50", &status);
+        ctx.ebs_manager.reconcile()?;
+        assert!(ctx.ebs_manager.high_iops_since.is_none());
+
+        // A sample that is not refreshed expires
+        status::route("POST", "/iops-utilization/95", &status);
+        ctx.ebs_manager.reconcile()?;
+        assert!(ctx.ebs_manager.high_iops_since.is_some());
+        clock.advance(IOPS_SAMPLE_MAX_AGE + Duration::from_secs(1));
+        ctx.ebs_manager.reconcile()?;
+        assert!(ctx.ebs_manager.high_iops_since.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_io2_promotion_skips_other_instances() -> Result<(), Box<dyn Error>> {
+        let mut volume = managed_volume("vol-other", false);
+        volume.instance_id = Some("i-0fedcba9876543210".to_string());
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![volume],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.state_dir = test_state_dir("io2-promotion-other-instance");
+        ctx.ebs_manager.config.io2_promotion = Some(config::Io2Promotion {
+            iops_utilization_threshold: 90,
+            sustained_secs: 0,
+            iops: 16000,
+        });
+        let status = ctx.ebs_manager.status_handle();
+        status::route("POST", "/iops-utilization/95", &status);
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_type"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_cache_serves_a_reconcile_with_one_call() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            ..aws::MockAWS::default()
+        };
+        let describes = mock_aws.describes.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        // Uncached, the report and the reconcile of a tick each list the volumes
+        ctx.ebs_manager.report()?;
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(describes.load(Ordering::Relaxed), 2);
+
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            ..aws::MockAWS::default()
+        };
+        let describes = mock_aws.describes.clone();
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.aws = Box::new(aws::CachingAWS::new(
+            Box::new(mock_aws),
+            Duration::from_secs(5),
+            Box::new(clock.clone()),
+        ));
+        ctx.ebs_manager.report()?;
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(describes.load(Ordering::Relaxed), 1);
+
+        // A change to the volumes drops the cached list
+        ctx.ebs_manager.aws.set_delete_on_termination("/dev/xvdb".to_string(), true)?;
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(describes.load(Ordering::Relaxed), 2);
+
+        // As does the TTL running out
+        clock.advance(Duration::from_secs(5));
+        ctx.ebs_manager.reconcile()?;
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(describes.load(Ordering::Relaxed), 3);
+        Ok(())
+    }
+
+    fn setup_growing_volume(
+        name: &str,
+        states: Vec<aws::ModificationState>,
+    ) -> Result<(Context, Arc<Mutex<Vec<String>>>, clock::MockClock), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            modification_states: [("vol-a".to_string(), states)].into_iter().collect(),
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.state_dir = test_state_dir(name);
+        ctx.ebs_manager.config.scale_mode = config::ScaleMode::GrowVolume;
+        Ok((ctx, calls, clock))
+    }
+
+    #[test]
+    fn test_grow_volume_waits_for_modification() -> Result<(), Box<dyn Error>> {
+        use aws::ModificationState::*;
+        // The first state is the previous modification, checked before modifying again
+        let (mut ctx, calls, clock) = setup_growing_volume(
+            "modification-optimizing",
+            vec![Completed, Modifying, Modifying, Optimizing, Completed],
+        )?;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 1);
+        // Optimizing is enough for the new size to be usable
+        assert_eq!(clock.sleeps.lock().unwrap().len(), 2);
+
+        let (mut ctx, _, clock) = setup_growing_volume(
+            "modification-completed",
+            vec![Completed, Modifying, Optimizing, Optimizing, Completed],
+        )?;
+        ctx.ebs_manager.config.wait_for_modification_completed = true;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(clock.sleeps.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_volume_modification_fails_or_times_out() -> Result<(), Box<dyn Error>> {
+        use aws::ModificationState::*;
+        let (mut ctx, _, _) = setup_growing_volume("modification-failed", vec![Completed, Failed])?;
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        let err = err.downcast_ref::<VolumeModificationError>().unwrap();
+        assert_eq!(err.state, Some(Failed));
+
+        let (mut ctx, _, _) = setup_growing_volume(
+            "modification-timeout",
+            vec![Completed].into_iter().chain(std::iter::repeat(Modifying).take(100)).collect(),
+        )?;
+        ctx.ebs_manager.config.modification_timeout_secs = 20;
+        let err = ctx.ebs_manager.add_more_space(1).unwrap_err();
+        let err = err.downcast_ref::<VolumeModificationError>().unwrap();
+        assert_eq!(err.state, Some(Modifying));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_modification_keeps_polling_on_errors() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS { simulate_aws_err: true, ..aws::MockAWS::default() },
+            fs::MockFS::default(),
+        )?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.modification_timeout_secs = 20;
+        let err = ctx.ebs_manager.wait_for_modification("vol-a").unwrap_err();
+        assert_eq!(err.state, None);
+        assert_eq!(clock.sleeps.lock().unwrap().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_volume_skips_modification_in_progress() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls, _) = setup_growing_volume(
+            "modification-in-progress",
+            vec![aws::ModificationState::Optimizing],
+        )?;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "modify_volume_size"), 0);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_blocks_after_ext4_grow() -> Result<(), Box<dyn Error>> {
+        for (fs_type, expected) in [
+            ("ext4", vec!["resize2fs /dev/xvdb", "tune2fs -m 1 /dev/xvdb"]),
+            ("xfs", vec!["xfs_growfs /mnt/data"]),
+        ] {
+            let runner = fs::MockCommandRunner::default();
+            let calls = runner.calls.clone();
+            let concrete_fs = fs::ConcreteFS {
+                fs_type: fs_type.to_string(),
+                mountpoint: "/mnt/data".to_string(),
+                runner: Box::new(runner),
+                rebalance: fs::RebalanceState::new(&test_state_dir("reserved-blocks")),
+                reserved_blocks_percent: Some(1),
+            };
+            assert!(concrete_fs.grow_device("/dev/xvdb".to_string())?);
+            assert_eq!(*calls.lock().unwrap(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale_up_golden_aws_calls() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                allocator: Some(disk::shared_device_allocator()),
+                ..Default::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default(),
+        )?;
+        let cost_allocation_tags = BTreeMap::from([("team".to_string(), "data".to_string())]);
+        let mut recording = aws::RecordingAWS::new(Box::new(aws::MockAWS::default()));
+        recording.cost_allocation_tags = cost_allocation_tags.clone();
+        let calls = recording.calls.clone();
+        ctx.ebs_manager.aws = Box::new(recording);
+        ctx.ebs_manager.config.mountpoint = "/mnt/data".to_string();
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:123456789012:ebs".to_string());
+        ctx.ebs_manager.config.name_tag_template = Some("data-{seq}".to_string());
+        ctx.ebs_manager.add_more_space(1)?;
+
+        let golden = serde_json::json!([
+            {
+                "call": "create_volume", "availability_zone": "us-east-1a", "size_gib": 150,
+                "vol_type": "gp3", "encrypted": true, "iops": 3000, "throughput": 125,
+                "tags": {
+                    "managed-by": "ebs-autoscale-rust",
+                    "owner-instance-id": "i-0123456789abcdef0",
+                    "Name": "data-2",
+                    "team": "data"
+                }
+            },
+            {
+                "call": "tag_volume", "volume_id": "vol-test", "key": "scale-event-id",
+                "value": "event"
+            },
+            { "call": "attach_volume", "volume_id": "vol-test", "device": "/dev/xvdb" },
+            {
+                "call": "set_delete_on_termination", "device": "/dev/xvdb",
+                "delete_on_termination": true
+            },
+            {
+                "call": "tag_volume", "volume_id": "vol-test",
+                "key": "delete-on-termination", "value": "true"
+            },
+            {
+                "call": "publish_event", "topic_arn": "arn:aws:sns:us-east-1:123456789012:ebs",
+                "message": concat!(
+                    r#"{"event":"scale_up","instance_id":"i-0123456789abcdef0","#,
+                    r#""mountpoint":"/mnt/data","volume_id":"vol-test","#,
+                    r#""size_gib":150,"total_size_gib":250}"#
+                )
+            },
+        ]);
+        let mut recorded = calls.lock().unwrap().clone();
+        // The scale event id is random
+        match &mut recorded[1] {
+            aws::AwsCall::TagVolume { value, .. } => *value = "event".to_string(),
+            call => panic!("Expected the volume to be tagged, got {:?}", call),
+        }
+        assert_eq!(serde_json::to_value(&recorded)?, golden);
+
+        // The log replays to the same calls
+        let log = aws::RecordingAWS::to_log(&recorded)?;
+        assert_eq!(log.lines().count(), 6);
+        // Cost-allocation tags come from the replaying client's config
+        let mut replayed = aws::RecordingAWS::new(Box::new(aws::MockAWS::default()));
+        replayed.cost_allocation_tags = cost_allocation_tags;
+        for call in aws::RecordingAWS::from_log(&log)? {
+            call.replay(&mut replayed)?;
+        }
+        assert_eq!(*replayed.calls.lock().unwrap(), recorded);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_config_rejects_unknown_keys() -> Result<(), Box<dyn Error>> {
+        let toml = r#"
+            detection_intervl = 10
+
+            [limits]
+            max_ebs_volume_count = 5
+            max_ebs_volum_size = 500
+
+            [profiles.prod]
+            moutpoint = "/mnt/data"
+        "#;
+        // Tolerated by default, falling back to the default
+        let conf = config_from_toml(toml)?;
+        assert_eq!(conf.detection_interval, 2);
+        assert_eq!(conf.limits.max_ebs_volume_count, 5);
+        assert!(!conf.strict_config);
+
+        let figment = Figment::from(Serialized::defaults(config::Config::default()))
+            .merge(Toml::string(toml));
+        let err = config::reject_unknown_keys(&figment).unwrap_err();
+        let err = err.downcast_ref::<config::UnknownConfigKeysError>().unwrap();
+        assert_eq!(
+            err.keys,
+            vec!["detection_intervl", "limits.max_ebs_volum_size", "profiles.prod.moutpoint"]
+        );
+
+        let figment = Figment::from(Serialized::defaults(config::Config::default()))
+            .merge(Toml::string(r#"
+                strict_config = true
+                growth_strategy = { FixedGb = 100 }
+
+                [volume]
+                iops = 4000
+
+                [cost_allocation_tags]
+                team = "storage"
+
+                [profiles.prod.cost_allocation_tags]
+                cost-center = "cc-42"
+            "#));
+        config::reject_unknown_keys(&figment)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_tiers_change_type_across_boundaries() -> Result<(), Box<dyn Error>> {
+        let conf = config_from_toml(r#"
+            [[volume_tiers]]
+            from_volume_count = 0
+
+            [[volume_tiers]]
+            from_volume_count = 3
+            vol_type = "st1"
+            size_gib = 500
+        "#)?;
+        conf.validate()?;
+        let (mut ctx, calls) = setup_needing_space();
+        ctx.ebs_manager.config = conf;
+        ctx.ebs_manager.attached_volume_count = Some(2);
+
+        ctx.ebs_manager.add_more_space(2)?;
+        ctx.ebs_manager.add_more_space(3)?;
+        ctx.ebs_manager.add_more_space(4)?;
+        let requests: Vec<String> = calls.lock().unwrap()
+            .iter()
+            .filter(|call| call.starts_with("request_ebs_volume"))
+            .cloned()
+            .collect();
+        assert_eq!(requests, vec![
+            "request_ebs_volume 150 gp3",
+            "request_ebs_volume 500 st1",
+            "request_ebs_volume 500 st1",
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_tiers_validation() {
+        let mut conf = config::Config::default();
+        conf.volume_tiers = vec![
+            config::VolumeTier {
+                from_volume_count: 4,
+                vol_type: Some("st1".to_string()),
+                iops: None,
+                throughput: None,
+                size_gib: None,
+            },
+            config::VolumeTier {
+                from_volume_count: 2,
+                vol_type: None,
+                iops: None,
+                throughput: None,
+                size_gib: Some(SizeGib(300)),
+            },
+        ];
+        let err = conf.validate().unwrap_err();
+        let err = err.downcast_ref::<config::VolumeTierOrderError>().unwrap();
+        assert_eq!((err.from_volume_count, err.previous), (2, 4));
+
+        conf.volume_tiers.swap(0, 1);
+        conf.volume.iops = Some(6000);
+        assert!(conf.validate().is_ok());
+        // gp3 iops and throughput are not carried over to st1
+        let volume = conf.volume_for(5);
+        assert_eq!((volume.vol_type.as_str(), volume.iops), ("st1", None));
+        assert_eq!(conf.volume_for(1).vol_type, "gp3");
+
+        conf.volume_tiers[1].vol_type = Some("io2".to_string());
+        let err = conf.validate().unwrap_err();
+        assert!(err.is::<config::MissingVolumeSettingError>());
+    }
+
+    #[test]
+    fn test_size_gib_conversions() {
+        assert_eq!(SizeGib::from_bytes(1 << 30), SizeGib(1));
+        // A partial GiB does not count
+        assert_eq!(SizeGib::from_bytes((150 << 30) - 1), SizeGib(149));
+        assert_eq!(SizeGib(2).as_bytes(), Some(2 << 30));
+        assert_eq!(SizeGib(u64::MAX).as_bytes(), None);
+        // 100GB is 93.13GiB, so holding it takes 94GiB
+        assert_eq!(SizeGib::from_gb_rounded_up(100), SizeGib(94));
+        assert_eq!(SizeGib::from_gb_rounded_up(0), SizeGib::ZERO);
+        assert_eq!(SizeGib::from(150u32), SizeGib(150));
+        assert_eq!(u64::from(SizeGib(150)), 150);
+        assert_eq!(SizeGib(150).to_string(), "150GiB");
+
+        assert!(SizeGib(150) < SizeGib(1000));
+        assert_eq!(SizeGib(1000).percent(80), SizeGib(800));
+        assert_eq!(SizeGib(100).saturating_sub(SizeGib(150)), SizeGib::ZERO);
+        assert_eq!(SizeGib(u64::MAX).saturating_add(SizeGib(1)), SizeGib(u64::MAX));
+        assert_eq!(SizeGib(u64::MAX).checked_add(SizeGib(1)), None);
+        // Plain numbers in config files and JSON output
+        let limits = config_from_toml("[limits]\nmax_ebs_volume_size = 500").unwrap().limits;
+        assert_eq!(limits.max_ebs_volume_size, SizeGib(500));
+        assert_eq!(serde_json::to_value(SizeGib(500)).unwrap(), 500);
+    }
+
+    #[test]
+    fn test_mount_unit_overrides_mountpoint() -> Result<(), Box<dyn Error>> {
+        let contents = r#"
+            [Unit]
+            Description=Autoscaled data volume
+            Where=/not/this
+
+            [Mount]
+            # Overridden below
+            Where=/mnt/old
+            What=/dev/mapper/data-lv
+            Where=/mnt/data
+            Type=xfs
+            Options=defaults,noatime
+
+            [Install]
+            WantedBy=multi-user.target
+        "#;
+        let unit = config::MountUnit::parse("mnt-data.mount", contents)?;
+        assert_eq!(unit.what, "/dev/mapper/data-lv");
+        assert_eq!(unit.mount_where, "/mnt/data");
+
+        let err = config::MountUnit::parse("mnt-data.mount", "[Mount]\nWhat=/dev/xvdb\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("no Where="));
+        let by_uuid = "[Mount]\nWhat=UUID=4a1b\nWhere=/mnt/data\n";
+        assert_eq!(config::MountUnit::parse("mnt-data.mount", by_uuid)?.what, "UUID=4a1b");
+        let nfs = "[Mount]\nWhat=fileserver:/export\nWhere=/mnt/data\n";
+        let err = config::MountUnit::parse("mnt-data.mount", nfs).unwrap_err();
+        assert!(err.to_string().contains("not a block device"));
+
+        let dir = test_state_dir("mount-unit");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(format!("{}/mnt-data.mount", dir), contents)?;
+        let unit = config::MountUnit::load("mnt-data.mount", &["/nonexistent", &dir])?;
+        assert_eq!(unit.mount_where, "/mnt/data");
+        assert!(config::MountUnit::load("mnt-other.mount", &[&dir]).is_err());
+
+        let mut conf = config_from_toml(r#"mount_unit = "mnt-data.mount""#)?;
+        assert_eq!(conf.mount_unit.as_deref(), Some("mnt-data.mount"));
+        let unit = conf.apply_mount_unit(&[&dir])?.unwrap();
+        assert_eq!(unit.what, "/dev/mapper/data-lv");
+        assert_eq!(conf.mountpoint, "/mnt/data");
+        Ok(())
+    }
+
+    #[test]
+    fn test_performance_target_picks_cheapest_volume() -> Result<(), Box<dyn Error>> {
+        let volume = config::Config::default().volume;
+        let cheapest = |iops: u64, throughput: u64| {
+            let target = config::PerformanceTarget { iops, throughput };
+            let volume = cost::cheapest_volume(&volume, target);
+            (volume.vol_type, volume.iops, volume.throughput)
+        };
+        // The gp3 baseline is used whenever it meets the target
+        assert_eq!(cheapest(3000, 125), ("gp3".to_string(), Some(3000), Some(125)));
+        assert_eq!(cheapest(1000, 100), ("gp3".to_string(), Some(3000), Some(125)));
+        // Throughput needs 1 IOPS per 0.25MiB/s on top of the throughput itself
+        assert_eq!(cheapest(1000, 1000), ("gp3".to_string(), Some(4000), Some(1000)));
+        assert_eq!(cheapest(6000, 500), ("gp3".to_string(), Some(6000), Some(500)));
+        // Never st1, even where its size-based throughput would meet the target
+        assert_eq!(cheapest(200, 500), ("gp3".to_string(), Some(3000), Some(500)));
+
+        let (mut ctx, _) = setup_needing_space();
+        let recording = aws::RecordingAWS::new(Box::new(aws::MockAWS::default()));
+        let calls = recording.calls.clone();
+        ctx.ebs_manager.aws = Box::new(recording);
+        ctx.ebs_manager.config.volume.vol_type = "gp2".to_string();
+        ctx.ebs_manager.config.performance_target =
+            Some(config::PerformanceTarget { iops: 5000, throughput: 250 });
+        ctx.ebs_manager.add_more_space(1)?;
+        assert!(matches!(
+            &calls.lock().unwrap()[0],
+            aws::AwsCall::CreateVolume { vol_type, iops: Some(5000), throughput: Some(250), .. }
+                if vol_type == "gp3"
+        ));
+
+        let mut conf = config::Config::default();
+        conf.performance_target = Some(config::PerformanceTarget { iops: 20000, throughput: 250 });
+        let err = conf.validate().unwrap_err();
+        assert!(err.is::<config::UnreachablePerformanceTargetError>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log_records_scale_up() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, _) = setup_needing_space();
+        let dir = test_state_dir("audit-scale-up");
+        let path = format!("{}/audit.jsonl", dir);
+        let mut conf = ctx.ebs_manager.config.clone();
+        conf.audit_log_path = Some(path.clone());
+        ctx.ebs_manager.reload_config(conf);
+        ctx.ebs_manager.add_more_space(1)?;
+
+        let entries = audit::AuditLog::new(&path).read()?;
+        let actions: Vec<&str> = entries.iter().map(|entry| entry.action.as_str()).collect();
+        assert_eq!(actions, vec!["create_volume", "attach_volume", "expand_filesystem"]);
+        assert!(entries.iter().all(|entry| entry.outcome == audit::AuditOutcome::Success));
+        assert_eq!(entries[0].params["vol_type"], "gp3");
+        assert_eq!(entries[0].params["size_gib"], 150);
+        assert_eq!(entries[0].result, "vol-test");
+        assert_eq!(entries[1].params["volume_id"], "vol-test");
+        assert_eq!(entries[1].result, "/dev/test");
+        assert_eq!(entries[2].params["device"], "/dev/test");
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log_records_failed_attach() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, _) = setup_needing_space();
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS {
+            fail_attach: true,
+            ..aws::MockAWS::default()
+        });
+        let dir = test_state_dir("audit-failed-attach");
+        let path = format!("{}/audit.jsonl", dir);
+        ctx.ebs_manager.audit_log = Some(audit::AuditLog::new(&path));
+        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+
+        let entries = audit::AuditLog::new(&path).read()?;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].action, "create_volume");
+        assert_eq!(entries[0].outcome, audit::AuditOutcome::Success);
+        assert_eq!(entries[1].action, "attach_volume");
+        assert_eq!(entries[1].outcome, audit::AuditOutcome::Failure);
+        assert_eq!(entries[1].result, serde_json::Value::Null);
+        assert!(entries[1].error.is_some());
+        assert!(entries[1].timestamp > 0);
+        // The unattached volume is deleted again
+        assert_eq!(entries[2].action, "delete_volume");
+        assert_eq!(entries[2].outcome, audit::AuditOutcome::Success);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encryption_by_default_conflict_warns() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS { encryption_by_default: true, ..aws::MockAWS::default() },
+            fs::MockFS::default(),
+        )?;
+        ctx.ebs_manager.config.volume.encrypted = false;
+        let logs = capture_logs(|| {
+            ctx.ebs_manager.power_on_self_test().unwrap();
+        });
+        assert!(logs.iter().any(|log| log.contains("EBS encryption by default is enabled")));
+        assert!(logs.iter().any(|log| log == "New volumes will be encrypted"));
+        assert_eq!(ctx.ebs_manager.effective_encryption(), Some(true));
+        assert_eq!(ctx.ebs_manager.report()?.encrypted, Some(true));
+
+        // No conflict when the config asks for encryption too
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS { encryption_by_default: true, ..aws::MockAWS::default() },
+            fs::MockFS::default(),
+        )?;
+        let logs = capture_logs(|| {
+            ctx.ebs_manager.power_on_self_test().unwrap();
+        });
+        assert!(!logs.iter().any(|log| log.contains("EBS encryption by default is enabled")));
+
+        ctx.ebs_manager.config.volume.encrypted = false;
+        ctx.ebs_manager.encryption_by_default = None;
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS::default());
+        assert_eq!(ctx.ebs_manager.report()?.encrypted, Some(false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_rate_triggers_scaling_below_threshold() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                // 1% a minute, then 19% a minute
+                pending_utilization: vec![10, 11, 30],
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        ctx.ebs_manager.config.fill_rate_horizon_secs = Some(600);
+
+        // No previous sample yet
+        assert!(!ctx.ebs_manager.need_more_space()?);
+        clock.advance(Duration::from_secs(60));
+        // Full in 5340s
+        assert!(!ctx.ebs_manager.need_more_space()?);
+        clock.advance(Duration::from_secs(60));
+        // Full in 221s, well under the 80% threshold
+        assert!(ctx.ebs_manager.need_more_space()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_rate_ignored_without_horizon() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr {
+                pending_utilization: vec![10, 30],
+                ..disk::MockDiskMgr::default()
+            },
+            aws::MockAWS::default(),
+            fs::MockFS::default()
+        )?;
+        let clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(clock.clone());
+        assert!(!ctx.ebs_manager.need_more_space()?);
+        clock.advance(Duration::from_secs(60));
+        assert!(!ctx.ebs_manager.need_more_space()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_range_env_value_names_the_variable() {
+        std::env::set_var("EBS_AUTOSCALE_RANGE_TEST_DETECTION_INTERVAL", "600");
+        let figment = Figment::from(Serialized::defaults(config::Config::default()))
+            .merge(Env::prefixed("EBS_AUTOSCALE_RANGE_TEST_"));
+        let err = config::extract(&figment).unwrap_err();
+        std::env::remove_var("EBS_AUTOSCALE_RANGE_TEST_DETECTION_INTERVAL");
+        let err = err.downcast_ref::<config::InvalidConfigValueError>().unwrap();
+        assert_eq!(err.key, "EBS_AUTOSCALE_RANGE_TEST_DETECTION_INTERVAL");
+        assert!(err.value.contains("600"), "{}", err.value);
+        assert!(err.to_string().ends_with("expected a whole number from 0 to 255"), "{}", err);
+    }
+
+    #[test]
+    fn test_scale_event_id_shared_by_the_volumes_of_one_event() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            total_disk_size: 2000,
+            allocator: Some(disk::shared_device_allocator()),
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(10000);
+        ctx.ebs_manager.config.scale_until_below_threshold = true;
+        ctx.ebs_manager.config.concurrent_volume_creation = true;
+        ctx.ebs_manager.config.max_volumes_per_tick = 2;
+        let event_ids = || -> Vec<String> {
+            calls.lock().unwrap()
+                .iter()
+                .filter_map(|call| call.strip_prefix("tag_volume vol-test scale-event-id "))
+                .map(str::to_string)
+                .collect()
+        };
+
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        let first = event_ids();
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0], first[1]);
+
+        calls.lock().unwrap().clear();
+        ctx.ebs_manager.add_more_space(3)?;
+        let second = event_ids();
+        assert_eq!(second.len(), 1);
+        assert_ne!(second[0], first[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_size_predictor() -> Result<(), Box<dyn Error>> {
+        struct FixedPredictor(Arc<Mutex<Vec<predict::SizeContext>>>);
+
+        impl predict::SizePredictor for FixedPredictor {
+            fn predict(&self, context: &predict::SizeContext) -> SizeGib {
+                self.0.lock().unwrap().push(*context);
+                SizeGib(42)
+            }
+        }
+
+        let (mut ctx, calls) = setup_needing_space();
+        let contexts = Arc::new(Mutex::new(Vec::new()));
+        ctx.ebs_manager.set_size_predictor(Box::new(FixedPredictor(contexts.clone())));
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert!(calls.lock().unwrap().contains(&"request_ebs_volume 42 gp3".to_string()));
+        let context = contexts.lock().unwrap()[0];
+        assert_eq!(context.current_size, SizeGib(100));
+        assert_eq!(context.utilization_percent, Some(95));
+        assert_eq!(context.fill_rate, None);
+
+        // Still within the volume size limits
+        ctx.ebs_manager.config.limits.min_ebs_volume_size = SizeGib(50);
+        assert_eq!(ctx.ebs_manager.calc_new_size(1, SizeGib(100)), SizeGib(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale_skipped_when_utilization_drops_before_create() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr {
+            // A large temp file is deleted right after detection
+            pending_utilization: vec![95, 40, 95, 79],
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default())?;
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::NoLongerNeeded);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+
+        // Within the margin below the threshold the volume is still created
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_allocator_quarantines_released_names() {
+        let clock = clock::MockClock::default();
+        let mut allocator = disk::DeviceAllocator::with_quarantine(
+            disk::DEVICE_PREFIX,
+            Duration::from_secs(60),
+            Box::new(clock.clone()),
+        );
+        let candidates = disk::candidate_device_names(disk::DEVICE_PREFIX);
+        let all_but_xvdb: Vec<String> = candidates[1..].to_vec();
+        assert_eq!(allocator.next_free(&all_but_xvdb).unwrap(), "/dev/xvdb");
+
+        allocator.release("/dev/xvdb");
+        assert!(allocator.next_free(&all_but_xvdb).is_err());
+        clock.advance(Duration::from_secs(59));
+        assert!(allocator.next_free(&all_but_xvdb).is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(allocator.next_free(&all_but_xvdb).unwrap(), "/dev/xvdb");
+    }
+
+    #[test]
+    fn test_fs_grow_duration_recorded_by_fs_type() -> Result<(), Box<dyn Error>> {
+        /// Moves time on by a step whenever it is read
+        struct SteppingClock {
+            clock: clock::MockClock,
+            step: Duration,
+        }
+
+        impl clock::Clock for SteppingClock {
+            fn now(&self) -> Instant {
+                self.clock.advance(self.step);
+                self.clock.now()
+            }
+
+            fn unix_secs(&self) -> u64 {
+                self.clock.unix_secs()
+            }
+
+            fn sleep(&self, duration: Duration) {
+                self.clock.sleep(duration)
+            }
+        }
+
+        let (mut ctx, _) = setup_needing_space();
+        ctx.ebs_manager.clock = Box::new(SteppingClock {
+            clock: clock::MockClock::default(),
+            step: Duration::from_secs(7),
+        });
+        ctx.ebs_manager.config.fs_type = "xfs".to_string();
+        ctx.ebs_manager.add_more_space(1)?;
+
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
+        assert_eq!(snapshot.fs_grow_duration_seconds.get("xfs"), Some(&7.0));
+        let response = status::route("GET", "/metrics", &ctx.ebs_manager.status_handle());
+        assert_eq!(response.content_type, "text/plain; version=0.0.4");
+        assert!(response.body.contains(
+            "ebs_autoscale_fs_grow_duration_seconds{fs_type=\"xfs\"} 7\n"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_adaptive_polling_interval_shortens_near_threshold() -> Result<(), Box<dyn Error>> {
+        let strategy = config::PollingStrategy::Adaptive { min_secs: 5, max_secs: 65 };
+        let base = Duration::from_secs(2);
+        let interval = |utilization| strategy.interval(base, utilization, 80).as_secs();
+        assert_eq!(interval(None), 65);
+        assert_eq!(interval(Some(0)), 65);
+        assert_eq!(interval(Some(40)), 35);
+        assert_eq!(interval(Some(60)), 20);
+        assert_eq!(interval(Some(80)), 5);
+        assert_eq!(interval(Some(95)), 5);
+        assert_eq!(config::PollingStrategy::Fixed.interval(base, Some(95), 80), base);
+
+        let mut ctx = setup(disk::MockDiskMgr {
+            pending_utilization: vec![20, 70],
+            ..disk::MockDiskMgr::default()
+        }, aws::MockAWS::default(), fs::MockFS::default())?;
+        ctx.ebs_manager.config.polling_strategy = strategy;
+        ctx.ebs_manager.reconcile()?;
+        let relaxed = ctx.ebs_manager.detection_interval();
+        ctx.ebs_manager.reconcile()?;
+        assert!(ctx.ebs_manager.detection_interval() < relaxed);
+
+        let mut conf = config::Config::default();
+        conf.polling_strategy = config::PollingStrategy::Adaptive { min_secs: 30, max_secs: 10 };
+        assert!(conf.validate().unwrap_err().is::<config::AdaptivePollingRangeError>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remaining_capacity_by_limit() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-a", false)],
+            account_storage_gib: 900,
+            ..aws::MockAWS::default()
+        };
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.attached_volume_count = Some(3);
+        let limits = &mut ctx.ebs_manager.config.limits;
+        limits.max_logical_volume_size = SizeGib(1000);
+        limits.max_ebs_volume_count = 5;
+
+        // 100GB mounted below a 1000GB logical volume
+        let capacity = ctx.ebs_manager.remaining_capacity()?;
+        assert_eq!(capacity.by_size_gib, SizeGib(900));
+        assert_eq!(capacity.by_count, 2);
+        assert_eq!(capacity.by_cost, None);
+
+        // The headroom lowers the size ceiling
+        ctx.ebs_manager.config.limits.logical_size_headroom_gb = SizeGib(300);
+        assert_eq!(ctx.ebs_manager.remaining_capacity()?.by_size_gib, SizeGib(600));
+
+        // 900GB of the account's 1000GB quota is used, up to 95% of it may be
+        ctx.ebs_manager.config.limits.account_storage_quota_gib = Some(SizeGib(1000));
+        ctx.ebs_manager.config.limits.account_quota_max_percent = 95;
+        assert_eq!(ctx.ebs_manager.remaining_capacity()?.by_size_gib, SizeGib(50));
+
+        // vol-a is 10GB of gp3 at baseline performance
+        ctx.ebs_manager.config.limits.max_monthly_cost_usd = Some(10.0);
+        let by_cost = ctx.ebs_manager.remaining_capacity()?.by_cost.unwrap();
+        let vol_a_cost = cost::monthly_cost("gp3", SizeGib(10), Some(3000), Some(125))?;
+        assert!((by_cost - (10.0 - vol_a_cost)).abs() < 1e-9);
+
+        ctx.ebs_manager.attached_volume_count = Some(7);
+        assert_eq!(ctx.ebs_manager.remaining_capacity()?.by_count, 0);
+
+        ctx.ebs_manager.reconcile()?;
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
+        assert_eq!(snapshot.remaining_capacity.unwrap().by_size_gib, SizeGib(50));
+        let metrics = status::metrics(&ctx.ebs_manager.status_handle().snapshot());
+        assert!(metrics.contains("ebs_autoscale_remaining_capacity_gib 50\n"), "{}", metrics);
+        assert!(metrics.contains("ebs_autoscale_remaining_volumes 0\n"), "{}", metrics);
+
+        // Ticks that change nothing keep the last capacity, a config reload recomputes it
+        ctx.ebs_manager.attached_volume_count = Some(3);
+        ctx.ebs_manager.reconcile()?;
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
+        assert_eq!(snapshot.remaining_capacity.unwrap().by_count, 0);
+        let conf = ctx.ebs_manager.config.clone();
+        ctx.ebs_manager.reload_config(conf);
+        ctx.ebs_manager.reconcile()?;
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
+        assert_eq!(snapshot.remaining_capacity.unwrap().by_count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_statsd_metrics_sent_over_udp() -> Result<(), Box<dyn Error>> {
+        let agent = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        agent.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut conf = config::Config {
+            statsd_addr: Some(agent.local_addr()?.to_string()),
+            statsd_tags: vec!["env:test".to_string()],
+            ..config::Config::default()
+        };
+        conf.limits.max_logical_volume_size = SizeGib(1000);
+        conf.limits.max_ebs_volume_count = 5;
+        let mut ctx = setup_with_config(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default(),
+            Some(conf),
+        )?;
+        ctx.ebs_manager.attached_volume_count = Some(3);
+        ctx.ebs_manager.status_handle().update(|snapshot| {
+            snapshot.fs_grow_duration_seconds.insert("btrfs".to_string(), 1.5);
+        });
+        ctx.ebs_manager.reconcile()?;
+
+        let mut buf = [0u8; 2048];
+        let len = agent.recv(&mut buf)?;
+        let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+        let tags = format!("mountpoint:{},env:test", ctx.ebs_manager.config.mountpoint);
+        let lines: Vec<&str> = packet.lines().collect();
+        assert_eq!(lines, vec![
+            format!("ebs_autoscale_fs_grow_duration_seconds:1.5|g|#{},fs_type:btrfs", tags),
+            format!("ebs_autoscale_remaining_capacity_gib:900|g|#{}", tags),
+            format!("ebs_autoscale_remaining_volumes:2|g|#{}", tags),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_limiter_caps_concurrent_calls() {
+        let limiter = aws::ApiLimiter::new(2);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let (in_flight, peak) = (in_flight.clone(), peak.clone());
+                thread::spawn(move || {
+                    let _permit = limiter.acquire();
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+
+        // Calls through the wrapper give their slot back
+        let mut aws_cli: Box<dyn aws::AWS> = Box::new(aws::LimitedAWS::new(
+            Box::new(aws::MockAWS::default()),
+            aws::ApiLimiter::new(1),
+        ));
+        for _ in 0..3 {
+            aws_cli.get_managed_ebs_volumes().unwrap();
+        }
+
+        // A batch larger than the limiter is sent in groups that fit
+        let request = aws::VolumeRequest {
+            size: SizeGib(100),
+            vol_type: "gp3".to_string(),
+            encrypted: true,
+            iops: None,
+            throughput: None,
+            name: None,
+        };
+        let created = aws_cli.request_ebs_volumes(vec![request; 3]);
+        assert_eq!(created.len(), 3);
+        assert!(created.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn test_decision_trace_lists_gates_in_order() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::AlertAndContinue);
+        let (outcome, trace) = ctx.ebs_manager.reconcile_traced();
+        assert_eq!(outcome?, ReconcileOutcome::MaxSizeReached);
+        assert_eq!(trace.summary(), vec![
+            ("drain", true),
+            ("observe_only", true),
+            ("paused", true),
+            ("quiesced", true),
+            ("utilization_threshold", true),
+            ("max_ebs_volume_count", true),
+            ("max_logical_volume_size", false),
+        ]);
+        assert_eq!(trace.gates[4].detail, "utilization 95% / threshold 80%");
+        assert_eq!(trace.outcome.as_deref(), Some("MaxSizeReached"));
+
+        // Untraced reconciles record nothing
+        ctx.ebs_manager.reconcile()?;
+        assert!(ctx.ebs_manager.trace.is_none());
+
+        // Once shutdown is requested the reconcile stops at the drain gate
+        let drain = drain::Drain::default();
+        ctx.ebs_manager.set_drain(drain.clone());
+        drain.request();
+        let (outcome, trace) = ctx.ebs_manager.reconcile_traced();
+        assert_eq!(outcome?, ReconcileOutcome::NoActionNeeded(SkipReason::Draining));
+        assert_eq!(trace.summary(), vec![("drain", false)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_autoscaler_refuses_to_start() {
+        let lister = lock::MockProcessLister {
+            processes: vec![
+                lock::Process { pid: 1, cmdline: "/sbin/init".to_string() },
+                lock::Process {
+                    pid: 42,
+                    cmdline: "/usr/local/bin/ebs-autoscale-rust --report".to_string(),
+                },
+            ],
+        };
+        assert!(lock::check_conflicting_autoscalers(&lister).is_ok());
+
+        let lister = lock::MockProcessLister {
+            processes: vec![lock::Process {
+                pid: 1234,
+                cmdline: "/bin/bash /usr/local/amazon-ebs-autoscale/bin/ebs-autoscale".to_string(),
+            }],
+        };
+        let err = lock::check_conflicting_autoscalers(&lister).unwrap_err();
+        let conflict = err.downcast_ref::<lock::ConflictingAutoscalerError>().unwrap();
+        assert_eq!(conflict.pid, 1234);
+        assert!(err.to_string().contains("--force"), "{}", err);
+    }
+
+    #[test]
+    fn test_cost_allocation_tags_at_create() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS::default(),
+            fs::MockFS::default(),
+        )?;
+        ctx.ebs_manager.config.required_cost_allocation_tags =
+            vec!["cost-center".to_string(), "team".to_string()];
+        ctx.ebs_manager.config.cost_allocation_tags =
+            [("team".to_string(), "storage".to_string())].into_iter().collect();
+        let err = ctx.ebs_manager.power_on_self_test().unwrap_err();
+        let missing = err.downcast_ref::<MissingCostAllocationTagsError>().unwrap();
+        assert_eq!(missing.keys, vec!["cost-center".to_string()]);
+
+        ctx.ebs_manager.config.cost_allocation_tags
+            .insert("cost-center".to_string(), "cc-42".to_string());
+        assert!(ctx.ebs_manager.power_on_self_test()?);
+
+        let spec = aws::volume_tag_specification(
+            "i-0123456789abcdef0", Some("data-1"), &ctx.ebs_manager.config.cost_allocation_tags
+        );
+        let tags: Vec<(&str, &str)> = spec.tags().iter()
+            .map(|tag| (tag.key().unwrap(), tag.value().unwrap()))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                (aws::MANAGED_BY_TAG, aws::MANAGED_BY_TAG_VALUE),
+                (aws::OWNER_TAG, "i-0123456789abcdef0"),
+                ("Name", "data-1"),
+                ("cost-center", "cc-42"),
+                ("team", "storage"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_panic_reserve_allocated_and_released_once() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_needing_space();
+        let state_dir = test_state_dir("panic-reserve");
+        std::fs::create_dir_all(&state_dir)?;
+        let path = std::path::Path::new(&state_dir).join("panic.reserve");
+        ctx.ebs_manager.config.panic_reserve_path = Some(path.to_string_lossy().into_owned());
+        ctx.ebs_manager.config.panic_reserve_size_mib = 1;
+        ctx.ebs_manager.config.panic_reserve_threshold_percent = 99;
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:123456789012:ebs".to_string());
+        // The logical volume is already at its max size, so scaling cannot help
+        ctx.ebs_manager.config.limits.max_logical_volume_size = SizeGib(100);
+        ctx.ebs_manager.config.on_max_size = config::OnMaxSize::AlertAndContinue;
+
+        ctx.ebs_manager.allocate_panic_reserve()?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 1024 * 1024);
+        ctx.ebs_manager.allocate_panic_reserve()?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 1024 * 1024);
+
+        // 95% is below the panic threshold
+        ctx.ebs_manager.reconcile()?;
+        assert!(path.exists());
+
+        ctx.ebs_manager.config.panic_reserve_threshold_percent = 95;
+        let logs = capture_logs(|| { ctx.ebs_manager.reconcile().unwrap(); });
+        assert!(!path.exists());
+        assert!(logs.iter().any(|log| log.starts_with("CRITICAL: /")), "{:?}", logs);
+        let released = |calls: &Arc<Mutex<Vec<String>>>| published_events(calls)
+            .into_iter()
+            .filter(|event| event["event"] == "panic_reserve_released")
+            .count();
+        assert_eq!(released(&calls), 1);
+
+        // Only released once
+        ctx.ebs_manager.allocate_panic_reserve()?;
+        ctx.ebs_manager.reconcile()?;
+        assert!(path.exists());
+        assert_eq!(released(&calls), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import_state_round_trip() -> Result<(), Box<dyn Error>> {
+        let managed_volumes = vec![managed_volume("vol-a", false), managed_volume("vol-b", false)];
+        let source_dir = test_state_dir("export-state");
+        let mut source = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS { managed_volumes: managed_volumes.clone(), ..aws::MockAWS::default() },
+            fs::MockFS::default(),
+        )?;
+        source.ebs_manager.config.state_dir = source_dir.clone();
+        source.ebs_manager.attached_volume_count = Some(2);
+        history::VolumeModifications::new(&source_dir).record("vol-a", 1_700_000_000)?;
+        fs::RebalanceState::new(&source_dir).mark_pending("/dev/xvdba")?;
+        let exported = source.ebs_manager.export_state()?;
+        assert_eq!(exported.version, migrate::STATE_VERSION);
+        assert_eq!(exported.volume_count, 2);
+        let json = exported.to_json()?;
+
+        let target_dir = test_state_dir("import-state");
+        let mut target = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS { managed_volumes, ..aws::MockAWS::default() },
+            fs::MockFS::default(),
+        )?;
+        target.ebs_manager.config.state_dir = target_dir.clone();
+        target.ebs_manager.attached_volume_count = Some(2);
+        target.ebs_manager.import_state(&migrate::DaemonState::from_json(&json)?)?;
+        assert_eq!(target.ebs_manager.export_state()?, exported);
+        assert_eq!(
+            history::VolumeModifications::new(&target_dir).load()?.get("vol-a"),
+            Some(&1_700_000_000)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_state_rejects_unknown_version() {
+        let json = r#"{"version": 99, "mountpoint": "/mnt/data"}"#;
+        let err = migrate::DaemonState::from_json(json).unwrap_err();
+        let incompatible = err.downcast_ref::<migrate::IncompatibleStateVersionError>().unwrap();
+        assert_eq!(incompatible.version, 99);
+    }
 
-impl fmt::Display for MaxEBSCountExceededError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Maximum number of EBS volumes exceeded")
+    #[test]
+    fn test_instance_tags_override_config_file() -> Result<(), Box<dyn Error>> {
+        let tags = [
+            ("Name", "web-1"),
+            ("ebs-autoscale:mountpoint", "/mnt/tagged"),
+            ("ebs-autoscale:limits.max_ebs_volume_count", "20"),
+        ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let figment = Figment::from(Serialized::defaults(config::Config::default()))
+            .merge(Toml::string(r#"
+                mountpoint = "/mnt/data"
+                detection_interval = 5
+
+                [limits]
+                max_ebs_volume_count = 10
+            "#))
+            .merge(config::TagProvider { tags });
+        let config = config::extract(&figment)?;
+        assert_eq!(config.mountpoint, "/mnt/tagged");
+        assert_eq!(config.limits.max_ebs_volume_count, 20);
+        // Keys without a tag keep the file values
+        assert_eq!(config.detection_interval, 5);
+        assert_eq!(
+            config.limits.min_ebs_volume_size,
+            config::Config::default().limits.min_ebs_volume_size
+        );
+        Ok(())
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct MaxLogicalVolumeSizeExceededError;
+    #[test]
+    fn test_attached_device_matched_by_volume_id() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            disk::volume_id_from_serial("vol0123456789abcdef0\n"),
+            Some("vol-0123456789abcdef0".to_string())
+        );
+        assert_eq!(disk::volume_id_from_serial("AWS12345"), None);
 
-impl Error for MaxLogicalVolumeSizeExceededError {}
+        // Another attach made at the same time shows up first under the device name
+        let (mut ctx, calls) = setup_needing_space();
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            device_volume_ids: vec!["vol-other".to_string(), "vol-test".to_string()],
+            ..disk::MockDiskMgr::default()
+        });
+        let logs = capture_logs(|| {
+            ctx.ebs_manager.create_and_attach_volume(SizeGib(150), None).unwrap();
+        });
+        assert!(logs.iter().any(|log| log.contains("is vol-other rather than vol-test")));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "set_delete_on_termination"), 1);
 
-impl fmt::Display for MaxLogicalVolumeSizeExceededError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Maximum logical volume size exceeded")
+        ctx.ebs_manager.config.device_mismatch_retries = 1;
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            device_volume_ids: vec!["vol-other".to_string(); 2],
+            ..disk::MockDiskMgr::default()
+        });
+        let err = ctx.ebs_manager.create_and_attach_volume(SizeGib(150), None).unwrap_err();
+        let mismatch = err.downcast_ref::<AttachedDeviceMismatchError>().unwrap();
+        assert_eq!(mismatch.found, "vol-other");
+        assert_eq!(aws::MockAWS::count_calls(&calls, "set_delete_on_termination"), 1);
+        Ok(())
     }
-}
 
-pub struct EBSManager {
-    config: config::Config,
-    diskmgr: Box<dyn disk::DiskMgr>,
-    aws: Box<dyn aws::AWS>,
-    fs: Box<dyn fs::FS>,
-}
+    #[test]
+    fn test_exit_report_on_graceful_and_error_exit() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, _calls) = setup_needing_space();
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
 
-impl EBSManager {
-    pub fn new(
-        conf: config::Config,
-        disks: Box<dyn disk::DiskMgr>,
-        aws_cli: Box<dyn aws::AWS>,
-        fs_lib: Box<dyn fs::FS>
-    ) -> Box<EBSManager> {
-        Box::new(Self {
-            config: conf,
-            diskmgr: disks,
-            aws: aws_cli,
-            fs: fs_lib,
-        })
+        let dir = test_state_dir("exit-report");
+        std::fs::create_dir_all(&dir)?;
+        let path = std::path::Path::new(&dir).join("exit-report.json");
+        let report = exit::ExitReport::new(&snapshot, &Ok(true));
+        let line = report.emit(Some(path.to_str().unwrap()))?;
+        assert_eq!(std::fs::read_to_string(&path)?, format!("{}\n", line));
+        let json: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(json["reason"], "graceful");
+        assert_eq!(json["cleanup_succeeded"], true);
+        assert_eq!(json["volume_count"], snapshot.volume_count.unwrap());
+        assert_eq!(json["actions"]["create_volume"], 1);
+        assert_eq!(json["actions"]["attach_volume"], 1);
+
+        let failed: Result<bool, Box<dyn Error>> = Err(Box::new(MissingPermissionError {
+            action: "ec2:CreateVolume",
+        }));
+        let report = exit::ExitReport::new(&snapshot, &failed);
+        assert_eq!(report.reason, exit::ExitReason::Error);
+        assert!(report.error.unwrap().contains("ec2:CreateVolume"));
+        assert_eq!(report.cleanup_succeeded, None);
+        assert_eq!(report.actions, snapshot.actions);
+        Ok(())
     }
 
-    pub fn power_on_self_test(&self) -> Result<bool, io::Error> {
-        Ok(true)
+    #[test]
+    fn test_exit_report_on_startup_error() -> Result<(), Box<dyn Error>> {
+        // Loading the config failed, so there is no manager and no report path
+        let failed: Result<bool, Box<dyn Error>> = Err(Box::new(config::UnknownProfileError {
+            profile: "staging".to_string(),
+        }));
+        let snapshot = status::SharedStatus::default().snapshot();
+        let line = exit::report(&snapshot, &failed, None).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(json["reason"], "error");
+        assert!(json["error"].as_str().unwrap().contains("[profiles.staging]"));
+        assert_eq!(json["volume_count"], serde_json::Value::Null);
+        assert_eq!(json["cleanup_succeeded"], serde_json::Value::Null);
+        Ok(())
     }
 
-    pub fn need_more_space(&mut self) -> Result<bool, Box<dyn Error>> {
-        let dev_count = self.aws.count_mounted_ebs_volumes();
-        let threshold = self.calc_threshold(dev_count).unwrap();
-        let disk_utilization = self.diskmgr.disk_usage_percent(
-            self.config.mountpoint.clone()
-        )?;
+    #[test]
+    fn test_volume_stuck_creating_is_deleted() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS { creating_checks: u32::MAX, ..aws::MockAWS::default() };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        let mock_clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(mock_clock.clone());
+        ctx.ebs_manager.config.volume_create_timeout_secs = 60;
 
-        if disk_utilization >= threshold {
-            info!("Low disk space - adding more disks");
-            return Ok(true);
-        }
-        Ok(false)
+        let err = ctx.ebs_manager.create_and_attach_volume(SizeGib(150), None).unwrap_err();
+        let timeout = err.downcast_ref::<VolumeCreateTimeoutError>().unwrap();
+        assert_eq!(timeout.volume_id, "vol-test");
+        assert!(calls.lock().unwrap().contains(&"delete_ebs_volume vol-test".to_string()));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 0);
+        let waited: Duration = mock_clock.sleeps.lock().unwrap().iter().sum();
+        assert_eq!(waited, Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_state_errors_while_creating() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS { volume_state_failures: 2, ..aws::MockAWS::default() };
+        let calls = mock_aws.calls.clone();
+        let mut ctx = setup(disk::MockDiskMgr::default(), mock_aws, fs::MockFS::default())?;
+        ctx.ebs_manager.clock = Box::new(clock::MockClock::default());
+        ctx.ebs_manager.config.volume_create_timeout_secs = 60;
+        // Failed checks are retried, and the volume used once it is available
+        ctx.ebs_manager.create_and_attach_volume(SizeGib(150), None)?;
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 1);
 
+        // A state that never comes back is a timeout, and the volume is not leaked
+        calls.lock().unwrap().clear();
+        ctx.ebs_manager.aws = Box::new(aws::MockAWS {
+            volume_state_failures: u32::MAX,
+            calls: calls.clone(),
+            ..aws::MockAWS::default()
+        });
+        let err = ctx.ebs_manager.create_and_attach_volume(SizeGib(150), None).unwrap_err();
+        assert!(err.is::<VolumeCreateTimeoutError>());
+        assert!(calls.lock().unwrap().contains(&"delete_ebs_volume vol-test".to_string()));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 0);
+        Ok(())
     }
 
-    pub fn add_more_space(&mut self, dev_count: u32) -> Result<bool, Box<dyn Error>> {
-        if dev_count >= self.config.limits.max_ebs_volume_count {
-            return Err(Box::new(MaxEBSCountExceededError));
-        }
-        let cur_size = self.diskmgr.disk_size(self.config.mountpoint.clone())?;
-        if cur_size >= self.config.limits.max_logical_volume_size.into() {
-           return Err(Box::new(MaxLogicalVolumeSizeExceededError));
-        }
-        let new_size = self.calc_new_size(dev_count).unwrap();
-        let created_volumes = self.aws.get_managed_ebs_volumes();
-        // TODO - check AWS payload to filter by this
-        // https://github.com/awslabs/amazon-ebs-autoscale/blob/8c8ac28914bf9302e4f5821ba99daac6e7fc05eb/bin/create-ebs-volume#L192
-        /*let attached_volumes_count = created_volumes
-            .filter(
-                |ebs_data|
-                ebs_data.as_ref()
-                        .map_or(false, true)
-            );
-        // TODO - same here
-        // https://github.com/awslabs/amazon-ebs-autoscale/blob/8c8ac28914bf9302e4f5821ba99daac6e7fc05eb/bin/create-ebs-volume#L223
-        // https://blog.malware.re/2022/12/23/Rust-filter-map/index.html
-        let total_created_volumes_size = created_volumes
-            .map();
-        if total_created_volumes_size >= self.config.limits.max_logical_volume_size {
-            return Err(Box::new(MaxLogicalVolumeSizeExceededError));
-        }
-        if attached_volumes_count >= self.config.limits.max_ebs_volume_count ||
-            created_volumes.len() >= self.config.limits.max_ebs_volume_count
-        {
-            return Err(Box::new(MaxEBSCountExceededError))
-        }*/
-        info!(
-            "Will extend volume {} by {}GB",
-            self.config.mountpoint.clone(),
-            new_size.clone()
+    #[test]
+    fn test_ec2_endpoint_url_override() {
+        let sdk_config = aws_config::SdkConfig::builder()
+            .region(aws_sdk_ec2::config::Region::new("us-gov-west-1"))
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .build();
+        let ec2_config = aws::ec2_sdk_config(
+            &sdk_config, Some("https://ec2.us-gov-west-1.amazonaws.com")
         );
-        Ok(self.aws.request_ebs_volume(
-            new_size.into(),
-            self.config.volume.vol_type.clone(),
-            self.config.volume.encrypted.clone(),
-            self.config.volume.throughput.clone(),
-        )
-            .and_then(
-                |_ret|
-                self.diskmgr.get_next_logical_device()
-                    .map_err(|_e| Box::new(aws::GenericAWSError))
-            )
-            .and_then(|dev| self.aws.attach_ebs_volume(dev))
-            .and_then(|dev| self.aws.tag_as_delete_on_term(dev))
-            .and_then(
-                |dev|
-                self.fs.expand_volume(dev)
-                    .map_err(|_e| Box::new(aws::GenericAWSError))
-            )?)
+        assert_eq!(ec2_config.endpoint_url(), Some("https://ec2.us-gov-west-1.amazonaws.com"));
+        assert_eq!(ec2_config.region().map(|region| region.as_ref()), Some("us-gov-west-1"));
+
+        let regional = aws::ec2_sdk_config(&sdk_config, None);
+        assert_eq!(regional.endpoint_url(), None);
+        assert_eq!(regional.region(), sdk_config.region());
     }
 
-    fn calc_threshold(&self, dev_count: u32) -> Option<u32> {
-        if dev_count >= 4 && dev_count <= 6 {
-            return Some(80);
-        }
-        if dev_count > 6 && dev_count <= 10 {
-            return Some(90)
-        }
-        if dev_count > 10 {
-            return Some(90)
-        }
-        Some(self.config.limits.initial_utilization_threshold)
+    fn test_state_dir(name: &str) -> String {
+        let dir = std::env::temp_dir()
+            .join(format!("ebs-autoscale-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_string_lossy().into_owned()
     }
 
-    fn calc_new_size(&self, dev_count: u32) -> Option<u32> {
-        Some(10)
+    #[test]
+    fn test_run_balance_clears_pending() {
+        let runner = fs::MockCommandRunner::default();
+        let state = fs::RebalanceState::new(&test_state_dir("balance-ok"));
+        assert!(fs::run_balance(&runner, &state, "/mnt/data").is_ok());
+        assert_eq!(state.pending(), None);
+        assert_eq!(
+            *runner.calls.lock().unwrap(),
+            vec!["btrfs balance start -m /mnt/data".to_string()]
+        );
     }
-}
 
+    #[test]
+    fn test_cancelled_balance_is_resumed() {
+        let state_dir = test_state_dir("balance-resume");
+        let runner = fs::MockCommandRunner {
+            failing: vec!["btrfs balance start -m /mnt/data".to_string()],
+            ..fs::MockCommandRunner::default()
+        };
+        let state = fs::RebalanceState::new(&state_dir);
+        assert!(fs::run_balance(&runner, &state, "/mnt/data").is_err());
+        assert_eq!(state.pending(), Some("/mnt/data".to_string()));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use figment::{Figment, providers::{Format, Toml, Serialized}};
+        // Shutdown with the balance still pending cancels it and keeps the marker
+        let runner = fs::MockCommandRunner::default();
+        assert!(fs::drain_rebalance(&runner, &state, Duration::ZERO).unwrap());
+        assert_eq!(
+            *runner.calls.lock().unwrap(),
+            vec!["btrfs balance cancel /mnt/data".to_string()]
+        );
+        assert_eq!(state.pending(), Some("/mnt/data".to_string()));
+    }
 
-    struct Context {
-        ebs_manager: Box<EBSManager>,
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_concrete_fs_resumes_pending_balance() {
+        let state_dir = test_state_dir("balance-concrete");
+        let runner = fs::MockCommandRunner::default();
+        let calls = runner.calls.clone();
+        let concrete_fs = fs::ConcreteFS {
+            fs_type: "btrfs".to_string(),
+            mountpoint: "/mnt/data".to_string(),
+            runner: Box::new(runner),
+            rebalance: fs::RebalanceState::new(&state_dir),
+            reserved_blocks_percent: None,
+        };
+        assert!(!concrete_fs.resume_pending_work().unwrap());
+        concrete_fs.rebalance.mark_pending("/mnt/data").unwrap();
+        assert!(concrete_fs.resume_pending_work().unwrap());
+        assert_eq!(concrete_fs.rebalance.pending(), None);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["btrfs balance start -m /mnt/data".to_string()]
+        );
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_expand_volume_through_privileged_helper() {
+        let runner = fs::MockCommandRunner::default();
+        let calls = runner.calls.clone();
+        let concrete_fs = fs::ConcreteFS {
+            fs_type: "btrfs".to_string(),
+            mountpoint: "/mnt/data".to_string(),
+            runner: Box::new(fs::PrivilegedCommandRunner {
+                helper: vec!["sudo".to_string(), "-n".to_string()],
+                inner: Box::new(runner),
+            }),
+            rebalance: fs::RebalanceState::new(&test_state_dir("privileged-helper")),
+            reserved_blocks_percent: None,
+        };
+        assert!(concrete_fs.expand_volume("/dev/xvdb".to_string()).unwrap());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "sudo -n btrfs device add /dev/xvdb /mnt/data".to_string(),
+                "sudo -n btrfs balance start -m /mnt/data".to_string(),
+            ]
+        );
 
-    fn setup(
-        mock_diskmgr: disk::MockDiskMgr,
-        mock_aws: aws::MockAWS,
-        mock_fs: fs::MockFS,
-    ) -> Result<Context, Box<dyn Error>> {
-        let config : config::Config = Figment::from(Serialized::defaults(config::Config::default()))
-            .merge(Toml::file("Test.toml"))
-            .extract()?;
-        let mock_diskmgr : Box<dyn disk::DiskMgr> = Box::new(mock_diskmgr);
-        let mock_aws = Box::new(mock_aws);
-        let mock_fs = Box::new(mock_fs);
-        Ok(Context {
-            ebs_manager: EBSManager::new(
-                config,
-                mock_diskmgr,
-                mock_aws,
-                mock_fs
-            ),
-        })
+        // Without a helper, commands run unchanged
+        let runner = fs::MockCommandRunner::default();
+        let calls = runner.calls.clone();
+        let direct = fs::PrivilegedCommandRunner { helper: Vec::new(), inner: Box::new(runner) };
+        direct.run("growpart", &["/dev/xvdb", "1"]).unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["growpart /dev/xvdb 1".to_string()]);
     }
 
     #[test]
-    fn test_power_on_self_test() -> Result<(), io::Error> {
+    fn test_drain_rebalance_nothing_pending() {
+        let runner = fs::MockCommandRunner::default();
+        let state = fs::RebalanceState::new(&test_state_dir("balance-none"));
+        assert!(!fs::drain_rebalance(&runner, &state, Duration::ZERO).unwrap());
+        assert!(runner.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_power_on_self_test_imds_unreachable() {
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
-            aws::MockAWS::default(),
+            aws::MockAWS {
+                imds_available: false,
+                ..aws::MockAWS::default()
+            },
             fs::MockFS::default()
         ).unwrap();
-        assert_eq!(ctx.ebs_manager.power_on_self_test()?, true);
-        Ok(())
+        let err = ctx.ebs_manager.power_on_self_test().unwrap_err();
+        assert!(err.is::<ImdsUnavailableError>());
+        // A single override is not enough to place new volumes
+        ctx.ebs_manager.config.region = Some("us-east-1".to_string());
+        assert!(ctx.ebs_manager.power_on_self_test().is_err());
     }
 
     #[test]
-    fn test_need_more_space_false() -> Result<(), Box<dyn Error>> {
+    fn test_power_on_self_test_missing_write_permission() {
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
-            aws::MockAWS::default(),
+            aws::MockAWS {
+                permission_probes: vec![
+                    (aws::Ec2Action::CreateVolume, aws::PermissionProbe::Denied),
+                ],
+                ..aws::MockAWS::default()
+            },
             fs::MockFS::default()
         ).unwrap();
-        assert_eq!(ctx.ebs_manager.need_more_space()?, false);
-        Ok(())
-    }
-
-    #[test]
-    fn test_need_more_space_true() -> Result<(), Box<dyn Error>> {
-        let mut ctx = setup(disk::MockDiskMgr {
-            disks: vec!["test".to_string()],
-            utilization_percentage: 95,
-            total_disk_size: 100,
-            sim_no_more_device_names: false,
-        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
-        assert_eq!(ctx.ebs_manager.need_more_space()?, true);
-        Ok(())
+        let err = ctx.ebs_manager.power_on_self_test().unwrap_err();
+        let err = err.downcast_ref::<MissingPermissionError>().unwrap();
+        assert_eq!(err.action, "ec2:CreateVolume");
+        assert!(err.to_string().contains("ec2:CreateVolume"));
     }
 
     #[test]
-    fn test_add_more_space_no_available_dev_name() -> Result<(), Box<dyn Error>> {
-        let mut ctx = setup(disk::MockDiskMgr {
-            disks: vec!["test".to_string()],
-            utilization_percentage: 95,
-            total_disk_size: 100,
-            sim_no_more_device_names: true,
-        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
-        assert!(ctx.ebs_manager.add_more_space(101).is_err());
-        Ok(())
+    fn test_power_on_self_test_missing_read_permission() {
+        let mut ctx = setup(
+            disk::MockDiskMgr::default(),
+            aws::MockAWS {
+                permission_probes: vec![
+                    (aws::Ec2Action::DescribeVolumes, aws::PermissionProbe::Denied),
+                ],
+                ..aws::MockAWS::default()
+            },
+            fs::MockFS::default()
+        ).unwrap();
+        let err = ctx.ebs_manager.power_on_self_test().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MissingPermissionError>().unwrap().action,
+            "ec2:DescribeVolumes"
+        );
     }
 
     #[test]
-    fn test_add_more_space_max_ebs_count() -> Result<(), Box<dyn Error>> {
+    fn test_power_on_self_test_inconclusive_permission() -> Result<(), Box<dyn Error>> {
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
-            aws::MockAWS::default(),
+            aws::MockAWS {
+                permission_probes: vec![(
+                    aws::Ec2Action::AttachVolume,
+                    aws::PermissionProbe::Inconclusive("throttled".to_string()),
+                )],
+                ..aws::MockAWS::default()
+            },
             fs::MockFS::default()
-        ).unwrap();
-        assert!(ctx.ebs_manager.add_more_space(101).is_err());
+        )?;
+        assert!(ctx.ebs_manager.power_on_self_test()?);
         Ok(())
     }
 
     #[test]
-    fn test_add_more_space_max_logical_size() -> Result<(), Box<dyn Error>> {
-        let mut ctx = setup(disk::MockDiskMgr {
-            disks: vec!["test".to_string()],
-            utilization_percentage: 10,
-            total_disk_size: 1000,
-            sim_no_more_device_names: false,
-        }, aws::MockAWS::default(), fs::MockFS::default()).unwrap();
-        assert!(ctx.ebs_manager.add_more_space(10).is_err());
-        Ok(())
+    fn test_permission_probe_from_error_code() {
+        assert_eq!(
+            aws::PermissionProbe::from_error_code(Some("DryRunOperation"), String::new()),
+            aws::PermissionProbe::Allowed
+        );
+        assert_eq!(
+            aws::PermissionProbe::from_error_code(Some("UnauthorizedOperation"), String::new()),
+            aws::PermissionProbe::Denied
+        );
+        assert_eq!(
+            aws::PermissionProbe::from_error_code(None, "timed out".to_string()),
+            aws::PermissionProbe::Inconclusive("timed out".to_string())
+        );
     }
 
     #[test]
-    fn test_add_more_space_aws_err() -> Result<(), Box<dyn Error>> {
+    fn test_power_on_self_test_imds_overrides() -> Result<(), Box<dyn Error>> {
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
             aws::MockAWS {
-                simulate_aws_err: true,
-                mounted_devices_count: 1
+                imds_available: false,
+                ..aws::MockAWS::default()
             },
             fs::MockFS::default()
         ).unwrap();
-        assert!(ctx.ebs_manager.add_more_space(1).is_err());
+        ctx.ebs_manager.config.region = Some("us-east-1".to_string());
+        ctx.ebs_manager.config.availability_zone = Some("us-east-1a".to_string());
+        assert!(ctx.ebs_manager.power_on_self_test()?);
         Ok(())
     }
 
     #[test]
-    fn test_add_more_space_logical_volume_err() -> Result<(), Box<dyn Error>> {
+    fn test_add_more_space_post_attach_settle() -> Result<(), Box<dyn Error>> {
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
             aws::MockAWS::default(),
-            fs::MockFS {
-                simulate_fs_err: true
-            }
+            fs::MockFS::default()
         ).unwrap();
-        assert!(ctx.ebs_manager.add_more_space(10).is_err());
+        let mock_clock = clock::MockClock::default();
+        ctx.ebs_manager.clock = Box::new(mock_clock.clone());
+        ctx.ebs_manager.add_more_space(1)?;
+        assert!(mock_clock.sleeps.lock().unwrap().is_empty());
+
+        ctx.ebs_manager.config.post_attach_settle_ms = 500;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert_eq!(*mock_clock.sleeps.lock().unwrap(), vec![Duration::from_millis(500)]);
         Ok(())
     }
 
-    #[test]
-    fn test_add_more_space() -> Result<(), Box<dyn Error>> {
-        let mut ctx = setup(
+    fn setup_with_detached_volume() -> (Context, Arc<Mutex<Vec<String>>>) {
+        let mut detached = managed_volume("vol-detached", false);
+        detached.state = "available".to_string();
+        detached.device = None;
+        detached.instance_id = None;
+        let mut other_instance = detached.clone();
+        other_instance.volume_id = "vol-other".to_string();
+        other_instance.tags
+            .insert(aws::OWNER_TAG.to_string(), "i-0fedcba9876543210".to_string());
+        let mock_aws = aws::MockAWS {
+            managed_volumes: vec![managed_volume("vol-attached", false), detached, other_instance],
+            ..aws::MockAWS::default()
+        };
+        let calls = mock_aws.calls.clone();
+        let ctx = setup(
             disk::MockDiskMgr::default(),
-            aws::MockAWS::default(),
+            mock_aws,
             fs::MockFS::default()
         ).unwrap();
-        assert!(ctx.ebs_manager.add_more_space(10).is_ok());
+        (ctx, calls)
+    }
+
+    #[test]
+    fn test_reconcile_attachments_detects_detached() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_with_detached_volume();
+        assert_eq!(ctx.ebs_manager.reconcile_attachments()?, vec!["vol-detached".to_string()]);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "attach_ebs_volume"), 0);
         Ok(())
     }
 
     #[test]
-    fn test_calc_threshold() {
+    fn test_reconcile_attachments_reattaches() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_with_detached_volume();
+        ctx.ebs_manager.config.reattach_detached_volumes = true;
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::BelowThreshold)
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "attach_ebs_volume vol-detached /dev/test".to_string(),
+                "set_delete_on_termination /dev/test true".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    fn published_events(calls: &Arc<Mutex<Vec<String>>>) -> Vec<serde_json::Value> {
+        calls.lock().unwrap()
+            .iter()
+            .filter_map(|call| call.strip_prefix("publish_event "))
+            .map(|call| {
+                let (_topic, message) = call.split_once(' ').unwrap();
+                serde_json::from_str(message).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_publish_scale_up_event() -> Result<(), Box<dyn Error>> {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
-            aws::MockAWS::default(),
+            mock_aws,
             fs::MockFS::default()
         ).unwrap();
-        assert_eq!(ctx.ebs_manager.calc_threshold(1),
-                   Some(ctx.ebs_manager.config.limits.initial_utilization_threshold)
+        ctx.ebs_manager.power_on_self_test()?;
+        ctx.ebs_manager.add_more_space(1)?;
+        assert!(published_events(&calls).is_empty());
+
+        let topic = "arn:aws:sns:us-east-1:123456789012:ebs-autoscale";
+        ctx.ebs_manager.config.sns_topic_arn = Some(topic.to_string());
+        ctx.ebs_manager.add_more_space(1)?;
+        assert!(calls.lock().unwrap().iter().any(|call| call.starts_with(&format!("publish_event {} ", topic))));
+        assert_eq!(published_events(&calls), vec![serde_json::json!({
+            "event": "scale_up",
+            "instance_id": "i-0123456789abcdef0",
+            "mountpoint": ctx.ebs_manager.config.mountpoint,
+            "volume_id": "vol-test",
+            "size_gib": 150,
+            "total_size_gib": 250,
+        })]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish_limit_reached_event_once() -> Result<(), Box<dyn Error>> {
+        let mut ctx = setup_at_max_size(config::OnMaxSize::Error);
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        ctx.ebs_manager.aws = Box::new(mock_aws);
+        ctx.ebs_manager.config.sns_topic_arn = Some("arn:aws:sns:us-east-1:123456789012:ebs".to_string());
+        assert!(ctx.ebs_manager.reconcile().is_err());
+        assert!(ctx.ebs_manager.reconcile().is_err());
+        let events = published_events(&calls);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "limit_reached");
+        assert_eq!(events[0]["volume_id"], serde_json::Value::Null);
+        assert_eq!(events[0]["total_size_gib"], 1000);
+        Ok(())
+    }
+
+    fn setup_needing_space() -> (Context, Arc<Mutex<Vec<String>>>) {
+        let mock_aws = aws::MockAWS::default();
+        let calls = mock_aws.calls.clone();
+        let ctx = setup(disk::MockDiskMgr {
+            utilization_percentage: 95,
+            ..disk::MockDiskMgr::default()
+        }, mock_aws, fs::MockFS::default()).unwrap();
+        (ctx, calls)
+    }
+
+    #[test]
+    fn test_reconcile_paused_by_config() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_needing_space();
+        let mut conf = config::Config::default();
+        conf.paused = true;
+        ctx.ebs_manager.reload_config(conf);
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::Paused)
         );
-        for dev_count in [4, 5, 6] {
-            assert_eq!(ctx.ebs_manager.calc_threshold(dev_count), Some(80));
-        }
-        for dev_count in [7, 8, 9, 10] {
-            assert_eq!(ctx.ebs_manager.calc_threshold(dev_count), Some(90));
-        }
-        assert_eq!(ctx.ebs_manager.calc_threshold(11), Some(90));
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+        let snapshot = ctx.ebs_manager.status_handle().snapshot();
+        assert!(snapshot.paused);
+        assert_eq!(snapshot.utilization_percent, Some(95));
+
+        ctx.ebs_manager.reload_config(config::Config::default());
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        Ok(())
     }
 
     #[test]
-    fn test_calc_new_size() {
-        // TODO https://github.com/awslabs/amazon-ebs-autoscale/blob/8c8ac28914bf9302e4f5821ba99daac6e7fc05eb/bin/ebs-autoscale#L123
+    fn test_status_post_routes_need_token_or_loopback() {
+        let loopback: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: std::net::IpAddr = "10.0.0.5".parse().unwrap();
+        assert!(status::authorized("GET", remote, None, Some("secret")));
+        // Without a token only loopback may change the daemon
+        assert!(status::authorized("POST", loopback, None, None));
+        assert!(!status::authorized("POST", remote, None, None));
+        // With one it is needed from anywhere
+        assert!(!status::authorized("POST", loopback, None, Some("secret")));
+        assert!(!status::authorized("POST", remote, Some("Bearer wrong"), Some("secret")));
+        assert!(status::authorized("POST", remote, Some("Bearer secret"), Some("secret")));
+    }
+
+    #[test]
+    fn test_reconcile_paused_at_runtime() -> Result<(), Box<dyn Error>> {
+        let (mut ctx, calls) = setup_needing_space();
+        let shared = ctx.ebs_manager.status_handle();
+        assert_eq!(status::route("POST", "/pause", &shared).status, 200);
+        assert_eq!(
+            ctx.ebs_manager.reconcile()?,
+            ReconcileOutcome::NoActionNeeded(SkipReason::Paused)
+        );
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 0);
+        let response = status::route("GET", "/status", &shared);
+        let body: serde_json::Value = serde_json::from_str(&response.body)?;
+        assert_eq!(body["paused"], true);
+        assert_eq!(body["utilization_percent"], 95);
+        assert_eq!(body["last_outcome"], "Paused");
+
+        assert_eq!(status::route("POST", "/resume", &shared).status, 200);
+        assert_eq!(ctx.ebs_manager.reconcile()?, ReconcileOutcome::ScaledUp);
+        assert_eq!(aws::MockAWS::count_calls(&calls, "request_ebs_volume"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_route_not_found() {
+        let shared = status::SharedStatus::default();
+        assert_eq!(status::route("GET", "/nope", &shared).status, 404);
+        assert_eq!(status::route("GET", "/pause", &shared).status, 404);
+        let body: serde_json::Value =
+            serde_json::from_str(&status::route("GET", "/nope", &shared).body).unwrap();
+        assert_eq!(body["error"]["kind"], "not_found");
+        assert_eq!(body["error"]["message"], "No route for GET /nope");
+    }
+
+    #[test]
+    fn test_live_and_ready_transitions() -> Result<(), Box<dyn Error>> {
         let mut ctx = setup(
             disk::MockDiskMgr::default(),
             aws::MockAWS::default(),
-            fs::MockFS::default()
-        ).unwrap();
-        assert_eq!(ctx.ebs_manager.calc_new_size(1),
-                   Some(ctx.ebs_manager.config.limits.initial_utilization_threshold)
-        );
-        for dev_count in [4, 5, 6] {
-            assert_eq!(ctx.ebs_manager.calc_new_size(dev_count), Some(80));
-        }
-        for dev_count in [7, 8, 9, 10] {
-            assert_eq!(ctx.ebs_manager.calc_new_size(dev_count), Some(90));
+            fs::MockFS::default(),
+        )?;
+        let shared = ctx.ebs_manager.status_handle();
+        let live = status::route("GET", "/live", &shared);
+        assert_eq!(live.status, 503);
+        assert!(live.body.contains("not_live"));
+        shared.set_live();
+        assert_eq!(status::route("GET", "/live", &shared).status, 200);
+
+        let ready = status::route("GET", "/ready", &shared);
+        assert_eq!(ready.status, 503);
+        assert!(ready.body.contains("not_ready"));
+        ctx.ebs_manager.power_on_self_test()?;
+        assert_eq!(status::route("GET", "/ready", &shared).status, 503);
+        // A failed reconcile does not count
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            sim_missing_mountpoint: true,
+            ..disk::MockDiskMgr::default()
+        });
+        assert!(ctx.ebs_manager.reconcile().is_err());
+        assert_eq!(status::route("GET", "/ready", &shared).status, 503);
+
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr::default());
+        ctx.ebs_manager.reconcile()?;
+        let ready = status::route("GET", "/ready", &shared);
+        assert_eq!((ready.status, ready.body.as_str()), (200, r#"{"ready":true}"#));
+
+        // Not ready again once reconciling keeps failing
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr {
+            sim_missing_mountpoint: true,
+            ..disk::MockDiskMgr::default()
+        });
+        for _ in 1..MAX_CONSECUTIVE_RECONCILE_ERRORS {
+            assert!(ctx.ebs_manager.reconcile().is_err());
+            assert_eq!(status::route("GET", "/ready", &shared).status, 200);
         }
-        assert_eq!(ctx.ebs_manager.calc_new_size(11), Some(90));
+        assert!(ctx.ebs_manager.reconcile().is_err());
+        assert_eq!(status::route("GET", "/ready", &shared).status, 503);
+        ctx.ebs_manager.diskmgr = Box::new(disk::MockDiskMgr::default());
+        ctx.ebs_manager.reconcile()?;
+        assert_eq!(status::route("GET", "/ready", &shared).status, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_internal_error() {
+        let shared = status::SharedStatus::default();
+        // A panic while updating poisons the snapshot, so reading it panics too
+        let poisoner = shared.clone();
+        let _ = std::thread::spawn(move || {
+            poisoner.update(|_| panic!("tick panicked"));
+        }).join();
+        let response = status::respond("GET", "/status", &shared);
+        assert_eq!(response.status, 500);
+        let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["error"]["kind"], "internal");
+        assert!(body["error"]["message"].as_str().unwrap().contains("PoisonError"));
+        // Routes that do not touch the snapshot keep working
+        assert_eq!(status::respond("POST", "/pause", &shared).status, 200);
     }
 }
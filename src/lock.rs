@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct StateLockedError {
+    pub path: PathBuf,
+}
+
+impl Error for StateLockedError {}
+
+impl fmt::Display for StateLockedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is held by another ebs-autoscale-rust process for the same mountpoint",
+            self.path.display()
+        )
+    }
+}
+
+/// Lock file of a mountpoint, e.g. mnt-data.lock for /mnt/data
+///
+/// Escaped as systemd escapes paths, `-` becoming `\x2d`, so /mnt/data-x and /mnt/data/x
+/// get different locks.
+pub fn lock_path(state_dir: &str, mountpoint: &str) -> PathBuf {
+    let name = mountpoint.trim_matches('/').replace('-', "\\x2d").replace('/', "-");
+    let name = if name.is_empty() { "root" } else { name.as_str() };
+    Path::new(state_dir).join(format!("{}.lock", name))
+}
+
+/// Exclusive advisory lock (flock) on the state of a mountpoint, released when dropped
+///
+/// Held for the life of the daemon, so a second instance for the same mountpoint
+/// cannot touch the state files.
+pub struct StateLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl StateLock {
+    pub fn acquire(state_dir: &str, mountpoint: &str) -> Result<StateLock, Box<dyn Error>> {
+        let path = lock_path(state_dir, mountpoint);
+        fs::create_dir_all(state_dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(Box::new(StateLockedError { path }));
+            }
+            return Err(Box::new(err));
+        }
+        Ok(StateLock { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Command lines that belong to the bash amazon-ebs-autoscale
+const CONFLICTING_AUTOSCALERS: &[&str] = &["amazon-ebs-autoscale", "bin/ebs-autoscale"];
+
+#[derive(Debug, Clone)]
+pub struct ConflictingAutoscalerError {
+    pub pid: u32,
+    pub cmdline: String,
+}
+
+impl Error for ConflictingAutoscalerError {}
+
+impl fmt::Display for ConflictingAutoscalerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Another autoscaler is running (pid {} - {}), stop it or start with --force",
+            self.pid, self.cmdline
+        )
+    }
+}
+
+/// A running process and its command line, arguments separated by spaces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    pub pid: u32,
+    pub cmdline: String,
+}
+
+/// Lists the processes running on the host
+pub trait ProcessLister: Send {
+    fn list_processes(&self) -> Result<Vec<Process>, io::Error>;
+}
+
+#[cfg(target_os = "linux")]
+/// Lists processes from /proc/<pid>/cmdline
+pub struct ProcProcessLister;
+
+#[cfg(target_os = "linux")]
+impl ProcessLister for ProcProcessLister {
+    fn list_processes(&self) -> Result<Vec<Process>, io::Error> {
+        let mut processes = Vec::new();
+        for entry in fs::read_dir("/proc")? {
+            let entry = entry?;
+            let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+                continue;
+            };
+            // Processes may exit while listing
+            let Ok(cmdline) = fs::read(entry.path().join("cmdline")) else {
+                continue;
+            };
+            let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ");
+            processes.push(Process { pid, cmdline: cmdline.trim_end().to_string() });
+        }
+        Ok(processes)
+    }
+}
+
+pub struct MockProcessLister {
+    pub processes: Vec<Process>,
+}
+
+impl ProcessLister for MockProcessLister {
+    fn list_processes(&self) -> Result<Vec<Process>, io::Error> {
+        Ok(self.processes.clone())
+    }
+}
+
+/// Refuses to start while the bash amazon-ebs-autoscale runs, as both would scale the
+/// same filesystem
+pub fn check_conflicting_autoscalers(lister: &dyn ProcessLister) -> Result<(), Box<dyn Error>> {
+    let own_pid = std::process::id();
+    for process in lister.list_processes()? {
+        let conflicting = process.cmdline.split(' ').any(|arg| {
+            CONFLICTING_AUTOSCALERS.iter().any(|name| arg.ends_with(name))
+        });
+        if conflicting && process.pid != own_pid {
+            return Err(Box::new(ConflictingAutoscalerError {
+                pid: process.pid,
+                cmdline: process.cmdline,
+            }));
+        }
+    }
+    Ok(())
+}
@@ -1,50 +1,274 @@
 use core::time;
 use figment::{Figment, providers::{Format, Toml, Json, Env, Serialized}};
-use log::{info, trace};
+use log::{info, trace, error};
 use std::error::Error;
+use std::process::ExitCode;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
+use std::sync::mpsc;
 use std::thread;
+use std::time::Instant;
 use signal_hook::flag;
-use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::consts::{SIGHUP, SIGTERM, TERM_SIGNALS};
+use signal_hook::low_level;
 use signal_hook::iterator::SignalsInfo;
 use signal_hook::iterator::exfiltrator::WithOrigin;
-use ebs_autoscale_rust::config::Config;
+use ebs_autoscale_rust::config::{self, Config};
+use ebs_autoscale_rust::{
+    delete_volumes_on_termination, drain, drain_filesystem_work, exit, lock, migrate,
+    status, watchdog, ApiLimiter, EBSManager, ReconcileOutcome,
+};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let figment = Figment::from(Serialized::defaults(Config::default()))
+/// Profile selected with --profile <name>, or else EBS_AUTOSCALE_PROFILE
+fn active_profile() -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--profile");
+    args.next()?;
+    args.next()
+}
+
+fn load_config() -> Result<Config, Box<dyn Error>> {
+    let profile = active_profile().or_else(|| std::env::var(config::PROFILE_ENV).ok());
+    let files = Figment::from(Serialized::defaults(Config::default()))
         .merge(Toml::file("ebs-autoscale.toml"))
-        .merge(Env::prefixed("EBS_AUTOSCALE_"))
         .join(Json::file("ebs-autoscale.json"));
+    // Environment variables still take precedence over the selected profile, and
+    // instance tags over both
+    let mut figment = config::select_profile(files.clone(), profile.as_deref())?
+        .merge(Env::prefixed("EBS_AUTOSCALE_"));
+    if figment.extract_inner::<bool>("config_from_instance_tags").unwrap_or(false) {
+        let tags = ebs_autoscale_rust::instance_tags()?;
+        figment = figment.merge(config::TagProvider { tags });
+    }
+    let mut config = config::extract(&figment)?;
+    if let Some(unit) = config.apply_mount_unit(config::MOUNT_UNIT_DIRS)? {
+        info!(
+            "Using mountpoint {} of {} from {}",
+            unit.mount_where,
+            unit.what,
+            config.mount_unit.as_deref().unwrap_or_default()
+        );
+    }
+    if config.strict_config {
+        config::reject_unknown_keys(&files)?;
+    }
+    config.validate()?;
+    Ok(config)
+}
 
-    let config : Config = figment.extract()?;
+/// State file given with --import-state <path>
+fn import_state_path() -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--import-state");
+    args.next()?;
+    args.next()
+}
 
-    env_logger::init_from_env(
-        env_logger::Env::default()
-            .filter_or("LOG_LEVEL", "info")
-    );
-    info!("Started ebs autoscaler - config {:?}", config);
+/// Number of steps requested with --plan <steps>
+fn plan_steps() -> Option<Result<u32, Box<dyn Error>>> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--plan");
+    args.next()?;
+    let steps = match args.next() {
+        Some(steps) => steps,
+        None => return Some(Err("--plan needs a number of steps".into())),
+    };
+    Some(steps.parse::<u32>().map_err(Into::into))
+}
+
+/// Where the log filter comes from, for both loggers
+fn log_env() -> env_logger::Env<'static> {
+    env_logger::Env::default().filter_or("LOG_LEVEL", "info")
+}
+
+/// Logs to the journal when built with journald and it is available, to stderr otherwise
+fn init_logging() {
+    #[cfg(feature = "journald")]
+    {
+        let filter = env_logger::Logger::from_env(log_env());
+        if ebs_autoscale_rust::journal::JournalLogger::init(filter) {
+            return;
+        }
+    }
+    env_logger::init_from_env(log_env());
+}
+
+/// How a run ended
+enum Ran {
+    /// The daemon stopped, with whether its cleanup succeeded
+    Daemon(bool),
+    /// A one-shot mode finished with this exit code, having printed its own output
+    OneShot(ExitCode),
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    // Before loading the config, so what loading it logs is not lost
+    init_logging();
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            // Without a config there is no exit_report_path, only stdout gets the report
+            let result: Result<bool, Box<dyn Error>> = Err(e);
+            exit::report(&status::SharedStatus::default().snapshot(), &result, None);
+            return result.map(|_| ExitCode::SUCCESS);
+        }
+    };
+    let report_path = config.exit_report_path.clone();
+    let mut exit_status = status::SharedStatus::default();
+    let result = match run(config, &mut exit_status) {
+        Ok(Ran::OneShot(code)) => return Ok(code),
+        Ok(Ran::Daemon(cleanup_succeeded)) => Ok(cleanup_succeeded),
+        Err(e) => Err(e),
+    };
+    exit::report(&exit_status.snapshot(), &result, report_path.as_deref());
+    result.map(|_| ExitCode::SUCCESS)
+}
+
+/// Starts the daemon, or runs the one-shot mode selected on the command line
+///
+/// --once exits with the code of its reconcile outcome, so a skipped tick tells why.
+/// exit_status is set to the status of the manager once there is one, so the exit
+/// report of a failed startup has what it had done.
+fn run(
+    config: Config, exit_status: &mut status::SharedStatus,
+) -> Result<Ran, Box<dyn Error>> {
+    info!("Started ebs autoscaler - effective config {}", config.to_redacted_json()?);
 
     let term_now = Arc::new(AtomicBool::new(false));
     for sig in TERM_SIGNALS {
         flag::register_conditional_shutdown(*sig, 1, Arc::clone(&term_now))?;
         flag::register(*sig, Arc::clone(&term_now))?;
     }
-    let mut signals = SignalsInfo::<WithOrigin>::new(TERM_SIGNALS)?;
+    let signals = SignalsInfo::<WithOrigin>::new(
+        TERM_SIGNALS.iter().chain(&[SIGHUP])
+    )?;
+
+    let shutdown_config = config.clone();
+    let limiter = ApiLimiter::new(config.max_concurrent_aws_calls);
+    let mut ebs_manager = EBSManager::from_config(config, limiter.clone())?;
+    *exit_status = ebs_manager.status_handle();
+    if std::env::args().any(|arg| arg == "--report") {
+        println!("{}", serde_json::to_string_pretty(&ebs_manager.report()?)?);
+        return Ok(Ran::OneShot(ExitCode::SUCCESS));
+    }
+    if let Some(steps) = plan_steps() {
+        println!("{}", serde_json::to_string_pretty(&ebs_manager.plan(steps?))?);
+        return Ok(Ran::OneShot(ExitCode::SUCCESS));
+    }
+    if !std::env::args().any(|arg| arg == "--force") {
+        lock::check_conflicting_autoscalers(&lock::ProcProcessLister)?;
+    }
+    if std::env::args().any(|arg| arg == "--export-state") {
+        println!("{}", ebs_manager.export_state()?.to_json()?);
+        return Ok(Ran::OneShot(ExitCode::SUCCESS));
+    }
+    if let Some(path) = import_state_path() {
+        let _state_lock = lock::StateLock::acquire(
+            &shutdown_config.state_dir,
+            &shutdown_config.mountpoint,
+        )?;
+        let state = migrate::DaemonState::from_json(&std::fs::read_to_string(path)?)?;
+        ebs_manager.import_state(&state)?;
+        return Ok(Ran::OneShot(ExitCode::SUCCESS));
+    }
+    if std::env::args().any(|arg| arg == "--once") {
+        let _state_lock = lock::StateLock::acquire(
+            &shutdown_config.state_dir,
+            &shutdown_config.mountpoint,
+        )?;
+        ebs_manager.power_on_self_test()?;
+        ebs_manager.reconcile_existing_volumes()?;
+        let outcome = if std::env::args().any(|arg| arg == "--trace") {
+            let (outcome, decisions) = ebs_manager.reconcile_traced();
+            println!("{}", serde_json::to_string_pretty(&decisions)?);
+            outcome?
+        } else {
+            ebs_manager.reconcile()?
+        };
+        info!("Reconciled once - {:?}", outcome);
+        return Ok(Ran::OneShot(ExitCode::from(outcome.exit_code())));
+    }
+    run_daemon(ebs_manager, &shutdown_config, limiter, term_now, signals).map(Ran::Daemon)
+}
 
+/// Runs the autoscaling loop until a termination signal, then cleans up
+///
+/// Returns whether the cleanup succeeded.
+fn run_daemon(
+    mut ebs_manager: Box<EBSManager>,
+    shutdown_config: &Config,
+    limiter: ApiLimiter,
+    term_now: Arc<AtomicBool>,
+    mut signals: SignalsInfo<WithOrigin>,
+) -> Result<bool, Box<dyn Error>> {
+    // Held until exit, so a second daemon for the mountpoint refuses to start
+    let _state_lock = lock::StateLock::acquire(
+        &shutdown_config.state_dir,
+        &shutdown_config.mountpoint,
+    )?;
+    ebs_manager.power_on_self_test()?;
+    ebs_manager.reconcile_existing_volumes()?;
+    ebs_manager.bootstrap_if_missing()?;
+    if let Err(e) = ebs_manager.allocate_panic_reserve() {
+        error!("Could not allocate the panic reserve - {}", e);
+    }
+    if let Some(addr) = &shutdown_config.status_addr {
+        let token = shutdown_config.status_token.clone();
+        status::serve(addr, ebs_manager.status_handle(), token)?;
+    }
+    let (reload_tx, reload_rx) = mpsc::channel::<Config>();
+    if let Err(e) = ebs_manager.resume_filesystem_work() {
+        error!("Could not resume interrupted filesystem work - {}", e);
+    }
+    let loop_status = ebs_manager.status_handle();
+    let drain = drain::Drain::default();
+    ebs_manager.set_drain(drain.clone());
+    let heartbeat = watchdog::Heartbeat::default();
+    if let Some(timeout) = watchdog::timeout(shutdown_config) {
+        let abort = shutdown_config.watchdog_abort;
+        watchdog::spawn(heartbeat.clone(), timeout, time::Duration::from_secs(1), move |elapsed| {
+            error!(
+                "CRITICAL - no reconcile finished in {}s, the detection thread may be hung",
+                elapsed.as_secs()
+            );
+            if abort {
+                std::process::abort();
+            }
+        });
+    }
+    let loop_drain = drain.clone();
     thread::spawn(move || {
+        loop_status.set_live();
         while !term_now.load(Ordering::Relaxed) {
+           let Some(in_flight) = loop_drain.begin() else {
+               break;
+           };
+           if let Ok(new_config) = reload_rx.try_recv() {
+               ebs_manager.reload_config(new_config);
+           }
            trace!("Checking if autoscaling is needed");
-           thread::sleep(
-               time::Duration::from_secs(config.detection_interval.into())
-           );
+           match ebs_manager.reconcile() {
+               Ok(ReconcileOutcome::Exit) => {
+                   let _ = low_level::raise(SIGTERM);
+                   break;
+               }
+               Ok(outcome) => trace!("Reconcile finished - {:?}", outcome),
+               Err(e) => error!("Reconcile failed - {}", e),
+           }
+           drop(in_flight);
+           heartbeat.beat();
+           loop_drain.sleep(ebs_manager.detection_interval());
         }
         trace!("Stopped checking...");
     });
 
     for info in &mut signals {
         match info.signal {
+            SIGHUP => match load_config() {
+                Ok(new_config) => {
+                    info!("Received SIGHUP - reloading config");
+                    let _ = reload_tx.send(new_config);
+                }
+                Err(e) => error!("Could not reload config - {}", e),
+            },
             _sigint => {
                 info!("Gracefully shutting down ebs autoscaler");
                 break;
@@ -52,11 +276,28 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    if config.ensure_ebs_deleted_on_term {
+    let cleanup_deadline = Instant::now()
+        + time::Duration::from_secs(shutdown_config.termination_timeout_secs);
+    // Let an in-flight reconcile finish attaching before cleaning up behind it
+    drain.request();
+    if !drain.wait(cleanup_deadline) {
+        error!("Reconcile still in flight at the termination timeout, cleaning up anyway");
+    }
+    let mut cleanup_succeeded = true;
+    if let Err(e) = drain_filesystem_work(shutdown_config) {
+        error!("Could not drain filesystem work - {}", e);
+        cleanup_succeeded = false;
+    }
+
+    if shutdown_config.ensure_ebs_deleted_on_term {
         info!("Deleting ebs volumes before termination...");
-        thread::sleep(time::Duration::from_millis(10000));
+        let timeout = cleanup_deadline.saturating_duration_since(Instant::now());
+        if let Err(e) = delete_volumes_on_termination(shutdown_config, limiter, timeout) {
+            error!("Could not delete ebs volumes - {}", e);
+            cleanup_succeeded = false;
+        }
     }
     info!("Finished ebs autoscaler gracefully");
 
-    Ok(())
+    Ok(cleanup_succeeded)
 }
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+use crate::aws::ManagedVolume;
+
+/// Version of the exported state format, bumped on incompatible changes
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct IncompatibleStateVersionError {
+    pub version: u32,
+}
+
+impl Error for IncompatibleStateVersionError {}
+
+impl fmt::Display for IncompatibleStateVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Exported state version {} is not supported - this build reads version {}",
+            self.version, STATE_VERSION
+        )
+    }
+}
+
+/// State carried to another instance with --export-state and --import-state
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DaemonState {
+    pub version: u32,
+    pub mountpoint: String,
+    /// Managed volumes making up the logical volume
+    pub managed_volumes: Vec<ManagedVolume>,
+    /// Volumes in the logical volume, which volume names are numbered after
+    pub volume_count: u32,
+    /// Last ModifyVolume of each volume in seconds since the Unix epoch, for the cooldown
+    pub volume_modifications: BTreeMap<String, u64>,
+    /// Mountpoint of an interrupted btrfs balance
+    pub rebalance_pending: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+impl DaemonState {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Parses an exported state, checking its version before the rest of the fields
+    pub fn from_json(json: &str) -> Result<DaemonState, Box<dyn Error>> {
+        let Versioned { version } = serde_json::from_str(json)?;
+        check_version(version)?;
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+pub fn check_version(version: u32) -> Result<(), IncompatibleStateVersionError> {
+    if version != STATE_VERSION {
+        return Err(IncompatibleStateVersionError { version });
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::json;
+
+use crate::config;
+use crate::event::{AutoscaleEvent, EventKind};
+
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends JSON bodies over HTTP, so notifiers can be tested without a server
+pub trait HttpTransport: Send {
+    fn post_json(&self, url: &str, body: &str) -> Result<(), Box<dyn Error>>;
+}
+
+pub struct UreqTransport;
+
+impl HttpTransport for UreqTransport {
+    fn post_json(&self, url: &str, body: &str) -> Result<(), Box<dyn Error>> {
+        ureq::post(url)
+            .timeout(POST_TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(body)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MockTransport {
+    /// Bodies posted so far, as (url, body)
+    pub posts: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl HttpTransport for MockTransport {
+    fn post_json(&self, url: &str, body: &str) -> Result<(), Box<dyn Error>> {
+        self.posts.lock().unwrap().push((url.to_string(), body.to_string()));
+        Ok(())
+    }
+}
+
+/// Tells operators about autoscale events
+pub trait Notifier: Send {
+    fn notify(&self, event: AutoscaleEvent) -> Result<(), Box<dyn Error>>;
+}
+
+/// Drops every event, used when no notifier is configured
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {
+    fn notify(&self, _event: AutoscaleEvent) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// POSTs each event as JSON, in the same format as the SNS messages
+pub struct WebhookNotifier {
+    pub url: String,
+    pub transport: Box<dyn HttpTransport>,
+}
+
+impl WebhookNotifier {
+    pub fn payload(event: &AutoscaleEvent) -> Result<String, serde_json::Error> {
+        serde_json::to_string(event)
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, event: AutoscaleEvent) -> Result<(), Box<dyn Error>> {
+        self.transport.post_json(&self.url, &WebhookNotifier::payload(&event)?)
+    }
+}
+
+/// Posts each event as a message to a Slack incoming webhook
+pub struct SlackNotifier {
+    pub webhook_url: String,
+    pub transport: Box<dyn HttpTransport>,
+}
+
+impl SlackNotifier {
+    pub fn payload(event: &AutoscaleEvent) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&json!({ "text": slack_text(event) }))
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn notify(&self, event: AutoscaleEvent) -> Result<(), Box<dyn Error>> {
+        self.transport.post_json(&self.webhook_url, &SlackNotifier::payload(&event)?)
+    }
+}
+
+fn slack_text(event: &AutoscaleEvent) -> String {
+    let instance = event.instance_id.as_deref().unwrap_or("unknown instance");
+    let volume = event.volume_id.as_deref().unwrap_or("unknown");
+    match event.event {
+        EventKind::ScaleUp => format!(
+            "Added {} volume {} to {} on {}, now {}",
+            event.size_gib.unwrap_or_default(), volume, event.mountpoint, instance,
+            event.total_size_gib
+        ),
+        EventKind::ScaleDown => format!(
+            "Removed volume {} from {} on {}, now {}",
+            volume, event.mountpoint, instance, event.total_size_gib
+        ),
+        EventKind::LimitReached => format!(
+            ":warning: {} on {} reached its size limit at {}",
+            event.mountpoint, instance, event.total_size_gib
+        ),
+        EventKind::HighUtilization => format!(
+            ":warning: {} on {} is {}% full",
+            event.mountpoint, instance, event.utilization_percent.unwrap_or_default()
+        ),
+        EventKind::HighUtilizationCleared => format!(
+            "{} on {} is back to {}% full",
+            event.mountpoint, instance, event.utilization_percent.unwrap_or_default()
+        ),
+        EventKind::ExpandFailed => format!(
+            ":warning: could not grow {} on {} onto volume {}, left attached",
+            event.mountpoint, instance, volume
+        ),
+        EventKind::PanicReserveReleased => format!(
+            ":rotating_light: {} on {} is {}% full and not scaling, released the panic reserve",
+            event.mountpoint, instance, event.utilization_percent.unwrap_or_default()
+        ),
+    }
+}
+
+/// Notifier selected by config, a no-op if none is set
+pub fn from_config(conf: &config::Config) -> Box<dyn Notifier> {
+    match &conf.notifier {
+        None => Box::new(NoopNotifier),
+        Some(config::NotifierConfig::Webhook(url)) => Box::new(WebhookNotifier {
+            url: url.clone(),
+            transport: Box::new(UreqTransport),
+        }),
+        Some(config::NotifierConfig::Slack(webhook_url)) => Box::new(SlackNotifier {
+            webhook_url: webhook_url.clone(),
+            transport: Box::new(UreqTransport),
+        }),
+    }
+}
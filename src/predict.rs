@@ -0,0 +1,48 @@
+use crate::config::GrowthStrategy;
+use crate::size::SizeGib;
+
+/// What the size of the next volume is predicted from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeContext {
+    /// Size of the logical volume
+    pub current_size: SizeGib,
+    /// Volumes already in the logical volume
+    pub volume_count: u32,
+    /// Last sampled disk utilization, None before the first sample
+    pub utilization_percent: Option<u32>,
+    /// Utilization change in percent per second between the last two samples, negative
+    /// when it dropped, None before the second sample
+    pub fill_rate: Option<f64>,
+}
+
+/// Sizes the next volume
+///
+/// The prediction is still clamped to min_ebs_volume_size and max_ebs_volume_size.
+pub trait SizePredictor: Send {
+    fn predict(&self, context: &SizeContext) -> SizeGib;
+}
+
+/// Sizes volumes with a growth_strategy, the default
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrategySizePredictor {
+    pub strategy: GrowthStrategy,
+    /// Size of the Tiered strategy from the eleventh volume on
+    pub max_ebs_volume_size: SizeGib,
+}
+
+impl SizePredictor for StrategySizePredictor {
+    fn predict(&self, context: &SizeContext) -> SizeGib {
+        match self.strategy {
+            GrowthStrategy::Tiered => match context.volume_count {
+                0..=3 => SizeGib(150),
+                4..=6 => SizeGib(300),
+                7..=10 => SizeGib(1000),
+                _ => self.max_ebs_volume_size,
+            },
+            GrowthStrategy::FixedGb(size) => size,
+            GrowthStrategy::PercentOfCurrent(percent) => {
+                context.current_size.percent(percent.into())
+            }
+        }
+    }
+}
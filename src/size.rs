@@ -0,0 +1,74 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+const BYTES_PER_GIB: u64 = 1 << 30;
+const BYTES_PER_GB: u64 = 1_000_000_000;
+
+/// A size in GiB (2^30 bytes), the unit EBS sizes and bills volumes in
+///
+/// Serialized as a plain number, so config files and JSON output are unchanged.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct SizeGib(pub u64);
+
+impl SizeGib {
+    pub const ZERO: SizeGib = SizeGib(0);
+
+    /// Whole GiB in a number of bytes, rounded down
+    pub fn from_bytes(bytes: u64) -> SizeGib {
+        SizeGib(bytes / BYTES_PER_GIB)
+    }
+
+    /// Smallest size holding gb decimal gigabytes (10^9 bytes)
+    pub fn from_gb_rounded_up(gb: u64) -> SizeGib {
+        let bytes = u128::from(gb) * u128::from(BYTES_PER_GB);
+        let gib = bytes.div_ceil(u128::from(BYTES_PER_GIB));
+        SizeGib(gib.try_into().unwrap_or(u64::MAX))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Size in bytes, None on overflow
+    pub fn as_bytes(self) -> Option<u64> {
+        self.0.checked_mul(BYTES_PER_GIB)
+    }
+
+    pub fn checked_add(self, other: SizeGib) -> Option<SizeGib> {
+        self.0.checked_add(other.0).map(SizeGib)
+    }
+
+    pub fn saturating_add(self, other: SizeGib) -> SizeGib {
+        SizeGib(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: SizeGib) -> SizeGib {
+        SizeGib(self.0.saturating_sub(other.0))
+    }
+
+    /// percent of the size, rounded down
+    pub fn percent(self, percent: u64) -> SizeGib {
+        SizeGib(self.0.saturating_mul(percent) / 100)
+    }
+}
+
+impl fmt::Display for SizeGib {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}GiB", self.0)
+    }
+}
+
+impl From<u32> for SizeGib {
+    fn from(gib: u32) -> SizeGib {
+        SizeGib(gib.into())
+    }
+}
+
+impl From<SizeGib> for u64 {
+    fn from(size: SizeGib) -> u64 {
+        size.0
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use log::{error, info, warn};
+use serde::Serialize;
+
+/// Last values observed by the autoscaling loop
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StatusSnapshot {
+    pub paused: bool,
+    pub utilization_percent: Option<u32>,
+    pub last_outcome: Option<String>,
+    /// Why the last tick did not scale, if it did not
+    pub skip_reason: Option<crate::SkipReason>,
+    /// Detection interval after throttling backoff, in seconds
+    pub effective_interval_secs: Option<u64>,
+    /// Utilization has been above alert_utilization_threshold for long enough
+    pub high_utilization_alert: bool,
+    /// Volumes that cannot be grown with ModifyVolume yet, with when (Unix seconds)
+    /// their cooldown ends
+    pub modify_cooldown_until: BTreeMap<String, u64>,
+    /// Last IOPS utilization fed with POST /iops-utilization/<percent>
+    pub iops_utilization_percent: Option<u32>,
+    /// Seconds the last filesystem grow took, by filesystem type
+    pub fs_grow_duration_seconds: BTreeMap<String, f64>,
+    /// Room left to grow under each limit, as of the last tick
+    pub remaining_capacity: Option<crate::RemainingCapacity>,
+    /// Volumes in the logical volume, as of the last tick
+    pub volume_count: Option<u32>,
+    /// Size of the logical volume, as of the last tick
+    pub total_size_gib: Option<crate::size::SizeGib>,
+    /// Successful mutating actions since startup by action, e.g. create_volume
+    pub actions: BTreeMap<String, u64>,
+}
+
+/// State shared between the autoscaling loop and the status listener
+#[derive(Clone, Default)]
+pub struct SharedStatus {
+    paused: Arc<AtomicBool>,
+    live: Arc<AtomicBool>,
+    preflight_passed: Arc<AtomicBool>,
+    reconciled: Arc<AtomicBool>,
+    /// IOPS utilization samples fed so far, so a repeated value still counts as fresh
+    iops_samples: Arc<AtomicU64>,
+    snapshot: Arc<Mutex<StatusSnapshot>>,
+}
+
+impl SharedStatus {
+    /// Runtime pause toggle, independent of the `paused` config
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Marks the daemon loop as running, for /live
+    pub fn set_live(&self) {
+        self.live.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    pub fn set_preflight_passed(&self) {
+        self.preflight_passed.store(true, Ordering::Relaxed);
+    }
+
+    /// Records whether reconciling currently succeeds
+    pub fn set_reconciled(&self, reconciled: bool) {
+        self.reconciled.store(reconciled, Ordering::Relaxed);
+    }
+
+    /// Ready once the power-on self test passed and reconciling succeeds, for /ready
+    pub fn is_ready(&self) -> bool {
+        self.preflight_passed.load(Ordering::Relaxed) && self.reconciled.load(Ordering::Relaxed)
+    }
+
+    /// Records an IOPS utilization fed with POST /iops-utilization/<percent>
+    pub fn feed_iops_utilization(&self, percent: u32) {
+        self.update(|snapshot| snapshot.iops_utilization_percent = Some(percent));
+        self.iops_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn iops_samples_fed(&self) -> u64 {
+        self.iops_samples.load(Ordering::Relaxed)
+    }
+
+    pub fn update<F: FnOnce(&mut StatusSnapshot)>(&self, f: F) {
+        f(&mut self.snapshot.lock().unwrap());
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Response {
+        match serde_json::to_string(body) {
+            Ok(body) => Response { status, content_type: "application/json", body },
+            Err(e) => Response::error(500, "serialization", &e.to_string()),
+        }
+    }
+
+    /// Error envelope: {"error": {"kind": ..., "message": ...}}
+    pub fn error(status: u16, kind: &str, message: &str) -> Response {
+        let body = serde_json::json!({ "error": { "kind": kind, "message": message } });
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    /// Prometheus text exposition format
+    fn metrics(body: String) -> Response {
+        Response { status: 200, content_type: "text/plain; version=0.0.4", body }
+    }
+}
+
+/// Renders the metrics of a snapshot in the Prometheus text format
+pub fn metrics(snapshot: &StatusSnapshot) -> String {
+    let mut body = String::from("# TYPE ebs_autoscale_fs_grow_duration_seconds gauge\n");
+    for (fs_type, secs) in &snapshot.fs_grow_duration_seconds {
+        body.push_str(&format!(
+            "ebs_autoscale_fs_grow_duration_seconds{{fs_type=\"{}\"}} {}\n",
+            fs_type, secs
+        ));
+    }
+    if let Some(capacity) = &snapshot.remaining_capacity {
+        body.push_str("# TYPE ebs_autoscale_remaining_capacity_gib gauge\n");
+        body.push_str(&format!(
+            "ebs_autoscale_remaining_capacity_gib {}\n",
+            capacity.by_size_gib.as_u64()
+        ));
+        body.push_str("# TYPE ebs_autoscale_remaining_volumes gauge\n");
+        body.push_str(&format!("ebs_autoscale_remaining_volumes {}\n", capacity.by_count));
+        if let Some(by_cost) = capacity.by_cost {
+            body.push_str("# TYPE ebs_autoscale_remaining_monthly_cost_usd gauge\n");
+            body.push_str(&format!("ebs_autoscale_remaining_monthly_cost_usd {}\n", by_cost));
+        }
+    }
+    body
+}
+
+/// Renders the metrics of a snapshot as DogStatsD lines, with tags added to each
+pub fn statsd_lines(snapshot: &StatusSnapshot, tags: &[String]) -> Vec<String> {
+    let line = |name: &str, value: String, extra: Option<String>| {
+        let tags: Vec<String> = tags.iter().cloned().chain(extra).collect();
+        if tags.is_empty() {
+            format!("{}:{}|g", name, value)
+        } else {
+            format!("{}:{}|g|#{}", name, value, tags.join(","))
+        }
+    };
+    let mut lines = Vec::new();
+    for (fs_type, secs) in &snapshot.fs_grow_duration_seconds {
+        lines.push(line(
+            "ebs_autoscale_fs_grow_duration_seconds",
+            secs.to_string(),
+            Some(format!("fs_type:{}", fs_type)),
+        ));
+    }
+    if let Some(capacity) = &snapshot.remaining_capacity {
+        lines.push(line(
+            "ebs_autoscale_remaining_capacity_gib",
+            capacity.by_size_gib.as_u64().to_string(),
+            None,
+        ));
+        lines.push(line("ebs_autoscale_remaining_volumes", capacity.by_count.to_string(), None));
+        if let Some(by_cost) = capacity.by_cost {
+            let name = "ebs_autoscale_remaining_monthly_cost_usd";
+            lines.push(line(name, by_cost.to_string(), None));
+        }
+    }
+    lines
+}
+
+/// Sends metrics over UDP to a StatsD agent, without ever blocking the caller
+pub struct StatsdSink {
+    socket: UdpSocket,
+    addr: String,
+    tags: Vec<String>,
+}
+
+impl StatsdSink {
+    pub fn new(addr: &str, tags: Vec<String>) -> io::Result<StatsdSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdSink { socket, addr: addr.to_string(), tags })
+    }
+
+    /// Sends the metrics of a snapshot as one datagram, dropping it if the agent is not there
+    pub fn send(&self, snapshot: &StatusSnapshot) {
+        let lines = statsd_lines(snapshot, &self.tags);
+        if lines.is_empty() {
+            return;
+        }
+        if let Err(e) = self.socket.send_to(lines.join("\n").as_bytes(), &self.addr) {
+            warn!("Could not send metrics to StatsD at {} - {}", self.addr, e);
+        }
+    }
+}
+
+/// How long a client may take to send its request, as the endpoint serves one at a time
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a request may be routed: POST routes change the daemon, so they need the
+/// bearer token if one is configured, and otherwise a loopback peer
+pub fn authorized(
+    method: &str, peer: IpAddr, authorization: Option<&str>, token: Option<&str>,
+) -> bool {
+    if method != "POST" {
+        return true;
+    }
+    match token {
+        Some(token) => authorization == Some(format!("Bearer {}", token).as_str()),
+        None => peer.is_loopback(),
+    }
+}
+
+/// Handles a request to the status endpoint
+pub fn route(method: &str, path: &str, shared: &SharedStatus) -> Response {
+    match (method, path) {
+        ("GET", "/status") => Response::json(200, &shared.snapshot()),
+        ("GET", "/metrics") => Response::metrics(metrics(&shared.snapshot())),
+        ("GET", "/live") if shared.is_live() => {
+            Response::json(200, &serde_json::json!({ "live": true }))
+        }
+        ("GET", "/live") => Response::error(503, "not_live", "The daemon loop is not running yet"),
+        ("GET", "/ready") if shared.is_ready() => {
+            Response::json(200, &serde_json::json!({ "ready": true }))
+        }
+        ("GET", "/ready") => Response::error(
+            503,
+            "not_ready",
+            "Waiting for the power-on self test and a successful reconcile",
+        ),
+        ("POST", "/pause") => {
+            info!("Pausing scaling from the status endpoint");
+            shared.set_paused(true);
+            Response::json(200, &serde_json::json!({ "paused": true }))
+        }
+        ("POST", "/resume") => {
+            info!("Resuming scaling from the status endpoint");
+            shared.set_paused(false);
+            Response::json(200, &serde_json::json!({ "paused": false }))
+        }
+        ("POST", path) if path.starts_with("/iops-utilization/") => {
+            let percent = &path["/iops-utilization/".len()..];
+            match percent.parse::<u32>() {
+                Ok(percent) => {
+                    shared.feed_iops_utilization(percent);
+                    Response::json(200, &serde_json::json!({ "iops_utilization_percent": percent }))
+                }
+                Err(_) => Response::error(
+                    400,
+                    "bad_request",
+                    &format!("Invalid IOPS utilization {}, expected a percentage", percent),
+                ),
+            }
+        }
+        _ => Response::error(404, "not_found", &format!("No route for {} {}", method, path)),
+    }
+}
+
+/// Routes a request, turning a handler panic into a 500 so the listener keeps serving
+pub fn respond(method: &str, path: &str, shared: &SharedStatus) -> Response {
+    panic::catch_unwind(AssertUnwindSafe(|| route(method, path, shared))).unwrap_or_else(|cause| {
+        let message = cause
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| cause.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "handler panicked".to_string());
+        error!("Status handler for {} {} panicked - {}", method, path, message);
+        Response::error(500, "internal", &message)
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream, shared: &SharedStatus, token: Option<&str>,
+) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let peer = stream.peer_addr()?.ip();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Only Authorization is needed, but all headers must be drained before responding
+    let mut authorization = None;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let response = if authorized(method, peer, authorization.as_deref(), token) {
+        respond(method, path, shared)
+    } else {
+        warn!("Refusing {} {} from {} without the status token", method, path, peer);
+        Response::error(403, "forbidden", "POST routes need the status token")
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+/// Serves the status endpoint on a background thread
+pub fn serve(
+    addr: &str, shared: SharedStatus, token: Option<String>,
+) -> Result<thread::JoinHandle<()>, io::Error> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Status endpoint listening on {}", addr);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| {
+                handle_connection(stream, &shared, token.as_deref())
+            });
+            if let Err(e) = result {
+                warn!("Status request failed - {}", e);
+            }
+        }
+    }))
+}
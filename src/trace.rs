@@ -0,0 +1,29 @@
+use serde::Serialize;
+
+/// A gate evaluated during a reconcile
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Gate {
+    pub name: &'static str,
+    /// Values the gate compared, e.g. "utilization 95% / threshold 70%"
+    pub detail: String,
+    /// Whether the gate let the reconcile go on toward scaling
+    pub passed: bool,
+}
+
+/// Gates evaluated by one reconcile, in order, and what it did
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DecisionTrace {
+    pub gates: Vec<Gate>,
+    pub outcome: Option<String>,
+}
+
+impl DecisionTrace {
+    pub fn record(&mut self, name: &'static str, detail: String, passed: bool) {
+        self.gates.push(Gate { name, detail, passed });
+    }
+
+    /// Names of the gates in order, with whether each passed
+    pub fn summary(&self) -> Vec<(&'static str, bool)> {
+        self.gates.iter().map(|gate| (gate.name, gate.passed)).collect()
+    }
+}
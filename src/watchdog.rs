@@ -0,0 +1,70 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Time of the last completed tick, shared between the loop and the watchdog
+#[derive(Clone)]
+pub struct Heartbeat {
+    last: Arc<Mutex<Instant>>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            last: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    pub fn since_last(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+}
+
+/// How long without a completed tick before the loop is considered hung
+///
+/// Based on the longest interval the loop may legitimately sleep, i.e. while backing off
+/// from throttling. None when the watchdog is disabled.
+pub fn timeout(conf: &config::Config) -> Option<Duration> {
+    if conf.watchdog_interval_multiplier == 0 {
+        return None;
+    }
+    let longest_interval = u64::from(conf.detection_interval)
+        .max(conf.max_detection_interval_secs);
+    Some(
+        Duration::from_secs(longest_interval)
+            .saturating_mul(conf.watchdog_interval_multiplier)
+    )
+}
+
+/// Checks the heartbeat every poll, calling on_stall once each time it goes stale
+pub fn spawn<F>(
+    heartbeat: Heartbeat,
+    timeout: Duration,
+    poll: Duration,
+    on_stall: F,
+) -> thread::JoinHandle<()>
+where
+    F: Fn(Duration) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut stalled = false;
+        loop {
+            thread::sleep(poll);
+            let elapsed = heartbeat.since_last();
+            if elapsed <= timeout {
+                stalled = false;
+            } else if !stalled {
+                stalled = true;
+                on_stall(elapsed);
+            }
+        }
+    })
+}